@@ -3,6 +3,7 @@ use bevy::{
     diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin},
     prelude::*,
 };
+use bevy_voxel_engine::TraceStats;
 
 pub struct FpsCounter;
 
@@ -39,11 +40,24 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
     });
 }
 
-fn fps_system(diagnostics: Res<DiagnosticsStore>, mut query: Query<&mut Text>) {
+fn fps_system(
+    diagnostics: Res<DiagnosticsStore>,
+    trace_stats: Res<TraceStats>,
+    mut query: Query<&mut Text>,
+) {
     if let Some(fps) = diagnostics.get(&FrameTimeDiagnosticsPlugin::FPS) {
         if let Some(average) = fps.average() {
+            let mut value = format!("{:.1}", average);
+            // only filled in by cameras with `TraceSettings::collect_stats`
+            if trace_stats.rays > 0 {
+                value += &format!(
+                    "\navg {:.0} / max {} steps",
+                    trace_stats.average_steps, trace_stats.max_steps
+                );
+            }
+
             for mut text in query.iter_mut() {
-                text.sections[0].value = format!("{:.1}", average);
+                text.sections[0].value = value.clone();
             }
         }
     }
@@ -1,4 +1,4 @@
-use super::{character::CharacterEntity, Bullet, Particle, VoxelizationPreviewCamera};
+use super::{Bullet, Particle, VoxelizationPreviewCamera};
 use bevy::{
     core_pipeline::{bloom::BloomSettings, fxaa::Fxaa, tonemapping::Tonemapping},
     prelude::*,
@@ -8,7 +8,8 @@ use bevy_egui::{
     EguiContexts, EguiPlugin,
 };
 use bevy_voxel_engine::{
-    Flags, LoadVoxelWorld, RenderGraphSettings, TraceSettings, VoxelPhysics,
+    DebugView, Flags, LoadVoxelWorld, PhysicsDebug, RenderGraphSettings, SampleSequence,
+    TraceSettings, VoxelFlyCamera, VoxelNode, VoxelPhysics, VoxelWorldDebug,
 };
 use rand::Rng;
 
@@ -21,12 +22,15 @@ impl Plugin for UiPlugin {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn ui_system(
     mut commands: Commands,
     mut contexts: EguiContexts,
-    particle_query: Query<Entity, (With<VoxelPhysics>, Without<CharacterEntity>)>,
+    particle_query: Query<Entity, (With<VoxelPhysics>, Without<VoxelFlyCamera>)>,
     mut load_voxel_world: ResMut<LoadVoxelWorld>,
     mut render_graph_settings: ResMut<RenderGraphSettings>,
+    mut physics_debug: ResMut<PhysicsDebug>,
+    mut voxel_world_debug: ResMut<VoxelWorldDebug>,
     mut camera_settings_query: Query<(
         &mut TraceSettings,
         Option<&mut BloomSettings>,
@@ -34,7 +38,7 @@ fn ui_system(
         Option<&mut Fxaa>,
     )>,
     mut voxelization_preview_camera_query: Query<&mut Camera, With<VoxelizationPreviewCamera>>,
-    mut character_query: Query<&mut CharacterEntity>,
+    mut character_query: Query<&mut VoxelFlyCamera>,
 ) {
     let mut character = character_query.single_mut();
 
@@ -47,15 +51,71 @@ fn ui_system(
                 //     .pick_file().block_on();
 
                 let path = tinyfiledialogs::open_file_dialog("Select file", "", None);
-                *load_voxel_world = LoadVoxelWorld::File(path.unwrap());
+                *load_voxel_world = LoadVoxelWorld::File(path.unwrap(), None);
             }
             for (i, (mut trace_settings, bloom_settings, tonemapping, fxaa)) in
                 camera_settings_query.iter_mut().enumerate()
             {
                 ui.collapsing(format!("Camera Settings {}", i), |ui| {
-                    ui.checkbox(&mut trace_settings.show_ray_steps, "Show ray steps");
+                    egui::ComboBox::from_label("Debug view")
+                        .selected_text(format!("{:?}", trace_settings.debug_view))
+                        .show_ui(ui, |ui| {
+                            for debug_view in [
+                                DebugView::None,
+                                DebugView::RaySteps,
+                                DebugView::Normals,
+                                DebugView::WorldPosition,
+                                DebugView::Depth,
+                            ] {
+                                ui.selectable_value(
+                                    &mut trace_settings.debug_view,
+                                    debug_view,
+                                    format!("{:?}", debug_view),
+                                );
+                            }
+                        });
                     ui.add(Slider::new(&mut trace_settings.samples, 1..=8).text("Samples"));
+                    let mut halton = trace_settings.sample_sequence == SampleSequence::Halton;
+                    if ui.checkbox(&mut halton, "Halton sampling").changed() {
+                        trace_settings.sample_sequence = if halton {
+                            SampleSequence::Halton
+                        } else {
+                            SampleSequence::Random
+                        };
+                    }
                     ui.checkbox(&mut trace_settings.shadows, "Shadows");
+                    ui.add(
+                        Slider::new(&mut trace_settings.shadow_softness, 0.0..=0.2)
+                            .text("Shadow softness"),
+                    );
+                    ui.add(
+                        Slider::new(&mut trace_settings.reflection_bounces, 0..=4)
+                            .text("Reflection bounces"),
+                    );
+                    ui.add(
+                        Slider::new(&mut trace_settings.max_transparency_steps, 0..=64)
+                            .text("Transparency steps"),
+                    );
+                    ui.checkbox(&mut trace_settings.ao, "Ambient occlusion");
+                    ui.add(
+                        Slider::new(&mut trace_settings.ao_radius, 0.1..=4.0).text("AO radius"),
+                    );
+                    ui.add(
+                        Slider::new(&mut trace_settings.render_scale, 0.25..=1.0)
+                            .text("Render scale"),
+                    );
+                    ui.add(
+                        Slider::new(&mut trace_settings.lighting_scale, 0.25..=1.0)
+                            .text("Lighting scale"),
+                    );
+                    ui.add(
+                        Slider::new(&mut trace_settings.exposure, 0.125..=8.0)
+                            .logarithmic(true)
+                            .text("Exposure"),
+                    );
+                    ui.add(
+                        Slider::new(&mut trace_settings.ray_near, 0.0..=2.0).text("Near clip"),
+                    );
                     if let Some(bloom_settings) = bloom_settings {
                         ui.add(
                             Slider::new(&mut bloom_settings.into_inner().intensity, 0.0..=1.0)
@@ -122,7 +182,6 @@ fn ui_system(
                                 )
                                 .clamp_length_max(1.0)
                                     * 10.0,
-                                Vec3::new(0.0, -9.81, 0.0),
                                 bevy_voxel_engine::CollisionEffect::None,
                             ),
                             Bullet { bullet_type: 0 },
@@ -135,16 +194,14 @@ fn ui_system(
                     }
                 }
                 ui.label(format!("Particle count: {}", particle_query.iter().count()));
+                ui.checkbox(&mut physics_debug.enabled, "Show colliders");
+                ui.checkbox(&mut voxel_world_debug.enabled, "Show world bounds");
             });
             
             ui.collapsing("Passes", |ui| {
-                ui.checkbox(&mut render_graph_settings.clear, "clear");
-                ui.checkbox(&mut render_graph_settings.automata, "automata");
-                ui.checkbox(&mut render_graph_settings.animation, "animation");
-                ui.checkbox(&mut render_graph_settings.voxelization, "voxelization");
-                ui.checkbox(&mut render_graph_settings.rebuild, "rebuild");
-                ui.checkbox(&mut render_graph_settings.physics, "physics");
-                ui.checkbox(&mut render_graph_settings.trace, "trace");
+                for node in VoxelNode::ALL {
+                    ui.checkbox(render_graph_settings.get_mut(node), node.name());
+                }
             });
 
             for mut voxelization_preview_camera in voxelization_preview_camera_query.iter_mut() {
@@ -153,6 +210,6 @@ fn ui_system(
                     format!("Preview"),
                 );
             }
-            ui.checkbox(&mut character.in_spectator, "Spectator mode");
+            ui.checkbox(&mut character.spectator, "Spectator mode");
         });
 }
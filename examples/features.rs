@@ -7,13 +7,9 @@ use bevy::{
 };
 use bevy_obj::*;
 use bevy_voxel_engine::*;
-use character::CharacterEntity;
 use rand::Rng;
 use std::f32::consts::PI;
 
-#[path = "common/character.rs"]
-mod character;
-
 #[path = "common/fps_counter.rs"]
 mod fps_counter;
 
@@ -41,7 +37,6 @@ fn main() {
         DefaultPlugins,
         ObjPlugin,
         BevyVoxelEnginePlugin,
-        character::Character,
         ui::UiPlugin,
         fps_counter::FpsCounter,
     ))
@@ -68,7 +63,23 @@ fn setup(
     asset_server: Res<AssetServer>,
 ) {
     // load a voxel world
-    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string());
+    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string(), None);
+
+    // torches
+    commands.insert_resource(PointLights(vec![
+        VoxelPointLight {
+            position: Vec3::new(5.0, 2.0, -5.0),
+            color: Color::rgb(1.0, 0.6, 0.2),
+            intensity: 20.0,
+            range: 12.0,
+        },
+        VoxelPointLight {
+            position: Vec3::new(-5.0, 2.0, 5.0),
+            color: Color::rgb(0.3, 0.5, 1.0),
+            intensity: 20.0,
+            range: 12.0,
+        },
+    ]));
 
     // character portals
     let mut character_portals = vec![None; 2];
@@ -97,6 +108,7 @@ fn setup(
                         voxelization_material: VoxelizationMaterial {
                             material: VoxelizationMaterialType::Material(120 + i as u8),
                             flags: Flags::ANIMATION_FLAG | Flags::COLLISION_FLAG,
+                            ..default()
                         },
                         ..default()
                     });
@@ -121,24 +133,16 @@ fn setup(
                 projection: projection.clone(),
                 ..default()
             },
-            CharacterEntity {
-                in_spectator: false,
-                grounded: false,
-                look_at: -*character_transform.local_z(),
-                up: Vec3::new(0.0, 1.0, 0.0),
+            VoxelFlyCamera {
+                spectator: false,
+                ..default()
             },
             CharacterPortals {
                 portal1: character_portals[0].unwrap(),
                 portal2: character_portals[1].unwrap(),
             },
-            VoxelPhysics::new(
-                Vec3::splat(0.0),
-                Vec3::ZERO, // Gravity handeled in character.rs
-                CollisionEffect::None,
-            ),
-            BoxCollider {
-                half_size: IVec3::new(2, 4, 2),
-            },
+            VoxelPhysics::new(Vec3::splat(0.0), CollisionEffect::None),
+            CapsuleCollider::new(2, 2),
             BloomSettings::default(),
             Fxaa::default(),
         ))
@@ -187,6 +191,7 @@ fn setup(
                     voxelization_material: VoxelizationMaterial {
                         material: VoxelizationMaterialType::Material(120 + i as u8),
                         flags: Flags::ANIMATION_FLAG | Flags::COLLISION_FLAG,
+                        ..default()
                     },
                     ..default()
                 });
@@ -203,6 +208,7 @@ fn setup(
                     asset_server.load("models/suzanne.png"),
                 ),
                 flags: Flags::COLLISION_FLAG | Flags::ANIMATION_FLAG,
+                ..default()
             },
             transform: Transform::from_scale(Vec3::splat(3.0)).looking_at(Vec3::Z, Vec3::Y),
             ..default()
@@ -227,7 +233,7 @@ fn update_suzanne(time: Res<Time>, mut cube: Query<&mut Transform, With<Suzanne>
 fn shoot(
     mut commands: Commands,
     keyboard: Res<ButtonInput<KeyCode>>,
-    mut character: Query<(&Transform, &mut CharacterEntity)>,
+    mut character: Query<(&Transform, &mut VoxelFlyCamera)>,
 ) {
     let (transform, mut character_entity) = character.single_mut();
 
@@ -238,11 +244,7 @@ fn shoot(
                 material: 120,
                 flags: Flags::ANIMATION_FLAG,
             },
-            VoxelPhysics::new(
-                -transform.local_z() * 50.0,
-                Vec3::new(0.0, -9.81, 0.0),
-                CollisionEffect::None,
-            ),
+            VoxelPhysics::new(-transform.local_z() * 50.0, CollisionEffect::None),
             Bullet { bullet_type: 1 },
         ));
     }
@@ -253,11 +255,7 @@ fn shoot(
                 material: 121,
                 flags: Flags::ANIMATION_FLAG,
             },
-            VoxelPhysics::new(
-                -transform.local_z() * 50.0,
-                Vec3::new(0.0, -9.81, 0.0),
-                CollisionEffect::None,
-            ),
+            VoxelPhysics::new(-transform.local_z() * 50.0, CollisionEffect::None),
             Bullet { bullet_type: 2 },
         ));
     }
@@ -273,7 +271,6 @@ fn shoot(
             },
             VoxelPhysics::new(
                 -transform.local_z() * 50.0,
-                Vec3::new(0.0, -9.81, 0.0),
                 CollisionEffect::SetFlags {
                     radius: 3.0,
                     flags: Flags::SAND_FLAG,
@@ -283,18 +280,49 @@ fn shoot(
         ));
     }
 
-    if keyboard.just_pressed(KeyCode::KeyP) {
-        character_entity.in_spectator = !character_entity.in_spectator;
+    // Rocket that carves a hole where it lands
+    if keyboard.just_pressed(KeyCode::KeyR) {
+        commands.spawn((
+            Transform::from_translation(transform.translation),
+            Particle {
+                material: 9,
+                flags: Flags::ANIMATION_FLAG,
+            },
+            VoxelPhysics::new(
+                -transform.local_z() * 40.0,
+                CollisionEffect::Destroy { radius: 2.0 },
+            ),
+            Bullet { bullet_type: 0 },
+        ));
     }
 
-    if keyboard.just_pressed(KeyCode::KeyB) {
+    // Paints the voxels it lands on
+    if keyboard.just_pressed(KeyCode::KeyF) {
         commands.spawn((
             Transform::from_translation(transform.translation),
+            Particle {
+                material: 14,
+                flags: Flags::ANIMATION_FLAG,
+            },
             VoxelPhysics::new(
-                -transform.local_z() * 10.0,
-                Vec3::new(0.0, -9.81, 0.0),
-                CollisionEffect::None,
+                -transform.local_z() * 40.0,
+                CollisionEffect::Paint {
+                    radius: 2.0,
+                    material: 14,
+                },
             ),
+            Bullet { bullet_type: 3 },
+        ));
+    }
+
+    if keyboard.just_pressed(KeyCode::KeyP) {
+        character_entity.spectator = !character_entity.spectator;
+    }
+
+    if keyboard.just_pressed(KeyCode::KeyB) {
+        commands.spawn((
+            Transform::from_translation(transform.translation),
+            VoxelPhysics::new(-transform.local_z() * 10.0, CollisionEffect::None),
             Bullet { bullet_type: 0 },
             BoxCollider {
                 half_size: IVec3::new(3, 3, 3),
@@ -325,7 +353,9 @@ fn spawn_stuff(
     bullet_query: Query<(&Transform, &VoxelPhysics, &Bullet, Entity)>,
     character_query: Query<&CharacterPortals>,
     mut portal_query: Query<&mut Transform, (With<Portal>, Without<Bullet>)>,
+    voxel_world: Res<VoxelWorld>,
 ) {
+    let voxels_per_meter = voxel_world.voxels_per_meter();
     for (transform, velocity, bullet, entity) in bullet_query.iter() {
         if velocity.hit_normal != Vec3::splat(0.0) {
             commands.entity(entity).despawn();
@@ -335,7 +365,7 @@ fn spawn_stuff(
 
                 let plane = 1.0 - normal.abs();
                 let pos =
-                    (transform.translation * plane * VOXELS_PER_METER).floor() / VOXELS_PER_METER;
+                    (transform.translation * plane * voxels_per_meter).floor() / voxels_per_meter;
                 let pos = pos + transform.translation * normal.abs();
 
                 let character_portals = character_query.single();
@@ -373,7 +403,6 @@ fn spawn_stuff(
                                 rng.gen_range(-1.0..1.0),
                                 rng.gen_range(-1.0..1.0),
                             ) * 10.0,
-                            Vec3::new(0.0, -9.81, 0.0),
                             bevy_voxel_engine::CollisionEffect::None,
                         ),
                     ));
@@ -0,0 +1,93 @@
+use bevy::{
+    app::{AppExit, ScheduleRunnerPlugin},
+    core::FrameCount,
+    prelude::*,
+    window::ExitCondition,
+    winit::WinitPlugin,
+};
+use bevy_voxel_engine::{
+    BevyVoxelEnginePlugin, ImageReadback, LoadVoxelWorld, VoxelCameraBundle, VoxelRenderTarget,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+// frames to wait for the world to load and the pipelines to compile before capturing
+const CAPTURE_FRAME: u32 = 30;
+
+fn main() {
+    App::new()
+        .add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .disable::<WinitPlugin>(),
+        )
+        .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0)))
+        .add_plugins(BevyVoxelEnginePlugin)
+        .init_resource::<Captured>()
+        .add_systems(Startup, setup)
+        .add_systems(Update, capture)
+        .run();
+}
+
+#[derive(Resource)]
+struct CaptureTarget(Handle<Image>);
+
+#[derive(Resource, Default)]
+struct Captured(Arc<AtomicBool>);
+
+fn setup(
+    mut commands: Commands,
+    mut load_voxel_world: ResMut<LoadVoxelWorld>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string(), None);
+
+    let target = VoxelRenderTarget::image(UVec2::new(1280, 720), &mut images);
+    if let VoxelRenderTarget::Image(image) = &target {
+        commands.insert_resource(CaptureTarget(image.clone()));
+    }
+
+    commands.spawn(
+        VoxelCameraBundle {
+            transform: Transform::from_xyz(5.0, 5.0, -5.0).looking_at(Vec3::ZERO, Vec3::Y),
+            ..default()
+        }
+        .with_target(target),
+    );
+}
+
+fn capture(
+    frame_count: Res<FrameCount>,
+    capture_target: Res<CaptureTarget>,
+    image_readback: Res<ImageReadback>,
+    captured: Res<Captured>,
+    mut app_exit: EventWriter<AppExit>,
+) {
+    if frame_count.0 == CAPTURE_FRAME {
+        let captured = captured.0.clone();
+        image_readback.read(capture_target.0.clone(), move |result| {
+            match result.map(|image| image.try_into_dynamic()) {
+                Ok(Ok(image)) => match image.save("headless.png") {
+                    Ok(()) => info!("Saved headless.png"),
+                    Err(error) => error!("Failed to save headless.png: {error}"),
+                },
+                Ok(Err(error)) => error!("Failed to convert the image: {error}"),
+                Err(error) => error!("Failed to read the image back: {error}"),
+            }
+            captured.store(true, Ordering::Relaxed);
+        });
+    }
+
+    if captured.0.load(Ordering::Relaxed) {
+        app_exit.send(AppExit);
+    }
+}
@@ -0,0 +1,65 @@
+use bevy::{core_pipeline::fxaa::Fxaa, prelude::*, render::camera::ScalingMode};
+use bevy_voxel_engine::{BevyVoxelEnginePlugin, LoadVoxelWorld, TraceSettings, VoxelCameraBundle};
+use std::f32::consts::PI;
+
+#[path = "common/fps_counter.rs"]
+mod fps_counter;
+
+const CAMERA_DISTANCE: f32 = 40.0;
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .add_plugins(BevyVoxelEnginePlugin)
+        .add_plugins(fps_counter::FpsCounter)
+        .add_systems(Startup, setup)
+        .add_systems(Update, rotate_camera)
+        .run();
+}
+
+#[derive(Component)]
+struct IsometricCamera {
+    angle: f32,
+}
+
+fn isometric_transform(angle: f32) -> Transform {
+    // looking down a diagonal of the voxel grid at 45 degree turns
+    let offset = Vec3::new(angle.cos(), 1.0 / 2.0_f32.sqrt(), angle.sin());
+    Transform::from_translation(offset.normalize() * CAMERA_DISTANCE)
+        .looking_at(Vec3::ZERO, Vec3::Y)
+}
+
+fn setup(mut commands: Commands, mut load_voxel_world: ResMut<LoadVoxelWorld>) {
+    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string(), None);
+
+    let angle = PI / 4.0;
+    commands.spawn((
+        VoxelCameraBundle {
+            transform: isometric_transform(angle),
+            // parallel rays, the world keeps its size no matter how far away the camera is
+            projection: Projection::Orthographic(OrthographicProjection {
+                scaling_mode: ScalingMode::FixedVertical(40.0),
+                far: CAMERA_DISTANCE * 2.0,
+                ..default()
+            }),
+            trace_settings: TraceSettings::builder().ao(true).build(),
+            ..default()
+        },
+        IsometricCamera { angle },
+        Fxaa::default(),
+    ));
+}
+
+/// Q and E turn the view by 90 degrees around the world
+fn rotate_camera(
+    input: Res<ButtonInput<KeyCode>>,
+    mut camera_query: Query<(&mut Transform, &mut IsometricCamera)>,
+) {
+    let (mut transform, mut camera) = camera_query.single_mut();
+
+    let turn = input.just_pressed(KeyCode::KeyE) as i32 - input.just_pressed(KeyCode::KeyQ) as i32;
+    if turn != 0 {
+        camera.angle += turn as f32 * PI / 2.0;
+        *transform = isometric_transform(camera.angle);
+    }
+}
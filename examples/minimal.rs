@@ -4,23 +4,18 @@ use bevy::{
 };
 use bevy_voxel_engine::{
     BevyVoxelEnginePlugin, Edges, Flags, LoadVoxelWorld, Portal, VoxelCameraBundle,
-    VoxelPhysics, CollisionEffect, BoxCollider,
+    VoxelFlyCamera, VoxelPhysics, CollisionEffect, CapsuleCollider,
 };
 use std::f32::consts::PI;
-use character::CharacterEntity;
 
 #[path = "common/fps_counter.rs"]
 mod fps_counter;
 
-#[path = "common/character.rs"]
-mod character;
-
 fn main() {
     App::new()
         .add_plugins(DefaultPlugins)
         .add_plugins(BevyVoxelEnginePlugin)
         .add_plugins(fps_counter::FpsCounter)
-        .add_plugins(character::Character)
         .add_systems(Startup, setup)
         .add_systems(Update, update)
         .run();
@@ -35,7 +30,7 @@ fn setup(
     mut _meshes: ResMut<Assets<Mesh>>,
 ) {
     // Voxel world
-    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string());
+    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string(), None);
 
     // character
     let character_transform = Transform::from_xyz(5.0, 5.0, -5.0)
@@ -53,20 +48,9 @@ fn setup(
             projection: projection.clone(),
             ..default()
         },
-        CharacterEntity {
-            in_spectator: true,
-            grounded: false,
-            look_at: *-character_transform.local_z(),
-            up: Vec3::new(0.0, 1.0, 0.0),
-        },
-        VoxelPhysics::new(
-            Vec3::splat(0.0),
-            Vec3::ZERO, // gravity handeled in character.rs
-            CollisionEffect::None,
-        ),
-        BoxCollider {
-            half_size: IVec3::new(2, 4, 2),
-        },
+        VoxelFlyCamera::default(),
+        VoxelPhysics::new(Vec3::splat(0.0), CollisionEffect::None),
+        CapsuleCollider::new(2, 2),
         Fxaa::default(),
     ));
 
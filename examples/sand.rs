@@ -3,13 +3,10 @@ use bevy::{
     prelude::*,
 };
 use bevy_voxel_engine::{
-    BevyVoxelEnginePlugin, BoxCollider, Flags, LoadVoxelWorld, VoxelCameraBundle, VoxelPhysics,
+    voxel_to_world, world_to_voxel, BevyVoxelEnginePlugin, CapsuleCollider, Flags,
+    LoadVoxelWorld, VoxelCameraBundle, VoxelFlyCamera, VoxelPhysics, VoxelUniforms,
     VoxelizationBundle, VoxelizationMaterial, VoxelizationMaterialType,
 };
-use character::CharacterEntity;
-
-#[path = "common/character.rs"]
-mod character;
 
 #[path = "common/fps_counter.rs"]
 mod fps_counter;
@@ -18,7 +15,6 @@ fn main() {
     App::new()
         .add_plugins(DefaultPlugins)
         .add_plugins(BevyVoxelEnginePlugin)
-        .add_plugins(character::Character)
         .add_plugins(fps_counter::FpsCounter)
         .add_systems(Startup, setup)
         .add_systems(Update, update)
@@ -34,7 +30,7 @@ fn setup(
     mut meshes: ResMut<Assets<Mesh>>,
 ) {
     // voxel world
-    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string());
+    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string(), None);
 
     let transform = Transform::from_xyz(5.0, 5.0, -5.0).looking_at(Vec3::ZERO, Vec3::Y);
 
@@ -47,20 +43,9 @@ fn setup(
             }),
             ..default()
         },
-        CharacterEntity {
-            in_spectator: true,
-            grounded: false,
-            look_at: -*transform.local_z(),
-            up: Vec3::new(0.0, 1.0, 0.0),
-        },
-        VoxelPhysics::new(
-            Vec3::splat(0.0),
-            Vec3::splat(0.0),
-            bevy_voxel_engine::CollisionEffect::None,
-        ),
-        BoxCollider {
-            half_size: IVec3::new(2, 4, 2),
-        },
+        VoxelFlyCamera::default(),
+        VoxelPhysics::new(Vec3::splat(0.0), bevy_voxel_engine::CollisionEffect::None),
+        CapsuleCollider::new(2, 2),
         // supports bloom and fxaa
         BloomSettings::default(),
         Fxaa::default(),
@@ -86,13 +71,17 @@ fn setup(
 
 fn update(
     mut sand_spawner: Query<(&mut Transform, &mut VoxelizationMaterial), With<SandSpawner>>,
-    character_query: Query<&Transform, (With<CharacterEntity>, Without<SandSpawner>)>,
+    character_query: Query<&Transform, (With<VoxelFlyCamera>, Without<SandSpawner>)>,
     input: Res<ButtonInput<MouseButton>>,
+    voxel_uniforms: Res<VoxelUniforms>,
 ) {
     let character = character_query.single();
     let (mut sand_spawner, mut sand_material) = sand_spawner.single_mut();
 
-    sand_spawner.translation = character.translation - character.local_z() * 10.0;
+    // snapped to the voxel grid so the spawner voxelizes the same shape wherever it is
+    let target = character.translation - character.local_z() * 10.0;
+    let voxel = world_to_voxel(target, &voxel_uniforms);
+    sand_spawner.translation = voxel_to_world(voxel, &voxel_uniforms);
 
     if input.pressed(MouseButton::Left) {
         sand_material.material = VoxelizationMaterialType::Material(8);
@@ -0,0 +1,97 @@
+use bevy::{core_pipeline::fxaa::Fxaa, prelude::*};
+use bevy_voxel_engine::{
+    write_chunked_world, BevyVoxelEnginePlugin, CapsuleCollider, CollisionEffect, Flags,
+    LoadVoxelWorld, TraceSettings, VoxelCameraBundle, VoxelFlyCamera, VoxelMaterial,
+    VoxelPalette, VoxelPhysics, VoxelWorldSettings,
+};
+use std::{f32::consts::PI, path::Path};
+
+#[path = "common/fps_counter.rs"]
+mod fps_counter;
+
+const WORLD_PATH: &str = "assets/streamed_terrain.bvcw";
+const CHUNK_SIZE: u32 = 32;
+
+fn main() {
+    if !Path::new(WORLD_PATH).exists() {
+        println!("Generating {}", WORLD_PATH);
+        write_terrain().expect("Failed to write the streamed world");
+    }
+
+    App::new()
+        // only 128 voxels around the camera are resident out of the 1024 wide terrain
+        .insert_resource(VoxelWorldSettings {
+            texture_size: 128,
+            ..default()
+        })
+        .add_plugins(DefaultPlugins)
+        .add_plugins(BevyVoxelEnginePlugin)
+        .add_plugins(fps_counter::FpsCounter)
+        .add_systems(Startup, setup)
+        .run();
+}
+
+fn write_terrain() -> std::io::Result<()> {
+    let mut palette = VoxelPalette::default();
+    for (i, color) in [
+        Color::rgb(0.3, 0.55, 0.2),
+        Color::rgb(0.45, 0.3, 0.15),
+        Color::rgb(0.5, 0.5, 0.5),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        palette[i + 1] = VoxelMaterial {
+            color: Vec3::from_slice(&color.as_linear_rgba_f32()),
+            ..default()
+        };
+    }
+
+    write_chunked_world(
+        WORLD_PATH,
+        UVec3::new(32, 4, 32),
+        CHUNK_SIZE,
+        &palette,
+        |position| {
+            let p = position.as_vec3();
+            let height = 40.0 + 16.0 * (p.x / 37.0).sin() * (p.z / 53.0).cos()
+                + 8.0 * ((p.x + p.z) / 19.0).sin();
+            let depth = height - p.y;
+            match depth {
+                d if d < 0.0 => (0, Flags::empty()),
+                d if d < 1.0 => (1, Flags::COLLISION_FLAG),
+                d if d < 6.0 => (2, Flags::COLLISION_FLAG),
+                _ => (3, Flags::COLLISION_FLAG),
+            }
+        },
+    )
+}
+
+fn setup(mut commands: Commands, mut load_voxel_world: ResMut<LoadVoxelWorld>) {
+    *load_voxel_world = LoadVoxelWorld::Stream(WORLD_PATH.to_string());
+
+    let character_transform =
+        Transform::from_xyz(0.0, 5.0, 0.0).looking_at(Vec3::new(10.0, 0.0, 10.0), Vec3::Y);
+
+    // the voxel camera is the streaming focus
+    commands.spawn((
+        VoxelCameraBundle {
+            transform: character_transform,
+            projection: Projection::Perspective(PerspectiveProjection {
+                fov: PI / 2.0,
+                ..default()
+            }),
+            trace_settings: TraceSettings::builder()
+                .samples(2)
+                .shadow_softness(0.05)
+                .ao(true)
+                .collect_stats(true)
+                .build(),
+            ..default()
+        },
+        VoxelFlyCamera::default(),
+        VoxelPhysics::new(Vec3::splat(0.0), CollisionEffect::None),
+        CapsuleCollider::new(2, 2),
+        Fxaa::default(),
+    ));
+}
@@ -0,0 +1,188 @@
+use crate::{PhysicsSettings, VoxelPhysics};
+use bevy::{
+    input::mouse::MouseMotion,
+    prelude::*,
+    window::{CursorGrabMode, PrimaryWindow},
+};
+use std::f32::consts::PI;
+
+/// Closest the camera looks to straight up or down, in radians
+const MAX_PITCH_ANGLE: f32 = 0.01;
+
+pub struct FlyCameraPlugin;
+
+impl Plugin for FlyCameraPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (grab_cursor, update_fly_cameras).chain());
+    }
+}
+
+/// First person controls for the camera it is on, it moves with WASD, space and shift and
+/// looks around with the mouse while the cursor is grabbed. Cameras with a [`VoxelPhysics`]
+/// collide with the world and walk and jump under gravity outside of `spectator`, the others
+/// fly through it. Requires the `fly_camera` feature
+#[derive(Component, Clone)]
+pub struct VoxelFlyCamera {
+    /// Meters per second
+    pub speed: f32,
+    /// Radians turned per pixel of mouse motion
+    pub sensitivity: f32,
+    /// Upward velocity added by a jump when walking
+    pub jump_speed: f32,
+    /// Flies in the direction the camera looks ignoring gravity, only cameras with a
+    /// [`VoxelPhysics`] walk
+    pub spectator: bool,
+    /// Grabs and releases the cursor of the primary window, it is grabbed when the camera is
+    /// added. `None` leaves the cursor to the app and always takes input
+    pub grab_key: Option<KeyCode>,
+}
+
+impl Default for VoxelFlyCamera {
+    fn default() -> Self {
+        Self {
+            speed: 5.0,
+            sensitivity: 0.006,
+            jump_speed: 8.0,
+            spectator: true,
+            grab_key: Some(KeyCode::Escape),
+        }
+    }
+}
+
+fn grab_cursor(
+    cameras: Query<Ref<VoxelFlyCamera>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut window: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    let Ok(mut window) = window.get_single_mut() else {
+        return;
+    };
+
+    let grab = if cameras.iter().any(|camera| camera.is_added() && camera.grab_key.is_some()) {
+        true
+    } else if cameras
+        .iter()
+        .filter_map(|camera| camera.grab_key)
+        .any(|key| keys.just_pressed(key))
+    {
+        window.cursor.grab_mode != CursorGrabMode::Locked
+    } else {
+        return;
+    };
+
+    window.cursor.grab_mode = if grab {
+        CursorGrabMode::Locked
+    } else {
+        CursorGrabMode::None
+    };
+    window.cursor.visible = !grab;
+}
+
+fn update_fly_cameras(
+    mut cameras: Query<(&mut Transform, &VoxelFlyCamera, Option<&mut VoxelPhysics>)>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut mouse_motion_events: EventReader<MouseMotion>,
+    time: Res<Time>,
+    physics_settings: Res<PhysicsSettings>,
+    window: Query<&Window, With<PrimaryWindow>>,
+) {
+    let mouse_delta: Vec2 = mouse_motion_events.read().map(|event| event.delta).sum();
+    let grabbed = match window.get_single() {
+        Ok(window) => window.cursor.grab_mode == CursorGrabMode::Locked,
+        Err(_) => false,
+    };
+    let input = Vec3::new(
+        (keys.pressed(KeyCode::KeyD) as i32 - keys.pressed(KeyCode::KeyA) as i32) as f32,
+        (keys.pressed(KeyCode::Space) as i32 - keys.pressed(KeyCode::ShiftLeft) as i32) as f32,
+        (keys.pressed(KeyCode::KeyS) as i32 - keys.pressed(KeyCode::KeyW) as i32) as f32,
+    )
+    .normalize_or_zero();
+    let dt = time.delta_seconds();
+
+    for (mut transform, camera, voxel_physics) in &mut cameras {
+        let active = grabbed || camera.grab_key.is_none();
+
+        // portals the body went through turn the view with it, the roll left by turning with
+        // a tilted portal is slowly undone
+        let mut look_at = *transform.forward();
+        let mut up = *transform.up();
+        if let Some(voxel_physics) = &voxel_physics {
+            look_at = voxel_physics.portal_rotation * look_at;
+            up = voxel_physics.portal_rotation * up;
+        }
+        up = slerp(up.normalize(), Vec3::Y, 0.04, dt);
+
+        if active && mouse_delta != Vec2::ZERO {
+            let angle = look_at.dot(up).clamp(-1.0, 1.0).acos();
+
+            // Order is important to prevent unintended roll
+            look_at = Quat::from_axis_angle(Vec3::Y, -mouse_delta.x * camera.sensitivity)
+                * Quat::from_axis_angle(
+                    *transform.local_x(),
+                    (-mouse_delta.y * camera.sensitivity)
+                        .min(angle - MAX_PITCH_ANGLE)
+                        .max(angle + MAX_PITCH_ANGLE - PI),
+                )
+                * look_at;
+        }
+        transform.look_to(look_at, up);
+
+        // Movement
+        let input = if active { input * camera.speed } else { Vec3::ZERO };
+        let flying_velocity = input.z * *transform.local_z()
+            + input.x * *transform.local_x()
+            + input.y * *transform.local_y();
+
+        let Some(mut voxel_physics) = voxel_physics else {
+            transform.translation += flying_velocity * dt;
+            continue;
+        };
+
+        // gravity is applied below instead so spectator mode can ignore it
+        let gravity = voxel_physics.effective_gravity(physics_settings.gravity);
+        voxel_physics.gravity_on_cpu = true;
+
+        // the velocity set here replaces the one the gpu reads back, so whatever goes into
+        // the surfaces the camera touches is dropped here instead of by the collision response
+        voxel_physics.velocity = voxel_physics.slide(voxel_physics.velocity);
+
+        let target_velocity = if camera.spectator {
+            flying_velocity
+        } else {
+            if active && keys.just_pressed(KeyCode::Space) && voxel_physics.grounded {
+                voxel_physics.velocity.y += camera.jump_speed;
+            }
+
+            voxel_physics.velocity += gravity * dt;
+
+            let plane_forward = transform.local_x().cross(Vec3::Y).normalize();
+
+            input.z * plane_forward
+                + input.x * *transform.local_x()
+                + voxel_physics.velocity.y * Vec3::Y
+        };
+
+        let acceleration = if camera.spectator || voxel_physics.grounded {
+            0.2
+        } else {
+            0.01
+        };
+
+        voxel_physics.velocity = lerp(voxel_physics.velocity, target_velocity, acceleration, dt);
+    }
+}
+
+fn lerp(i: Vec3, f: Vec3, s: f32, dt: f32) -> Vec3 {
+    let s = (1.0 - s).powf(dt * 120.0);
+    i * s + f * (1.0 - s)
+}
+
+// https://youtu.be/ibkT5ao8kGY
+fn slerp(i: Vec3, f: Vec3, s: f32, dt: f32) -> Vec3 {
+    let s = (1.0 - s).powf(dt * 120.0);
+    let theta = i.dot(f).clamp(-1.0, 1.0).acos();
+    if theta.sin() == 0.0 {
+        return i;
+    }
+    ((s * theta).sin() / theta.sin()) * i + (((1.0 - s) * theta).sin() / theta.sin()) * f
+}
@@ -1,16 +1,55 @@
 use bevy::{
     core_pipeline::tonemapping::Tonemapping,
     prelude::*,
-    render::{camera::CameraRenderGraph, camera::CameraMainTextureUsages, primitives::Frustum, view::VisibleEntities},
+    render::{
+        camera::{CameraMainTextureUsages, CameraRenderGraph, RenderTarget},
+        primitives::Frustum,
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages},
+        view::VisibleEntities,
+    },
+};
+#[cfg(feature = "fly_camera")]
+pub use fly_camera::VoxelFlyCamera;
+pub use load::{
+    QbLoader, VoxLoader, VoxelFormat, VoxelLoader, VoxelMaterial, VoxelModel, VoxelPalette,
 };
 use physics::PhysicsPlugin;
-pub use physics::VOXELS_PER_METER;
-use voxel_pipeline::{RenderPlugin, VoxelGraph};
+pub use physics::{
+    AnimationSettings, PhysicsDebug, PhysicsSettings, PhysicsStats, VOXELS_PER_METER,
+};
+use voxel_pipeline::RenderPlugin;
 pub use voxel_pipeline::{
-    trace::TraceSettings, voxelization::VoxelizationMaterial,
-    voxelization::VoxelizationMaterialType, RenderGraphSettings,
+    attachments::{PositionFormat, RenderAttachmentSettings, RenderAttachments},
+    compute::{
+        automata::{AutomataRegion, AutomataRule, AutomataRules, MAX_AUTOMATA_RULES},
+        clear::{ClearRegion, ClearRegions, MAX_CLEAR_REGIONS},
+        rebuild::RequestRebuild,
+        ComputeBufferSettings, PhysicsData, SimulationSeed,
+    },
+    readback::ImageReadback,
+    streaming::{write_chunked_world, VoxelStreaming, VoxelStreamingFocus},
+    trace::{
+        DebugView, PointLights, SampleSequence, SkySettings, Skybox, TraceHistory, TraceSettings,
+        TraceSettingsBuilder, TraceStats, VoxelBackground, VoxelPointLight, MAX_POINT_LIGHTS,
+    },
+    voxel_world::{
+        voxel_to_world, world_to_voxel, MaterialAnimation, MaterialDensities, PaletteAnimation,
+        ReflectiveMaterials, TransparentMaterials, Voxel, VoxelClipboard, VoxelEdit, VoxelHit,
+        VoxelUniforms, VoxelWorld, VoxelWorldDebug, VoxelWorldSettings, MAX_PALETTE_ANIMATIONS,
+        MAX_PALETTE_ANIMATION_COLORS,
+    },
+    voxelization::VoxelizationMaterial,
+    voxelization::VoxelizationMaterialType,
+    voxelization::VoxelizationAxes,
+    voxelization::VoxelizationDebug,
+    voxelization::VoxelizationMode,
+    voxelization::VoxelizationScene, RenderGraphSettings, SimulationControl, VoxelGraph,
+    VoxelGraphLabel, VoxelNode,
 };
 
+#[cfg(feature = "fly_camera")]
+mod fly_camera;
 mod load;
 mod physics;
 mod voxel_pipeline;
@@ -18,7 +57,7 @@ mod voxel_pipeline;
 #[derive(Component)]
 pub struct Particle {
     pub material: u8,
-    pub flags: u8,
+    pub flags: Flags,
 }
 
 /// normal must be a normalized voxel normal
@@ -28,51 +67,216 @@ pub struct Portal;
 #[derive(Component)]
 pub struct Edges {
     pub material: u8,
-    pub flags: u8,
+    pub flags: Flags,
     pub half_size: IVec3,
 }
 
 #[derive(Component)]
 pub struct Box {
     pub material: u8,
-    pub flags: u8,
+    pub flags: Flags,
     pub half_size: IVec3,
 }
 
 #[derive(Component)]
 pub struct VoxelPhysics {
     pub velocity: Vec3,
-    pub gravity: Vec3,
+    /// Gravity of this body in place of the world gravity of [`PhysicsSettings`], `None` (the
+    /// default) falls under the world gravity, see [`VoxelPhysics::with_gravity`]
+    pub gravity: Option<Vec3>,
     pub collision_effect: CollisionEffect,
+    /// Normal of the voxel face last hit, zero when the body didn't hit anything
     pub hit_normal: Vec3,
+    /// Surface normal where the body last hit, estimated from the voxels around the contact so
+    /// slopes and steps get a tilted normal rather than the one of the face. Zero when the
+    /// body didn't hit anything, see [`VoxelPhysics::slide`]
+    pub contact_normal: Vec3,
+    /// Set from the physics readback when the body rests on an upward facing surface
+    pub grounded: bool,
     pub portal_rotation: Mat3,
+    /// Fraction of the velocity into a surface kept as bounce, 0 doesn't bounce
+    pub restitution: f32,
+    /// How quickly velocity along a surface is lost per second of contact
+    pub friction: f32,
+    /// Layers this body is on, bodies push each other out of the boxes around their shapes when
+    /// each one's `mask` contains a layer of the other. The default `mask` of 0 only collides
+    /// with the voxel world, which is always collided with. Triggers don't touch other bodies,
+    /// and every body with a `mask` is checked against all the others each step
+    pub layer: u32,
+    pub mask: u32,
+    /// Direction the body was last pushed out of another body in, zero when it didn't touch
+    /// one. These contacts aren't voxel hits: they don't set `hit_normal` or `grounded`, send
+    /// a [`VoxelCollisionEvent`] or apply the collision effect
+    pub body_contact_normal: Vec3,
+    /// Sweeps the center of box and sphere bodies along their path each step and stops them at
+    /// the first solid voxel, so fast bodies don't tunnel through thin walls. Point bodies
+    /// always raycast their whole step
+    pub ccd: bool,
+    /// Multiplier of the gravity the body falls under, its own `gravity` or the world's in
+    /// [`PhysicsSettings`]. 0 floats and negative values rise
+    pub gravity_scale: f32,
+    /// Compared with the [`MaterialDensities`] of the voxels the body is in, bodies less dense
+    /// than the fluid around them float. 0 ignores fluids
+    pub density: f32,
+    /// Set from the physics readback once the body rested for
+    /// [`PhysicsSettings::sleep_frames`]. The physics pass doesn't move sleeping bodies but
+    /// still collides the others with them, until they are moved, given a velocity, hit near
+    /// by another body or woken with [`VoxelPhysics::wake`]
+    pub sleeping: bool,
+    pub(crate) rest_frames: u32,
+    pub(crate) sleep_translation: Vec3,
+    /// Whether the body hit voxels in the last physics readback
+    pub(crate) in_contact: bool,
+    /// Set by `VoxelFlyCamera`, which applies the gravity itself so spectator mode can
+    /// ignore it
+    pub(crate) gravity_on_cpu: bool,
+    /// Translation and velocity the gpu has for the body as far as the cpu knows, from the
+    /// last upload or readback. `None` until the body is first uploaded
+    pub(crate) gpu_state: Option<(Vec3, Vec3)>,
+    /// Frames the translation and velocity were last uploaded on, readbacks from before them
+    /// don't overwrite the edits
+    pub(crate) translation_frame: u32,
+    pub(crate) velocity_frame: u32,
 }
 
 impl VoxelPhysics {
-    pub fn new(velocity: Vec3, gravity: Vec3, collision_effect: CollisionEffect) -> Self {
+    pub fn new(velocity: Vec3, collision_effect: CollisionEffect) -> Self {
         Self {
             velocity,
-            gravity,
+            gravity: None,
             collision_effect,
             hit_normal: Vec3::ZERO,
+            contact_normal: Vec3::ZERO,
+            grounded: false,
             portal_rotation: Mat3::IDENTITY,
+            restitution: 0.0,
+            friction: 0.0,
+            layer: 1,
+            mask: 0,
+            body_contact_normal: Vec3::ZERO,
+            ccd: false,
+            gravity_scale: 1.0,
+            density: 1.0,
+            sleeping: false,
+            rest_frames: 0,
+            sleep_translation: Vec3::ZERO,
+            in_contact: false,
+            gravity_on_cpu: false,
+            gpu_state: None,
+            translation_frame: 0,
+            velocity_frame: 0,
         }
     }
+
+    pub fn with_restitution(mut self, restitution: f32) -> Self {
+        self.restitution = restitution;
+        self
+    }
+
+    pub fn with_friction(mut self, friction: f32) -> Self {
+        self.friction = friction;
+        self
+    }
+
+    pub fn with_collision_layers(mut self, layer: u32, mask: u32) -> Self {
+        self.layer = layer;
+        self.mask = mask;
+        self
+    }
+
+    pub fn with_ccd(mut self) -> Self {
+        self.ccd = true;
+        self
+    }
+
+    /// Gives the body its own gravity in place of the world gravity of [`PhysicsSettings`]
+    pub fn with_gravity(mut self, gravity: Vec3) -> Self {
+        self.gravity = Some(gravity);
+        self
+    }
+
+    pub fn with_gravity_scale(mut self, gravity_scale: f32) -> Self {
+        self.gravity_scale = gravity_scale;
+        self
+    }
+
+    pub fn with_density(mut self, density: f32) -> Self {
+        self.density = density;
+        self
+    }
+
+    pub fn wake(&mut self) {
+        self.sleeping = false;
+        self.rest_frames = 0;
+    }
+
+    /// Removes the part of the velocity going into the surface at `contact_normal`, so
+    /// bodies pushed against a wall slide along it
+    pub fn slide(&self, velocity: Vec3) -> Vec3 {
+        velocity - self.contact_normal * velocity.dot(self.contact_normal).min(0.0)
+    }
+
+    /// Gravity the body falls under, scaled by `gravity_scale`
+    pub fn effective_gravity(&self, world_gravity: Vec3) -> Vec3 {
+        self.gravity.unwrap_or(world_gravity) * self.gravity_scale
+    }
+}
+
+/// Sent after the physics readback when a `VoxelPhysics` body starts hitting voxels, not again
+/// while it stays in contact, e.g. resting on the ground
+#[derive(Event, Clone, Copy, Debug)]
+pub struct VoxelCollisionEvent {
+    pub entity: Entity,
+    pub normal: Vec3,
+    pub position: Vec3,
+}
+
+/// Sent after the physics readback for every `VoxelPhysics` body with
+/// [`CollisionEffect::Trigger`] that overlapped voxels last frame
+#[derive(Event, Clone, Copy, Debug)]
+pub struct VoxelTriggerEvent {
+    pub entity: Entity,
+    pub position: Vec3,
+    /// Number of voxels inside the body's collider
+    pub voxels: u32,
+    /// Material of the first overlapped voxel
+    pub material: u8,
 }
 
 pub enum CollisionEffect {
     None,
+    /// Clears the voxels within `radius` meters of the impact point, including their flags so
+    /// they also stop colliding and leave the automata. Animated voxels are left alone since
+    /// they're rewritten from their entities every frame
     Destroy {
         radius: f32,
     },
     Place {
         radius: f32,
         material: u8,
-        flags: u8,
+        flags: Flags,
     },
     SetFlags {
         radius: f32,
-        flags: u8,
+        flags: Flags,
+    },
+    /// Passes through voxels without any response, sending a [`VoxelTriggerEvent`] while
+    /// overlapping them instead. Voxels written by other bodies are overlapped too
+    Trigger,
+    /// Converts the material of the voxels within `radius` meters of the impact point, keeping
+    /// their flags. Empty and animated voxels are left alone
+    Paint {
+        radius: f32,
+        material: u8,
+    },
+    /// Destroys the voxels within `radius` meters of the impact point like `Destroy` and
+    /// throws up to `count` of them outwards as [`Particle`] bodies of their material. The
+    /// debris is despawned after a few seconds and only a few hundred exist at once. The
+    /// debris materials are read from the [`VoxelWorld`], which doesn't see voxels added on
+    /// the gpu
+    Shatter {
+        radius: f32,
+        count: u32,
     },
 }
 
@@ -81,21 +285,26 @@ impl CollisionEffect {
         let mut vec = Vec3::ZERO;
         vec.x = match self {
             CollisionEffect::None => 0u32 as f32,
-            CollisionEffect::Destroy { .. } => 1u32 as f32,
+            CollisionEffect::Destroy { .. } | CollisionEffect::Shatter { .. } => 1u32 as f32,
             CollisionEffect::Place { .. } => 2u32 as f32,
             CollisionEffect::SetFlags { .. } => 3u32 as f32,
+            CollisionEffect::Trigger => 4.0,
+            CollisionEffect::Paint { .. } => 5.0,
         };
         vec.y = match self {
             CollisionEffect::Destroy { radius }
+            | CollisionEffect::Shatter { radius, .. }
             | CollisionEffect::Place { radius, .. }
-            | CollisionEffect::SetFlags { radius, .. } => *radius,
+            | CollisionEffect::SetFlags { radius, .. }
+            | CollisionEffect::Paint { radius, .. } => *radius,
             _ => 0.0,
         };
         vec.z = match self {
             CollisionEffect::Place {
                 material, flags, ..
-            } => bytemuck::cast(*material as u32 | ((*flags as u32) << 8)),
-            CollisionEffect::SetFlags { flags, .. } => bytemuck::cast(*flags as u32),
+            } => bytemuck::cast(*material as u32 | ((flags.bits() as u32) << 8)),
+            CollisionEffect::SetFlags { flags, .. } => bytemuck::cast(flags.bits() as u32),
+            CollisionEffect::Paint { material, .. } => bytemuck::cast(*material as u32),
             _ => 0.0,
         };
 
@@ -103,11 +312,63 @@ impl CollisionEffect {
     }
 }
 
+/// Entities should have at most one collider, a `BoxCollider` takes priority over a
+/// `SphereCollider`, which takes priority over a `CapsuleCollider` when several are present
 #[derive(Component)]
 pub struct BoxCollider {
     pub half_size: IVec3,
 }
 
+#[derive(Component)]
+pub struct SphereCollider {
+    /// Radius in voxels
+    pub radius: u32,
+}
+
+/// Sphere swept along an axis, its rounded ends ride over voxel edges a box would catch on
+#[derive(Component)]
+pub struct CapsuleCollider {
+    /// Radius in voxels
+    pub radius: u32,
+    /// Distance in voxels from the center to the center of either rounded end
+    pub half_height: u32,
+    pub axis: CapsuleAxis,
+}
+
+impl CapsuleCollider {
+    /// Y-up capsule
+    pub fn new(radius: u32, half_height: u32) -> Self {
+        Self {
+            radius,
+            half_height,
+            axis: CapsuleAxis::Y,
+        }
+    }
+
+    pub fn with_axis(mut self, axis: CapsuleAxis) -> Self {
+        self.axis = axis;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CapsuleAxis {
+    X,
+    #[default]
+    Y,
+    Z,
+}
+
+impl CapsuleAxis {
+    pub fn to_vec3(self) -> Vec3 {
+        match self {
+            CapsuleAxis::X => Vec3::X,
+            CapsuleAxis::Y => Vec3::Y,
+            CapsuleAxis::Z => Vec3::Z,
+        }
+    }
+}
+
 #[derive(Bundle)]
 pub struct VoxelCameraBundle {
     pub camera: Camera,
@@ -144,6 +405,49 @@ impl Default for VoxelCameraBundle {
     }
 }
 
+impl VoxelCameraBundle {
+    pub fn with_target(mut self, target: VoxelRenderTarget) -> Self {
+        self.camera.target = match target {
+            VoxelRenderTarget::Window => RenderTarget::default(),
+            VoxelRenderTarget::Image(image) => RenderTarget::Image(image),
+        };
+        self
+    }
+}
+
+/// Where a [`VoxelCameraBundle`] renders to, an image target can be rendered without a
+/// window and read back with [`ImageReadback`]
+#[derive(Clone, Debug, Default)]
+pub enum VoxelRenderTarget {
+    /// The primary window
+    #[default]
+    Window,
+    Image(Handle<Image>),
+}
+
+impl VoxelRenderTarget {
+    /// Creates an image of `size` pixels that can be rendered to and read back
+    pub fn image(size: UVec2, images: &mut Assets<Image>) -> Self {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0; 4],
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::default(),
+        );
+        image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+            | TextureUsages::COPY_DST
+            | TextureUsages::COPY_SRC
+            | TextureUsages::RENDER_ATTACHMENT;
+
+        Self::Image(images.add(image))
+    }
+}
+
 #[derive(Bundle, Default)]
 pub struct VoxelizationBundle {
     pub mesh_handle: Handle<Mesh>,
@@ -155,6 +459,18 @@ pub struct VoxelizationBundle {
     pub view_visibility: ViewVisibility,
 }
 
+/// Voxelizes every mesh of a scene, e.g. a glTF file, at its world position
+#[derive(Bundle, Default)]
+pub struct VoxelizationSceneBundle {
+    pub scene: Handle<Scene>,
+    pub voxelization_scene: VoxelizationScene,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+    pub visibility: Visibility,
+    pub inherited_visibility: InheritedVisibility,
+    pub view_visibility: ViewVisibility,
+}
+
 pub struct BevyVoxelEnginePlugin;
 
 impl Plugin for BevyVoxelEnginePlugin {
@@ -162,22 +478,85 @@ impl Plugin for BevyVoxelEnginePlugin {
         app.insert_resource(Msaa::Off)
             .add_plugins(PhysicsPlugin)
             .add_plugins(RenderPlugin);
+
+        #[cfg(feature = "fly_camera")]
+        app.add_plugins(fly_camera::FlyCameraPlugin);
     }
 }
 
 #[derive(Resource)]
 pub enum LoadVoxelWorld {
-    Empty(u32),
-    File(String),
+    /// Blank world of the given voxel dimensions with the default palette, for building from
+    /// scratch with [`VoxelWorld::set_voxel`]. The world is a cube, so the largest dimension
+    /// is used, rounded up to a power of two the gpu supports
+    Empty(UVec3),
+    /// Path to a voxel file and the model to load from it, defaults to 0. The
+    /// [`VoxelFormat`] is picked by the extension, or the contents for unknown extensions
+    File(String, Option<usize>),
+    /// Contents of a voxel file, e.g. from `include_bytes!`, in the [`VoxelFormat`] detected
+    /// from its first bytes
+    Bytes(Vec<u8>),
+    /// Fills a world the size of the current one from the (material, flags) returned for
+    /// each voxel position, keeping the current [`VoxelPalette`]
+    Generate(std::boxed::Box<dyn Fn(IVec3) -> (u8, Flags) + Send + Sync>),
+    /// Path to a chunked world written with [`write_chunked_world`], which can be larger than
+    /// the voxel world. The chunks around the [`VoxelStreamingFocus`] are streamed into it as
+    /// the focus moves, see [`VoxelStreaming`]
+    Stream(String),
     None,
 }
 
-#[allow(non_snake_case)]
-pub mod Flags {
-    pub const AUTOMATA_FLAG: u8 = 128; // 0b10000000
-    pub const PORTAL_FLAG: u8 = 64; // 0b01000000
-    pub const ANIMATION_FLAG: u8 = 32; // 0b00100000
-    pub const COLLISION_FLAG: u8 = 16; // 0b00010000
-    pub const SAND_FLAG: u8 = 8; // 0b00001000
-    pub const NONE: u8 = 0; // 0b00000000
+/// Sent once a world loaded with [`LoadVoxelWorld`] has finished uploading to the gpu, a frame
+/// or two after the load. Not sent for [`LoadVoxelWorld::Stream`], whose chunks keep arriving
+/// as the focus moves
+#[derive(Event, Clone, Copy, Debug)]
+pub struct VoxelWorldLoaded {
+    /// Voxels along each axis of the new world, see [`VoxelWorld::size`]
+    pub size: UVec3,
+}
+
+bitflags::bitflags! {
+    /// Flag bits stored in the upper byte of every voxel
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct Flags: u8 {
+        /// Voxel is simulated by the automata pass
+        const AUTOMATA_FLAG = 128; // 0b10000000
+        /// Voxel belongs to a portal, rays passing through it are teleported
+        const PORTAL_FLAG = 64; // 0b01000000
+        /// Voxel is cleared and rewritten every frame
+        const ANIMATION_FLAG = 32; // 0b00100000
+        /// Voxel is solid to `VoxelPhysics` bodies
+        const COLLISION_FLAG = 16; // 0b00010000
+        /// Voxel falls like sand in the automata pass
+        const SAND_FLAG = 8; // 0b00001000
+        /// Emission level of the voxel from 0 to 7, see `VoxelizationMaterial::emissive`. These
+        /// are the bits automata data used to live in, the two can't share them
+        const EMISSION_MASK = 7; // 0b00000111
+    }
+}
+
+impl Flags {
+    pub const NONE: Self = Self::empty();
+
+    /// Emission level from 0 to 7 stored in the low bits
+    pub fn emission(self) -> u8 {
+        (self & Self::EMISSION_MASK).bits()
+    }
+
+    /// Replaces the emission level, clamped to 7
+    pub fn with_emission(self, emission: u8) -> Self {
+        (self - Self::EMISSION_MASK) | Self::from_bits_retain(emission.min(7))
+    }
+}
+
+impl From<u8> for Flags {
+    fn from(bits: u8) -> Self {
+        Self::from_bits_retain(bits)
+    }
+}
+
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> Self {
+        flags.bits()
+    }
 }
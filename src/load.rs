@@ -1,17 +1,338 @@
-use bevy::prelude::*;
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+    },
+};
 
 use crate::Flags;
+use std::{io, path::Path};
 
 #[derive(Clone)]
 pub struct GH {
     pub levels: [u32; 8],
     pub texture_size: u32,
     pub texture_data: Vec<u8>,
-    pub pallete: Pallete,
+    pub pallete: VoxelPalette,
 }
 
-#[derive(Clone, Deref, DerefMut)]
-pub struct Pallete([[f32; 4]; 256]);
+/// Surface properties of a material id, read from the `RGBA` and `MATL` chunks of `.vox` files
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VoxelMaterial {
+    /// Linear rgb
+    pub color: Vec3,
+    /// Multiplier on `color` for light emitted by the material, 0 for non emissive materials
+    pub emission: f32,
+    pub roughness: f32,
+    pub metal: f32,
+}
+
+/// Materials indexed by voxel material id, id 0 is empty space. Set when a `.vox` file is
+/// loaded, changes are uploaded to the gpu
+#[derive(Resource, ExtractResource, Clone, Deref, DerefMut)]
+pub struct VoxelPalette(pub [VoxelMaterial; 256]);
+
+impl Default for VoxelPalette {
+    fn default() -> Self {
+        Self([VoxelMaterial::default(); 256])
+    }
+}
+
+impl VoxelPalette {
+    /// Color of the material `index`
+    pub fn get_color(&self, index: u8) -> Color {
+        let color = self[index as usize].color;
+        Color::rgb_linear(color.x, color.y, color.z)
+    }
+
+    /// Recolors every voxel of the material `index` from the next frame, without voxelizing
+    /// anything again. The alpha is ignored
+    pub fn set_color(&mut self, index: u8, color: Color) {
+        self[index as usize].color = Vec3::from_slice(&color.as_linear_rgba_f32());
+    }
+
+    /// Id of the non emissive material closest to the linear rgb `color`
+    pub fn nearest_material(&self, color: Vec3) -> u8 {
+        (1..256)
+            .filter(|i| self[*i].emission == 0.0)
+            .min_by(|a, b| {
+                let a = self[*a].color.distance_squared(color);
+                let b = self[*b].color.distance_squared(color);
+                a.total_cmp(&b)
+            })
+            .unwrap_or(1) as u8
+    }
+}
+
+/// A single model or animation frame from a voxel file, in engine axes (y up)
+#[derive(Clone, Debug)]
+pub struct VoxelModel {
+    pub size: UVec3,
+    /// Position and material of every solid voxel, materials are palette indices + 1 so
+    /// that 0 stays empty
+    pub voxels: Vec<(UVec3, u8)>,
+}
+
+impl VoxelModel {
+    /// Parses every `SIZE`/`XYZI` model in a `.vox` file, in file order
+    pub fn load_all(file: &[u8]) -> Result<Vec<VoxelModel>, String> {
+        let vox = dot_vox::load_bytes(file)?;
+        Ok(Self::from_dot_vox(&vox))
+    }
+
+    fn from_dot_vox(vox: &dot_vox::DotVoxData) -> Vec<VoxelModel> {
+        vox.models
+            .iter()
+            .map(|model| VoxelModel {
+                size: UVec3::new(model.size.x, model.size.z, model.size.y),
+                voxels: model
+                    .voxels
+                    .iter()
+                    .map(|voxel| {
+                        let pos = UVec3::new(
+                            model.size.x - 1 - voxel.x as u32,
+                            voxel.z as u32,
+                            voxel.y as u32,
+                        );
+                        (pos, voxel.i + 1)
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// File formats [`LoadVoxelWorld::File`](crate::LoadVoxelWorld) and
+/// [`LoadVoxelWorld::Bytes`](crate::LoadVoxelWorld) can read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoxelFormat {
+    /// MagicaVoxel `.vox`, with its palette and `MATL` materials
+    Vox,
+    /// Qubicle `.qb`, its matrices are merged into one model and the colors into a palette
+    Qb,
+}
+
+impl VoxelFormat {
+    /// Format of the file extension, `None` for unknown extensions
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "vox" => Some(VoxelFormat::Vox),
+            "qb" => Some(VoxelFormat::Qb),
+            _ => None,
+        }
+    }
+
+    /// Format of the file from its first bytes, `None` when no format matches
+    pub fn from_bytes(file: &[u8]) -> Option<Self> {
+        match file.get(..4)? {
+            b"VOX " => Some(VoxelFormat::Vox),
+            // qb files start with their version, 1.1.0.0
+            [1, 1, 0, 0] => Some(VoxelFormat::Qb),
+            _ => None,
+        }
+    }
+
+    /// The [`VoxelLoader`] that parses files of this format
+    pub fn loader(self) -> &'static dyn VoxelLoader {
+        match self {
+            VoxelFormat::Vox => &VoxLoader,
+            VoxelFormat::Qb => &QbLoader,
+        }
+    }
+}
+
+/// Parser of a [`VoxelFormat`]
+pub trait VoxelLoader {
+    /// Reads the model at index `model` and the palette its materials index into
+    fn load(&self, file: &[u8], model: usize) -> Result<(VoxelModel, VoxelPalette), String>;
+}
+
+/// Loads [`VoxelFormat::Vox`] files
+pub struct VoxLoader;
+
+impl VoxelLoader for VoxLoader {
+    fn load(&self, file: &[u8], model: usize) -> Result<(VoxelModel, VoxelPalette), String> {
+        let vox = dot_vox::load_bytes(file)?;
+        let models = VoxelModel::from_dot_vox(&vox);
+        let count = models.len();
+        let model = models.into_iter().nth(model).ok_or(format!(
+            "Model {} not found, the file contains {} models",
+            model, count
+        ))?;
+
+        // palette index i is material id i + 1, matching the material ids of MATL chunks
+        let mut palette = VoxelPalette::default();
+        for (i, colour) in vox.palette.iter().enumerate().take(255) {
+            let id = i + 1;
+            let mut material = VoxelMaterial {
+                color: (Vec3::new(colour.r as f32, colour.g as f32, colour.b as f32) / 255.0)
+                    .powf(2.2),
+                ..default()
+            };
+
+            if let Some(vox_material) = vox.materials.iter().find(|m| m.id as usize == id) {
+                let material_type = vox_material.material_type();
+                if material_type == Some("_emit") {
+                    material.emission = vox_material.emission().unwrap_or(0.0);
+                }
+                if material_type == Some("_metal") {
+                    material.metal = vox_material.metalness().unwrap_or(0.0);
+                }
+                material.roughness = vox_material.roughness().unwrap_or(0.0);
+            }
+
+            palette[id] = material;
+        }
+
+        Ok((model, palette))
+    }
+}
+
+/// Loads [`VoxelFormat::Qb`] files
+pub struct QbLoader;
+
+impl QbLoader {
+    /// Run length encoded matrices mark a repeated color with this, followed by the count
+    const CODE_FLAG: u32 = 2;
+    /// Ends a z slice of a run length encoded matrix
+    const NEXT_SLICE_FLAG: u32 = 6;
+}
+
+/// Little endian reads from the start of a qb file
+struct QbReader<'a> {
+    file: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> QbReader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .file
+            .get(self.offset..self.offset + length)
+            .ok_or("Unexpected end of the qb file")?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+impl VoxelLoader for QbLoader {
+    fn load(&self, file: &[u8], model: usize) -> Result<(VoxelModel, VoxelPalette), String> {
+        if model != 0 {
+            return Err(format!("Model {} not found, qb files contain a single model", model));
+        }
+
+        let mut reader = QbReader { file, offset: 0 };
+        let _version = reader.u32()?;
+        let bgra = reader.u32()? == 1;
+        let left_handed = reader.u32()? == 0;
+        let compressed = reader.u32()? == 1;
+        let _visibility_mask = reader.u32()?;
+        let matrix_count = reader.u32()?;
+
+        // solid voxels of every matrix at their position in the model
+        let mut voxels: Vec<(IVec3, [u8; 3])> = Vec::new();
+        for _ in 0..matrix_count {
+            let name_length = reader.bytes(1)?[0] as usize;
+            reader.bytes(name_length)?;
+            let size = UVec3::new(reader.u32()?, reader.u32()?, reader.u32()?);
+            let position = IVec3::new(reader.i32()?, reader.i32()?, reader.i32()?);
+
+            // the alpha is 0 for empty voxels, or a mask of the visible faces
+            let mut push = |x: u32, y: u32, z: u32, color: u32| {
+                let [a, b, c, alpha] = color.to_le_bytes();
+                if alpha != 0 && y < size.y {
+                    let rgb = if bgra { [c, b, a] } else { [a, b, c] };
+                    voxels.push((position + UVec3::new(x, y, z).as_ivec3(), rgb));
+                }
+            };
+
+            if !compressed {
+                for z in 0..size.z {
+                    for y in 0..size.y {
+                        for x in 0..size.x {
+                            push(x, y, z, reader.u32()?);
+                        }
+                    }
+                }
+            } else if size.x > 0 {
+                for z in 0..size.z {
+                    let mut index = 0;
+                    loop {
+                        match reader.u32()? {
+                            Self::NEXT_SLICE_FLAG => break,
+                            Self::CODE_FLAG => {
+                                let count = reader.u32()?;
+                                let color = reader.u32()?;
+                                for _ in 0..count {
+                                    push(index % size.x, index / size.x, z, color);
+                                    index += 1;
+                                }
+                            }
+                            color => {
+                                push(index % size.x, index / size.x, z, color);
+                                index += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if voxels.is_empty() {
+            return Err("The qb file contains no voxels".to_string());
+        }
+
+        // drop low bits of the colors until they fit in the 255 materials
+        let quantize = |rgb: [u8; 3], shift: u32| rgb.map(|c| c >> shift << shift);
+        let mut shift = 0;
+        let colors = loop {
+            let mut colors: Vec<_> = voxels.iter().map(|(_, rgb)| quantize(*rgb, shift)).collect();
+            colors.sort_unstable();
+            colors.dedup();
+            if colors.len() <= 255 {
+                break colors;
+            }
+            shift += 1;
+        };
+
+        let mut palette = VoxelPalette::default();
+        for (i, [r, g, b]) in colors.iter().enumerate() {
+            palette[i + 1] = VoxelMaterial {
+                color: (Vec3::new(*r as f32, *g as f32, *b as f32) / 255.0).powf(2.2),
+                ..default()
+            };
+        }
+
+        let min = voxels.iter().fold(IVec3::MAX, |min, (pos, _)| min.min(*pos));
+        let max = voxels.iter().fold(IVec3::MIN, |max, (pos, _)| max.max(*pos));
+        let size = (max - min + 1).as_uvec3();
+        let voxels = voxels
+            .iter()
+            .map(|(pos, rgb)| {
+                let mut pos = (*pos - min).as_uvec3();
+                // engine axes are right handed
+                if left_handed {
+                    pos.z = size.z - 1 - pos.z;
+                }
+                let material = colors.binary_search(&quantize(*rgb, shift)).unwrap() + 1;
+                (pos, material as u8)
+            })
+            .collect();
+
+        Ok((VoxelModel { size, voxels }, palette))
+    }
+}
 
 impl GH {
     pub fn empty(texture_size: u32) -> Self {
@@ -24,8 +345,9 @@ impl GH {
         Self {
             levels,
             texture_size,
-            texture_data: vec![0; (texture_size * texture_size * texture_size * 2) as usize],
-            pallete: Pallete([[0.0; 4]; 256]),
+            // in usize, the texture data of a 2048 world is larger than u32::MAX
+            texture_data: vec![0; (texture_size as usize).pow(3) * 2],
+            pallete: VoxelPalette::default(),
         }
     }
 
@@ -42,22 +364,59 @@ impl GH {
     pub fn get_buffer_size_from_levels(levels: &[u32; 8]) -> usize {
         let mut length = 0;
         for i in 0..8 {
-            length += levels[i] * levels[i] * levels[i];
+            length += (levels[i] as usize).pow(3);
         }
-        length as usize / 8
+        length / 8
     }
 
     pub fn get_buffer_size(&self) -> usize {
         Self::get_buffer_size_from_levels(&self.levels)
     }
 
-    pub fn from_vox(file: &[u8]) -> Result<GH, String> {
-        let vox = dot_vox::load_bytes(file)?;
-        let size = vox.models[0].size;
+    /// Calls `generator` once for every voxel coordinate, returning its (material, flags)
+    pub fn generate(
+        texture_size: u32,
+        pallete: VoxelPalette,
+        generator: &dyn Fn(IVec3) -> (u8, Flags),
+    ) -> Self {
+        let mut gh = GH::empty(texture_size);
+        gh.pallete = pallete;
+
+        let size = texture_size as i32;
+        let dim = texture_size as usize;
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    let (material, flags) = generator(IVec3::new(x, y, z));
+
+                    let index = x as usize * dim * dim + y as usize * dim + z as usize;
+                    gh.texture_data[index * 2] = material;
+                    gh.texture_data[index * 2 + 1] = flags.bits();
+                }
+            }
+        }
+
+        gh
+    }
+
+    /// Loads the model at index `model` of a file in `format`, most files only contain model 0
+    pub fn load(file: &[u8], format: VoxelFormat, model: usize) -> Result<GH, String> {
+        let (model, palette) = format.loader().load(file, model)?;
+        Self::from_model(&model, palette)
+    }
+
+    /// World of the smallest power of two size that fits the model, every voxel collides
+    pub fn from_model(model: &VoxelModel, palette: VoxelPalette) -> Result<GH, String> {
+        let size = model.size;
+        if size.min_element() == 0 || model.voxels.is_empty() {
+            return Err(format!("Model of size {:?} contains no voxels", size));
+        }
+
+        // worlds are at least 8 wide, the smallest level of the hierarchy
         let max_dim = size.x.max(size.y).max(size.z);
-        let dim = Self::next_power_of_2(max_dim as u32) as usize;
+        let dim = Self::next_power_of_2(max_dim).max(8) as usize;
 
-        println!("dim: {:?}", dim);
+        debug!("Loading a {:?} model into a world of size {}", size, dim);
 
         if dim > 256 {
             return Err(format!(
@@ -67,46 +426,240 @@ impl GH {
         }
 
         let mut gh = GH::empty(dim as u32);
+        gh.pallete = palette;
+
+        for (pos, material) in &model.voxels {
+            let index = pos.x as usize * dim * dim + pos.y as usize * dim + pos.z as usize;
+
+            gh.texture_data[index * 2] = *material;
+            gh.texture_data[index * 2 + 1] = Flags::COLLISION_FLAG.bits();
+        }
 
-        for i in 0..256 {
-            let colour = vox.palette[i];
-            let mut material = Vec4::new(
-                colour.r as f32 / 255.0,
-                colour.g as f32 / 255.0,
-                colour.b as f32 / 255.0,
-                0.0,
-            );
-            material = material.powf(2.2);
-
-            if let Some(vox_material) = vox.materials.get(i) {
-                let vox_material = vox_material.properties.clone();
-                if vox_material["_type"] == "_emit" {
-                    material *= 1.0 + vox_material["_emit"].parse::<f32>().unwrap();
-                    if vox_material.contains_key("_flux") {
-                        material = material.powf(vox_material["_flux"].parse::<f32>().unwrap());
+        Ok(gh)
+    }
+
+    /// Inverse of loading a `.vox` file. Fails with `InvalidInput` for worlds wider than the
+    /// 256 voxels a `.vox` model can hold
+    pub fn to_vox(&self) -> io::Result<dot_vox::DotVoxData> {
+        if self.texture_size > 256 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "A {} voxel wide world doesn't fit in a .vox model of at most 256",
+                    self.texture_size
+                ),
+            ));
+        }
+
+        let dim = self.texture_size as usize;
+
+        let mut voxels = Vec::new();
+        for x in 0..dim {
+            for y in 0..dim {
+                for z in 0..dim {
+                    let index = x * dim * dim + y * dim + z;
+                    let material = self.texture_data[index * 2];
+                    if material == 0 {
+                        continue;
                     }
-                    material.w = 1.0;
+
+                    voxels.push(dot_vox::Voxel {
+                        x: (dim - 1 - x) as u8,
+                        y: z as u8,
+                        z: y as u8,
+                        i: material - 1,
+                    });
                 }
             }
-
-            gh.pallete[i] = material.to_array();
         }
 
-        for voxel in &vox.models[0].voxels {
-            let pos = IVec3::new(
-                size.x as i32 - 1 - voxel.x as i32,
-                voxel.z as i32,
-                voxel.y as i32,
-            );
+        let mut palette: Vec<dot_vox::Color> = self.pallete[1..]
+            .iter()
+            .map(|material| {
+                let colour = material.color.clamp(Vec3::ZERO, Vec3::ONE).powf(1.0 / 2.2) * 255.0;
+                dot_vox::Color {
+                    r: colour.x.round() as u8,
+                    g: colour.y.round() as u8,
+                    b: colour.z.round() as u8,
+                    a: 255,
+                }
+            })
+            .collect();
+        palette.push(dot_vox::Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        });
+
+        let materials = self
+            .pallete
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(id, material)| {
+                let mut properties = dot_vox::Dict::new();
+                let material_type = if material.emission > 0.0 {
+                    properties.insert("_emit".to_string(), material.emission.to_string());
+                    "_emit"
+                } else if material.metal > 0.0 {
+                    properties.insert("_metal".to_string(), material.metal.to_string());
+                    "_metal"
+                } else {
+                    "_diffuse"
+                };
+                properties.insert("_type".to_string(), material_type.to_string());
+                properties.insert("_rough".to_string(), material.roughness.to_string());
+
+                dot_vox::Material {
+                    id: id as u32,
+                    properties,
+                }
+            })
+            .collect();
 
-            let index = 
-                pos.x as usize * dim * dim + pos.y as usize * dim + pos.z as usize;
+        Ok(dot_vox::DotVoxData {
+            version: 150,
+            index_map: dot_vox::DEFAULT_INDEX_MAP.to_vec(),
+            models: vec![dot_vox::Model {
+                size: dot_vox::Size {
+                    x: dim as u32,
+                    y: dim as u32,
+                    z: dim as u32,
+                },
+                voxels,
+            }],
+            palette,
+            materials,
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        })
+    }
+
+    /// Greedy meshes the surfaces of every voxel that isn't animated, merging faces of the
+    /// same material into quads, with vertex colors from the palette. Positions are in meters
+    /// matching the traced world at `voxels_per_meter`
+    pub fn to_mesh(&self, voxels_per_meter: f32) -> Mesh {
+        let dim = self.texture_size as i32;
+        let material = |position: [i32; 3]| -> u8 {
+            if position.iter().any(|&p| p < 0 || p >= dim) {
+                return 0;
+            }
 
-            gh.texture_data[index as usize * 2] = voxel.i;
-            gh.texture_data[index as usize * 2 + 1] = Flags::COLLISION_FLAG;
+            let index = ((position[0] * dim + position[1]) * dim + position[2]) as usize * 2;
+            let flags = Flags::from(self.texture_data[index + 1]);
+            if flags.contains(Flags::ANIMATION_FLAG) {
+                return 0;
+            }
+            self.texture_data[index]
+        };
+
+        let mut positions: Vec<[f32; 3]> = Vec::new();
+        let mut normals: Vec<[f32; 3]> = Vec::new();
+        let mut colors: Vec<[f32; 4]> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        let to_world = |position: [i32; 3]| -> [f32; 3] {
+            position.map(|p| (p - dim / 2) as f32 / voxels_per_meter)
+        };
+        let add = |a: [i32; 3], b: [i32; 3]| [a[0] + b[0], a[1] + b[1], a[2] + b[2]];
+
+        // faces between slices along axis d, positive materials face +d and negative face -d
+        let mut mask = vec![0i16; (dim * dim) as usize];
+        for d in 0..3 {
+            let u = (d + 1) % 3;
+            let v = (d + 2) % 3;
+
+            for slice in 0..=dim {
+                for j in 0..dim {
+                    for i in 0..dim {
+                        let mut front = [0; 3];
+                        front[d] = slice;
+                        front[u] = i;
+                        front[v] = j;
+                        let mut back = front;
+                        back[d] -= 1;
+
+                        let (a, b) = (material(back), material(front));
+                        mask[(j * dim + i) as usize] = match (a != 0, b != 0) {
+                            (true, false) => a as i16,
+                            (false, true) => -(b as i16),
+                            _ => 0,
+                        };
+                    }
+                }
+
+                for j in 0..dim {
+                    let mut i = 0;
+                    while i < dim {
+                        let face = mask[(j * dim + i) as usize];
+                        if face == 0 {
+                            i += 1;
+                            continue;
+                        }
+
+                        let mut width = 1;
+                        while i + width < dim && mask[(j * dim + i + width) as usize] == face {
+                            width += 1;
+                        }
+                        let mut height = 1;
+                        'grow: while j + height < dim {
+                            for k in 0..width {
+                                if mask[((j + height) * dim + i + k) as usize] != face {
+                                    break 'grow;
+                                }
+                            }
+                            height += 1;
+                        }
+                        for l in 0..height {
+                            for k in 0..width {
+                                mask[((j + l) * dim + i + k) as usize] = 0;
+                            }
+                        }
+
+                        let mut corner = [0; 3];
+                        corner[d] = slice;
+                        corner[u] = i;
+                        corner[v] = j;
+                        let mut du = [0; 3];
+                        du[u] = width;
+                        let mut dv = [0; 3];
+                        dv[v] = height;
+                        let quad = [
+                            corner,
+                            add(corner, du),
+                            add(add(corner, du), dv),
+                            add(corner, dv),
+                        ];
+                        let mut normal = [0.0; 3];
+                        normal[d] = face.signum() as f32;
+                        let color = self.pallete[face.unsigned_abs() as usize].color;
+
+                        let start = positions.len() as u32;
+                        for position in quad {
+                            positions.push(to_world(position));
+                            normals.push(normal);
+                            colors.push([color.x, color.y, color.z, 1.0]);
+                        }
+                        // u x v points along +d, flip the winding for faces towards -d
+                        let order = if face > 0 {
+                            [0, 1, 2, 0, 2, 3]
+                        } else {
+                            [0, 2, 1, 0, 3, 2]
+                        };
+                        indices.extend(order.map(|i| start + i));
+
+                        i += width;
+                    }
+                }
+            }
         }
 
-        Ok(gh)
+        Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors)
+            .with_inserted_indices(Indices::U32(indices))
     }
 
     fn next_power_of_2(number: u32) -> u32 {
@@ -1,130 +1,552 @@
 use crate::{
     voxel_pipeline::{
         compute::{AnimationData, PhysicsData},
-        voxel_world::{ExtractedPortal, VoxelUniforms},
+        voxel_world::{world_to_voxel, ExtractedPortal, VoxelUniforms, VoxelWorld},
     },
-    Box, BoxCollider, Edges, Particle, Portal, RenderGraphSettings, VoxelPhysics,
-    VoxelizationMaterial, VoxelizationMaterialType,
+    Box, BoxCollider, CapsuleCollider, CollisionEffect, Edges, Flags, Particle, Portal,
+    RenderGraphSettings, SimulationControl, SphereCollider, VoxelCollisionEvent, VoxelPhysics,
+    VoxelTriggerEvent, VoxelizationMaterial, VoxelizationMaterialType,
 };
 use bevy::{
+    core::FrameCount,
+    gizmos::GizmoPlugin,
     prelude::*,
-    render::render_resource::MapMode,
+    render::extract_resource::{ExtractResource, ExtractResourcePlugin},
+    render::render_resource::Buffer,
     render::renderer::{RenderDevice, RenderQueue},
     utils::HashMap,
 };
+use std::collections::BTreeSet;
 
+/// Default for [`VoxelWorldSettings::voxels_per_meter`](crate::VoxelWorldSettings)
 pub const VOXELS_PER_METER: f32 = 4.0;
 
+/// Settings of the physics compute pass
+#[derive(Resource, Clone, ExtractResource)]
+pub struct PhysicsSettings {
+    /// World gravity of the `VoxelPhysics` bodies without their own
+    /// [`gravity`](VoxelPhysics::gravity), also what fluids push against
+    pub gravity: Vec3,
+    /// Steps the physics pass is run for each frame, each advancing the bodies by an equal
+    /// part of the frame time. More steps keep fast bodies from tunneling and stacked bodies
+    /// from jittering, at the cost of gpu time. 0 is treated as 1
+    pub substeps: u32,
+    /// Frames a body has to rest on a surface slower than `sleep_velocity` before the physics
+    /// pass stops moving it, 0 never puts bodies to sleep. See [`VoxelPhysics::sleeping`]
+    pub sleep_frames: u32,
+    /// Speed in meters per second below which a body counts as resting
+    pub sleep_velocity: f32,
+}
+
+impl Default for PhysicsSettings {
+    fn default() -> Self {
+        Self {
+            gravity: Vec3::new(0.0, -9.81, 0.0),
+            substeps: 1,
+            sleep_frames: 0,
+            sleep_velocity: 0.1,
+        }
+    }
+}
+
+/// Words of the physics state of each body, its translation, velocity and the snapshot of its
+/// position other bodies are pushed out of
+const PHYSICS_STATE_WORDS: usize = 9;
+
+/// Sleeping bodies are woken by collisions of other bodies closer than this, in meters
+const WAKE_DISTANCE: f32 = 2.0;
+
+/// Speed in meters per second debris of [`CollisionEffect::Shatter`] is thrown at
+const SHATTER_SPEED: f32 = 4.0;
+
+/// Seconds debris of [`CollisionEffect::Shatter`] lasts before it is despawned
+const SHATTER_LIFETIME: f32 = 3.0;
+
+/// Most debris of [`CollisionEffect::Shatter`] alive at once, later shatters throw less
+const MAX_SHATTER_DEBRIS: usize = 256;
+
+/// Hit read back for a body with [`CollisionEffect::Shatter`], sent on every hit unlike
+/// [`VoxelCollisionEvent`]
+#[derive(Event, Clone, Copy)]
+pub(crate) struct ShatterHit {
+    entity: Entity,
+    normal: Vec3,
+    position: Vec3,
+}
+
+/// Debris spawned by [`shatter_voxels`], despawned when the timer finishes
+#[derive(Component)]
+struct ShatterDebris(Timer);
+
+/// Playback speed of the voxel animations, the wind swaying grass in the automata pass and the
+/// [`PaletteAnimation`](crate::PaletteAnimation) colors. `0.0` freezes them without affecting
+/// physics
+#[derive(Resource, Clone, ExtractResource)]
+pub struct AnimationSettings {
+    pub speed: f32,
+}
+
+impl Default for AnimationSettings {
+    fn default() -> Self {
+        Self { speed: 1.0 }
+    }
+}
+
+/// Seconds the voxel animations have played for, advanced by the frame time scaled by
+/// [`AnimationSettings::speed`] while the simulation runs
+#[derive(Resource, Clone, Copy, Default, ExtractResource)]
+pub(crate) struct AnimationClock(pub f32);
+
+/// Draws the collider bounds and velocity of every `VoxelPhysics` body with gizmos, on top of
+/// the voxels. Requires bevy's `GizmoPlugin`, which `DefaultPlugins` includes
+#[derive(Resource, Clone)]
+pub struct PhysicsDebug {
+    pub enabled: bool,
+    /// Seconds of travel the velocity vector is drawn for
+    pub velocity_scale: f32,
+}
+
+impl Default for PhysicsDebug {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            velocity_scale: 0.1,
+        }
+    }
+}
+
+/// Physics buffer usage of the bodies uploaded this frame, bodies stop simulating once they
+/// no longer fit in the buffer
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct PhysicsStats {
+    /// `VoxelPhysics` bodies sent to the physics pass, sleeping ones included, 0 when they
+    /// didn't fit
+    pub bodies: u32,
+    /// Bytes the bodies need, counted even when they didn't fit
+    pub bytes_used: u64,
+    /// Size of the physics buffer in bytes, see [`ComputeBufferSettings`](crate::ComputeBufferSettings)
+    pub capacity: u64,
+}
+
+impl PhysicsStats {
+    /// Fraction of the physics buffer used, above 1.0 when the bodies didn't fit
+    pub fn usage(&self) -> f32 {
+        match self.capacity {
+            0 => 0.0,
+            capacity => self.bytes_used as f32 / capacity as f32,
+        }
+    }
+}
+
+/// [`PhysicsStats::usage`] above which a warning is logged
+const PHYSICS_USAGE_WARNING: f32 = 0.9;
+
 pub struct PhysicsPlugin;
 
 impl Plugin for PhysicsPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(PreUpdate, insert_physics_data)
-            .add_systems(PostUpdate, extract_physics_data)
-            .add_systems(PostUpdate, extract_animation_data);
+        app.init_resource::<PhysicsSettings>()
+            .init_resource::<AnimationSettings>()
+            .init_resource::<AnimationClock>()
+            .init_resource::<PhysicsStats>()
+            .add_event::<VoxelCollisionEvent>()
+            .add_event::<VoxelTriggerEvent>()
+            .add_event::<ShatterHit>()
+            .add_plugins(ExtractResourcePlugin::<PhysicsSettings>::default())
+            .add_plugins(ExtractResourcePlugin::<AnimationClock>::default())
+            .add_systems(PreUpdate, (insert_physics_data, shatter_voxels).chain())
+            .add_systems(Update, despawn_shatter_debris)
+            .add_systems(PostUpdate, (wake_bodies, extract_physics_data).chain())
+            .add_systems(PostUpdate, (advance_animation_clock, extract_animation_data));
+
+        app.init_resource::<PhysicsDebug>();
+        if app.is_plugin_added::<GizmoPlugin>() {
+            app.add_systems(PostUpdate, draw_physics_debug);
+        }
     }
 }
 
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
 pub fn extract_physics_data(
-    particle_query: Query<(&Transform, &VoxelPhysics, Entity), Without<BoxCollider>>,
-    box_query: Query<(&Transform, &VoxelPhysics, &BoxCollider, Entity)>,
+    mut particle_query: Query<
+        (&Transform, &mut VoxelPhysics, Entity),
+        (
+            Without<BoxCollider>,
+            Without<SphereCollider>,
+            Without<CapsuleCollider>,
+        ),
+    >,
+    mut box_query: Query<(&Transform, &mut VoxelPhysics, &BoxCollider, Entity)>,
+    mut sphere_query: Query<
+        (&Transform, &mut VoxelPhysics, &SphereCollider, Entity),
+        Without<BoxCollider>,
+    >,
+    mut capsule_query: Query<
+        (&Transform, &mut VoxelPhysics, &CapsuleCollider, Entity),
+        (Without<BoxCollider>, Without<SphereCollider>),
+    >,
     mut physics_data: ResMut<PhysicsData>,
+    mut physics_stats: ResMut<PhysicsStats>,
     render_queue: Res<RenderQueue>,
+    render_graph_settings: Res<RenderGraphSettings>,
+    simulation_control: Res<SimulationControl>,
+    frame_count: Res<FrameCount>,
+    mut physics_slots: Local<PhysicsSlots>,
 ) {
     let mut type_buffer = TypeBuffer::new();
     let mut entities = HashMap::new();
+    let state_buffer = &physics_data.physics_state_gpu;
+    let frame = frame_count.0;
 
     // Add points
-    for (transform, voxel_physics, entity) in particle_query.iter() {
-        entities.insert(entity, type_buffer.header.len());
+    for (transform, voxel_physics, entity) in particle_query.iter_mut() {
+        let slot = physics_slots.slot(entity);
+        entities.insert(entity, slot);
 
-        type_buffer.push_object(0, |type_buffer| {
-            type_buffer.push_vec3(transform.translation);
-            type_buffer.push_vec3(voxel_physics.velocity);
-            type_buffer.push_vec3(voxel_physics.gravity);
-            type_buffer.push_vec3(voxel_physics.collision_effect.to_vec3());
-            type_buffer.push_vec3(Vec3::ZERO); // space to recieve hit data
-            type_buffer.push_mat3(Mat3::IDENTITY); // space to recieve portal rotation
+        type_buffer.insert_object(slot, 0, |type_buffer| {
+            push_body_header(type_buffer, transform, &voxel_physics);
         });
+        upload_body_state(state_buffer, &render_queue, slot, transform, voxel_physics, frame);
     }
 
     // Add boxes
-    for (transform, voxel_physics, box_collider, entity) in box_query.iter() {
-        entities.insert(entity, type_buffer.header.len());
+    for (transform, voxel_physics, box_collider, entity) in box_query.iter_mut() {
+        let slot = physics_slots.slot(entity);
+        entities.insert(entity, slot);
 
-        type_buffer.push_object(1, |type_buffer| {
-            type_buffer.push_vec3(transform.translation);
-            type_buffer.push_vec3(voxel_physics.velocity);
-            type_buffer.push_vec3(voxel_physics.gravity);
-            type_buffer.push_vec3(voxel_physics.collision_effect.to_vec3());
-            type_buffer.push_vec3(Vec3::ZERO); // space to recieve hit data
-            type_buffer.push_mat3(Mat3::IDENTITY); // space to recieve portal rotation
+        type_buffer.insert_object(slot, 1, |type_buffer| {
+            push_body_header(type_buffer, transform, &voxel_physics);
             type_buffer.push_ivec3(box_collider.half_size);
         });
+        upload_body_state(state_buffer, &render_queue, slot, transform, voxel_physics, frame);
     }
 
-    physics_data.dispatch_size = type_buffer.header.len() as u32;
+    // Add spheres
+    for (transform, voxel_physics, sphere_collider, entity) in sphere_query.iter_mut() {
+        let slot = physics_slots.slot(entity);
+        entities.insert(entity, slot);
+
+        type_buffer.insert_object(slot, 2, |type_buffer| {
+            push_body_header(type_buffer, transform, &voxel_physics);
+            type_buffer.push_u32(sphere_collider.radius);
+        });
+        upload_body_state(state_buffer, &render_queue, slot, transform, voxel_physics, frame);
+    }
+
+    // Add capsules
+    for (transform, voxel_physics, capsule_collider, entity) in capsule_query.iter_mut() {
+        let slot = physics_slots.slot(entity);
+        entities.insert(entity, slot);
+
+        type_buffer.insert_object(slot, 3, |type_buffer| {
+            push_body_header(type_buffer, transform, &voxel_physics);
+            type_buffer.push_u32(capsule_collider.radius);
+            type_buffer.push_u32(capsule_collider.half_height);
+            type_buffer.push_vec3(capsule_collider.axis.to_vec3());
+        });
+        upload_body_state(state_buffer, &render_queue, slot, transform, voxel_physics, frame);
+    }
+
+    let oldest_readback = physics_data
+        .readbacks
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|readback| readback.in_use)
+        .map(|readback| readback.frame)
+        .min();
+    physics_slots.release(&entities, frame_count.0, oldest_readback);
+
+    // sleeping bodies stay in the header for the others to collide with, only the awake ones
+    // are dispatched
+    let awake_slots = type_buffer.awake_slots();
+    physics_data.dispatch_size = awake_slots.len() as u32;
     physics_data.buffer_length = (type_buffer.header.len() + type_buffer.data.len() + 1) as u64;
 
-    // Copy physics data to the buffer
-    render_queue.write_buffer(
-        &physics_data.physics_buffer_gpu,
-        0,
-        bytemuck::cast_slice(&type_buffer.finish()),
-    );
+    let last_usage = physics_stats.usage();
+    *physics_stats = PhysicsStats {
+        bodies: entities.len() as u32,
+        bytes_used: physics_data.buffer_length * 4,
+        capacity: physics_data.physics_buffer_gpu.size(),
+    };
+    let usage = physics_stats.usage();
+    if usage > PHYSICS_USAGE_WARNING && last_usage <= PHYSICS_USAGE_WARNING && usage <= 1.0 {
+        warn!(
+            "{} physics bodies use {:.0}% of the physics buffer, increase \
+             `ComputeBufferSettings::max_buffer_entries`",
+            physics_stats.bodies,
+            usage * 100.0
+        );
+    }
+
+    if physics_data.buffer_length * 4 > physics_data.physics_buffer_gpu.size() {
+        warn!(
+            "Physics data doesn't fit in the physics buffer, increase \
+             `ComputeBufferSettings::max_buffer_entries`"
+        );
+        physics_data.dispatch_size = 0;
+        physics_data.buffer_length = 0;
+        physics_stats.bodies = 0;
+    } else {
+        // Copy physics data to the buffer
+        render_queue.write_buffer(
+            &physics_data.physics_buffer_gpu,
+            0,
+            bytemuck::cast_slice(&type_buffer.finish()),
+        );
+        let mut awake = vec![physics_data.dispatch_size];
+        awake.extend(awake_slots);
+        render_queue.write_buffer(
+            &physics_data.physics_awake_gpu,
+            0,
+            bytemuck::cast_slice(&awake),
+        );
+    }
+
+    // Reserve a free readback buffer, skipping the readback when all are still in flight
+    // or when the physics pass won't dispatch this frame
+    physics_data.readback_index = None;
+    if render_graph_settings.physics
+        && simulation_control.should_run()
+        && physics_data.dispatch_size > 0
+    {
+        let mut readbacks = physics_data.readbacks.lock().unwrap();
+        let free = readbacks.iter().position(|readback| !readback.in_use);
+        if let Some(index) = free {
+            let readback = &mut readbacks[index];
+            readback.in_use = true;
+            readback.frame = frame_count.0;
+            readback.buffer_length = physics_data.buffer_length;
+            readback.entities = entities.clone();
+        }
+        drop(readbacks);
+        physics_data.readback_index = free;
+    }
 
     physics_data.entities = entities;
 }
 
+/// Wakes sleeping bodies that were moved or given a velocity since they fell asleep, or that
+/// a moving body hit voxels next to. Resting bodies keep hitting the surface they rest on, so
+/// they don't wake their neighbours
+fn wake_bodies(mut bodies: Query<(&Transform, &mut VoxelPhysics)>) {
+    let impacts: Vec<Vec3> = bodies
+        .iter()
+        .filter(|(_, voxel_physics)| {
+            !voxel_physics.sleeping
+                && voxel_physics.rest_frames == 0
+                && voxel_physics.hit_normal != Vec3::ZERO
+        })
+        .map(|(transform, _)| transform.translation)
+        .collect();
+    for (transform, mut voxel_physics) in bodies.iter_mut() {
+        if !voxel_physics.sleeping {
+            continue;
+        }
+
+        let moved = transform.translation != voxel_physics.sleep_translation
+            || voxel_physics.velocity != Vec3::ZERO;
+        let hit_nearby = impacts.iter().any(|impact| {
+            impact.distance_squared(transform.translation) < WAKE_DISTANCE * WAKE_DISTANCE
+        });
+        if moved || hit_nearby {
+            voxel_physics.wake();
+        }
+    }
+}
+
+/// Spawns the debris of the bodies with [`CollisionEffect::Shatter`] that hit voxels, the gpu
+/// has already destroyed them by the time the collision is read back
+#[allow(clippy::type_complexity)]
+fn shatter_voxels(
+    mut commands: Commands,
+    mut shatter_hits: EventReader<ShatterHit>,
+    bodies: Query<(
+        &VoxelPhysics,
+        Option<&BoxCollider>,
+        Option<&SphereCollider>,
+        Option<&CapsuleCollider>,
+    )>,
+    debris_query: Query<(), With<ShatterDebris>>,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    let mut debris_count = debris_query.iter().count();
+    for event in shatter_hits.read() {
+        let Ok((voxel_physics, box_collider, sphere_collider, capsule_collider)) =
+            bodies.get(event.entity)
+        else {
+            continue;
+        };
+        let CollisionEffect::Shatter { radius, count } = voxel_physics.collision_effect else {
+            continue;
+        };
+
+        // the impact is on the face that hit, like the gpu's collision effects
+        let extent = match (box_collider, sphere_collider, capsule_collider) {
+            (Some(box_collider), ..) => event.normal.abs().dot(box_collider.half_size.as_vec3()),
+            (None, Some(sphere_collider), _) => sphere_collider.radius as f32,
+            (None, None, Some(capsule_collider)) => {
+                capsule_collider.radius as f32
+                    + event.normal.dot(capsule_collider.axis.to_vec3()).abs()
+                        * capsule_collider.half_height as f32
+            }
+            (None, None, None) => 0.0,
+        };
+        let impact = event.position - event.normal * extent / voxel_world.voxels_per_meter();
+
+        let min = voxel_world.world_to_voxel(impact - radius);
+        let max = voxel_world.world_to_voxel(impact + radius);
+        let shattered: Vec<_> = voxel_world
+            .voxels_in(min, max)
+            .into_iter()
+            .filter(|(position, voxel)| {
+                !voxel.flags.contains(Flags::ANIMATION_FLAG)
+                    && voxel_world.voxel_to_world(*position).distance(impact) < radius
+            })
+            .collect();
+
+        // spread the debris evenly over the shattered voxels
+        let debris = (count as usize)
+            .min(shattered.len())
+            .min(MAX_SHATTER_DEBRIS.saturating_sub(debris_count));
+        debris_count += debris;
+        for i in 0..debris {
+            let (position, voxel) = shattered[i * shattered.len() / debris];
+            let translation = voxel_world.voxel_to_world(position);
+            let direction =
+                ((translation - impact).normalize_or_zero() + event.normal).normalize_or_zero();
+            commands.spawn((
+                Transform::from_translation(translation),
+                Particle {
+                    material: voxel.material,
+                    flags: Flags::ANIMATION_FLAG,
+                },
+                VoxelPhysics::new(direction * SHATTER_SPEED, CollisionEffect::None),
+                ShatterDebris(Timer::from_seconds(SHATTER_LIFETIME, TimerMode::Once)),
+            ));
+        }
+
+        // keep the cpu copy in step with the voxels destroyed on the gpu, without writing over
+        // the rest of the sphere which the cpu copy may not have up to date
+        for (position, _) in shattered {
+            voxel_world.set_voxel(position, 0, Flags::empty());
+        }
+    }
+}
+
+fn despawn_shatter_debris(
+    mut commands: Commands,
+    mut debris_query: Query<(Entity, &mut ShatterDebris)>,
+    time: Res<Time>,
+) {
+    for (entity, mut debris) in &mut debris_query {
+        if debris.0.tick(time.delta()).finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn insert_physics_data(
     mut voxel_physics_query: Query<(&mut Transform, &mut VoxelPhysics, Entity)>,
-    physics_data: Res<PhysicsData>,
+    physics_settings: Res<PhysicsSettings>,
+    mut physics_data: ResMut<PhysicsData>,
     render_device: Res<RenderDevice>,
     render_graph_settings: Res<RenderGraphSettings>,
+    mut collision_events: EventWriter<VoxelCollisionEvent>,
+    mut trigger_events: EventWriter<VoxelTriggerEvent>,
+    mut shatter_hits: EventWriter<ShatterHit>,
 ) {
     if !render_graph_settings.physics {
         return;
     }
 
-    // Process last frames physics data
-    if physics_data.dispatch_size > 0 {
-        let physics_buffer_slice = physics_data
-            .physics_buffer_cpu
-            .slice(..physics_data.buffer_length * 4);
-
-        physics_buffer_slice.map_async(MapMode::Read, |_| {});
-        render_device.poll(wgpu::Maintain::Wait);
-
-        let data = physics_buffer_slice.get_mapped_range();
-        let result: Vec<u32> = bytemuck::cast_slice(&data).to_vec();
+    // Run any map callbacks without blocking on the gpu
+    render_device.poll(wgpu::Maintain::Poll);
 
-        drop(data);
-        physics_data.physics_buffer_cpu.unmap();
+    // Read every completed readback, keeping the most recent one
+    let mut latest: Option<(u32, Vec<u32>, HashMap<Entity, usize>)> = None;
+    {
+        let mut readbacks = physics_data.readbacks.lock().unwrap();
+        for index in physics_data.readback_receiver.try_iter() {
+            let readback = &mut readbacks[index];
+
+            let data = readback
+                .buffer
+                .slice(..readback.buffer_length * 4)
+                .get_mapped_range();
+            let result: Vec<u32> = bytemuck::cast_slice(&data).to_vec();
+
+            drop(data);
+            readback.buffer.unmap();
+            readback.in_use = false;
+
+            let newer = match &latest {
+                Some((frame, ..)) => readback.frame > *frame,
+                None => true,
+            };
+            if newer {
+                latest = Some((readback.frame, result, std::mem::take(&mut readback.entities)));
+            }
+        }
+    }
 
-        if result[0] == 0 {            
+    // Process the latest completed frame's physics data
+    if let Some((frame, result, entities)) = latest {
+        if result[0] == 0 {
             warn!("No physics data returned from the gpu!");
             return;
         }
 
+        physics_data.set_latest_frame(frame);
+
         // Process points and boxes
         for (mut transform, mut voxel_physics, entity) in voxel_physics_query.iter_mut() {
-            if let Some(index) = physics_data.entities.get(&entity) {
+            // readbacks from before the body fell asleep
+            if voxel_physics.sleeping {
+                continue;
+            }
+
+            if let Some(index) = entities.get(&entity) {
+                let Some((mut gpu_translation, mut gpu_velocity)) = voxel_physics.gpu_state else {
+                    continue;
+                };
+
+                // edits uploaded after this frame are newer than its results
                 let data_index = result[index + 1] as usize & 0xFFFFFF;
-                transform.translation = Vec3::new(
-                    bytemuck::cast(result[data_index + 0]),
-                    bytemuck::cast(result[data_index + 1]),
-                    bytemuck::cast(result[data_index + 2]),
-                );
-                voxel_physics.velocity = Vec3::new(
-                    bytemuck::cast(result[data_index + 3]),
-                    bytemuck::cast(result[data_index + 4]),
-                    bytemuck::cast(result[data_index + 5]),
-                );
+                if frame >= voxel_physics.translation_frame {
+                    gpu_translation = Vec3::new(
+                        bytemuck::cast(result[data_index + 0]),
+                        bytemuck::cast(result[data_index + 1]),
+                        bytemuck::cast(result[data_index + 2]),
+                    );
+                    transform.translation = gpu_translation;
+                }
+                if frame >= voxel_physics.velocity_frame {
+                    gpu_velocity = Vec3::new(
+                        bytemuck::cast(result[data_index + 3]),
+                        bytemuck::cast(result[data_index + 4]),
+                        bytemuck::cast(result[data_index + 5]),
+                    );
+                    voxel_physics.velocity = gpu_velocity;
+                }
+                voxel_physics.gpu_state = Some((gpu_translation, gpu_velocity));
                 voxel_physics.hit_normal = Vec3::new(
                     bytemuck::cast(result[data_index + 12]),
                     bytemuck::cast(result[data_index + 13]),
                     bytemuck::cast(result[data_index + 14]),
                 );
+                voxel_physics.grounded = voxel_physics.hit_normal.y > 0.5;
+                voxel_physics.contact_normal = Vec3::new(
+                    bytemuck::cast(result[data_index + 31]),
+                    bytemuck::cast(result[data_index + 32]),
+                    bytemuck::cast(result[data_index + 33]),
+                );
+                voxel_physics.body_contact_normal = Vec3::new(
+                    bytemuck::cast(result[data_index + 34]),
+                    bytemuck::cast(result[data_index + 35]),
+                    bytemuck::cast(result[data_index + 36]),
+                );
                 voxel_physics.portal_rotation = Mat3::from_cols(
                     Vec3::new(
                         bytemuck::cast(result[data_index + 15]),
@@ -142,22 +564,121 @@ pub fn insert_physics_data(
                         bytemuck::cast(result[data_index + 23]),
                     ),
                 );
+
+                // Triggers return the overlap in place of the collision effect
+                if let CollisionEffect::Trigger = voxel_physics.collision_effect {
+                    let voxels = result[data_index + 10];
+                    if voxels > 0 {
+                        trigger_events.send(VoxelTriggerEvent {
+                            entity,
+                            position: transform.translation,
+                            voxels,
+                            material: result[data_index + 11] as u8,
+                        });
+                    }
+                } else if voxel_physics.hit_normal != Vec3::ZERO && !voxel_physics.in_contact {
+                    collision_events.send(VoxelCollisionEvent {
+                        entity,
+                        normal: voxel_physics.hit_normal,
+                        position: transform.translation,
+                    });
+                }
+                voxel_physics.in_contact = voxel_physics.hit_normal != Vec3::ZERO;
+
+                // the gpu shatters voxels on every hit, not only the first of a contact
+                let shatters =
+                    matches!(voxel_physics.collision_effect, CollisionEffect::Shatter { .. });
+                if shatters && voxel_physics.in_contact {
+                    shatter_hits.send(ShatterHit {
+                        entity,
+                        normal: voxel_physics.hit_normal,
+                        position: transform.translation,
+                    });
+                }
+
+                let resting = physics_settings.sleep_frames > 0
+                    && !matches!(voxel_physics.collision_effect, CollisionEffect::Trigger)
+                    && voxel_physics.hit_normal != Vec3::ZERO
+                    && voxel_physics.velocity.length() < physics_settings.sleep_velocity;
+                if resting {
+                    voxel_physics.rest_frames += 1;
+                    if voxel_physics.rest_frames >= physics_settings.sleep_frames {
+                        voxel_physics.sleeping = true;
+                        voxel_physics.velocity = Vec3::ZERO;
+                        voxel_physics.sleep_translation = transform.translation;
+                    }
+                } else {
+                    voxel_physics.rest_frames = 0;
+                }
             }
         }
     }
 }
 
 #[allow(unused)]
-pub fn world_to_voxel(world_pos: Vec3, voxel_world_size: u32) -> IVec3 {
-    let world_pos = world_pos * VOXELS_PER_METER;
-    world_pos.as_ivec3() + IVec3::splat(voxel_world_size as i32 / 2)
+pub fn world_to_render(world_pos: Vec3, voxel_uniforms: &VoxelUniforms) -> Vec3 {
+    let world_pos = world_pos - voxel_uniforms.origin;
+    2.0 * world_pos * voxel_uniforms.voxels_per_meter / voxel_uniforms.texture_size as f32
 }
 
-#[allow(unused)]
-pub fn world_to_render(world_pos: Vec3, voxel_world_size: u32) -> Vec3 {
-    2.0 * world_pos * VOXELS_PER_METER / voxel_world_size as f32
+/// Header slots of the physics bodies. A body keeps its slot while it lives, the slot of a
+/// despawned body is only reused once no readback from before the despawn is in flight
+#[derive(Default)]
+pub(crate) struct PhysicsSlots {
+    slots: HashMap<Entity, usize>,
+    /// Slots of despawned bodies and the frame they were released on
+    released: Vec<(usize, u32)>,
+    free: BTreeSet<usize>,
+    len: usize,
 }
 
+impl PhysicsSlots {
+    /// Slot of the body, the lowest free slot for new bodies
+    fn slot(&mut self, entity: Entity) -> usize {
+        *self.slots.entry(entity).or_insert_with(|| match self.free.pop_first() {
+            Some(slot) => slot,
+            None => {
+                self.len += 1;
+                self.len - 1
+            }
+        })
+    }
+
+    /// Releases the slots of the bodies missing from `entities`, and frees the released
+    /// slots that no readback started before `oldest_readback` can still refer to
+    fn release(
+        &mut self,
+        entities: &HashMap<Entity, usize>,
+        frame: u32,
+        oldest_readback: Option<u32>,
+    ) {
+        let released = &mut self.released;
+        self.slots.retain(|entity, slot| {
+            let live = entities.contains_key(entity);
+            if !live {
+                released.push((*slot, frame));
+            }
+            live
+        });
+
+        let free = &mut self.free;
+        self.released.retain(|&(slot, released_frame)| match oldest_readback {
+            Some(oldest) if oldest < released_frame => true,
+            _ => {
+                free.insert(slot);
+                false
+            }
+        });
+
+        while self.len > 0 && self.free.remove(&(self.len - 1)) {
+            self.len -= 1;
+        }
+    }
+}
+
+/// Object type of the header entries left empty by [`TypeBuffer::insert_object`]
+const EMPTY_OBJECT: u32 = 0xFF;
+
 #[derive(Clone)]
 struct TypeBuffer {
     header: Vec<u32>,
@@ -197,10 +718,38 @@ impl TypeBuffer {
         function(self);
     }
 
+    /// Writes the object to the header entry `slot` instead of the next one, the entries
+    /// before it that weren't written are left empty
+    fn insert_object<F>(&mut self, slot: usize, object_type: u32, function: F)
+    where
+        F: Fn(&mut Self),
+    {
+        if self.header.len() <= slot {
+            self.header.resize(slot + 1, EMPTY_OBJECT << 24);
+        }
+        self.header[slot] = self.data.len() as u32 | (object_type << 24);
+        function(self);
+    }
+
+    /// Header slots of the bodies without the sleeping bit in their flags
+    fn awake_slots(&self) -> Vec<u32> {
+        (0..self.header.len() as u32)
+            .filter(|&slot| {
+                let object = self.header[slot as usize];
+                object >> 24 != EMPTY_OBJECT
+                    && self.data[(object & 0xFFFFFF) as usize + 28] & 2 == 0
+            })
+            .collect()
+    }
+
     fn push_u32(&mut self, value: u32) {
         self.data.push(bytemuck::cast(value));
     }
 
+    fn push_f32(&mut self, value: f32) {
+        self.data.push(bytemuck::cast(value));
+    }
+
     fn push_vec3(&mut self, value: Vec3) {
         self.data.push(bytemuck::cast(value.x));
         self.data.push(bytemuck::cast(value.y));
@@ -226,6 +775,95 @@ impl TypeBuffer {
     }
 }
 
+/// Words every body starts with, the collider's follow them
+fn push_body_header(
+    type_buffer: &mut TypeBuffer,
+    transform: &Transform,
+    voxel_physics: &VoxelPhysics,
+) {
+    let (gravity, gravity_scale) = packed_gravity(voxel_physics);
+    // the gpu steps the body from its physics state, these receive the result
+    type_buffer.push_vec3(transform.translation);
+    type_buffer.push_vec3(voxel_physics.velocity);
+    type_buffer.push_vec3(gravity);
+    type_buffer.push_vec3(voxel_physics.collision_effect.to_vec3());
+    type_buffer.push_vec3(Vec3::ZERO); // space to recieve hit data
+    type_buffer.push_mat3(Mat3::IDENTITY); // space to recieve portal rotation
+    type_buffer.push_f32(voxel_physics.restitution);
+    type_buffer.push_f32(voxel_physics.friction);
+    type_buffer.push_u32(voxel_physics.layer);
+    type_buffer.push_u32(voxel_physics.mask);
+    type_buffer.push_u32(body_flags(voxel_physics));
+    type_buffer.push_f32(gravity_scale);
+    type_buffer.push_f32(voxel_physics.density);
+    type_buffer.push_vec3(Vec3::ZERO); // space to recieve the contact normal
+    type_buffer.push_vec3(Vec3::ZERO); // space to recieve the body contact normal
+}
+
+/// Writes the translation or velocity of a body to its slot of the physics state when the gpu
+/// doesn't have them yet, for new bodies and the ones changed since they were read back. The
+/// gpu keeps stepping the others from the state it left them in
+fn upload_body_state(
+    state_buffer: &Buffer,
+    render_queue: &RenderQueue,
+    slot: usize,
+    transform: &Transform,
+    mut voxel_physics: Mut<VoxelPhysics>,
+    frame: u32,
+) {
+    let offset = (slot * PHYSICS_STATE_WORDS * 4) as u64;
+    if offset + PHYSICS_STATE_WORDS as u64 * 4 > state_buffer.size() {
+        return;
+    }
+
+    let gpu_state = voxel_physics.gpu_state;
+    let (gpu_translation, gpu_velocity) = gpu_state.unwrap_or_default();
+    let moved = gpu_state.is_none() || transform.translation != gpu_translation;
+    let accelerated = gpu_state.is_none() || voxel_physics.velocity != gpu_velocity;
+    if moved {
+        let translation = transform.translation.to_array();
+        render_queue.write_buffer(state_buffer, offset, bytemuck::cast_slice(&translation));
+        voxel_physics.translation_frame = frame;
+    }
+    if accelerated {
+        let velocity = voxel_physics.velocity.to_array();
+        render_queue.write_buffer(state_buffer, offset + 12, bytemuck::cast_slice(&velocity));
+        voxel_physics.velocity_frame = frame;
+    }
+    if moved || accelerated {
+        voxel_physics.gpu_state = Some((transform.translation, voxel_physics.velocity));
+    }
+}
+
+/// Ccd in the first bit and sleeping in the second, sleeping bodies are left out of the awake
+/// list the physics pass is dispatched for and only collided with
+fn body_flags(voxel_physics: &VoxelPhysics) -> u32 {
+    voxel_physics.ccd as u32 | ((voxel_physics.sleeping as u32) << 1)
+}
+
+/// Gravity and world gravity scale sent for a body, its own gravity replaces the world's and
+/// `gravity_scale` scales whichever it falls under
+fn packed_gravity(voxel_physics: &VoxelPhysics) -> (Vec3, f32) {
+    if voxel_physics.gravity_on_cpu {
+        return (Vec3::ZERO, 0.0);
+    }
+    match voxel_physics.gravity {
+        Some(gravity) => (gravity * voxel_physics.gravity_scale, 0.0),
+        None => (Vec3::ZERO, voxel_physics.gravity_scale),
+    }
+}
+
+fn advance_animation_clock(
+    time: Res<Time>,
+    animation_settings: Res<AnimationSettings>,
+    simulation_control: Res<SimulationControl>,
+    mut animation_clock: ResMut<AnimationClock>,
+) {
+    if simulation_control.should_run() {
+        animation_clock.0 += time.delta_seconds() * animation_settings.speed;
+    }
+}
+
 pub fn extract_animation_data(
     mut animation_data: ResMut<AnimationData>,
     particle_query: Query<(&Transform, &Particle)>,
@@ -237,36 +875,34 @@ pub fn extract_animation_data(
 ) {
     let mut type_buffer = TypeBuffer::new();
 
-    let voxel_world_size = voxel_uniforms.texture_size;
-
     // Add particles
     for (transform, particle) in particle_query.iter() {
-        let pos = world_to_voxel(transform.translation, voxel_world_size);
+        let pos = world_to_voxel(transform.translation, &voxel_uniforms);
         type_buffer.push_object(0, |type_buffer| {
             type_buffer.push_ivec3(pos);
             type_buffer.push_u32(particle.material as u32);
-            type_buffer.push_u32(particle.flags as u32);
+            type_buffer.push_u32(particle.flags.bits() as u32);
         });
     }
 
     // Add edges
     for (transform, edges) in edges_query.iter() {
-        let pos = world_to_voxel(transform.translation, voxel_world_size);
+        let pos = world_to_voxel(transform.translation, &voxel_uniforms);
         type_buffer.push_object(1, |type_buffer| {
             type_buffer.push_ivec3(pos);
             type_buffer.push_u32(edges.material as u32);
-            type_buffer.push_u32(edges.flags as u32);
+            type_buffer.push_u32(edges.flags.bits() as u32);
             type_buffer.push_ivec3(edges.half_size);
         });
     }
 
     // Add boxes
     for (transform, boxes) in boxes_query.iter() {
-        let pos = world_to_voxel(transform.translation, voxel_world_size);
+        let pos = world_to_voxel(transform.translation, &voxel_uniforms);
         type_buffer.push_object(2, |type_buffer| {
             type_buffer.push_ivec3(pos);
             type_buffer.push_u32(boxes.material as u32);
-            type_buffer.push_u32(boxes.flags as u32);
+            type_buffer.push_u32(boxes.flags.bits() as u32);
             type_buffer.push_ivec3(boxes.half_size);
         });
     }
@@ -307,6 +943,16 @@ pub fn extract_animation_data(
 
     animation_data.dispatch_size = type_buffer.header.len() as u32;
 
+    let buffer_length = (type_buffer.header.len() + type_buffer.data.len() + 1) as u64;
+    if buffer_length * 4 > animation_data.animation_buffer.size() {
+        warn!(
+            "Animation data doesn't fit in the animation buffer, increase \
+             `ComputeBufferSettings::max_buffer_entries`"
+        );
+        animation_data.dispatch_size = 0;
+        return;
+    }
+
     // Copy animation data to the buffer
     render_queue.write_buffer(
         &animation_data.animation_buffer,
@@ -314,3 +960,53 @@ pub fn extract_animation_data(
         bytemuck::cast_slice(&type_buffer.finish()),
     );
 }
+
+#[allow(clippy::type_complexity)]
+fn draw_physics_debug(
+    physics_debug: Res<PhysicsDebug>,
+    voxel_uniforms: Res<VoxelUniforms>,
+    mut gizmos: Gizmos,
+    query: Query<(
+        &Transform,
+        &VoxelPhysics,
+        Option<&BoxCollider>,
+        Option<&SphereCollider>,
+        Option<&CapsuleCollider>,
+    )>,
+) {
+    if !physics_debug.enabled {
+        return;
+    }
+
+    for (transform, voxel_physics, box_collider, sphere_collider, capsule_collider) in query.iter() {
+        let position = transform.translation;
+
+        // Colliders are sized in voxels, points still cover the voxel they're in
+        let half_size = if let Some(box_collider) = box_collider {
+            box_collider.half_size.as_vec3()
+        } else if let Some(sphere_collider) = sphere_collider {
+            Vec3::splat(sphere_collider.radius as f32)
+        } else if let Some(capsule_collider) = capsule_collider {
+            Vec3::splat(capsule_collider.radius as f32)
+                + capsule_collider.axis.to_vec3() * capsule_collider.half_height as f32
+        } else {
+            Vec3::splat(0.5)
+        } / voxel_uniforms.voxels_per_meter;
+
+        let color = if voxel_physics.hit_normal != Vec3::ZERO {
+            Color::RED
+        } else {
+            Color::GREEN
+        };
+
+        gizmos.cuboid(
+            Transform::from_translation(position).with_scale(half_size * 2.0),
+            color,
+        );
+        gizmos.ray(
+            position,
+            voxel_physics.velocity * physics_debug.velocity_scale,
+            Color::YELLOW,
+        );
+    }
+}
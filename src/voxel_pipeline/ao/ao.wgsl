@@ -0,0 +1,71 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import bevy_voxel_engine::common::{
+    TraceUniforms,
+    cosine_hemisphere,
+    sample_2d,
+}
+
+@group(0) @binding(0)
+var<uniform> trace_uniforms: TraceUniforms;
+@group(0) @binding(1)
+var source: texture_2d<f32>;
+@group(0) @binding(2)
+var normal: texture_2d<f32>;
+@group(0) @binding(3)
+var position: texture_2d<f32>;
+
+const AO_SAMPLES: u32 = 16u;
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let resolution = vec2<f32>(textureDimensions(position));
+    let color = textureLoad(source, vec2<i32>(in.position.xy), 0);
+
+    // the pass covers the whole target, which can be shared with other cameras' viewports
+    let viewport = trace_uniforms.viewport;
+    let viewport_uv = (in.position.xy - viewport.xy) / viewport.zw;
+    if any(viewport_uv < vec2(0.0)) || any(viewport_uv >= vec2(1.0)) {
+        return color;
+    }
+
+    // attachments may be rendered below the view resolution
+    let attachment_pixel = vec2<i32>(viewport_uv * resolution);
+    let surface_normal = textureLoad(normal, attachment_pixel, 0).xyz;
+
+    // missed rays have no normal
+    if all(surface_normal == vec3(0.0)) {
+        return color;
+    }
+
+    let surface_pos = textureLoad(position, attachment_pixel, 0).xyz;
+    let seed = vec3<u32>(in.position.xyz) * 100u + u32(trace_uniforms.time * 120.0) * 15236u;
+    let pixel_seed = vec3<u32>(in.position.xyz) * 100u + trace_uniforms.seed;
+    let radius = trace_uniforms.ao_radius;
+
+    var occlusion = 0.0;
+    for (var i = 0u; i < AO_SAMPLES; i += 1u) {
+        // bias samples towards the surface so close geometry counts more
+        let scale = mix(0.1, 1.0, f32(i * i) / f32(AO_SAMPLES * AO_SAMPLES));
+        let u = sample_2d(trace_uniforms.sample_sequence, trace_uniforms.frame * AO_SAMPLES + i, 2u, pixel_seed, seed + i);
+        let sample_pos = surface_pos + cosine_hemisphere(surface_normal, u) * radius * scale;
+
+        let sample_clip = trace_uniforms.camera * vec4(sample_pos, 1.0);
+        let sample_ndc = sample_clip.xyz / sample_clip.w;
+        let uv = sample_ndc.xy * vec2(0.5, -0.5) + 0.5;
+        if any(uv < vec2(0.0)) || any(uv >= vec2(1.0)) {
+            continue;
+        }
+
+        let occluder_pos = textureLoad(position, vec2<i32>(uv * resolution), 0).xyz;
+        let occluder_clip = trace_uniforms.camera * vec4(occluder_pos, 1.0);
+        let occluder_depth = occluder_clip.z / occluder_clip.w;
+
+        // reversed z, the occluder is in front of the sample if its depth is larger
+        let range = smoothstep(0.0, 1.0, radius / length(occluder_pos - surface_pos));
+        occlusion += select(0.0, 1.0, occluder_depth > sample_ndc.z + 0.000001) * range;
+    }
+
+    let ao = 1.0 - occlusion / f32(AO_SAMPLES);
+
+    return vec4(color.rgb * ao, color.a);
+}
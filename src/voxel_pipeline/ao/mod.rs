@@ -0,0 +1,129 @@
+use super::{
+    attachments::color_format,
+    trace::{TraceSettings, TraceUniforms},
+};
+use bevy::{
+    asset::embedded_asset,
+    core_pipeline::fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    prelude::*,
+    render::{
+        render_resource::*, renderer::RenderDevice, view::ExtractedView, Render, RenderApp,
+        RenderSet,
+    },
+};
+pub use node::AoNode;
+
+mod node;
+
+pub struct AoPlugin;
+
+impl Plugin for AoPlugin {
+    fn build(&self, app: &mut App) {
+        embedded_asset!(app, "src/", "ao.wgsl");
+    }
+
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<AoPipelineData>()
+            .init_resource::<SpecializedRenderPipelines<AoPipelineData>>()
+            .add_systems(Render, prepare_ao_pipelines.in_set(RenderSet::Prepare));
+    }
+}
+
+#[derive(Resource)]
+struct AoPipelineData {
+    ao_shader: Handle<Shader>,
+    ao_bind_group_layout: BindGroupLayout,
+}
+
+/// Ao pipeline for the format of the view target
+#[derive(Component)]
+pub struct ViewAoPipeline(CachedRenderPipelineId);
+
+fn prepare_ao_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    ao_pipeline_data: Res<AoPipelineData>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<AoPipelineData>>,
+    query: Query<(Entity, &ExtractedView), With<TraceSettings>>,
+) {
+    for (entity, view) in query.iter() {
+        let pipeline = pipelines.specialize(&pipeline_cache, &ao_pipeline_data, view.hdr);
+        commands.entity(entity).insert(ViewAoPipeline(pipeline));
+    }
+}
+
+impl FromWorld for AoPipelineData {
+    fn from_world(render_world: &mut World) -> Self {
+        let asset_server = render_world.resource::<AssetServer>();
+
+        let ao_shader = asset_server.load("embedded://bevy_voxel_engine/voxel_pipeline/ao/ao.wgsl");
+
+        let attachment_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let ao_bind_group_layout = render_world
+            .resource::<RenderDevice>()
+            .create_bind_group_layout(
+                "ao bind group layout",
+                &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(TraceUniforms::SHADER_SIZE.into()),
+                        },
+                        count: None,
+                    },
+                    // color written by the trace pass
+                    attachment_entry(1),
+                    // normal
+                    attachment_entry(2),
+                    // position
+                    attachment_entry(3),
+                ],
+            );
+
+        AoPipelineData {
+            ao_shader,
+            ao_bind_group_layout,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for AoPipelineData {
+    /// Whether the view target is hdr
+    type Key = bool;
+
+    fn specialize(&self, hdr: Self::Key) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            label: Some("ao pipeline".into()),
+            layout: vec![self.ao_bind_group_layout.clone()],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: self.ao_shader.clone(),
+                shader_defs: Vec::new(),
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: color_format(hdr),
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            push_constant_ranges: vec![],
+        }
+    }
+}
@@ -3,8 +3,11 @@ use bevy::{
     prelude::*,
     render::{
         extract_component::{ExtractComponent, ExtractComponentPlugin},
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
         render_asset::RenderAssetUsages,
         render_resource::*,
+        texture::{BevyDefault, TextureFormatPixelInfo},
+        view::ViewTarget,
     },
 };
 
@@ -12,65 +15,167 @@ pub struct AttachmentsPlugin;
 
 impl Plugin for AttachmentsPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(ExtractComponentPlugin::<RenderAttachments>::default())
+        app.init_resource::<RenderAttachmentSettings>()
+            .add_plugins(ExtractResourcePlugin::<RenderAttachmentSettings>::default())
+            .add_plugins(ExtractComponentPlugin::<RenderAttachments>::default())
             .add_systems(PostUpdate, add_render_attachments)
             .add_systems(PostUpdate, resize_attachments);
     }
 }
 
+/// Formats of the [`RenderAttachments`] created for every view
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct RenderAttachmentSettings {
+    pub position_format: PositionFormat,
+}
+
+/// Format of the world space position attachment
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PositionFormat {
+    #[default]
+    Rgba32Float,
+    /// Halves the attachment's memory, but precision falls off with distance from the origin:
+    /// positions snap to half a voxel past 128 meters and a whole voxel past 256 meters, which
+    /// shows up as banding and false occlusion in ambient occlusion on large worlds
+    Rgba16Float,
+}
+
+impl PositionFormat {
+    pub fn texture_format(self) -> TextureFormat {
+        match self {
+            PositionFormat::Rgba32Float => TextureFormat::Rgba32Float,
+            PositionFormat::Rgba16Float => TextureFormat::Rgba16Float,
+        }
+    }
+}
+
+/// Format of the view target's main texture, and of the color and lighting attachments and
+/// trace output of its views. Cameras without [`Camera::hdr`] trace to an 8 bit target and
+/// tonemap in the trace shader with their `Tonemapping`, for devices that can't render to
+/// float textures. The shader only has the curves that need no lookup table, `TonyMcMapface`,
+/// `AgX`, `BlenderFilmic` and `SomewhatBoringDisplayTransform` fall back to `AcesFitted`
+pub(crate) fn color_format(hdr: bool) -> TextureFormat {
+    match hdr {
+        true => ViewTarget::TEXTURE_FORMAT_HDR,
+        false => TextureFormat::bevy_default(),
+    }
+}
+
+/// Per view textures written by the trace pass, sized to the traced resolution which may be
+/// below the view target's
 #[derive(Component, Clone, ExtractComponent)]
 pub struct RenderAttachments {
     current_size: UVec2,
+    current_lighting_size: UVec2,
+    /// Trace output when rendering below native resolution, upscaled to the view target
+    /// afterwards. In the [`color_format`] of the camera
+    pub color: Handle<Image>,
+    /// `Rgba16Float` world space normal of the first hit, zero where rays missed
     pub normal: Handle<Image>,
+    /// World space position of the first hit, in [`RenderAttachmentSettings::position_format`]
     pub position: Handle<Image>,
+    /// Light reaching the shaded hit in the [`color_format`] of the camera, rendered at
+    /// [`TraceSettings::lighting_scale`] of the traced resolution when below `1.0`. Always
+    /// at full scale without hdr, since the 8 bit format would clip the lighting
+    pub lighting: Handle<Image>,
+}
+
+/// Traced and lighting resolutions of a camera's attachments, `None` until its viewport is known
+fn attachment_sizes(camera: &Camera, trace_settings: &TraceSettings) -> Option<(UVec2, UVec2)> {
+    let size = camera.physical_viewport_size()?;
+    let size = (size.as_vec2() * trace_settings.clamped_render_scale())
+        .as_uvec2()
+        .max(UVec2::ONE);
+    let lighting_scale = if camera.hdr {
+        trace_settings.clamped_lighting_scale()
+    } else {
+        1.0
+    };
+    let lighting_size = (size.as_vec2() * lighting_scale)
+        .as_uvec2()
+        .max(UVec2::ONE);
+
+    Some((size, lighting_size))
 }
 
+fn extent(size: UVec2) -> Extent3d {
+    Extent3d {
+        width: size.x,
+        height: size.y,
+        depth_or_array_layers: 1,
+    }
+}
+
+fn attachment_image(size: UVec2, format: TextureFormat, usage: TextureUsages) -> Image {
+    let mut image = Image::new_fill(
+        extent(size),
+        TextureDimension::D2,
+        &vec![0; format.pixel_size()],
+        format,
+        RenderAssetUsages::default(),
+    );
+    image.texture_descriptor.usage = TextureUsages::COPY_DST | usage;
+    image
+}
+
+/// Attachments are created at the camera's size so they aren't reallocated on the next frame
 fn add_render_attachments(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
-    mut query: Query<Entity, (With<TraceSettings>, Without<RenderAttachments>)>,
+    settings: Res<RenderAttachmentSettings>,
+    query: Query<(Entity, &Camera, &TraceSettings), Without<RenderAttachments>>,
 ) {
-    for entity in query.iter_mut() {
-        let size = Extent3d {
-            width: 1,
-            height: 1,
-            depth_or_array_layers: 1,
+    for (entity, camera, trace_settings) in query.iter() {
+        let Some((size, lighting_size)) = attachment_sizes(camera, trace_settings) else {
+            continue;
         };
-        let mut image = Image::new_fill(
-            size,
-            TextureDimension::D2,
-            &[0; 8],
-            TextureFormat::Rgba16Float,
-            RenderAssetUsages::default(),
-        );
-        image.texture_descriptor.usage = TextureUsages::COPY_DST
-            | TextureUsages::STORAGE_BINDING
-            | TextureUsages::TEXTURE_BINDING;
-        let mut highp_image = Image::new_fill(
-            size,
-            TextureDimension::D2,
-            &[0; 16],
-            TextureFormat::Rgba32Float,
-            RenderAssetUsages::default(),
-        );
-        highp_image.texture_descriptor.usage = TextureUsages::COPY_DST
-            | TextureUsages::STORAGE_BINDING
-            | TextureUsages::TEXTURE_BINDING;
+
+        let storage = TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
+        let render = TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING;
+        let position_format = settings.position_format.texture_format();
 
         commands.entity(entity).insert(RenderAttachments {
-            current_size: UVec2::new(1, 1),
-            normal: images.add(image.clone()),
-            position: images.add(highp_image),
+            current_size: size,
+            current_lighting_size: lighting_size,
+            color: images.add(attachment_image(size, color_format(camera.hdr), render)),
+            normal: images.add(attachment_image(size, TextureFormat::Rgba16Float, storage)),
+            position: images.add(attachment_image(size, position_format, storage)),
+            lighting: images.add(attachment_image(
+                lighting_size,
+                color_format(camera.hdr),
+                render,
+            )),
         });
     }
 }
 
+/// Only touches images whose size or format changed, `Assets::get_mut` alone marks an image as
+/// modified and reallocates its texture on the gpu
 fn resize_attachments(
     mut images: ResMut<Assets<Image>>,
-    mut query: Query<(&mut RenderAttachments, &Camera)>,
+    settings: Res<RenderAttachmentSettings>,
+    mut query: Query<(&mut RenderAttachments, &Camera, &TraceSettings)>,
 ) {
-    for (i, (mut render_attachments, camera)) in query.iter_mut().enumerate() {
-        let size = camera.physical_viewport_size().unwrap();
+    for (i, (mut render_attachments, camera, trace_settings)) in query.iter_mut().enumerate() {
+        let Some((size, lighting_size)) = attachment_sizes(camera, trace_settings) else {
+            continue;
+        };
+
+        // Switching formats reallocates the attachment at the current size
+        let mut set_format = |image: &Handle<Image>, format: TextureFormat| {
+            let changed = images
+                .get(image)
+                .is_some_and(|image| image.texture_descriptor.format != format);
+            if changed {
+                let image = images.get_mut(image).unwrap();
+                image.texture_descriptor.format = format;
+                image.data.clear();
+                image.resize(image.texture_descriptor.size);
+            }
+        };
+        set_format(&render_attachments.position, settings.position_format.texture_format());
+        set_format(&render_attachments.color, color_format(camera.hdr));
+        set_format(&render_attachments.lighting, color_format(camera.hdr));
 
         if size != render_attachments.current_size {
             render_attachments.current_size = size;
@@ -79,17 +184,21 @@ fn resize_attachments(
                 i, size.x, size.y
             );
 
-            let size = Extent3d {
-                width: size.x,
-                height: size.y,
-                depth_or_array_layers: 1,
-            };
+            let color_image = images.get_mut(&render_attachments.color).unwrap();
+            color_image.resize(extent(size));
 
             let normal_image = images.get_mut(&render_attachments.normal).unwrap();
-            normal_image.resize(size);
+            normal_image.resize(extent(size));
 
             let position_image = images.get_mut(&render_attachments.position).unwrap();
-            position_image.resize(size);
+            position_image.resize(extent(size));
+        }
+
+        if lighting_size != render_attachments.current_lighting_size {
+            render_attachments.current_lighting_size = lighting_size;
+
+            let lighting_image = images.get_mut(&render_attachments.lighting).unwrap();
+            lighting_image.resize(extent(lighting_size));
         }
     }
-}
\ No newline at end of file
+}
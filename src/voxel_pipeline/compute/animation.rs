@@ -1,5 +1,5 @@
 use super::{AnimationData, ComputeData};
-use crate::{voxel_pipeline::voxel_world::VoxelData, RenderGraphSettings};
+use crate::{voxel_pipeline::voxel_world::VoxelData, RenderGraphSettings, SimulationControl};
 use bevy::{
     prelude::*,
     render::{
@@ -50,8 +50,9 @@ impl render_graph::Node for AnimationNode {
         let pipeline_cache = world.resource::<PipelineCache>();
         let animation_data = world.resource::<AnimationData>();
         let render_graph_settings = world.resource::<RenderGraphSettings>();
+        let simulation_control = world.resource::<SimulationControl>();
 
-        if !render_graph_settings.animation {
+        if !render_graph_settings.animation || !simulation_control.should_run() {
             return Ok(());
         }
 
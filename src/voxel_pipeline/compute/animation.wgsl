@@ -9,6 +9,10 @@
 struct ComputeUniforms {
     time: f32,
     delta_time: f32,
+    gravity: vec3<f32>,
+    frame: u32,
+    seed: u32,
+    animation_time: f32,
 }
 
 @group(1) @binding(0)
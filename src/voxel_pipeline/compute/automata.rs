@@ -1,20 +1,118 @@
 use super::ComputeData;
 use crate::{
     voxel_pipeline::voxel_world::{VoxelData, VoxelUniforms},
-    RenderGraphSettings,
+    RenderGraphSettings, SimulationControl,
 };
 use bevy::{
     prelude::*,
     render::{
+        extract_resource::ExtractResource,
         render_graph::{self, NodeRunError, RenderGraphContext},
         render_resource::*,
-        renderer::RenderContext,
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        Extract,
     },
 };
 use std::borrow::Cow;
 
+pub const MAX_AUTOMATA_RULES: usize = 16;
+
 pub struct AutomataNode;
 
+/// Birth and survival rule for voxels of `material`, counted over the 26 surrounding voxels.
+/// Bit n of `birth` turns an empty voxel with n neighbours of `material` into `material`,
+/// bit n of `survive` keeps a voxel of `material` with n such neighbours. Only voxels with
+/// `AUTOMATA_FLAG` die, so the rules don't eat into the rest of the world
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AutomataRule {
+    pub material: u8,
+    pub birth: u32,
+    pub survive: u32,
+}
+
+impl AutomataRule {
+    /// From neighbour counts, e.g. `AutomataRule::new(12, &[4], &[4, 5])` for the 3d game of
+    /// life rule 4/45
+    pub fn new(material: u8, birth: &[u32], survive: &[u32]) -> Self {
+        let mask = |counts: &[u32]| counts.iter().fold(0, |mask, count| mask | 1 << count);
+        Self {
+            material,
+            birth: mask(birth),
+            survive: mask(survive),
+        }
+    }
+}
+
+/// Rules run by the automata pass in order, the first rule matching a voxel is applied.
+/// Voxels are updated in place, so neighbours can already hold this frame's result. At most
+/// [`MAX_AUTOMATA_RULES`] are used
+#[derive(Resource, ExtractResource, Clone, Default, Deref, DerefMut)]
+pub struct AutomataRules(pub Vec<AutomataRule>);
+
+/// Limits the automata pass to the voxels between `min` and `max` inclusive, the whole world
+/// is updated when this resource is absent
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct AutomataRegion {
+    pub min: IVec3,
+    pub max: IVec3,
+}
+
+#[derive(Resource, Default)]
+pub(super) struct ExtractedAutomataRegion(Option<AutomataRegion>);
+
+pub(super) fn extract_automata_region(
+    automata_region: Extract<Option<Res<AutomataRegion>>>,
+    mut extracted_automata_region: ResMut<ExtractedAutomataRegion>,
+) {
+    extracted_automata_region.0 = automata_region.as_deref().copied();
+}
+
+/// Region clamped to the world
+fn clamped_region(automata_region: &ExtractedAutomataRegion, texture_size: u32) -> (IVec3, IVec3) {
+    let world_max = IVec3::splat(texture_size as i32 - 1);
+    match automata_region.0 {
+        Some(region) => (
+            region.min.clamp(IVec3::ZERO, world_max),
+            region.max.clamp(IVec3::ZERO, world_max),
+        ),
+        None => (IVec3::ZERO, world_max),
+    }
+}
+
+#[derive(Default, ShaderType)]
+pub(super) struct AutomataUniforms {
+    /// material, birth, survive, unused
+    rules: [UVec4; MAX_AUTOMATA_RULES],
+    rule_count: u32,
+    region_min: IVec3,
+    region_max: IVec3,
+}
+
+pub(super) fn prepare_automata_rules(
+    automata_rules: Res<AutomataRules>,
+    automata_region: Res<ExtractedAutomataRegion>,
+    voxel_uniforms: Res<VoxelUniforms>,
+    mut compute_data: ResMut<ComputeData>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let (region_min, region_max) = clamped_region(&automata_region, voxel_uniforms.texture_size);
+    let mut uniforms = AutomataUniforms {
+        region_min,
+        region_max,
+        ..default()
+    };
+    for (i, rule) in automata_rules.iter().take(MAX_AUTOMATA_RULES).enumerate() {
+        uniforms.rules[i] = UVec4::new(rule.material as u32, rule.birth, rule.survive, 0);
+        uniforms.rule_count += 1;
+    }
+
+    compute_data.automata_buffer.set(uniforms);
+    compute_data
+        .automata_buffer
+        .write_buffer(&render_device, &render_queue);
+}
+
 #[derive(Resource)]
 pub struct Pipeline(CachedComputePipelineId);
 
@@ -52,10 +150,11 @@ impl render_graph::Node for AutomataNode {
         let compute_data = world.resource::<ComputeData>();
         let voxel_uniforms = world.resource::<VoxelUniforms>();
         let pipeline_cache = world.resource::<PipelineCache>();
-        let dispatch_size = voxel_uniforms.texture_size / 4;
+        let automata_region = world.resource::<ExtractedAutomataRegion>();
         let render_graph_settings = world.resource::<RenderGraphSettings>();
+        let simulation_control = world.resource::<SimulationControl>();
 
-        if !render_graph_settings.automata {
+        if !render_graph_settings.automata || !simulation_control.should_run() {
             return Ok(());
         }
 
@@ -64,6 +163,12 @@ impl render_graph::Node for AutomataNode {
             None => return Ok(()),
         };
 
+        let (region_min, region_max) = clamped_region(automata_region, voxel_uniforms.texture_size);
+        if region_min.cmpgt(region_max).any() {
+            return Ok(());
+        }
+        let dispatch_size = ((region_max - region_min).as_uvec3() + 4) / 4;
+
         let mut pass = render_context
             .command_encoder()
             .begin_compute_pass(&ComputePassDescriptor::default());
@@ -72,7 +177,7 @@ impl render_graph::Node for AutomataNode {
         pass.set_bind_group(1, &compute_data.bind_group, &[]);
 
         pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+        pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
 
         Ok(())
     }
@@ -17,6 +17,10 @@
 struct ComputeUniforms {
     time: f32,
     delta_time: f32,
+    gravity: vec3<f32>,
+    frame: u32,
+    seed: u32,
+    animation_time: f32,
 }
 
 @group(1) @binding(0)
@@ -24,6 +28,17 @@ var<uniform> compute_uniforms: ComputeUniforms;
 @group(1) @binding(1)
 var<storage, read_write> physics_data: array<u32>;
 
+struct AutomataUniforms {
+    // material, birth, survive, unused
+    rules: array<vec4<u32>, 16>,
+    rule_count: u32,
+    region_min: vec3<i32>,
+    region_max: vec3<i32>,
+}
+
+@group(1) @binding(3)
+var<uniform> automata_uniforms: AutomataUniforms;
+
 fn in_texture_bounds(pos: vec3<i32>) -> bool {
     return all(pos >= vec3(0)) && all(pos < vec3(i32(voxel_uniforms.texture_size)));
 }
@@ -43,14 +58,52 @@ fn write_pos(pos: vec3<i32>, material: u32, flags: u32) {
     }
 }
 
+fn count_neighbours(pos: vec3<i32>, material: u32) -> u32 {
+    var count = 0u;
+    for (var x = -1; x <= 1; x++) {
+        for (var y = -1; y <= 1; y++) {
+            for (var z = -1; z <= 1; z++) {
+                let neighbour_pos = pos + vec3(x, y, z);
+                if (any(vec3(x, y, z) != vec3(0)) && in_texture_bounds(neighbour_pos) && get_texture_value(neighbour_pos).x == material) {
+                    count += 1u;
+                }
+            }
+        }
+    }
+    return count;
+}
+
 @compute @workgroup_size(4, 4, 4)
 fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let pos = vec3(i32(invocation_id.x), i32(invocation_id.y), i32(invocation_id.z));
-    let pos_seed = vec3<u32>(pos);
-    let pos_time_seed = vec3<u32>(vec3<f32>(pos) + compute_uniforms.time * 240.0);
+    let pos = automata_uniforms.region_min + vec3<i32>(invocation_id);
+    if (any(pos > automata_uniforms.region_max)) {
+        return;
+    }
+
+    // derived from the seed and frame rather than time so runs are reproducible
+    let pos_seed = vec3<u32>(pos) + compute_uniforms.seed * 7919u;
+    let pos_time_seed = pos_seed + compute_uniforms.frame * 4u;
+    let animation_time = compute_uniforms.animation_time;
 
     let material = get_texture_value(pos);
 
+    // rules from AutomataRules
+    for (var i = 0u; i < automata_uniforms.rule_count; i++) {
+        let rule = automata_uniforms.rules[i];
+        if (material.x == 0u) {
+            if (((rule.y >> count_neighbours(pos, rule.x)) & 1u) != 0u) {
+                textureStore(voxel_world, pos.zyx, vec4(rule.x | (AUTOMATA_FLAG << 8u)));
+                break;
+            }
+        } else if (material.x == rule.x) {
+            let dies = ((rule.z >> count_neighbours(pos, rule.x)) & 1u) == 0u;
+            if (dies && (material.y & AUTOMATA_FLAG) > 0u) {
+                textureStore(voxel_world, pos.zyx, vec4(0u));
+            }
+            break;
+        }
+    }
+
     // grass
     let pos_rand = hash(pos_seed + 100u);
 
@@ -59,9 +112,9 @@ fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
             let i = f32(i);
 
             let offset = vec3(
-                3.0 * snoise(vec3<f32>(pos) / 50.0 + compute_uniforms.time * 0.3) - 0.5, 
+                3.0 * snoise(vec3<f32>(pos) / 50.0 + animation_time * 0.3) - 0.5, 
                 i, 
-                3.0 * snoise(vec3<f32>(pos) / 50.0 + compute_uniforms.time * 0.3) - 0.5
+                3.0 * snoise(vec3<f32>(pos) / 50.0 + animation_time * 0.3) - 0.5
             );
 
             let new_pos = vec3<f32>(pos) + vec3(
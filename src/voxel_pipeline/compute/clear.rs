@@ -1,25 +1,108 @@
+use super::ComputeData;
 use crate::{
     voxel_pipeline::voxel_world::{VoxelData, VoxelUniforms},
-    RenderGraphSettings,
+    RenderGraphSettings, SimulationControl,
 };
 use bevy::{
     prelude::*,
     render::{
+        extract_resource::ExtractResource,
         render_graph::{self, NodeRunError, RenderGraphContext},
         render_resource::*,
-        renderer::RenderContext,
+        renderer::{RenderContext, RenderDevice, RenderQueue},
     },
 };
 use std::borrow::Cow;
 
+pub const MAX_CLEAR_REGIONS: usize = 16;
+
 pub struct ClearNode;
 
+/// Voxels between `min` and `max` inclusive
+#[derive(Clone, Copy, Debug)]
+pub struct ClearRegion {
+    pub min: IVec3,
+    pub max: IVec3,
+}
+
+/// Limits the clear pass to these regions, animated voxels outside of them persist between
+/// frames instead of being re-voxelized. The whole world is cleared when the list is empty.
+/// At most [`MAX_CLEAR_REGIONS`] are used
+#[derive(Resource, ExtractResource, Clone, Default, Deref, DerefMut)]
+pub struct ClearRegions(pub Vec<ClearRegion>);
+
+/// Regions clamped to the world with the empty ones removed
+fn clamped_regions(clear_regions: &ClearRegions, texture_size: u32) -> Vec<(IVec3, IVec3)> {
+    let world_max = IVec3::splat(texture_size as i32 - 1);
+    if clear_regions.is_empty() {
+        return vec![(IVec3::ZERO, world_max)];
+    }
+
+    clear_regions
+        .iter()
+        .take(MAX_CLEAR_REGIONS)
+        .map(|region| {
+            (
+                region.min.clamp(IVec3::ZERO, world_max),
+                region.max.clamp(IVec3::ZERO, world_max),
+            )
+        })
+        .filter(|(min, max)| min.cmple(*max).all())
+        .collect()
+}
+
+/// Smallest box containing all of the regions
+fn bounds(regions: &[(IVec3, IVec3)]) -> Option<(IVec3, IVec3)> {
+    regions
+        .iter()
+        .copied()
+        .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)))
+}
+
+#[derive(Default, ShaderType)]
+pub(super) struct ClearUniforms {
+    /// w is unused
+    region_min: [IVec4; MAX_CLEAR_REGIONS],
+    /// w is unused
+    region_max: [IVec4; MAX_CLEAR_REGIONS],
+    region_count: u32,
+    bounds_min: IVec3,
+    bounds_max: IVec3,
+}
+
+pub(super) fn prepare_clear_regions(
+    clear_regions: Res<ClearRegions>,
+    voxel_uniforms: Res<VoxelUniforms>,
+    mut compute_data: ResMut<ComputeData>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let regions = clamped_regions(&clear_regions, voxel_uniforms.texture_size);
+    let (bounds_min, bounds_max) = bounds(&regions).unwrap_or_default();
+    let mut uniforms = ClearUniforms {
+        region_count: regions.len() as u32,
+        bounds_min,
+        bounds_max,
+        ..default()
+    };
+    for (i, (min, max)) in regions.iter().enumerate() {
+        uniforms.region_min[i] = min.extend(0);
+        uniforms.region_max[i] = max.extend(0);
+    }
+
+    compute_data.clear_buffer.set(uniforms);
+    compute_data
+        .clear_buffer
+        .write_buffer(&render_device, &render_queue);
+}
+
 #[derive(Resource)]
 pub struct Pipeline(CachedComputePipelineId);
 
 impl FromWorld for Pipeline {
     fn from_world(world: &mut World) -> Self {
         let voxel_bind_group_layout = world.resource::<VoxelData>().bind_group_layout.clone();
+        let compute_bind_group_layout = world.resource::<ComputeData>().bind_group_layout.clone();
 
         let asset_server = world.resource_mut::<AssetServer>();
         let shader = asset_server.load("embedded://bevy_voxel_engine/voxel_pipeline/compute/clear.wgsl");
@@ -28,7 +111,7 @@ impl FromWorld for Pipeline {
 
         let update_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
             label: Some(Cow::from("clear pipeline")),
-            layout: vec![voxel_bind_group_layout],
+            layout: vec![voxel_bind_group_layout, compute_bind_group_layout],
             shader,
             shader_defs: vec![],
             entry_point: Cow::from("clear"),
@@ -47,12 +130,14 @@ impl render_graph::Node for ClearNode {
         world: &World,
     ) -> Result<(), NodeRunError> {
         let voxel_data = world.resource::<VoxelData>();
+        let compute_data = world.resource::<ComputeData>();
         let voxel_uniforms = world.resource::<VoxelUniforms>();
         let pipeline_cache = world.resource::<PipelineCache>();
-        let dispatch_size = voxel_uniforms.texture_size / 4;
+        let clear_regions = world.resource::<ClearRegions>();
         let render_graph_settings = world.resource::<RenderGraphSettings>();
+        let simulation_control = world.resource::<SimulationControl>();
 
-        if !render_graph_settings.clear {
+        if !render_graph_settings.clear || !simulation_control.should_run() {
             return Ok(());
         }
 
@@ -61,14 +146,21 @@ impl render_graph::Node for ClearNode {
             None => return Ok(()),
         };
 
+        let regions = clamped_regions(clear_regions, voxel_uniforms.texture_size);
+        let Some((bounds_min, bounds_max)) = bounds(&regions) else {
+            return Ok(());
+        };
+        let dispatch_size = ((bounds_max - bounds_min).as_uvec3() + 4) / 4;
+
         let mut pass = render_context
             .command_encoder()
             .begin_compute_pass(&ComputePassDescriptor::default());
 
         pass.set_bind_group(0, &voxel_data.bind_group, &[]);
+        pass.set_bind_group(1, &compute_data.bind_group, &[]);
 
         pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+        pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
 
         Ok(())
     }
@@ -10,6 +10,26 @@
     gh
 }
 
+struct ClearUniforms {
+    region_min: array<vec4<i32>, 16>,
+    region_max: array<vec4<i32>, 16>,
+    region_count: u32,
+    bounds_min: vec3<i32>,
+    bounds_max: vec3<i32>,
+}
+
+@group(1) @binding(4)
+var<uniform> clear_uniforms: ClearUniforms;
+
+fn in_clear_region(pos: vec3<i32>) -> bool {
+    for (var i = 0u; i < clear_uniforms.region_count; i++) {
+        if (all(pos >= clear_uniforms.region_min[i].xyz) && all(pos <= clear_uniforms.region_max[i].xyz)) {
+            return true;
+        }
+    }
+    return false;
+}
+
 fn get_texture_value(pos: vec3<i32>) -> vec2<u32> {
     let texture_value = textureLoad(voxel_world, pos.zyx).r;
     return vec2(
@@ -20,7 +40,10 @@ fn get_texture_value(pos: vec3<i32>) -> vec2<u32> {
 
 @compute @workgroup_size(4, 4, 4)
 fn clear(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let pos = vec3(i32(invocation_id.x), i32(invocation_id.y), i32(invocation_id.z));
+    let pos = clear_uniforms.bounds_min + vec3<i32>(invocation_id);
+    if (any(pos > clear_uniforms.bounds_max) || !in_clear_region(pos)) {
+        return;
+    }
 
     let material = get_texture_value(pos);
 
@@ -1,8 +1,10 @@
+use crate::physics::{AnimationClock, PhysicsSettings};
 use bevy::{
     asset::embedded_asset,
+    core::FrameCount,
     prelude::*,
     render::{
-        Render,
+        Render, ExtractSchedule,
         extract_resource::{ExtractResource, ExtractResourcePlugin},
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
@@ -10,6 +12,11 @@ use bevy::{
     },
     utils::HashMap,
 };
+use crossbeam_channel::{Receiver, Sender};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
 
 pub mod animation;
 pub mod automata;
@@ -18,11 +25,30 @@ pub mod physics;
 pub mod rebuild;
 
 const MAX_TYPE_BUFFER_DATA: usize = 1000000; // 4mb
+const PHYSICS_READBACK_BUFFERS: usize = 3;
 
 pub struct ComputeResourcesPlugin;
 
+/// Sizes of the gpu buffers of the physics and animation passes, insert before adding the
+/// engine plugin
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ComputeBufferSettings {
+    /// Number of u32s allocated for each of the physics, physics state, awake bodies and
+    /// animation buffers
+    pub max_buffer_entries: usize,
+}
+
+impl Default for ComputeBufferSettings {
+    fn default() -> Self {
+        Self {
+            max_buffer_entries: MAX_TYPE_BUFFER_DATA,
+        }
+    }
+}
+
 impl Plugin for ComputeResourcesPlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<ComputeBufferSettings>();
         embedded_asset!(app, "src/", "animation.wgsl");
         embedded_asset!(app, "src/", "automata.wgsl");
         embedded_asset!(app, "src/", "clear.wgsl");
@@ -31,6 +57,11 @@ impl Plugin for ComputeResourcesPlugin {
     }
 
     fn finish(&self, app: &mut App) {
+        let max_buffer_entries = app
+            .world
+            .resource::<ComputeBufferSettings>()
+            .max_buffer_entries
+            .max(1);
         let render_device = app.sub_app(RenderApp).world.resource::<RenderDevice>();
 
         let render_queue = app.sub_app(RenderApp).world.resource::<RenderQueue>();
@@ -38,21 +69,50 @@ impl Plugin for ComputeResourcesPlugin {
         let mut uniform_buffer = UniformBuffer::from(ComputeUniforms {
             time: 0.0,
             delta_time: 0.0,
+            gravity: Vec3::ZERO,
+            frame: 0,
+            seed: 0,
+            animation_time: 0.0,
         });
         uniform_buffer.write_buffer(&render_device, &render_queue);
 
+        let mut automata_buffer = UniformBuffer::from(automata::AutomataUniforms::default());
+        automata_buffer.write_buffer(&render_device, &render_queue);
+
+        let mut clear_buffer = UniformBuffer::from(clear::ClearUniforms::default());
+        clear_buffer.write_buffer(&render_device, &render_queue);
+
         let physics_buffer_gpu = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: bytemuck::cast_slice(&vec![0u32; MAX_TYPE_BUFFER_DATA]),
+            contents: bytemuck::cast_slice(&vec![0u32; max_buffer_entries]),
             label: None,
             usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
         });
-        let physics_buffer_cpu = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: bytemuck::cast_slice(&vec![0u32; MAX_TYPE_BUFFER_DATA]),
+        let physics_state_gpu = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            contents: bytemuck::cast_slice(&vec![0u32; max_buffer_entries]),
             label: None,
-            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
         });
+        let physics_awake_gpu = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            contents: bytemuck::cast_slice(&vec![0u32; max_buffer_entries]),
+            label: None,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        });
+        let readbacks = (0..PHYSICS_READBACK_BUFFERS)
+            .map(|_| PhysicsReadback {
+                buffer: render_device.create_buffer_with_data(&BufferInitDescriptor {
+                    contents: bytemuck::cast_slice(&vec![0u32; max_buffer_entries]),
+                    label: None,
+                    usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                }),
+                frame: 0,
+                buffer_length: 0,
+                entities: HashMap::new(),
+                in_use: false,
+            })
+            .collect();
+        let (readback_sender, readback_receiver) = crossbeam_channel::unbounded();
         let animation_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: bytemuck::cast_slice(&vec![0u32; MAX_TYPE_BUFFER_DATA]),
+            contents: bytemuck::cast_slice(&vec![0u32; max_buffer_entries]),
             label: None,
             usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
         });
@@ -91,6 +151,50 @@ impl Plugin for ComputeResourcesPlugin {
                         },
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(
+                                automata::AutomataUniforms::SHADER_SIZE.into(),
+                            ),
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(
+                                clear::ClearUniforms::SHADER_SIZE.into(),
+                            ),
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(4),
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(4),
+                        },
+                        count: None,
+                    },
                 ],
             );
 
@@ -110,6 +214,22 @@ impl Plugin for ComputeResourcesPlugin {
                     binding: 2,
                     resource: animation_buffer.as_entire_binding(),
                 },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: automata_buffer.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: clear_buffer.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: physics_state_gpu.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: physics_awake_gpu.as_entire_binding(),
+                },
             ],
         );
 
@@ -118,14 +238,29 @@ impl Plugin for ComputeResourcesPlugin {
             buffer_length: 0,
             entities: HashMap::new(),
             physics_buffer_gpu,
-            physics_buffer_cpu,
+            physics_state_gpu,
+            physics_awake_gpu,
+            readbacks: Arc::new(Mutex::new(readbacks)),
+            readback_index: None,
+            readback_sender,
+            readback_receiver,
+            latest_frame: None,
         })
         .insert_resource(AnimationData {
             dispatch_size: 0,
             animation_buffer,
         })
+        .init_resource::<automata::AutomataRules>()
+        .init_resource::<SimulationSeed>()
+        .init_resource::<clear::ClearRegions>()
+        .init_resource::<rebuild::RequestRebuild>()
         .add_plugins(ExtractResourcePlugin::<PhysicsData>::default())
-        .add_plugins(ExtractResourcePlugin::<AnimationData>::default());
+        .add_plugins(ExtractResourcePlugin::<AnimationData>::default())
+        .add_plugins(ExtractResourcePlugin::<automata::AutomataRules>::default())
+        .add_plugins(ExtractResourcePlugin::<SimulationSeed>::default())
+        .add_plugins(ExtractResourcePlugin::<clear::ClearRegions>::default())
+        .add_plugins(ExtractResourcePlugin::<rebuild::RequestRebuild>::default())
+        .add_systems(First, rebuild::reset_request_rebuild);
 
         let render_app = app.sub_app_mut(RenderApp);
 
@@ -134,25 +269,74 @@ impl Plugin for ComputeResourcesPlugin {
                 bind_group_layout,
                 bind_group,
                 uniform_buffer,
+                automata_buffer,
+                clear_buffer,
             })
             .init_resource::<clear::Pipeline>()
             .init_resource::<rebuild::Pipeline>()
             .init_resource::<automata::Pipeline>()
+            .init_resource::<automata::ExtractedAutomataRegion>()
+            .add_systems(ExtractSchedule, automata::extract_automata_region)
             .init_resource::<physics::Pipeline>()
+            .init_resource::<PhysicsReadbackCopied>()
             .init_resource::<animation::Pipeline>()
-            .add_systems(Render, prepare_uniforms.in_set(RenderSet::Prepare));
+            .add_systems(Render, prepare_uniforms.in_set(RenderSet::Prepare))
+            .add_systems(
+                Render,
+                automata::prepare_automata_rules.in_set(RenderSet::Prepare),
+            )
+            .add_systems(Render, clear::prepare_clear_regions.in_set(RenderSet::Prepare))
+            .add_systems(Render, map_physics_readback.in_set(RenderSet::Cleanup));
     }
 }
 
+/// Maps the readback buffer copied to this frame once the commands have been submitted,
+/// the index is sent back to the main world when it can be read
+fn map_physics_readback(physics_data: Res<PhysicsData>, copied: Res<PhysicsReadbackCopied>) {
+    let Some(index) = physics_data.readback_index else {
+        return;
+    };
+
+    let mut readbacks = physics_data.readbacks.lock().unwrap();
+    // Nothing was copied when no voxel camera ran the physics node or its pipeline wasn't
+    // ready, the readback is free again
+    if !copied.0.swap(false, Ordering::Relaxed) {
+        readbacks[index].in_use = false;
+        readbacks[index].entities.clear();
+        return;
+    }
+
+    let readback = &readbacks[index];
+    let sender = physics_data.readback_sender.clone();
+
+    readback
+        .buffer
+        .slice(..readback.buffer_length * 4)
+        .map_async(MapMode::Read, move |result| {
+            if result.is_ok() {
+                let _ = sender.send(index);
+            }
+        });
+}
+
+#[allow(clippy::too_many_arguments)]
 fn prepare_uniforms(
     time: Res<Time>,
+    frame_count: Res<FrameCount>,
+    physics_settings: Res<PhysicsSettings>,
+    animation_clock: Res<AnimationClock>,
+    simulation_seed: Res<SimulationSeed>,
     mut compute_data: ResMut<ComputeData>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
 ) {
     let uniforms = ComputeUniforms {
         time: time.elapsed_seconds_f64() as f32,
-        delta_time: time.delta_seconds() as f32,
+        delta_time: time.delta_seconds() / physics_settings.substeps.max(1) as f32,
+        gravity: physics_settings.gravity,
+        frame: frame_count.0,
+        seed: simulation_seed.0,
+        animation_time: animation_clock.0,
     };
     compute_data.uniform_buffer.set(uniforms);
     compute_data
@@ -163,16 +347,67 @@ fn prepare_uniforms(
 #[derive(Resource, ShaderType)]
 struct ComputeUniforms {
     time: f32,
+    /// Seconds per physics substep
     delta_time: f32,
+    gravity: Vec3,
+    frame: u32,
+    seed: u32,
+    /// Seconds the animations have played for, scaled by `AnimationSettings::speed`
+    animation_time: f32,
+}
+
+/// Seed for the randomness of the automata pass, the simulation evolves identically for the
+/// same seed and frame count
+#[derive(Resource, Clone, Copy, Default, ExtractResource)]
+pub struct SimulationSeed(pub u32);
+
+/// Set by the [`PhysicsNode`](physics::PhysicsNode) once it encoded the copy of the physics
+/// buffer to this frame's readback
+#[derive(Resource, Default)]
+pub(crate) struct PhysicsReadbackCopied(pub AtomicBool);
+
+/// Cpu readable copy of the physics buffer and the entities it was written for
+pub struct PhysicsReadback {
+    pub buffer: Buffer,
+    pub frame: u32,
+    pub buffer_length: u64,
+    pub entities: HashMap<Entity, usize>,
+    /// Set while the buffer is waiting to be copied to, mapped or read
+    pub in_use: bool,
 }
 
 #[derive(Clone, Resource, ExtractResource)]
 pub struct PhysicsData {
+    /// Awake bodies the physics pass is dispatched for
     pub dispatch_size: u32,
     pub buffer_length: u64,
+    /// Header slot of each body, kept for as long as the body lives
     pub entities: HashMap<Entity, usize>,
     pub physics_buffer_gpu: Buffer,
-    pub physics_buffer_cpu: Buffer,
+    /// Translation, velocity and position snapshot of the body in each header slot. It stays
+    /// on the gpu between frames, only new bodies and the ones changed on the cpu are written
+    /// to it
+    pub physics_state_gpu: Buffer,
+    /// Number of awake bodies followed by their header slots, the physics pass is only
+    /// dispatched for these
+    pub physics_awake_gpu: Buffer,
+    pub readbacks: Arc<Mutex<Vec<PhysicsReadback>>>,
+    /// Readback the physics buffer is copied to this frame, `None` when all of them are in use
+    pub readback_index: Option<usize>,
+    pub readback_sender: Sender<usize>,
+    pub readback_receiver: Receiver<usize>,
+    latest_frame: Option<u32>,
+}
+
+impl PhysicsData {
+    /// Frame whose physics results were last applied to the bodies
+    pub fn latest_frame(&self) -> Option<u32> {
+        self.latest_frame
+    }
+
+    pub(crate) fn set_latest_frame(&mut self, frame: u32) {
+        self.latest_frame = Some(frame);
+    }
 }
 
 #[derive(Clone, Resource, ExtractResource)]
@@ -186,4 +421,6 @@ pub struct ComputeData {
     pub bind_group_layout: BindGroupLayout,
     pub bind_group: BindGroup,
     uniform_buffer: UniformBuffer<ComputeUniforms>,
+    automata_buffer: UniformBuffer<automata::AutomataUniforms>,
+    clear_buffer: UniformBuffer<clear::ClearUniforms>,
 }
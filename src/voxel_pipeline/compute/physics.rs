@@ -1,5 +1,8 @@
-use super::{ComputeData, PhysicsData};
-use crate::{voxel_pipeline::voxel_world::VoxelData, RenderGraphSettings};
+use super::{ComputeData, PhysicsData, PhysicsReadbackCopied};
+use crate::{
+    voxel_pipeline::voxel_world::VoxelData, PhysicsSettings, RenderGraphSettings,
+    SimulationControl,
+};
 use bevy::{
     prelude::*,
     render::{
@@ -8,12 +11,25 @@ use bevy::{
         renderer::RenderContext,
     },
 };
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-pub struct PhysicsNode;
+/// Node of the per view [`VoxelGraph`](crate::VoxelGraph) that only runs for the first voxel
+/// camera each frame, otherwise bodies would be stepped once per camera. It runs after the voxelization
+/// cameras so it sees the meshes voxelized this frame
+#[derive(Default)]
+pub struct PhysicsNode {
+    ran: AtomicBool,
+}
 
 #[derive(Resource)]
-pub struct Pipeline(CachedComputePipelineId);
+pub struct Pipeline {
+    update: CachedComputePipelineId,
+    /// Copies the positions of the awake bodies before each substep
+    snapshot: CachedComputePipelineId,
+}
 
 impl FromWorld for Pipeline {
     fn from_world(world: &mut World) -> Self {
@@ -27,18 +43,32 @@ impl FromWorld for Pipeline {
 
         let update_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
             label: Some(Cow::from("physics pipeline")),
+            layout: vec![voxel_bind_group_layout.clone(), compute_bind_group_layout.clone()],
+            shader: shader.clone(),
+            shader_defs: vec![],
+            entry_point: Cow::from("physics"),
+            push_constant_ranges: vec![],
+        });
+        let snapshot_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some(Cow::from("physics snapshot pipeline")),
             layout: vec![voxel_bind_group_layout, compute_bind_group_layout],
             shader,
             shader_defs: vec![],
-            entry_point: Cow::from("physics"),
+            entry_point: Cow::from("snapshot"),
             push_constant_ranges: vec![],
         });
 
-        Pipeline(update_pipeline)
+        Pipeline {
+            update: update_pipeline,
+            snapshot: snapshot_pipeline,
+        }
     }
 }
 
 impl render_graph::Node for PhysicsNode {
+    fn update(&mut self, _world: &mut World) {
+        *self.ran.get_mut() = false;
+    }
 
     fn run(
         &self,
@@ -46,19 +76,28 @@ impl render_graph::Node for PhysicsNode {
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), NodeRunError> {
+        if self.ran.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+
         let voxel_data = world.resource::<VoxelData>();
         let compute_data = world.resource::<ComputeData>();
         let pipeline_cache = world.resource::<PipelineCache>();
         let physics_data = world.resource::<PhysicsData>();
         let render_graph_settings = world.resource::<RenderGraphSettings>();
+        let simulation_control = world.resource::<SimulationControl>();
+        let physics_settings = world.resource::<PhysicsSettings>();
 
-        if !render_graph_settings.physics {
+        if !render_graph_settings.physics || !simulation_control.should_run() {
             return Ok(());
         }
 
-        let pipeline = match pipeline_cache.get_compute_pipeline(world.resource::<Pipeline>().0) {
-            Some(pipeline) => pipeline,
-            None => return Ok(()),
+        let pipelines = world.resource::<Pipeline>();
+        let (Some(pipeline), Some(snapshot_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(pipelines.update),
+            pipeline_cache.get_compute_pipeline(pipelines.snapshot),
+        ) else {
+            return Ok(());
         };
 
         {
@@ -71,18 +110,28 @@ impl render_graph::Node for PhysicsNode {
 
             let dispatch_size = (physics_data.dispatch_size as f32).cbrt().ceil() as u32;
             if dispatch_size > 0 {
-                pass.set_pipeline(pipeline);
-                pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+                // each substep continues from the bodies written by the previous one, sleeping
+                // bodies aren't in the awake list the dispatch size counts
+                for _ in 0..physics_settings.substeps.max(1) {
+                    pass.set_pipeline(snapshot_pipeline);
+                    pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+                    pass.set_pipeline(pipeline);
+                    pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+                }
             }
         }
 
-        render_context.command_encoder().copy_buffer_to_buffer(
-            &physics_data.physics_buffer_gpu,
-            0,
-            &physics_data.physics_buffer_cpu,
-            0,
-            physics_data.buffer_length * 4,
-        );
+        if let Some(index) = physics_data.readback_index {
+            let readbacks = physics_data.readbacks.lock().unwrap();
+            render_context.command_encoder().copy_buffer_to_buffer(
+                &physics_data.physics_buffer_gpu,
+                0,
+                &readbacks[index].buffer,
+                0,
+                physics_data.buffer_length * 4,
+            );
+            world.resource::<PhysicsReadbackCopied>().0.store(true, Ordering::Relaxed);
+        }
 
         Ok(())
     }
@@ -1,7 +1,7 @@
 #import bevy_voxel_engine::common::{
-    VOXELS_PER_METER,
     VoxelUniforms,
     Ray,
+    ANIMATION_FLAG,
     COLLISION_FLAG,
 }
 #import bevy_voxel_engine::raytracing::{
@@ -17,34 +17,265 @@
 struct ComputeUniforms {
     time: f32,
     delta_time: f32,
+    gravity: vec3<f32>,
+    frame: u32,
+    seed: u32,
+    animation_time: f32,
 }
 
+// fraction of the velocity lost per second in a fluid of density 1
+const FLUID_DRAG: f32 = 2.0;
+
 @group(1) @binding(0)
 var<uniform> compute_uniforms: ComputeUniforms;
 @group(1) @binding(1)
 var<storage, read_write> physics_data: array<u32>;
+// translation, velocity and position snapshot of the body in each header slot, kept between
+// frames
+@group(1) @binding(5)
+var<storage, read_write> physics_state: array<u32>;
+const STATE_WORDS = 9;
+// number of awake bodies followed by their header slots
+@group(1) @binding(6)
+var<storage, read> physics_awake: array<u32>;
+
+// Bounces the normal part of the velocity and slows the sliding part
+fn collision_response(velocity: vec3<f32>, normal: vec3<f32>, restitution: f32, friction: f32, delta_time: f32) -> vec3<f32> {
+    let normal_velocity = dot(velocity, normal) * normal;
+    let tangent_velocity = velocity - normal_velocity;
+    return tangent_velocity * max(1.0 - friction * delta_time, 0.0) - normal_velocity * restitution;
+}
+
+fn respond_to_axes(velocity: vec3<f32>, hit_axes: vec3<f32>, restitution: f32, friction: f32, delta_time: f32) -> vec3<f32> {
+    var response = velocity;
+    if (hit_axes.x != 0.0) {
+        response = collision_response(response, vec3(1.0, 0.0, 0.0), restitution, friction, delta_time);
+    }
+    if (hit_axes.y != 0.0) {
+        response = collision_response(response, vec3(0.0, 1.0, 0.0), restitution, friction, delta_time);
+    }
+    if (hit_axes.z != 0.0) {
+        response = collision_response(response, vec3(0.0, 0.0, 1.0), restitution, friction, delta_time);
+    }
+    return response;
+}
+
+// Offset from the closest point on a capsule's center segment, its length is the distance to
+// the capsule's core and its direction the surface normal there
+fn capsule_offset(offset: vec3<f32>, axis: vec3<f32>, half_height: f32) -> vec3<f32> {
+    return offset - axis * clamp(dot(offset, axis), -half_height, half_height);
+}
+
+fn read_capsule_axis(data_index: i32) -> vec3<f32> {
+    return vec3(
+        bitcast<f32>(physics_data[data_index + 39]),
+        bitcast<f32>(physics_data[data_index + 40]),
+        bitcast<f32>(physics_data[data_index + 41]),
+    );
+}
+
+fn voxel_coords(world_pos: vec3<f32>) -> vec3<i32> {
+    return vec3<i32>((world_pos - voxel_uniforms.origin) * voxel_uniforms.voxels_per_meter + vec3(f32(voxel_uniforms.texture_size) / 2.0));
+}
+
+fn in_texture(texture_coords: vec3<i32>) -> bool {
+    return all(texture_coords >= vec3(0)) && all(texture_coords < vec3(i32(voxel_uniforms.texture_size)));
+}
+
+// Half size in voxels of the box around a body's shape, 0 for points
+fn body_extent(data_type: i32, data_index: i32) -> vec3<i32> {
+    if (data_type == 1) {
+        return vec3(
+            bitcast<i32>(physics_data[data_index + 37]),
+            bitcast<i32>(physics_data[data_index + 38]),
+            bitcast<i32>(physics_data[data_index + 39]),
+        );
+    } else if (data_type == 2) {
+        return vec3(i32(physics_data[data_index + 37]));
+    } else if (data_type == 3) {
+        return vec3(i32(physics_data[data_index + 37]))
+            + vec3<i32>(abs(read_capsule_axis(data_index))) * i32(physics_data[data_index + 38]);
+    }
+    return vec3(0);
+}
+
+// Whether the voxel at the offset from a body's center is inside its shape
+fn in_body(offset: vec3<i32>, data_type: i32, data_index: i32) -> bool {
+    if (data_type == 2) {
+        return length(vec3<f32>(offset)) <= f32(physics_data[data_index + 37]);
+    } else if (data_type == 3) {
+        let radius = f32(physics_data[data_index + 37]);
+        let half_height = f32(physics_data[data_index + 38]);
+        return length(capsule_offset(vec3<f32>(offset), read_capsule_axis(data_index), half_height)) <= radius;
+    }
+    return true;
+}
+
+// Surface normal at a contact estimated from the solid voxels around the voxel that was hit,
+// slopes and steps tilt it away from the normal of the face
+fn contact_normal(contact_pos: vec3<f32>, face_normal: vec3<f32>) -> vec3<f32> {
+    let center = voxel_coords(contact_pos);
+    var gradient = vec3(0.0);
+    for (var x = -1; x <= 1; x++) {
+        for (var y = -1; y <= 1; y++) {
+            for (var z = -1; z <= 1; z++) {
+                let offset = vec3(x, y, z);
+                let texture_coords = center + offset;
+                if (all(offset == vec3(0)) || !in_texture(texture_coords)) {
+                    continue;
+                }
+
+                let voxel = textureLoad(voxel_world, texture_coords.zyx).r;
+                if ((voxel & 0xFFu) != 0u && ((voxel >> 8u) & COLLISION_FLAG) != 0u) {
+                    gradient -= normalize(vec3<f32>(offset));
+                }
+            }
+        }
+    }
+
+    // surrounded or thin voxels have no clear gradient towards the body
+    if (dot(gradient, face_normal) <= 0.0) {
+        return face_normal;
+    }
+    return normalize(gradient);
+}
+
+// Density of the fluid voxels inside a body averaged over all of its voxels, so a body half
+// in water of density 1 gets 0.5
+fn submerged_density(world_pos: vec3<f32>, data_type: i32, data_index: i32) -> f32 {
+    let center = voxel_coords(world_pos);
+    let extent = body_extent(data_type, data_index);
+    var density = 0.0;
+    var voxels = 0.0;
+    for (var x = -extent.x; x <= extent.x; x++) {
+        for (var y = -extent.y; y <= extent.y; y++) {
+            for (var z = -extent.z; z <= extent.z; z++) {
+                let offset = vec3(x, y, z);
+                if (!in_body(offset, data_type, data_index)) {
+                    continue;
+                }
+
+                voxels += 1.0;
+                let texture_coords = center + offset;
+                if (in_texture(texture_coords)) {
+                    let material = textureLoad(voxel_world, texture_coords.zyx).r & 0xFFu;
+                    density += voxel_uniforms.material_densities[material / 4u][material % 4u];
+                }
+            }
+        }
+    }
+    return density / max(voxels, 1.0);
+}
+
+// Writes a body's collision effect to the voxels within its radius of the impact
+fn apply_collision_effect(impact_pos: vec3<f32>, collision_effect: vec3<f32>) {
+    if collision_effect.x == 0.0 {
+        return;
+    }
+
+    let texture_coords =
+        vec3<i32>((impact_pos - voxel_uniforms.origin) * voxel_uniforms.voxels_per_meter + vec3(f32(voxel_uniforms.texture_size) / 2.0));
+
+    let radius = collision_effect.y;
+    let range = i32(ceil(radius * voxel_uniforms.voxels_per_meter));
+    for (var x = -range; x <= range; x++) {
+        for (var y = -range; y <= range; y++) {
+            for (var z = -range; z <= range; z++) {
+                let offset = vec3(x, y, z);
+                let texture_coords = texture_coords + offset;
+                if (length(vec3<f32>(offset) / voxel_uniforms.voxels_per_meter) >= radius) {
+                    continue;
+                }
+                if (any(texture_coords < vec3(0)) || any(texture_coords >= vec3(i32(voxel_uniforms.texture_size)))) {
+                    continue;
+                }
+
+                // Destroy
+                if (collision_effect.x == 1.0) {
+                    // animated voxels are rewritten from their entities every frame, clearing
+                    // them would only leave a hole for one frame. Anything else is cleared with
+                    // its flags so collision, automata and sand voxels leave the simulation too
+                    let voxel = textureLoad(voxel_world, texture_coords.zyx).r;
+                    if (((voxel >> 8u) & ANIMATION_FLAG) == 0u) {
+                        textureStore(voxel_world, texture_coords.zyx, vec4(0u));
+                    }
+                }
+                // Place
+                if (collision_effect.x == 2.0) {
+                    let material = bitcast<u32>(collision_effect.z);
+                    textureStore(voxel_world, texture_coords.zyx, vec4(material));
+                }
+                // Set Flags
+                if (collision_effect.x == 3.0) {
+                    let flags = bitcast<u32>(collision_effect.z);
+                    var voxel = textureLoad(voxel_world, texture_coords.zyx).r;
+                    voxel |= flags << 8u;
+                    textureStore(voxel_world, texture_coords.zyx, vec4(voxel));
+                }
+                // Paint
+                if (collision_effect.x == 5.0) {
+                    let material = bitcast<u32>(collision_effect.z);
+                    let voxel = textureLoad(voxel_world, texture_coords.zyx).r;
+                    if ((voxel & 0xFFu) != 0u && ((voxel >> 8u) & ANIMATION_FLAG) == 0u) {
+                        textureStore(voxel_world, texture_coords.zyx, vec4((voxel & 0xFF00u) | material));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Header index of the awake body of the invocation, 0 when there is none
+fn awake_body(invocation_id: vec3<u32>) -> i32 {
+    let awake_len = i32(physics_awake[0]);
+    let dispatch_size = i32(ceil(pow(f32(awake_len), 1.0 / 3.0)));
+
+    let pos = vec3(i32(invocation_id.x), i32(invocation_id.y), i32(invocation_id.z));
+    let awake_index = pos.x * dispatch_size * dispatch_size + pos.y * dispatch_size + pos.z + 1;
+    if (awake_index > awake_len) {
+        return 0;
+    }
+    return i32(physics_awake[awake_index]) + 1;
+}
+
+// Copies the positions of the awake bodies before each substep, bodies push each other out of
+// the copies so it doesn't matter which of a pair runs first
+@compute @workgroup_size(1, 1, 1)
+fn snapshot(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = awake_body(invocation_id);
+    if (index > 0) {
+        let state_index = (index - 1) * STATE_WORDS;
+        physics_state[state_index + 6] = physics_state[state_index + 0];
+        physics_state[state_index + 7] = physics_state[state_index + 1];
+        physics_state[state_index + 8] = physics_state[state_index + 2];
+    }
+}
 
 @compute @workgroup_size(1, 1, 1)
 fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let header_len = i32(physics_data[0]);
-    let dispatch_size = i32(ceil(pow(f32(header_len), 1.0 / 3.0)));
+    let index = awake_body(invocation_id);
 
-    let pos = vec3(i32(invocation_id.x), i32(invocation_id.y), i32(invocation_id.z));
-    let index = pos.x * dispatch_size * dispatch_size + pos.y * dispatch_size + pos.z + 1;
-
-    if (index <= header_len) {
+    if (index > 0) {
         let data_index = i32(u32(physics_data[index]) & 0x00FFFFFFu);
         let data_type = i32(u32(physics_data[index]) >> 24u);
 
+        // slot of a despawned body
+        if (data_type == 0xFF) {
+            return;
+        }
+
+        // the body continues from where the gpu left it, the cpu only writes its edits
+        let state_index = (index - 1) * STATE_WORDS;
         var world_pos = vec3(
-            bitcast<f32>(physics_data[data_index + 0]),
-            bitcast<f32>(physics_data[data_index + 1]),
-            bitcast<f32>(physics_data[data_index + 2]),
+            bitcast<f32>(physics_state[state_index + 0]),
+            bitcast<f32>(physics_state[state_index + 1]),
+            bitcast<f32>(physics_state[state_index + 2]),
         );
         var velocity = vec3(
-            bitcast<f32>(physics_data[data_index + 3]),
-            bitcast<f32>(physics_data[data_index + 4]),
-            bitcast<f32>(physics_data[data_index + 5]),
+            bitcast<f32>(physics_state[state_index + 3]),
+            bitcast<f32>(physics_state[state_index + 4]),
+            bitcast<f32>(physics_state[state_index + 5]),
         );
         var gravity = vec3(
             bitcast<f32>(physics_data[data_index + 6]),
@@ -56,18 +287,57 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
             bitcast<f32>(physics_data[data_index + 10]),
             bitcast<f32>(physics_data[data_index + 11]),
         );
-        var hit_normal = vec3(0.0);
+        let restitution = bitcast<f32>(physics_data[data_index + 24]);
+        let friction = bitcast<f32>(physics_data[data_index + 25]);
+        // the voxel world ignores collision layers, they only filter contacts between bodies
+        let layer = physics_data[data_index + 26];
+        let mask = physics_data[data_index + 27];
+        let ccd = (physics_data[data_index + 28] & 1u) != 0u;
+        let gravity_scale = bitcast<f32>(physics_data[data_index + 29]);
+        let density = bitcast<f32>(physics_data[data_index + 30]);
+        let previous_pos = world_pos;
+        // zero and the identity on the first substep, keeps the hit of an earlier substep
+        var hit_normal = vec3(
+            bitcast<f32>(physics_data[data_index + 12]),
+            bitcast<f32>(physics_data[data_index + 13]),
+            bitcast<f32>(physics_data[data_index + 14]),
+        );
+        var body_normal = vec3(
+            bitcast<f32>(physics_data[data_index + 34]),
+            bitcast<f32>(physics_data[data_index + 35]),
+            bitcast<f32>(physics_data[data_index + 36]),
+        );
+        let previous_rotation = mat4x4(
+            vec4(bitcast<vec3<f32>>(vec3(physics_data[data_index + 15], physics_data[data_index + 16], physics_data[data_index + 17])), 0.0),
+            vec4(bitcast<vec3<f32>>(vec3(physics_data[data_index + 18], physics_data[data_index + 19], physics_data[data_index + 20])), 0.0),
+            vec4(bitcast<vec3<f32>>(vec3(physics_data[data_index + 21], physics_data[data_index + 22], physics_data[data_index + 23])), 0.0),
+            vec4(0.0, 0.0, 0.0, 1.0),
+        );
         var portal_rotation = IDENTITY;
+        let is_trigger = collision_effect.x == 4.0;
         
         let delta_time = compute_uniforms.delta_time;
 
-        velocity += gravity * delta_time;
+        velocity += (gravity + compute_uniforms.gravity * gravity_scale) * delta_time;
+
+        // Fluids push against the world gravity by their density relative to the body's and
+        // slow it down, so lighter bodies float and heavier ones sink slowly
+        if (voxel_uniforms.fluid_materials != 0u && density > 0.0) {
+            let fluid_density = submerged_density(world_pos, data_type, data_index);
+            velocity -= compute_uniforms.gravity * fluid_density / density * delta_time;
+            velocity *= max(1.0 - fluid_density * FLUID_DRAG * delta_time, 0.0);
+        }
+
+        // Triggers move freely and report the voxels they overlap instead
+        if (is_trigger) {
+            world_pos += velocity * delta_time;
+        }
 
         if (data_type == 0) {
             // Point
 
             // Step point by ray
-            if (any(abs(velocity) > vec3(0.0001))) {
+            if (!is_trigger && any(abs(velocity) > vec3(0.0001))) {
                 let direction = Ray(world_pos, normalize(velocity));
                 let distance = length(velocity) * delta_time;
                 let hit = shoot_ray(direction, distance, COLLISION_FLAG);
@@ -79,70 +349,38 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                     // velocity = reflect(velocity, normalize(hit.normal));
                     // velocity = hit.normal * 10.0;
 
-                    velocity = velocity - dot(velocity, hit.normal) * hit.normal;
+                    velocity = collision_response(velocity, hit.normal, restitution, friction, delta_time);
                     hit_normal = hit.normal;
                     
-                    // Collision effects
-
-                    let texture_coords = 
-                        vec3<i32>(world_pos * VOXELS_PER_METER + vec3(f32(voxel_uniforms.texture_size) / 2.0));
-
-                    if collision_effect.x != 0.0 {
-                        let radius = collision_effect.y;
-                        let range = i32(ceil(radius * VOXELS_PER_METER));
-                        for (var x = -range; x <= range; x++) {
-                            for (var y = -range; y <= range; y++) {
-                                for (var z = -range; z <= range; z++) {
-                                    let offset = vec3(x, y, z);
-                                    let texture_coords = texture_coords + offset;
-                                    if (length(vec3<f32>(offset) / VOXELS_PER_METER) >= radius) {
-                                        continue;
-                                    }
-
-                                    // Destroy
-                                    if (collision_effect.x == 1.0) {
-                                        textureStore(voxel_world, texture_coords.zyx, vec4(0u));
-                                    }
-                                    // Place
-                                    if (collision_effect.x == 2.0) {
-                                        let material = bitcast<u32>(collision_effect.z);
-                                        textureStore(voxel_world, texture_coords.zyx, vec4(material));
-                                    }
-                                    // Set Flags
-                                    if (collision_effect.x == 3.0) {
-                                        let flags = bitcast<u32>(collision_effect.z);
-                                        var voxel = textureLoad(voxel_world, texture_coords.zyx).r;
-                                        voxel |= flags << 8u;
-                                        textureStore(voxel_world, texture_coords.zyx, vec4(voxel));
-                                    }
-                                }
-                            }
-                        }
-                    }
+                    apply_collision_effect(world_pos, collision_effect);
                 }
             }
         } else if (data_type == 1) {
             // Player
-            if (any(abs(velocity) > vec3(0.01))) {
+            if (!is_trigger && any(abs(velocity) > vec3(0.01))) {
                 let direction = normalize(velocity);
                 let distance = length(velocity) * delta_time;
 
                 let size = vec3(
-                    bitcast<i32>(physics_data[data_index + 24]),
-                    bitcast<i32>(physics_data[data_index + 25]),
-                    bitcast<i32>(physics_data[data_index + 26]),
+                    bitcast<i32>(physics_data[data_index + 37]),
+                    bitcast<i32>(physics_data[data_index + 38]),
+                    bitcast<i32>(physics_data[data_index + 39]),
                 );
                 let v_sign = sign(velocity);
 
+                // axes of the faces that hit voxels, responded to once after casting
+                var hit_axes = vec3(0.0);
+
                 // x face
                 for (var y = -size.y; y <= size.y; y++) {
                     for (var z = -size.z; z <= size.z; z++) {
-                        let offset = vec3(f32(size.x) * v_sign.x, f32(y), f32(z)) / (VOXELS_PER_METER * 1.0001);
+                        let offset = vec3(f32(size.x) * v_sign.x, f32(y), f32(z)) / (voxel_uniforms.voxels_per_meter * 1.0001);
                         let hit = shoot_ray(Ray((world_pos + offset), direction), distance, COLLISION_FLAG);
                         
                         let plane_normal = vec3(1.0, 0.0, 0.0);
                         if (hit.hit && all(abs(hit.normal) == plane_normal)) {
-                            velocity = velocity - dot(velocity, plane_normal) * plane_normal;
+                            hit_axes = max(hit_axes, plane_normal);
+                            hit_normal = hit.normal;
                             // world_pos = hit.pos - offset;
                         }
                     }
@@ -151,12 +389,13 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                 // y face
                 for (var x = -size.x; x <= size.x; x++) {
                     for (var z = -size.z; z <= size.z; z++) {
-                        let offset = vec3(f32(x), f32(size.y) * v_sign.y, f32(z)) / (VOXELS_PER_METER * 1.001);
+                        let offset = vec3(f32(x), f32(size.y) * v_sign.y, f32(z)) / (voxel_uniforms.voxels_per_meter * 1.001);
                         let hit = shoot_ray(Ray((world_pos + offset), direction), distance, COLLISION_FLAG);
                         
                         let plane_normal = vec3(0.0, 1.0, 0.0);
                         if (hit.hit && all(abs(hit.normal) == plane_normal)) {
-                            velocity = velocity - dot(velocity, plane_normal) * plane_normal;
+                            hit_axes = max(hit_axes, plane_normal);
+                            hit_normal = hit.normal;
                             // world_pos = hit.pos - offset;
                         }
                     }
@@ -165,17 +404,117 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                 // z face
                 for (var x = -size.x; x <= size.x; x++) {
                     for (var y = -size.y; y <= size.y; y++) {
-                        let offset = vec3(f32(x), f32(y), f32(size.z) * v_sign.z) / (VOXELS_PER_METER * 1.0001);
+                        let offset = vec3(f32(x), f32(y), f32(size.z) * v_sign.z) / (voxel_uniforms.voxels_per_meter * 1.0001);
                         let hit = shoot_ray(Ray((world_pos + offset), direction), distance, COLLISION_FLAG);
                         
                         let plane_normal = vec3(0.0, 0.0, 1.0);
                         if (hit.hit && all(abs(hit.normal) == plane_normal)) {
-                            velocity = velocity - dot(velocity, plane_normal) * plane_normal;
+                            hit_axes = max(hit_axes, plane_normal);
+                            hit_normal = hit.normal;
                             // world_pos = hit.pos - offset;
                         }
                     }
                 }
 
+                velocity = respond_to_axes(velocity, hit_axes, restitution, friction, delta_time);
+
+                if (any(abs(velocity) > vec3(0.01))) {
+                    let direction = normalize(velocity * delta_time);
+                    let distance = length(velocity) * delta_time;
+                    let hit = shoot_ray(Ray(world_pos, direction), distance, 1u);
+                    portal_rotation = hit.portals;
+                    velocity = (hit.portals * vec4(velocity, 0.0)).xyz;
+                    world_pos = hit.pos;
+                }
+
+                // the impact is on the face that hit, half the box's size from its center
+                if (any(hit_axes != vec3(0.0))) {
+                    let half_size = dot(abs(hit_normal), vec3<f32>(size)) / voxel_uniforms.voxels_per_meter;
+                    apply_collision_effect(world_pos - hit_normal * half_size, collision_effect);
+                }
+            }
+        } else if (data_type == 2) {
+            // Sphere
+            if (!is_trigger && any(abs(velocity) > vec3(0.01))) {
+                let direction = normalize(velocity);
+                let distance = length(velocity) * delta_time;
+
+                let radius = i32(physics_data[data_index + 37]);
+
+                // axes of the voxel faces that were hit, responded to once after casting
+                var hit_axes = vec3(0.0);
+
+                // cast from the surface voxels facing the direction of travel
+                for (var x = -radius; x <= radius; x++) {
+                    for (var y = -radius; y <= radius; y++) {
+                        for (var z = -radius; z <= radius; z++) {
+                            let offset = vec3(f32(x), f32(y), f32(z));
+                            let offset_length = length(offset);
+                            if (offset_length > f32(radius) || offset_length < f32(radius) - 1.0 || dot(offset, direction) < 0.0) {
+                                continue;
+                            }
+
+                            let hit = shoot_ray(Ray(world_pos + offset / (voxel_uniforms.voxels_per_meter * 1.0001), direction), distance, COLLISION_FLAG);
+                            if (hit.hit) {
+                                hit_axes = max(hit_axes, abs(hit.normal));
+                                hit_normal = hit.normal;
+                            }
+                        }
+                    }
+                }
+
+                velocity = respond_to_axes(velocity, hit_axes, restitution, friction, delta_time);
+
+                if (any(abs(velocity) > vec3(0.01))) {
+                    let direction = normalize(velocity * delta_time);
+                    let distance = length(velocity) * delta_time;
+                    let hit = shoot_ray(Ray(world_pos, direction), distance, 1u);
+                    portal_rotation = hit.portals;
+                    velocity = (hit.portals * vec4(velocity, 0.0)).xyz;
+                    world_pos = hit.pos;
+                }
+
+                if (any(hit_axes != vec3(0.0))) {
+                    let impact_pos = world_pos - hit_normal * f32(radius) / voxel_uniforms.voxels_per_meter;
+                    apply_collision_effect(impact_pos, collision_effect);
+                }
+            }
+        } else if (data_type == 3) {
+            // Capsule
+            if (!is_trigger && any(abs(velocity) > vec3(0.01))) {
+                let direction = normalize(velocity);
+                let distance = length(velocity) * delta_time;
+
+                let radius = i32(physics_data[data_index + 37]);
+                let half_height = i32(physics_data[data_index + 38]);
+                let axis = read_capsule_axis(data_index);
+                let extent = vec3(radius) + vec3<i32>(abs(axis)) * half_height;
+
+                // axes of the voxel faces that were hit, responded to once after casting
+                var hit_axes = vec3(0.0);
+
+                // cast from the surface voxels facing the direction of travel
+                for (var x = -extent.x; x <= extent.x; x++) {
+                    for (var y = -extent.y; y <= extent.y; y++) {
+                        for (var z = -extent.z; z <= extent.z; z++) {
+                            let offset = vec3(f32(x), f32(y), f32(z));
+                            let surface_offset = capsule_offset(offset, axis, f32(half_height));
+                            let offset_length = length(surface_offset);
+                            if (offset_length > f32(radius) || offset_length < f32(radius) - 1.0 || dot(surface_offset, direction) < 0.0) {
+                                continue;
+                            }
+
+                            let hit = shoot_ray(Ray(world_pos + offset / (voxel_uniforms.voxels_per_meter * 1.0001), direction), distance, COLLISION_FLAG);
+                            if (hit.hit) {
+                                hit_axes = max(hit_axes, abs(hit.normal));
+                                hit_normal = hit.normal;
+                            }
+                        }
+                    }
+                }
+
+                velocity = respond_to_axes(velocity, hit_axes, restitution, friction, delta_time);
+
                 if (any(abs(velocity) > vec3(0.01))) {
                     let direction = normalize(velocity * delta_time);
                     let distance = length(velocity) * delta_time;
@@ -184,9 +523,140 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                     velocity = (hit.portals * vec4(velocity, 0.0)).xyz;
                     world_pos = hit.pos;
                 }
+
+                if (any(hit_axes != vec3(0.0))) {
+                    let reach = f32(radius) + abs(dot(hit_normal, axis)) * f32(half_height);
+                    apply_collision_effect(world_pos - hit_normal * reach / voxel_uniforms.voxels_per_meter, collision_effect);
+                }
             }
         }
 
+        // Sweep the center from where the step started to where it ended, the shape's casts
+        // above can slip between thin voxels when moving several voxels per frame
+        if (ccd && !is_trigger) {
+            let travel = world_pos - previous_pos;
+            let travel_distance = length(travel);
+            if (travel_distance > 0.0001) {
+                let hit = shoot_ray(Ray(previous_pos, travel / travel_distance), travel_distance, COLLISION_FLAG);
+                if (hit.hit) {
+                    // back off by the shape's extent along the normal so it rests against the wall
+                    var extent = 0.0;
+                    if (data_type == 1) {
+                        let size = vec3(
+                            f32(bitcast<i32>(physics_data[data_index + 37])),
+                            f32(bitcast<i32>(physics_data[data_index + 38])),
+                            f32(bitcast<i32>(physics_data[data_index + 39])),
+                        );
+                        extent = dot(abs(hit.normal), size);
+                    } else if (data_type == 2) {
+                        extent = f32(physics_data[data_index + 37]);
+                    } else if (data_type == 3) {
+                        let axis = read_capsule_axis(data_index);
+                        extent = f32(physics_data[data_index + 37])
+                            + abs(dot(hit.normal, axis)) * f32(physics_data[data_index + 38]);
+                    }
+
+                    world_pos = hit.pos + hit.normal * extent / voxel_uniforms.voxels_per_meter;
+                    portal_rotation = hit.portals;
+                    velocity = collision_response(velocity, hit.normal, restitution, friction, delta_time);
+                    hit_normal = hit.normal;
+                }
+            }
+        }
+
+        // Push out of the boxes around the other bodies, when this body's mask contains one of
+        // their layers and their mask one of this body's. The overlap is measured between the
+        // positions at the start of the substep so each body of an awake pair moves half of it
+        if (!is_trigger && mask != 0u) {
+            let size = (vec3<f32>(body_extent(data_type, data_index)) + 0.5) / voxel_uniforms.voxels_per_meter;
+            let snapshot_pos = vec3(
+                bitcast<f32>(physics_state[state_index + 6]),
+                bitcast<f32>(physics_state[state_index + 7]),
+                bitcast<f32>(physics_state[state_index + 8]),
+            );
+            for (var other = 1; other <= header_len; other++) {
+                let other_index = i32(u32(physics_data[other]) & 0x00FFFFFFu);
+                let other_type = i32(u32(physics_data[other]) >> 24u);
+                if (other == index
+                    || other_type == 0xFF
+                    || (mask & physics_data[other_index + 26]) == 0u
+                    || (physics_data[other_index + 27] & layer) == 0u
+                    || bitcast<f32>(physics_data[other_index + 9]) == 4.0) {
+                    continue;
+                }
+
+                // sleeping bodies aren't snapshot, nothing moves them
+                let other_sleeping = (physics_data[other_index + 28] & 2u) != 0u;
+                let other_state = (other - 1) * STATE_WORDS + select(6, 0, other_sleeping);
+                let other_pos = vec3(
+                    bitcast<f32>(physics_state[other_state + 0]),
+                    bitcast<f32>(physics_state[other_state + 1]),
+                    bitcast<f32>(physics_state[other_state + 2]),
+                );
+                let other_size = (vec3<f32>(body_extent(other_type, other_index)) + 0.5) / voxel_uniforms.voxels_per_meter;
+                let offset = snapshot_pos - other_pos;
+                let overlap = size + other_size - abs(offset);
+                if (any(overlap <= vec3(0.0))) {
+                    continue;
+                }
+
+                // separate along the axis of least overlap
+                var normal = vec3(0.0, 0.0, select(-1.0, 1.0, offset.z >= 0.0));
+                var depth = overlap.z;
+                if (overlap.x <= overlap.y && overlap.x <= overlap.z) {
+                    normal = vec3(select(-1.0, 1.0, offset.x >= 0.0), 0.0, 0.0);
+                    depth = overlap.x;
+                } else if (overlap.y <= overlap.z) {
+                    normal = vec3(0.0, select(-1.0, 1.0, offset.y >= 0.0), 0.0);
+                    depth = overlap.y;
+                }
+
+                // sleeping bodies don't move out of the way
+                let share = select(0.5, 1.0, other_sleeping);
+                world_pos += normal * depth * share;
+                if (dot(velocity, normal) < 0.0) {
+                    velocity = collision_response(velocity, normal, restitution, friction, delta_time);
+                }
+                body_normal = normal;
+            }
+        }
+
+        if (is_trigger) {
+            let center = voxel_coords(world_pos);
+            let extent = body_extent(data_type, data_index);
+            var overlapped = 0u;
+            var overlap_material = 0u;
+            for (var x = -extent.x; x <= extent.x; x++) {
+                for (var y = -extent.y; y <= extent.y; y++) {
+                    for (var z = -extent.z; z <= extent.z; z++) {
+                        let offset = vec3(x, y, z);
+                        let texture_coords = center + offset;
+                        if (!in_body(offset, data_type, data_index) || !in_texture(texture_coords)) {
+                            continue;
+                        }
+
+                        let material = textureLoad(voxel_world, texture_coords.zyx).r & 0xFFu;
+                        if (material != 0u) {
+                            if (overlapped == 0u) {
+                                overlap_material = material;
+                            }
+                            overlapped += 1u;
+                        }
+                    }
+                }
+            }
+
+            // read back in place of the collision effect
+            physics_data[data_index + 10] = overlapped;
+            physics_data[data_index + 11] = overlap_material;
+        }
+
+        physics_state[state_index + 0] = bitcast<u32>(world_pos.x);
+        physics_state[state_index + 1] = bitcast<u32>(world_pos.y);
+        physics_state[state_index + 2] = bitcast<u32>(world_pos.z);
+        physics_state[state_index + 3] = bitcast<u32>(velocity.x);
+        physics_state[state_index + 4] = bitcast<u32>(velocity.y);
+        physics_state[state_index + 5] = bitcast<u32>(velocity.z);
         physics_data[data_index + 0] = bitcast<u32>(world_pos.x);
         physics_data[data_index + 1] = bitcast<u32>(world_pos.y);
         physics_data[data_index + 2] = bitcast<u32>(world_pos.z);
@@ -196,6 +666,20 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         physics_data[data_index + 12] = bitcast<u32>(hit_normal.x);
         physics_data[data_index + 13] = bitcast<u32>(hit_normal.y);
         physics_data[data_index + 14] = bitcast<u32>(hit_normal.z);
+        // the voxel hit is just past the shape's extent along the face normal
+        var surface_normal = vec3(0.0);
+        if (any(hit_normal != vec3(0.0))) {
+            let reach = dot(abs(hit_normal), vec3<f32>(body_extent(data_type, data_index))) + 0.5;
+            surface_normal = contact_normal(world_pos - hit_normal * reach / voxel_uniforms.voxels_per_meter, hit_normal);
+        }
+        physics_data[data_index + 31] = bitcast<u32>(surface_normal.x);
+        physics_data[data_index + 32] = bitcast<u32>(surface_normal.y);
+        physics_data[data_index + 33] = bitcast<u32>(surface_normal.z);
+        physics_data[data_index + 34] = bitcast<u32>(body_normal.x);
+        physics_data[data_index + 35] = bitcast<u32>(body_normal.y);
+        physics_data[data_index + 36] = bitcast<u32>(body_normal.z);
+        // rotation of the portals passed through across all substeps
+        portal_rotation = portal_rotation * previous_rotation;
         physics_data[data_index + 15] = bitcast<u32>(portal_rotation.x.x);
         physics_data[data_index + 16] = bitcast<u32>(portal_rotation.x.y);
         physics_data[data_index + 17] = bitcast<u32>(portal_rotation.x.z);
@@ -6,14 +6,37 @@ use crate::{
 use bevy::{
     prelude::*,
     render::{
+        extract_resource::ExtractResource,
         render_graph::{self, NodeRunError, RenderGraphContext},
         render_resource::*,
         renderer::{RenderContext, RenderQueue},
     },
 };
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Node of the per view [`VoxelGraph`](crate::VoxelGraph) that only runs for the first voxel
+/// camera each frame, otherwise the grid hierarchy would be rebuilt once per camera. It runs after the voxelization
+/// cameras so it sees the meshes voxelized this frame
+#[derive(Default)]
+pub struct RebuildNode {
+    ran: AtomicBool,
+}
 
-pub struct RebuildNode;
+/// Rebuilds the grid hierarchy from the voxel texture this frame even when
+/// [`RenderGraphSettings::rebuild`] is off, set it after bulk edits like
+/// [`VoxelWorld::paste_region`](crate::VoxelWorld::paste_region). Streamed chunk loads set
+/// it themselves. Reset at the start of the next frame
+#[derive(Resource, Clone, Copy, Default, ExtractResource)]
+pub struct RequestRebuild(pub bool);
+
+pub(super) fn reset_request_rebuild(mut request_rebuild: ResMut<RequestRebuild>) {
+    if request_rebuild.0 {
+        request_rebuild.0 = false;
+    }
+}
 
 #[derive(Resource)]
 pub struct Pipeline(CachedComputePipelineId);
@@ -41,20 +64,28 @@ impl FromWorld for Pipeline {
 }
 
 impl render_graph::Node for RebuildNode {
+    fn update(&mut self, _world: &mut World) {
+        *self.ran.get_mut() = false;
+    }
     fn run(
         &self,
         _graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), NodeRunError> {
+        if self.ran.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+
         let voxel_data = world.resource::<VoxelData>();
         let voxel_uniforms = world.resource::<VoxelUniforms>();
         let pipeline_cache = world.resource::<PipelineCache>();
         let render_queue = world.resource::<RenderQueue>();
         let dispatch_size = voxel_uniforms.texture_size / 4;
         let render_graph_settings = world.resource::<RenderGraphSettings>();
+        let request_rebuild = world.resource::<RequestRebuild>();
 
-        if !render_graph_settings.rebuild {
+        if !render_graph_settings.rebuild && !request_rebuild.0 {
             return Ok(());
         }
 
@@ -1,5 +1,8 @@
 use self::{
+    ao::{AoNode, AoPlugin},
     attachments::AttachmentsPlugin,
+    readback::{CaptureNode, ReadbackPlugin},
+    streaming::StreamingPlugin,
     compute::{
         animation::AnimationNode, automata::AutomataNode, clear::ClearNode,
         physics::PhysicsNode, rebuild::RebuildNode, ComputeResourcesPlugin,
@@ -9,7 +12,9 @@ use self::{
     voxelization::VoxelizationPlugin,
 };
 use bevy::{
+    asset::embedded_asset,
     core_pipeline::{
+        core_3d::MainTransparentPass3dNode,
         fxaa::FxaaNode, 
         tonemapping::TonemappingNode, 
         upscaling::UpscalingNode,
@@ -19,27 +24,55 @@ use bevy::{
         RenderApp,
         extract_resource::{ExtractResource, ExtractResourcePlugin},
         graph::CameraDriverLabel,
-        render_graph::{RenderGraph, RenderSubGraph, RenderLabel, ViewNodeRunner},
+        render_graph::{EmptyNode, RenderGraph, RenderSubGraph, RenderLabel, ViewNodeRunner},
     },
     ui::UiPassNode,
 };
 
+pub mod ao;
 pub mod attachments;
 pub mod compute;
+pub mod readback;
+pub mod streaming;
 pub mod trace;
 pub mod voxel_world;
 pub mod voxelization;
 
 pub struct RenderPlugin;
 
+/// Nodes of the [`VoxelGraph`]. Custom fullscreen effects can be ordered between
+/// `PostProcess` and `Tonemapping`, where the view target holds the traced hdr color, or the
+/// already tonemapped color for cameras without hdr, from a plugin added after
+/// `BevyVoxelEnginePlugin`:
+///
+/// ```ignore
+/// render_app
+///     .add_render_graph_node::<ViewNodeRunner<MyNode>>(VoxelGraph, MyLabel)
+///     .add_render_graph_edges(
+///         VoxelGraph,
+///         (VoxelGraphLabel::PostProcess, MyLabel, VoxelGraphLabel::Tonemapping),
+///     );
+/// ```
+///
+/// Such nodes can query the view's [`RenderAttachments`](attachments::RenderAttachments)
+/// for the normal and position of the traced surfaces.
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
-enum VoxelGraphLabel {
+pub enum VoxelGraphLabel {
     Trace,
+    Ao,
+    /// Bevy's transparent 3d phase, drawn over the traced color without depth testing against
+    /// the voxels. This is where gizmos are rendered
+    Transparent,
+    /// Empty node after the voxel passes, before tonemapping
+    PostProcess,
     //Bloom,
     Tonemapping,
     Fxaa,
     Ui,
     Upscaling,
+    /// Copies the upscaled output of cameras read back with
+    /// [`ImageReadback::read_camera`](readback::ImageReadback::read_camera)
+    Capture,
     Rebuild,
     Physics,
 }
@@ -54,13 +87,42 @@ enum RenderGraphLabel {
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderSubGraph)]
 pub struct VoxelGraph;
 
+/// Shaders only imported by the engine's pipelines, kept loaded so their import paths resolve
+#[derive(Resource)]
+struct ShaderImports(#[allow(dead_code)] Vec<Handle<Shader>>);
+
 impl Plugin for RenderPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(RenderGraphSettings::default())
+        // Embedded shaders are watched on disk with the `dev_shaders` feature, the pipeline
+        // cache rebuilds the pipelines using them when they change
+        embedded_asset!(app, "src/", "shaders/common.wgsl");
+        embedded_asset!(app, "src/", "shaders/bindings.wgsl");
+        embedded_asset!(app, "src/", "shaders/raytracing.wgsl");
+
+        let asset_server = app.world.resource::<AssetServer>();
+        let shader_imports = ShaderImports(
+            ["common", "bindings", "raytracing"]
+                .into_iter()
+                .map(|name| {
+                    asset_server.load(format!(
+                        "embedded://bevy_voxel_engine/voxel_pipeline/shaders/{name}.wgsl"
+                    ))
+                })
+                .collect(),
+        );
+
+        app.insert_resource(shader_imports)
+            .insert_resource(RenderGraphSettings::default())
             .add_plugins(ExtractResourcePlugin::<RenderGraphSettings>::default())
+            .insert_resource(SimulationControl::default())
+            .add_plugins(ExtractResourcePlugin::<SimulationControl>::default())
+            .add_systems(First, reset_simulation_step)
             .add_plugins(AttachmentsPlugin)
             .add_plugins(VoxelWorldPlugin)
+            .add_plugins(StreamingPlugin)
             .add_plugins(TracePlugin)
+            .add_plugins(AoPlugin)
+            .add_plugins(ReadbackPlugin)
             .add_plugins(VoxelizationPlugin)
             .add_plugins(ComputeResourcesPlugin);
 
@@ -75,6 +137,8 @@ impl Plugin for RenderPlugin {
 
         // Voxel render graph
         let trace = TraceNode::from_world(render_world);
+        let ao = AoNode::from_world(render_world);
+        let transparent = MainTransparentPass3dNode::from_world(render_world);
         //let bloom = BloomNode::new(render_world);
         let tonemapping = TonemappingNode::from_world(render_world);
         let fxaa = FxaaNode::from_world(render_world);
@@ -82,21 +146,32 @@ impl Plugin for RenderPlugin {
         let upscaling = UpscalingNode::from_world(render_world);
 
         voxel_graph.add_node(VoxelGraphLabel::Trace, ViewNodeRunner::new(trace, render_world));
+        voxel_graph.add_node(VoxelGraphLabel::Ao, ViewNodeRunner::new(ao, render_world));
+        voxel_graph.add_node(
+            VoxelGraphLabel::Transparent,
+            ViewNodeRunner::new(transparent, render_world),
+        );
+        voxel_graph.add_node(VoxelGraphLabel::PostProcess, EmptyNode);
         //voxel_graph.add_node(VoxelGraphLabel::Bloom, ViewNodeRunner::new(bloom, render_world));
         voxel_graph.add_node(VoxelGraphLabel::Tonemapping, ViewNodeRunner::new(tonemapping, render_world));
         voxel_graph.add_node(VoxelGraphLabel::Fxaa, ViewNodeRunner::new(fxaa, render_world));
         voxel_graph.add_node(VoxelGraphLabel::Ui, ui);
         voxel_graph.add_node(VoxelGraphLabel::Upscaling, ViewNodeRunner::new(upscaling, render_world));
+        voxel_graph.add_node(VoxelGraphLabel::Capture, ViewNodeRunner::new(CaptureNode, render_world));
 
-        voxel_graph.add_node_edge(VoxelGraphLabel::Trace, VoxelGraphLabel::Tonemapping);
+        voxel_graph.add_node_edge(VoxelGraphLabel::Trace, VoxelGraphLabel::Ao);
+        voxel_graph.add_node_edge(VoxelGraphLabel::Ao, VoxelGraphLabel::Transparent);
+        voxel_graph.add_node_edge(VoxelGraphLabel::Transparent, VoxelGraphLabel::PostProcess);
+        voxel_graph.add_node_edge(VoxelGraphLabel::PostProcess, VoxelGraphLabel::Tonemapping);
         //voxel_graph.add_node_edge(VoxelGraphLabel::Bloom, VoxelGraphLabel::Tonemapping);
         voxel_graph.add_node_edge(VoxelGraphLabel::Tonemapping, VoxelGraphLabel::Fxaa);
         voxel_graph.add_node_edge(VoxelGraphLabel::Fxaa, VoxelGraphLabel::Ui);
         voxel_graph.add_node_edge(VoxelGraphLabel::Ui, VoxelGraphLabel::Upscaling);
+        voxel_graph.add_node_edge(VoxelGraphLabel::Upscaling, VoxelGraphLabel::Capture);
 
         // Voxel render graph compute
-        voxel_graph.add_node(VoxelGraphLabel::Rebuild, RebuildNode);
-        voxel_graph.add_node(VoxelGraphLabel::Physics, PhysicsNode);
+        voxel_graph.add_node(VoxelGraphLabel::Rebuild, RebuildNode::default());
+        voxel_graph.add_node(VoxelGraphLabel::Physics, PhysicsNode::default());
 
         voxel_graph.add_node_edge(VoxelGraphLabel::Rebuild, VoxelGraphLabel::Physics);
         voxel_graph.add_node_edge(VoxelGraphLabel::Physics, VoxelGraphLabel::Trace);
@@ -115,7 +190,7 @@ impl Plugin for RenderPlugin {
         // Insert the voxel graph into the main render graph
         render_graph.add_sub_graph(VoxelGraph, voxel_graph);
 
-        println!("Voxel render graph built");
+        debug!("Voxel render graph built");
     }
 }
 
@@ -124,6 +199,8 @@ pub struct RenderGraphSettings {
     pub clear: bool,
     pub automata: bool,
     pub animation: bool,
+    /// Also turns off the cameras meshes are voxelized through, meshes changed in the
+    /// meantime are voxelized once it is back on
     pub voxelization: bool,
     pub rebuild: bool,
     pub physics: bool,
@@ -143,3 +220,104 @@ impl Default for RenderGraphSettings {
         }
     }
 }
+
+/// Nodes that can be toggled with [`RenderGraphSettings`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VoxelNode {
+    Clear,
+    Automata,
+    Animation,
+    Voxelization,
+    Rebuild,
+    Physics,
+    Trace,
+}
+
+impl VoxelNode {
+    pub const ALL: [VoxelNode; 7] = [
+        VoxelNode::Clear,
+        VoxelNode::Automata,
+        VoxelNode::Animation,
+        VoxelNode::Voxelization,
+        VoxelNode::Rebuild,
+        VoxelNode::Physics,
+        VoxelNode::Trace,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VoxelNode::Clear => "clear",
+            VoxelNode::Automata => "automata",
+            VoxelNode::Animation => "animation",
+            VoxelNode::Voxelization => "voxelization",
+            VoxelNode::Rebuild => "rebuild",
+            VoxelNode::Physics => "physics",
+            VoxelNode::Trace => "trace",
+        }
+    }
+}
+
+impl RenderGraphSettings {
+    pub fn get(&self, node: VoxelNode) -> bool {
+        match node {
+            VoxelNode::Clear => self.clear,
+            VoxelNode::Automata => self.automata,
+            VoxelNode::Animation => self.animation,
+            VoxelNode::Voxelization => self.voxelization,
+            VoxelNode::Rebuild => self.rebuild,
+            VoxelNode::Physics => self.physics,
+            VoxelNode::Trace => self.trace,
+        }
+    }
+
+    pub fn get_mut(&mut self, node: VoxelNode) -> &mut bool {
+        match node {
+            VoxelNode::Clear => &mut self.clear,
+            VoxelNode::Automata => &mut self.automata,
+            VoxelNode::Animation => &mut self.animation,
+            VoxelNode::Voxelization => &mut self.voxelization,
+            VoxelNode::Rebuild => &mut self.rebuild,
+            VoxelNode::Physics => &mut self.physics,
+            VoxelNode::Trace => &mut self.trace,
+        }
+    }
+
+    pub fn set(&mut self, node: VoxelNode, enabled: bool) {
+        *self.get_mut(node) = enabled;
+    }
+
+    /// Every node with whether it's enabled
+    pub fn iter(&self) -> impl Iterator<Item = (VoxelNode, bool)> + '_ {
+        VoxelNode::ALL.into_iter().map(|node| (node, self.get(node)))
+    }
+}
+
+/// Pauses the simulation passes (clear, automata, animation and physics) while `running` is false.
+/// Setting `step_once` runs them for a single frame, after which it is reset.
+#[derive(Resource, Clone, ExtractResource)]
+pub struct SimulationControl {
+    pub running: bool,
+    pub step_once: bool,
+}
+
+impl SimulationControl {
+    pub fn should_run(&self) -> bool {
+        self.running || self.step_once
+    }
+}
+
+impl Default for SimulationControl {
+    fn default() -> Self {
+        Self {
+            running: true,
+            step_once: false,
+        }
+    }
+}
+
+// The step was extracted at the end of the previous frame
+fn reset_simulation_step(mut simulation_control: ResMut<SimulationControl>) {
+    if simulation_control.step_once {
+        simulation_control.step_once = false;
+    }
+}
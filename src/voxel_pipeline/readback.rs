@@ -0,0 +1,483 @@
+use bevy::{
+    core_pipeline::blit::{BlitPipeline, BlitPipelineKey},
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        Render, RenderApp, RenderSet,
+        camera::ExtractedCamera,
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_graph::{NodeRunError, RenderGraphContext, ViewNode},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::TextureFormatPixelInfo,
+        view::ViewTarget,
+    },
+    utils::HashMap,
+};
+use crossbeam_channel::{Receiver, Sender};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+pub struct ReadbackPlugin;
+
+impl Plugin for ReadbackPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        app.insert_resource(ImageReadback { sender });
+
+        let render_app = match app.get_sub_app_mut(RenderApp) {
+            Ok(render_app) => render_app,
+            Err(_) => return,
+        };
+
+        render_app
+            .insert_resource(ImageReadbacks {
+                requests: receiver,
+                waiting: Vec::new(),
+                pending: Vec::new(),
+            })
+            .init_resource::<CameraCaptures>()
+            .add_systems(Render, prepare_camera_captures.in_set(RenderSet::Prepare))
+            .add_systems(Render, read_back_images.in_set(RenderSet::Cleanup));
+    }
+}
+
+/// Reads images rendered to by a camera, e.g. one created with
+/// [`VoxelRenderTarget::image`](crate::VoxelRenderTarget::image), or the output of any camera
+/// back to the cpu
+#[derive(Resource, Clone)]
+pub struct ImageReadback {
+    sender: Sender<ReadbackRequest>,
+}
+
+impl ImageReadback {
+    /// Copies `image` once it has been rendered this frame, after upscaling, and calls
+    /// `on_complete` from the render world with its contents on a later frame
+    pub fn read(
+        &self,
+        image: Handle<Image>,
+        on_complete: impl FnOnce(io::Result<Image>) + Send + Sync + 'static,
+    ) {
+        let _ = self.sender.send(ReadbackRequest {
+            source: ReadbackSource::Image(image),
+            on_complete: std::boxed::Box::new(on_complete),
+        });
+    }
+
+    /// Copies what `camera` outputs after upscaling once it has been rendered, window cameras
+    /// included, and calls `on_complete` from the render world with it on a later frame. The
+    /// image has the size and format of the camera's target, `Bgra8UnormSrgb` for most windows
+    pub fn read_camera(
+        &self,
+        camera: Entity,
+        on_complete: impl FnOnce(io::Result<Image>) + Send + Sync + 'static,
+    ) {
+        let _ = self.sender.send(ReadbackRequest {
+            source: ReadbackSource::Camera(camera),
+            on_complete: std::boxed::Box::new(on_complete),
+        });
+    }
+
+    /// Saves the output of `camera` as a png at `path` once it has been rendered. Float targets
+    /// hold the tonemapped linear colors and are encoded to srgb like the window surface, so
+    /// the file matches what is shown on screen
+    pub fn capture_frame(&self, camera: Entity, path: impl Into<PathBuf>) {
+        let path = path.into();
+        self.read_camera(camera, move |result| {
+            let saved = result
+                .and_then(|image| to_srgb8(image).try_into_dynamic().map_err(io::Error::other))
+                .and_then(|image| image.save(&path).map_err(io::Error::other));
+            match saved {
+                Ok(()) => info!("Saved {}", path.display()),
+                Err(error) => error!("Failed to capture {}: {}", path.display(), error),
+            }
+        });
+    }
+}
+
+/// Encodes linear float images to 8 bit srgb, other formats are returned as they are
+fn to_srgb8(image: Image) -> Image {
+    let linear: Vec<f32> = match image.texture_descriptor.format {
+        TextureFormat::Rgba32Float => image
+            .data
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect(),
+        TextureFormat::Rgba16Float => image
+            .data
+            .chunks_exact(2)
+            .map(|bytes| f16_to_f32(u16::from_le_bytes([bytes[0], bytes[1]])))
+            .collect(),
+        _ => return image,
+    };
+
+    let data = linear
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let [r, g, b, a] = [0, 1, 2, 3].map(|i| pixel[i].clamp(0.0, 1.0));
+            Color::rgba_linear(r, g, b, a).as_rgba_u8()
+        })
+        .collect();
+    Image::new(
+        image.texture_descriptor.size,
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    )
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1F) as i32;
+    let mantissa = (bits & 0x3FF) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+enum ReadbackSource {
+    Image(Handle<Image>),
+    Camera(Entity),
+}
+
+struct ReadbackRequest {
+    source: ReadbackSource,
+    on_complete: std::boxed::Box<dyn FnOnce(io::Result<Image>) + Send + Sync>,
+}
+
+struct PendingReadback {
+    request: ReadbackRequest,
+    buffer: Buffer,
+    size: Extent3d,
+    format: TextureFormat,
+    padded_bytes_per_row: u32,
+    mapped: Arc<Mutex<Option<Result<(), BufferAsyncError>>>>,
+}
+
+#[derive(Resource)]
+struct ImageReadbacks {
+    requests: Receiver<ReadbackRequest>,
+    /// Requests for images that haven't been prepared on the gpu yet, or cameras that aren't
+    /// being rendered
+    waiting: Vec<ReadbackRequest>,
+    pending: Vec<PendingReadback>,
+}
+
+/// Output of a camera copied by the [`CaptureNode`] of its view this frame
+struct CameraCapture {
+    request: ReadbackRequest,
+    pipeline: CachedRenderPipelineId,
+    texture: Texture,
+    view: TextureView,
+    buffer: Buffer,
+    size: Extent3d,
+    format: TextureFormat,
+    padded_bytes_per_row: u32,
+    /// Set by the node once the copy is encoded, the request waits for the next frame otherwise
+    copied: AtomicBool,
+}
+
+/// Camera readbacks of this frame by view entity
+#[derive(Resource, Default)]
+struct CameraCaptures(HashMap<Entity, CameraCapture>);
+
+/// Blits the main texture of views with a camera readback to a copyable texture the way the
+/// upscaling node does to their target, then copies it to the readback buffer
+#[derive(Default)]
+pub struct CaptureNode;
+
+impl ViewNode for CaptureNode {
+    type ViewQuery = &'static ViewTarget;
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        view_target: QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let Some(capture) = world.resource::<CameraCaptures>().0.get(&graph.view_entity()) else {
+            return Ok(());
+        };
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(capture.pipeline) else {
+            return Ok(());
+        };
+
+        let blit_pipeline = world.resource::<BlitPipeline>();
+        let main_texture = view_target.main_texture_view();
+        let bind_group = render_context.render_device().create_bind_group(
+            None,
+            &blit_pipeline.texture_bind_group,
+            &BindGroupEntries::sequential((main_texture, &blit_pipeline.sampler)),
+        );
+
+        let mut render_pass = render_context
+            .command_encoder()
+            .begin_render_pass(&RenderPassDescriptor {
+                label: Some("camera readback pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &capture.view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(default()),
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+        drop(render_pass);
+
+        render_context.command_encoder().copy_texture_to_buffer(
+            capture.texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &capture.buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(capture.padded_bytes_per_row),
+                    rows_per_image: Some(capture.size.height),
+                },
+            },
+            capture.size,
+        );
+        capture.copied.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+/// Sets up the copies of the cameras read back this frame, after their view targets are made
+fn prepare_camera_captures(
+    mut readbacks: ResMut<ImageReadbacks>,
+    mut captures: ResMut<CameraCaptures>,
+    views: Query<(&ViewTarget, &ExtractedCamera)>,
+    blit_pipeline: Res<BlitPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<BlitPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+) {
+    let readbacks = readbacks.as_mut();
+    readbacks.waiting.extend(readbacks.requests.try_iter());
+
+    let mut i = 0;
+    while i < readbacks.waiting.len() {
+        let ReadbackSource::Camera(camera) = readbacks.waiting[i].source else {
+            i += 1;
+            continue;
+        };
+        // one copy per camera and frame, later requests wait for the next one
+        let target = views.get(camera).ok().filter(|_| !captures.0.contains_key(&camera));
+        let Some((view_target, size)) = target
+            .and_then(|(view_target, camera)| Some((view_target, camera.physical_target_size?)))
+        else {
+            i += 1;
+            continue;
+        };
+        let request = readbacks.waiting.swap_remove(i);
+
+        let size = Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        };
+        let format = view_target.out_texture_format();
+        let padded_bytes_per_row = (size.width * format.pixel_size() as u32)
+            .next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("camera readback texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("camera readback buffer"),
+            size: padded_bytes_per_row as u64 * size.height as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let pipeline = pipelines.specialize(
+            &pipeline_cache,
+            &blit_pipeline,
+            BlitPipelineKey {
+                texture_format: format,
+                blend_state: None,
+                samples: 1,
+            },
+        );
+
+        captures.0.insert(
+            camera,
+            CameraCapture {
+                request,
+                pipeline,
+                texture,
+                view,
+                buffer,
+                size,
+                format,
+                padded_bytes_per_row,
+                copied: AtomicBool::new(false),
+            },
+        );
+    }
+}
+
+/// Maps a buffer the readback was copied to, it is finished once mapped
+fn map_readback(
+    request: ReadbackRequest,
+    buffer: Buffer,
+    size: Extent3d,
+    format: TextureFormat,
+    padded_bytes_per_row: u32,
+) -> PendingReadback {
+    let mapped = Arc::new(Mutex::new(None));
+    let callback_mapped = mapped.clone();
+    buffer.slice(..).map_async(MapMode::Read, move |result| {
+        *callback_mapped.lock().unwrap() = Some(result);
+    });
+
+    PendingReadback {
+        request,
+        buffer,
+        size,
+        format,
+        padded_bytes_per_row,
+        mapped,
+    }
+}
+
+/// Runs after the render graph so the copy sees the final output of the frame
+fn read_back_images(
+    mut readbacks: ResMut<ImageReadbacks>,
+    mut captures: ResMut<CameraCaptures>,
+    gpu_images: Res<RenderAssets<Image>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let readbacks = readbacks.as_mut();
+
+    // Finish readbacks whose buffers are mapped
+    let mut i = 0;
+    while i < readbacks.pending.len() {
+        let mapped = readbacks.pending[i].mapped.lock().unwrap().take();
+        let Some(mapped) = mapped else {
+            i += 1;
+            continue;
+        };
+
+        let pending = readbacks.pending.swap_remove(i);
+        let result = mapped.map_err(io::Error::other).map(|_| {
+            let row = (pending.size.width * pending.format.pixel_size() as u32) as usize;
+            let padded_row = pending.padded_bytes_per_row as usize;
+
+            let mapped_range = pending.buffer.slice(..).get_mapped_range();
+            let mut data = Vec::with_capacity(row * pending.size.height as usize);
+            for chunk in mapped_range.chunks(padded_row) {
+                data.extend_from_slice(&chunk[..row]);
+            }
+            drop(mapped_range);
+            pending.buffer.unmap();
+
+            Image::new(
+                pending.size,
+                TextureDimension::D2,
+                data,
+                pending.format,
+                RenderAssetUsages::default(),
+            )
+        });
+
+        (pending.request.on_complete)(result);
+    }
+
+    // Start new readbacks
+    let requests: Vec<ReadbackRequest> = readbacks
+        .waiting
+        .drain(..)
+        .chain(readbacks.requests.try_iter())
+        .collect();
+    for request in requests {
+        let gpu_image = match &request.source {
+            ReadbackSource::Image(image) => gpu_images.get(image),
+            ReadbackSource::Camera(_) => None,
+        };
+        let Some(gpu_image) = gpu_image else {
+            readbacks.waiting.push(request);
+            continue;
+        };
+
+        let size = Extent3d {
+            width: gpu_image.size.x as u32,
+            height: gpu_image.size.y as u32,
+            depth_or_array_layers: 1,
+        };
+        let format = gpu_image.texture_format;
+        let padded_bytes_per_row = (size.width * format.pixel_size() as u32)
+            .next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("image readback buffer"),
+            size: padded_bytes_per_row as u64 * size.height as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("image readback encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            gpu_image.texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(size.height),
+                },
+            },
+            size,
+        );
+        render_queue.submit([encoder.finish()]);
+
+        let pending = map_readback(request, buffer, size, format, padded_bytes_per_row);
+        readbacks.pending.push(pending);
+    }
+
+    // The render graph copied the cameras, the ones it skipped are tried again next frame
+    for (_, capture) in captures.0.drain() {
+        if !capture.copied.into_inner() {
+            readbacks.waiting.push(capture.request);
+            continue;
+        }
+
+        let pending = map_readback(
+            capture.request,
+            capture.buffer,
+            capture.size,
+            capture.format,
+            capture.padded_bytes_per_row,
+        );
+        readbacks.pending.push(pending);
+    }
+}
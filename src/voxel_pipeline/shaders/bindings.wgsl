@@ -4,4 +4,6 @@
 
 @group(0) @binding(0) var<uniform> voxel_uniforms: VoxelUniforms;
 @group(0) @binding(1) var voxel_world: texture_storage_3d<r16uint, read_write>;
-@group(0) @binding(2) var<storage, read_write> gh: array<u32>;
\ No newline at end of file
+@group(0) @binding(2) var<storage, read_write> gh: array<u32>;
+@group(0) @binding(4) var voxel_normals: texture_storage_3d<r32uint, read_write>;
+@group(0) @binding(5) var voxel_colors: texture_storage_3d<r32uint, read_write>;
\ No newline at end of file
@@ -5,8 +5,7 @@ const PORTAL_FLAG = 64u; // 0b01000000
 const ANIMATION_FLAG = 32u; // 0b00100000
 const COLLISION_FLAG = 16u; // 0b00010000
 const SAND_FLAG = 8u; // 0b00001000
-
-const VOXELS_PER_METER: f32 = 4.0;
+const EMISSION_MASK = 7u; // 0b00000111
 
 const PI: f32 = 3.14159265358979323846264338327950288;
 
@@ -16,12 +15,31 @@ struct Portal {
     normal: vec3<f32>,
 }
 
+struct PaletteAnimation {
+    colors: array<vec4<f32>, 4>,
+    material: u32,
+    color_count: u32,
+    period: f32,
+}
+
 struct VoxelUniforms {
     materials: array<vec4<f32>, 256>,
     portals: array<Portal, 32>,
     levels: array<vec4<u32>, 8>,
     offsets: array<vec4<u32>, 8>,
+    reflective_materials: array<vec4<u32>, 2>,
+    material_densities: array<vec4<f32>, 64>,
+    material_alphas: array<vec4<f32>, 64>,
+    fluid_materials: u32,
     texture_size: u32,
+    surface_normals: u32,
+    voxel_colors: u32,
+    voxels_per_meter: f32,
+    // world position of the center of the voxel texture
+    origin: vec3<f32>,
+    time: f32,
+    palette_animation_count: u32,
+    palette_animations: array<PaletteAnimation, 16>,
 };
 
 struct TraceUniforms {
@@ -30,9 +48,24 @@ struct TraceUniforms {
     last_camera: mat4x4<f32>,
     projection: mat4x4<f32>,
     time: f32,
-    show_ray_steps: u32,
+    debug_view: u32,
     samples: u32,
+    sample_sequence: u32,
+    frame: u32,
+    seed: u32,
     shadows: u32,
+    shadow_softness: f32,
+    ao_radius: f32,
+    skybox: u32,
+    reflection_bounces: u32,
+    max_transparency_steps: u32,
+    exposure: f32,
+    ray_near: f32,
+    ray_far: f32,
+    sun_intensity: f32,
+    background_mode: u32,
+    background: vec4<f32>,
+    viewport: vec4<f32>,
 };
 
 fn get_clip_space(frag_pos: vec4<f32>, dimensions: vec2<f32>) -> vec2<f32> {
@@ -60,9 +93,33 @@ fn hash(value: vec3<u32>) -> vec3<f32> {
     return vec3<f32>((v >> vec3(1u)) & vec3(0x7fffffffu)) / f32(0x7fffffff);
 }
 
-fn cosine_hemisphere(n: vec3<f32>, seed: vec3<u32>) -> vec3<f32> {
-    let u = hash(seed);
+// radical inverse of index in base, the halton sequence of that base
+fn halton(index: u32, base: u32) -> f32 {
+    var result = 0.0;
+    var fraction = 1.0 / f32(base);
+    var i = index;
+    while i > 0u {
+        result += f32(i % base) * fraction;
+        i /= base;
+        fraction /= f32(base);
+    }
+    return result;
+}
 
+// point in [0, 1) for the sample at index. Sequence 1 is a halton sequence offset by the
+// pixel_seed so neighbouring pixels don't share the pattern, dimensions 0 to 2 use different
+// bases to not correlate with each other. Otherwise the seed is hashed
+fn sample_2d(sequence: u32, index: u32, dimension: u32, pixel_seed: vec3<u32>, seed: vec3<u32>) -> vec2<f32> {
+    if sequence != 1u {
+        return hash(seed).xy;
+    }
+
+    let bases = select(select(vec2(11u, 13u), vec2(5u, 7u), dimension == 1u), vec2(2u, 3u), dimension == 0u);
+    let point = vec2(halton(index, bases.x), halton(index, bases.y));
+    return fract(point + hash(pixel_seed + dimension).xy);
+}
+
+fn cosine_hemisphere(n: vec3<f32>, u: vec2<f32>) -> vec3<f32> {
     let r = sqrt(u.x);
     let theta = 2.0 * PI * u.y;
  
@@ -72,6 +129,22 @@ fn cosine_hemisphere(n: vec3<f32>, seed: vec3<u32>) -> vec3<f32> {
     return normalize(r * sin(theta) * b + sqrt(1.0 - u.x) * n + r * cos(theta) * t);
 }
 
+// uniform direction within `angle` radians of n
+fn uniform_cone(n: vec3<f32>, angle: f32, u: vec2<f32>) -> vec3<f32> {
+    let cos_theta = mix(1.0, cos(angle), u.x);
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let phi = 2.0 * PI * u.y;
+
+    var up = vec3(0.0, 1.0, 0.0);
+    if abs(n.y) > 0.99 {
+        up = vec3(1.0, 0.0, 0.0);
+    }
+    let b = normalize(cross(n, up));
+    let t = cross(b, n);
+
+    return normalize(sin_theta * cos(phi) * b + sin_theta * sin(phi) * t + cos_theta * n);
+}
+
 struct Ray {
     pos: vec3<f32>,
     dir: vec3<f32>,
@@ -106,6 +179,38 @@ fn ray_plane(r: Ray, pos: vec3<f32>, normal: vec3<f32>) -> vec4<f32> {
     return vec4(0.0);
 }
 
+// Octahedral encoding of a unit normal in 12 bits per axis above the id of the material it was
+// written for, 0 when there is none
+fn pack_normal(normal: vec3<f32>, material: u32) -> u32 {
+    var oct = normal.xy / (abs(normal.x) + abs(normal.y) + abs(normal.z));
+    if normal.z < 0.0 {
+        oct = (1.0 - abs(oct.yx)) * select(vec2(-1.0), vec2(1.0), oct >= vec2(0.0));
+    }
+    let quantized = vec2<u32>(round((oct * 0.5 + 0.5) * 4095.0));
+    return material | (quantized.x << 8u) | (quantized.y << 20u);
+}
+
+fn unpack_normal(packed: u32) -> vec3<f32> {
+    let oct = vec2(f32((packed >> 8u) & 0xFFFu), f32(packed >> 20u)) / 4095.0 * 2.0 - 1.0;
+    var normal = vec3(oct, 1.0 - abs(oct.x) - abs(oct.y));
+    if normal.z < 0.0 {
+        normal = vec3((1.0 - abs(normal.yx)) * select(vec2(-1.0), vec2(1.0), normal.xy >= vec2(0.0)), normal.z);
+    }
+    return normalize(normal);
+}
+
+// Linear color in 8 bits per channel above the id of the material it was written for, 0 when
+// there is none. Stored as the square root to keep precision in the dark colors
+fn pack_color(color: vec3<f32>, material: u32) -> u32 {
+    let quantized = vec3<u32>(round(sqrt(saturate(color)) * 255.0));
+    return material | (quantized.r << 8u) | (quantized.g << 16u) | (quantized.b << 24u);
+}
+
+fn unpack_color(packed: u32) -> vec3<f32> {
+    let color = vec3(f32((packed >> 8u) & 0xFFu), f32((packed >> 16u) & 0xFFu), f32(packed >> 24u)) / 255.0;
+    return color * color;
+}
+
 fn in_bounds(v: vec3<f32>) -> bool {
     let s = step(vec3<f32>(-1.0), v) - step(vec3<f32>(1.0), v);
     return (s.x * s.y * s.z) > 0.5;
@@ -1,24 +1,92 @@
 #define_import_path bevy_voxel_engine::raytracing
 
 #import bevy_voxel_engine::common::{
-    VOXELS_PER_METER,
     PORTAL_FLAG,
+    EMISSION_MASK,
     VoxelUniforms,
     Ray,
     ray_plane,
     in_bounds,
     ray_box_dist,
+    unpack_normal,
+    unpack_color,
 }
 #import bevy_voxel_engine::bindings::{
     voxel_world,
     voxel_uniforms,
-    gh
+    gh,
+    voxel_normals,
+    voxel_colors,
 }
 
 fn get_value_index(index: u32) -> bool {
     return ((gh[index / 32u] >> (index % 32u)) & 1u) != 0u;
 }
 
+fn is_reflective(data: u32) -> bool {
+    let material = data & 0xFFu;
+    let bits = voxel_uniforms.reflective_materials[material / 128u][(material / 32u) % 4u];
+    return ((bits >> (material % 32u)) & 1u) != 0u;
+}
+
+/// 1 for opaque materials, see `TransparentMaterials`
+fn material_alpha(data: u32) -> f32 {
+    let material = data & 0xFFu;
+    return voxel_uniforms.material_alphas[material / 4u][material % 4u];
+}
+
+/// pallete colour, brightened and marked emissive when the voxel has an emission level
+fn get_material(data: u32) -> vec4<f32> {
+    let id = data & 0xFFu;
+    var material = voxel_uniforms.materials[id];
+
+    // animated materials blend between their colors over time
+    for (var i = 0u; i < voxel_uniforms.palette_animation_count; i += 1u) {
+        if voxel_uniforms.palette_animations[i].material == id {
+            let count = voxel_uniforms.palette_animations[i].color_count;
+            let t = fract(voxel_uniforms.time / voxel_uniforms.palette_animations[i].period) * f32(count);
+            let current = min(u32(t), count - 1u);
+            let next = (current + 1u) % count;
+            material = mix(
+                voxel_uniforms.palette_animations[i].colors[current],
+                voxel_uniforms.palette_animations[i].colors[next],
+                fract(t),
+            );
+            break;
+        }
+    }
+
+    let emission = (data >> 8u) & EMISSION_MASK;
+    if emission == 0u {
+        return material;
+    }
+    return vec4(material.rgb * (1.0 + f32(emission)), 1.0);
+}
+
+/// get_material with the color stored by voxelization for the voxel at the position in render
+/// space, see `VoxelWorldSettings::voxel_colors`
+fn get_voxel_material(data: u32, pos: vec3<f32>) -> vec4<f32> {
+    let material = get_material(data);
+    if voxel_uniforms.voxel_colors == 0u {
+        return material;
+    }
+
+    let size = i32(voxel_uniforms.texture_size);
+    let texture_coords = clamp(vec3<i32>(floor((pos * 0.5 + 0.5) * f32(size))), vec3(0), vec3(size - 1));
+    // colors left behind by voxels that were since replaced are for another material
+    let packed = textureLoad(voxel_colors, texture_coords.zyx).r;
+    if packed == 0u || (packed & 0xFFu) != (data & 0xFFu) {
+        return material;
+    }
+
+    let color = unpack_color(packed);
+    let emission = (data >> 8u) & EMISSION_MASK;
+    if emission == 0u {
+        return vec4(color, material.a);
+    }
+    return vec4(color * (1.0 + f32(emission)), 1.0);
+}
+
 struct Voxel {
     data: u32,
     pos: vec3<f32>,
@@ -103,8 +171,32 @@ struct HitInfo {
     normal: vec3<f32>,
     portals: mat4x4<f32>,
     steps: u32,
+    // light let through by the transparent voxels in front of the hit
+    transmittance: vec3<f32>,
 };
 
+/// mesh normal stored by voxelization for the voxel that was hit, otherwise the normal of the
+/// face that was hit. Turned to the side of the face so the ray's side of the surface is lit
+fn surface_normal(hit: HitInfo) -> vec3<f32> {
+    if voxel_uniforms.surface_normals == 0u || !hit.hit || (hit.data & 0xFFu) == 0u {
+        return hit.normal;
+    }
+
+    let texture_coords = vec3<i32>(floor((hit.pos - voxel_uniforms.origin) * voxel_uniforms.voxels_per_meter + f32(voxel_uniforms.texture_size) / 2.0 - hit.normal * 0.5));
+    if any(texture_coords < vec3(0)) || any(texture_coords >= vec3(i32(voxel_uniforms.texture_size))) {
+        return hit.normal;
+    }
+
+    // normals left behind by voxels that were since replaced are for another material
+    let packed = textureLoad(voxel_normals, texture_coords.zyx).r;
+    if (packed & 0xFFu) != (hit.data & 0xFFu) {
+        return hit.normal;
+    }
+
+    let normal = unpack_normal(packed);
+    return select(normal, -normal, dot(normal, hit.normal) < 0.0);
+}
+
 const IDENTITY = mat4x4<f32>(
     vec4<f32>(1.0, 0.0, 0.0, 0.0), 
     vec4<f32>(0.0, 1.0, 0.0, 0.0), 
@@ -113,7 +205,7 @@ const IDENTITY = mat4x4<f32>(
 );
 
 fn intersect_scene(r: Ray, steps: u32) -> HitInfo {
-    let rtw = f32(voxel_uniforms.texture_size) / (VOXELS_PER_METER * 2.0); // render to world ratio
+    let rtw = f32(voxel_uniforms.texture_size) / (voxel_uniforms.voxels_per_meter * 2.0); // render to world ratio
 
     let normal = vec3(0.0, 1.0, 0.0);
     let hit = ray_plane(r, vec3(0.0, -1.0, 0.0), normal).xyz;
@@ -124,22 +216,29 @@ fn intersect_scene(r: Ray, steps: u32) -> HitInfo {
         // green floor
         let color = vec3(113.0, 129.0, 44.0) / 255.0;
 
-        return HitInfo(true, 0u, vec4(color, 0.0), pos * rtw, pos * rtw, normal, IDENTITY, steps);
+        let world_pos = pos * rtw + voxel_uniforms.origin;
+        return HitInfo(true, 0u, vec4(color, 0.0), world_pos, world_pos, normal, IDENTITY, steps, vec3(1.0));
     }
 
     let infinity = 1000000000.0 * r.dir;
 
-    return HitInfo(false, 0u, vec4(0.0), infinity, infinity, vec3(0.0), IDENTITY, steps);
+    return HitInfo(false, 0u, vec4(0.0), infinity, infinity, vec3(0.0), IDENTITY, steps, vec3(1.0));
 }
 
 /// physics_distance is in terms of t so make sure to normalize your 
 /// ray direction if you want it to be in world cordinates.
 /// only hits voxels that have any of the flags set or hits everything if flags is 0
 fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
-    let wtr = VOXELS_PER_METER * 2.0 / f32(voxel_uniforms.texture_size); // world to render
-    let rtw = f32(voxel_uniforms.texture_size) / (VOXELS_PER_METER * 2.0); // render to world
+    return shoot_ray_through(r, physics_distance, flags, 0u);
+}
+
+/// shoot_ray that continues through up to max_transparency_steps transparent voxels,
+/// accumulating their tint in the transmittance of the hit
+fn shoot_ray_through(r: Ray, physics_distance: f32, flags: u32, max_transparency_steps: u32) -> HitInfo {
+    let wtr = voxel_uniforms.voxels_per_meter * 2.0 / f32(voxel_uniforms.texture_size); // world to render
+    let rtw = f32(voxel_uniforms.texture_size) / (voxel_uniforms.voxels_per_meter * 2.0); // render to world
 
-    var pos = r.pos * wtr;
+    var pos = (r.pos - voxel_uniforms.origin) * wtr;
     let dir_mask = vec3<f32>(r.dir == vec3(0.0));
     var dir = r.dir + dir_mask * 0.000001;
 
@@ -150,7 +249,7 @@ fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
 
         if (dist == 0.0) {
             if (physics_distance * wtr > 0.0) {
-                return HitInfo(false, 0u, vec4(0.0), (pos + dir * physics_distance * wtr) * rtw, vec3(0.0), vec3(0.0), IDENTITY, 1u);
+                return HitInfo(false, 0u, vec4(0.0), (pos + dir * physics_distance * wtr) * rtw + voxel_uniforms.origin, vec3(0.0), vec3(0.0), IDENTITY, 1u, vec3(1.0));
             }
             return intersect_scene(Ray(pos, dir), 1u);
         }
@@ -166,12 +265,21 @@ fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
     var voxel = Voxel(0u, vec3(0.0), 0u);
     var portal_mat = IDENTITY;
     var reprojection_pos = pos;
+    var transmittance = vec3(1.0);
+    var transparency_steps = 0u;
     while (steps < 100u) {
         voxel = get_value(tcpotr);
 
         let should_portal_skip = ((voxel.data >> 8u) & PORTAL_FLAG) > 0u;
         if ((voxel.data & 0xFFu) != 0u && !should_portal_skip && (((voxel.data >> 8u) & flags) > 0u || flags == 0u)) {
-            break;
+            let alpha = material_alpha(voxel.data);
+            if (alpha >= 1.0 || transparency_steps >= max_transparency_steps) {
+                break;
+            }
+
+            // the light from behind the voxel is tinted by its color
+            transmittance *= mix(vec3(1.0), get_voxel_material(voxel.data, tcpotr).rgb, alpha);
+            transparency_steps += 1u;
         }
 
         let voxel_size = 2.0 / f32(voxel.grid_size);
@@ -189,9 +297,9 @@ fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
         if (should_portal_skip) {
             let portal = voxel_uniforms.portals[i32(voxel.data & 0xFFu)];
 
-            let intersection = ray_plane(Ray(pos * rtw, dir), portal.position + portal.normal * 0.00002, portal.normal);
+            let intersection = ray_plane(Ray(pos * rtw + voxel_uniforms.origin, dir), portal.position + portal.normal * 0.00002, portal.normal);
             if (intersection.w != 0.0 && intersection.w * wtr < t_current) {
-                pos = (portal.transformation * vec4(intersection.xyz - portal.normal * 0.00004, 1.0)).xyz * wtr;
+                pos = ((portal.transformation * vec4(intersection.xyz - portal.normal * 0.00004, 1.0)).xyz - voxel_uniforms.origin) * wtr;
                 dir = (portal.transformation * vec4(dir, 0.0)).xyz;
                 r_sign = sign(dir);
                 tcpotr = pos;
@@ -203,19 +311,21 @@ fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
         }
 
         if (t_current + distance > physics_distance * wtr && physics_distance > 0.0) {
-            return HitInfo(false, 0u, vec4(0.0), (pos + dir * (physics_distance * wtr - distance)) * rtw, vec3(0.0), vec3(0.0), portal_mat, steps);
+            return HitInfo(false, 0u, vec4(0.0), (pos + dir * (physics_distance * wtr - distance)) * rtw + voxel_uniforms.origin, vec3(0.0), vec3(0.0), portal_mat, steps, transmittance);
         }
 
         if (!in_bounds(tcpotr)) {
             if (physics_distance > 0.0) {
-                return HitInfo(false, 0u, vec4(0.0), (pos + dir * (physics_distance * wtr - distance)) * rtw, vec3(0.0), vec3(0.0), portal_mat, steps);
+                return HitInfo(false, 0u, vec4(0.0), (pos + dir * (physics_distance * wtr - distance)) * rtw + voxel_uniforms.origin, vec3(0.0), vec3(0.0), portal_mat, steps, transmittance);
             }
 
-            return intersect_scene(Ray(pos, dir), steps);
+            var scene_hit = intersect_scene(Ray(pos, dir), steps);
+            scene_hit.transmittance = transmittance;
+            return scene_hit;
         }
 
         steps = steps + 1u;
     }
 
-    return HitInfo(true, voxel.data, voxel_uniforms.materials[voxel.data & 0xFFu], tcpotr * rtw + normal * 0.0001, reprojection_pos, normal, portal_mat, steps);
+    return HitInfo(true, voxel.data, get_voxel_material(voxel.data, tcpotr), tcpotr * rtw + voxel_uniforms.origin + normal * 0.0001, reprojection_pos, normal, portal_mat, steps, transmittance);
 }
\ No newline at end of file
@@ -6,17 +6,28 @@
 }
 #import bevy_voxel_engine::common::{
     VoxelUniforms,
-    VOXELS_PER_METER,
+    pack_normal,
+    pack_color,
+    hash,
 }
 
 struct VoxelizationUniforms {
     material: u32,
     flags: u32,
+    vertex_colors: u32,
+    alpha_cutoff: f32,
+    thickness: u32,
+    normals: u32,
+    dither: u32,
+    direct_color: u32,
+    color: vec4<f32>,
 }
 
 @group(2) @binding(0) var<uniform> voxel_uniforms: VoxelUniforms;
 @group(2) @binding(1) var voxel_world: texture_storage_3d<r16uint, read_write>;
 @group(2) @binding(2) var<storage, read> gh: array<u32>;
+@group(2) @binding(4) var voxel_normals: texture_storage_3d<r32uint, read_write>;
+@group(2) @binding(5) var voxel_colors: texture_storage_3d<r32uint, read_write>;
 
 @group(3) @binding(0) var<uniform> voxelization_uniforms: VoxelizationUniforms;
 @group(3) @binding(1) var material_texture: texture_2d<f32>;
@@ -27,11 +38,16 @@ struct Vertex {
     @location(0) position: vec3<f32>,
     @location(1) normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
+#ifdef VERTEX_COLORS
+    @location(5) color: vec4<f32>,
+#endif
 };
 
 struct VertexOutput {
     @builtin(position) pos: vec4<f32>,
     @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) normal: vec3<f32>,
 };
 
 @vertex
@@ -42,6 +58,12 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     out.pos = mesh_functions::mesh_position_local_to_clip(model, vec4<f32>(vertex.position, 1.0));
 
     out.uv = vertex.uv;
+    out.normal = mesh_functions::mesh_normal_local_to_world(vertex.normal, vertex.instance_index);
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#else
+    out.color = vec4(1.0);
+#endif
     
     return out;
 }
@@ -55,30 +77,97 @@ fn get_texture_value(pos: vec3<i32>) -> vec2<u32> {
     );
 }
 
-fn write_pos(pos: vec3<i32>, material: u32, flags: u32) {
+fn write_pos(pos: vec3<i32>, material: u32, flags: u32) -> bool {
     let voxel_type = get_texture_value(pos);
 
     if (voxel_type.x == 0u) {
         textureStore(voxel_world, pos.zyx, vec4(material | (flags << 8u)));
+        return true;
     }
+    return false;
+}
+
+fn nearest_material(color: vec3<f32>) -> u32 {
+    var nearest = 1u;
+    var nearest_distance = 1e10;
+    for (var i = 1u; i < 256u; i++) {
+        let material = voxel_uniforms.materials[i];
+        if material.a != 0.0 {
+            continue;
+        }
+
+        let offset = material.rgb - color;
+        let distance = dot(offset, offset);
+        if distance < nearest_distance {
+            nearest = i;
+            nearest_distance = distance;
+        }
+    }
+    return nearest;
 }
 
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
-    let clip_space_xy = vec2(1.0, -1.0) * (2.0 * in.pos.xy / f32(voxel_uniforms.texture_size) - 1.0);
+    // the image can be smaller or larger than the world, its size is the viewport
+    let clip_space_xy = vec2(1.0, -1.0) * (2.0 * in.pos.xy / view.viewport.zw - 1.0);
     let clip_space = vec4(clip_space_xy, in.pos.z, 1.0);
     let world = position_clip_to_world(clip_space);
-    let texture_pos = VOXELS_PER_METER * world + vec3(f32(voxel_uniforms.texture_size) / 2.0);
+    let texture_pos = voxel_uniforms.voxels_per_meter * (world - voxel_uniforms.origin) + vec3(f32(voxel_uniforms.texture_size) / 2.0);
     let texture_value = textureSample(material_texture, material_sampler, vec2(in.uv.xy));
 
     var material = 0u;
-    if voxelization_uniforms.material == 255u {
+    var coverage = 1.0;
+    // exact color kept for the voxels when the world stores colors, palette materials have none
+    var voxel_color = vec3(0.0);
+    var has_color = true;
+    if voxelization_uniforms.vertex_colors != 0u {
+        material = nearest_material(in.color.rgb);
+        voxel_color = in.color.rgb;
+    } else if voxelization_uniforms.direct_color != 0u {
+        if voxelization_uniforms.color.a < voxelization_uniforms.alpha_cutoff {
+            discard;
+        }
+        material = nearest_material(voxelization_uniforms.color.rgb);
+        coverage = voxelization_uniforms.color.a;
+        voxel_color = voxelization_uniforms.color.rgb;
+    } else if voxelization_uniforms.material == 255u {
+        if texture_value.a < voxelization_uniforms.alpha_cutoff {
+            discard;
+        }
         material = max(u32(texture_value.r * 255.0), 1u);
+        coverage = texture_value.a;
+        voxel_color = texture_value.rgb;
     } else {
         material = voxelization_uniforms.material;
+        has_color = false;
     }
 
-    write_pos(vec3<i32>(texture_pos), material, voxelization_uniforms.flags);
+    // only the surface is written, the shell grows inwards against the normal
+    let normal = select(vec3(0.0), normalize(in.normal), dot(in.normal, in.normal) > 0.0);
+    let store_normal = voxelization_uniforms.normals != 0u && voxel_uniforms.surface_normals != 0u && any(normal != vec3(0.0));
+    let store_color = has_color && voxel_uniforms.voxel_colors != 0u;
+    for (var i = 0u; i < voxelization_uniforms.thickness; i++) {
+        let pos = vec3<i32>(texture_pos - f32(i) * normal);
+        if voxelization_uniforms.dither != 0u && any(normal != vec3(0.0)) {
+            // fraction of the voxel behind the surface plane, from the distance of its center
+            // to the plane over the voxel's extent along the normal
+            let distance = dot(vec3<f32>(pos) + 0.5 - texture_pos, normal);
+            let extent = dot(abs(normal), vec3(1.0));
+            let inside = clamp(0.5 - distance / extent, 0.0, 1.0) * coverage;
+            // a fixed threshold per voxel so the pattern doesn't shimmer between frames
+            if inside <= hash(bitcast<vec3<u32>>(pos)).x {
+                continue;
+            }
+        }
+        if write_pos(pos, material, voxelization_uniforms.flags) {
+            if store_normal {
+                textureStore(voxel_normals, pos.zyx, vec4(pack_normal(normal, material)));
+            }
+            if store_color {
+                textureStore(voxel_colors, pos.zyx, vec4(pack_color(voxel_color, material)));
+            }
+        }
+    }
 
     let color = voxel_uniforms.materials[material].rgb;
     
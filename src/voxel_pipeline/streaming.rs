@@ -0,0 +1,418 @@
+use super::{
+    compute::rebuild::RequestRebuild,
+    voxel_world::{load_voxel_world, NewGH, VoxelUniforms, VoxelWorld},
+};
+use crate::{
+    load::{VoxelMaterial, VoxelPalette},
+    Flags, LoadVoxelWorld, TraceSettings,
+};
+use bevy::{prelude::*, tasks::IoTaskPool, utils::HashSet};
+use crossbeam_channel::{Receiver, Sender};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Arc,
+};
+
+const MAGIC: &[u8; 4] = b"BVCW";
+const VERSION: u32 = 1;
+
+/// Distance in chunks the focus can move from the center of the window before it follows
+const WINDOW_HYSTERESIS: f32 = 0.75;
+
+pub struct StreamingPlugin;
+
+impl Plugin for StreamingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VoxelStreaming>().add_systems(
+            Update,
+            (
+                start_streaming.before(load_voxel_world),
+                stream_chunks.after(load_voxel_world),
+            ),
+        );
+    }
+}
+
+/// Entity the chunks of a world streamed with [`LoadVoxelWorld::Stream`] are kept resident
+/// around, the first voxel camera is used when there is none
+#[derive(Component, Default)]
+pub struct VoxelStreamingFocus;
+
+/// State of the world streamed with [`LoadVoxelWorld::Stream`]. The voxel world holds the
+/// window of chunks around the [`VoxelStreamingFocus`] and is moved by whole chunks to follow
+/// it, see [`VoxelWorld::origin`]. Edits made on the gpu by physics and automata are lost
+/// when the window moves, and chunks are read from disk again whenever they re-enter it
+#[derive(Resource, Default)]
+pub struct VoxelStreaming {
+    stream: Option<Stream>,
+}
+
+impl VoxelStreaming {
+    pub fn is_streaming(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// First and one past the last chunk of the streamed world in the voxel world
+    pub fn window(&self) -> Option<(IVec3, IVec3)> {
+        let stream = self.stream.as_ref()?;
+        let min = stream.window_min?;
+        Some((min, min + stream.window_size))
+    }
+
+    /// Chunks still being read from disk
+    pub fn pending_chunks(&self) -> usize {
+        self.stream.as_ref().map_or(0, |stream| stream.pending.len())
+    }
+}
+
+struct Stream {
+    file: Arc<ChunkedWorld>,
+    /// Chunks along each axis of the voxel world
+    window_size: i32,
+    window_min: Option<IVec3>,
+    pending: HashSet<IVec3>,
+    sender: Sender<(IVec3, io::Result<Vec<u8>>)>,
+    receiver: Receiver<(IVec3, io::Result<Vec<u8>>)>,
+}
+
+/// Header of a chunked world file. It is followed by the voxels of every chunk that isn't
+/// empty, laid out like the voxel world texture data
+struct ChunkedWorld {
+    path: String,
+    chunk_size: u32,
+    /// Chunks along each axis
+    chunks: UVec3,
+    palette: VoxelPalette,
+    /// File offset of the voxels of each chunk indexed like the voxels, 0 for empty chunks
+    offsets: Vec<u64>,
+}
+
+impl ChunkedWorld {
+    fn open(path: &str) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a chunked voxel world"));
+        }
+        if read_u32(&mut reader)? != VERSION {
+            return Err(invalid_data("unsupported chunked voxel world version"));
+        }
+
+        let chunk_size = read_u32(&mut reader)?;
+        if !chunk_size.is_power_of_two() || chunk_size < 8 {
+            return Err(invalid_data("chunk size must be a power of two of at least 8"));
+        }
+        let chunks = UVec3::new(
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+        );
+
+        let mut palette = VoxelPalette::default();
+        for material in palette.iter_mut() {
+            let mut values = [0.0; 6];
+            for value in values.iter_mut() {
+                *value = f32::from_bits(read_u32(&mut reader)?);
+            }
+            *material = VoxelMaterial {
+                color: Vec3::new(values[0], values[1], values[2]),
+                emission: values[3],
+                roughness: values[4],
+                metal: values[5],
+            };
+        }
+
+        let count = chunks.x as usize * chunks.y as usize * chunks.z as usize;
+        let mut offsets = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut bytes = [0; 8];
+            reader.read_exact(&mut bytes)?;
+            offsets.push(u64::from_le_bytes(bytes));
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            chunk_size,
+            chunks,
+            palette,
+            offsets,
+        })
+    }
+
+    /// `None` outside the file and for empty chunks
+    fn chunk_offset(&self, chunk: IVec3) -> Option<u64> {
+        if chunk.cmplt(IVec3::ZERO).any() || chunk.cmpge(self.chunks.as_ivec3()).any() {
+            return None;
+        }
+
+        let chunk = chunk.as_uvec3();
+        let index = (chunk.x as usize * self.chunks.y as usize + chunk.y as usize)
+            * self.chunks.z as usize
+            + chunk.z as usize;
+        Some(self.offsets[index]).filter(|offset| *offset != 0)
+    }
+
+    fn read_chunk(&self, offset: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![0; self.chunk_size.pow(3) as usize * 2];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Writes a world of `chunks` chunks of `chunk_size` voxels along each axis for
+/// [`LoadVoxelWorld::Stream`], from the (material, flags) returned for each voxel position.
+/// The world is generated a chunk at a time so it never has to fit in memory, and empty
+/// chunks take no space in the file. Its center is placed at the world origin when streamed
+pub fn write_chunked_world(
+    path: impl AsRef<Path>,
+    chunks: UVec3,
+    chunk_size: u32,
+    palette: &VoxelPalette,
+    voxel: impl Fn(IVec3) -> (u8, Flags),
+) -> io::Result<()> {
+    if !chunk_size.is_power_of_two() || chunk_size < 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunk size must be a power of two of at least 8",
+        ));
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    for value in [VERSION, chunk_size, chunks.x, chunks.y, chunks.z] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    for material in palette.iter() {
+        let color = material.color;
+        for value in [
+            color.x,
+            color.y,
+            color.z,
+            material.emission,
+            material.roughness,
+            material.metal,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    // The offsets are written once the chunks have been
+    let count = chunks.x as usize * chunks.y as usize * chunks.z as usize;
+    let offsets_start = writer.stream_position()?;
+    writer.write_all(&vec![0; count * 8])?;
+
+    let size = chunk_size as i32;
+    let mut offsets = Vec::with_capacity(count);
+    let mut data = vec![0; chunk_size.pow(3) as usize * 2];
+    for cx in 0..chunks.x as i32 {
+        for cy in 0..chunks.y as i32 {
+            for cz in 0..chunks.z as i32 {
+                let min = IVec3::new(cx, cy, cz) * size;
+                for x in 0..size {
+                    for y in 0..size {
+                        for z in 0..size {
+                            let (material, flags) = voxel(min + IVec3::new(x, y, z));
+                            let index = ((x * size + y) * size + z) as usize * 2;
+                            data[index] = material;
+                            data[index + 1] = flags.bits();
+                        }
+                    }
+                }
+
+                if data.iter().all(|byte| *byte == 0) {
+                    offsets.push(0);
+                } else {
+                    offsets.push(writer.stream_position()?);
+                    writer.write_all(&data)?;
+                }
+            }
+        }
+    }
+
+    writer.seek(SeekFrom::Start(offsets_start))?;
+    for offset in offsets {
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+fn start_streaming(
+    mut load_voxel_world: ResMut<LoadVoxelWorld>,
+    mut streaming: ResMut<VoxelStreaming>,
+    mut voxel_palette: ResMut<VoxelPalette>,
+    voxel_world: Res<VoxelWorld>,
+) {
+    let path = match load_voxel_world.as_ref() {
+        LoadVoxelWorld::Stream(path) => path.clone(),
+        LoadVoxelWorld::None => return,
+        // Loading any other world ends the stream
+        _ => {
+            streaming.stream = None;
+            return;
+        }
+    };
+    *load_voxel_world = LoadVoxelWorld::None;
+    streaming.stream = None;
+
+    let file = match ChunkedWorld::open(&path) {
+        Ok(file) => file,
+        Err(error) => {
+            error!("Failed to open the chunked voxel world {}: {}", path, error);
+            return;
+        }
+    };
+    if file.chunk_size > voxel_world.size() {
+        error!(
+            "Chunks of {} voxels don't fit in the voxel world of size {}",
+            file.chunk_size,
+            voxel_world.size()
+        );
+        return;
+    }
+
+    *voxel_palette = file.palette.clone();
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    streaming.stream = Some(Stream {
+        window_size: (voxel_world.size() / file.chunk_size) as i32,
+        file: Arc::new(file),
+        window_min: None,
+        pending: HashSet::new(),
+        sender,
+        receiver,
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn stream_chunks(
+    mut streaming: ResMut<VoxelStreaming>,
+    mut voxel_world: ResMut<VoxelWorld>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+    mut new_gh: ResMut<NewGH>,
+    mut request_rebuild: ResMut<RequestRebuild>,
+    voxel_palette: Res<VoxelPalette>,
+    focus_query: Query<&GlobalTransform, With<VoxelStreamingFocus>>,
+    camera_query: Query<&GlobalTransform, With<TraceSettings>>,
+) {
+    let Some(stream) = streaming.stream.as_mut() else {
+        return;
+    };
+
+    let size = voxel_world.size() as i32;
+    let chunk_size = stream.file.chunk_size as i32;
+    let window_size = stream.window_size;
+    let chunks = stream.file.chunks.as_ivec3();
+    let file_size = chunks * chunk_size;
+    let voxels_per_meter = voxel_uniforms.voxels_per_meter;
+
+    let focus = focus_query
+        .iter()
+        .next()
+        .or_else(|| camera_query.iter().next())
+        .map_or(Vec3::ZERO, |transform| transform.translation());
+    // The center of the file is at the world origin
+    let focus_chunk = (focus * voxels_per_meter + file_size.as_vec3() / 2.0) / chunk_size as f32;
+
+    let follow = match stream.window_min {
+        Some(min) => {
+            let center = min.as_vec3() + window_size as f32 / 2.0;
+            (focus_chunk - center).abs().max_element() > WINDOW_HYSTERESIS
+        }
+        None => true,
+    };
+    let old_min = stream.window_min;
+    let mut window_min = old_min.unwrap_or_default();
+    if follow {
+        // Kept inside the file, and centered on axes the file is smaller than the window along
+        let desired = (focus_chunk - window_size as f32 / 2.0).round().as_ivec3();
+        let window = IVec3::splat(window_size);
+        window_min = IVec3::select(
+            chunks.cmpge(window),
+            desired.clamp(IVec3::ZERO, (chunks - window).max(IVec3::ZERO)),
+            (chunks - window).div_euclid(IVec3::splat(2)),
+        );
+    }
+
+    let origin = (window_min * chunk_size + size / 2 - file_size / 2).as_vec3() / voxels_per_meter;
+
+    if old_min != Some(window_min) {
+        // Without a window the whole world is moved out and starts empty
+        let offset = old_min.map_or(IVec3::splat(size), |old_min| {
+            (old_min - window_min) * chunk_size
+        });
+        *new_gh = NewGH::Some(voxel_world.shift(offset, origin, &voxel_palette));
+        stream.window_min = Some(window_min);
+
+        // Read the chunks that weren't already resident
+        for x in 0..window_size {
+            for y in 0..window_size {
+                for z in 0..window_size {
+                    let chunk = window_min + IVec3::new(x, y, z);
+                    let resident = old_min.is_some_and(|old_min| {
+                        chunk.cmpge(old_min).all() && chunk.cmplt(old_min + window_size).all()
+                    });
+                    if resident || stream.pending.contains(&chunk) {
+                        continue;
+                    }
+                    let Some(offset) = stream.file.chunk_offset(chunk) else {
+                        continue;
+                    };
+
+                    stream.pending.insert(chunk);
+                    let file = stream.file.clone();
+                    let sender = stream.sender.clone();
+                    IoTaskPool::get()
+                        .spawn(async move {
+                            let _ = sender.send((chunk, file.read_chunk(offset)));
+                        })
+                        .detach();
+                }
+            }
+        }
+    }
+
+    // Also follows changes of the voxels per meter
+    if voxel_uniforms.origin != origin {
+        voxel_uniforms.origin = origin;
+        voxel_world.set_origin(origin);
+    }
+
+    let loaded: Vec<_> = stream.receiver.try_iter().collect();
+    for (chunk, data) in loaded {
+        stream.pending.remove(&chunk);
+
+        let local = chunk - window_min;
+        if local.cmplt(IVec3::ZERO).any() || local.cmpge(IVec3::splat(window_size)).any() {
+            continue;
+        }
+
+        match data {
+            Ok(data) => {
+                voxel_world.write_region(
+                    local * chunk_size,
+                    UVec3::splat(chunk_size as u32),
+                    &data,
+                );
+                request_rebuild.0 = true;
+            }
+            Err(error) => error!("Failed to read voxel chunk {}: {}", chunk, error),
+        }
+    }
+}
@@ -1,25 +1,37 @@
-use super::voxel_world::VoxelData;
+use super::{
+    attachments::{color_format, PositionFormat, RenderAttachmentSettings},
+    compute::SimulationSeed,
+    voxel_world::VoxelData,
+};
 use bevy::{
-    asset::{embedded_asset, load_internal_asset},
-    core_pipeline::fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    asset::embedded_asset,
+    core::FrameCount,
+    core_pipeline::{
+        blit::{BlitPipeline, BlitPipelineKey},
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        tonemapping::Tonemapping,
+    },
     prelude::*,
     render::{
         Render,
         extract_component::{ExtractComponent, ExtractComponentPlugin},
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_asset::RenderAssets,
         render_resource::*,
-        renderer::{RenderDevice, RenderQueue},
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
         view::{ExtractedView, ViewTarget},
         RenderApp, RenderSet,
     },
     utils::HashMap,
 };
 pub use node::TraceNode;
+pub use stats::TraceStats;
 
 mod node;
+mod stats;
 
-const COMMON_HANDLE: Handle<Shader> = Handle::weak_from_u128(1874948457211004189);
-const BINDINGS_HANDLE: Handle<Shader> = Handle::weak_from_u128(1874948457211004188);
-const RAYTRACING_HANDLE: Handle<Shader> = Handle::weak_from_u128(10483863284569474370);
+/// Maximum number of point lights uploaded to the trace shader, extra lights are ignored
+pub const MAX_POINT_LIGHTS: usize = 16;
 
 pub struct TracePlugin;
 
@@ -27,48 +39,323 @@ impl Plugin for TracePlugin {
     fn build(&self, app: &mut App) {
         embedded_asset!(app, "src/", "trace.wgsl");
 
-        load_internal_asset!(app, COMMON_HANDLE, "../shaders/common.wgsl", Shader::from_wgsl);
-        load_internal_asset!(app, BINDINGS_HANDLE, "../shaders/bindings.wgsl", Shader::from_wgsl);
-        load_internal_asset!(app, RAYTRACING_HANDLE, "../shaders/raytracing.wgsl", Shader::from_wgsl);
+        app.init_resource::<PointLights>()
+            .init_resource::<Skybox>()
+            .init_resource::<SkySettings>()
+            .init_resource::<TraceHistory>()
+            .add_plugins(ExtractResourcePlugin::<PointLights>::default())
+            .add_plugins(ExtractResourcePlugin::<Skybox>::default())
+            .add_plugins(ExtractResourcePlugin::<SkySettings>::default())
+            .add_plugins(ExtractResourcePlugin::<TraceHistory>::default())
+            .add_systems(First, reset_trace_history);
+
+        stats::build(app);
     }
 
     fn finish(&self, app: &mut App) {
         app.add_plugins(ExtractComponentPlugin::<TraceSettings>::default());
 
+        stats::finish(app);
+
+        // e.g. WebGL2 without float color buffers, cameras are switched to an 8 bit target
+        let hdr_supported = app
+            .world
+            .resource::<RenderAdapter>()
+            .get_texture_format_features(ViewTarget::TEXTURE_FORMAT_HDR)
+            .allowed_usages
+            .contains(TextureUsages::RENDER_ATTACHMENT);
+        if !hdr_supported {
+            warn!("The device can't render to hdr textures, tracing voxel cameras without hdr");
+            app.add_systems(PostUpdate, disable_hdr);
+        }
+
         // Setup custom render pipeline
         
         let render_app = app.sub_app_mut(RenderApp);
 
         render_app
             .init_resource::<TracePipelineData>()
+            .init_resource::<SpecializedRenderPipelines<TracePipelineData>>()
             .insert_resource(LastCameras(HashMap::new()))
-            .add_systems(Render, prepare_uniforms.in_set(RenderSet::Prepare));
+            .insert_resource(PointLightsBuffer(StorageBuffer::default()))
+            .add_systems(Render, prepare_uniforms.in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_point_lights.in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_upscaling_pipelines.in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_trace_pipelines.in_set(RenderSet::Prepare));
+    }
+}
+
+fn disable_hdr(mut cameras: Query<&mut Camera, With<TraceSettings>>) {
+    for mut camera in cameras.iter_mut() {
+        if camera.hdr {
+            camera.hdr = false;
+        }
     }
 }
 
 #[derive(Resource)]
 struct TracePipelineData {
-    trace_pipeline_id: CachedRenderPipelineId,
-    trace_bind_group_layout: BindGroupLayout,
+    trace_shader: Handle<Shader>,
+    voxel_bind_group_layout: BindGroupLayout,
+    /// The storage texture format of the position attachment is part of the layout
+    trace_bind_group_layouts: HashMap<PositionFormat, BindGroupLayout>,
+    lighting_bind_group_layout: BindGroupLayout,
+    lighting_sampler: Sampler,
+}
+
+impl TracePipelineData {
+    fn trace_bind_group_layout(&self, position_format: PositionFormat) -> &BindGroupLayout {
+        &self.trace_bind_group_layouts[&position_format]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum TracePass {
+    /// Shades every pixel in one pass
+    Trace,
+    /// Writes only the lighting of each hit to the lighting attachment
+    Lighting,
+    /// Shades with the upsampled lighting attachment instead of computing it per pixel
+    Composite,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct TracePipelineKey {
+    pass: TracePass,
+    position_format: PositionFormat,
+    stats: bool,
+    /// Whether the view target is hdr, otherwise the trace output is tonemapped in the shader
+    hdr: bool,
+    /// Curve tonemapping in the shader without hdr, `None` for hdr views
+    tonemapping: Tonemapping,
+}
+
+/// Trace pipelines specialized for the view's settings
+#[derive(Component)]
+pub struct ViewTracePipelines {
+    trace: CachedRenderPipelineId,
+    /// Set when the lighting is rendered below the traced resolution
+    lighting: Option<CachedRenderPipelineId>,
+}
+
+/// Buffer the trace pass outputs in place of the shaded color
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DebugView {
+    #[default]
+    None,
+    RaySteps,
+    Normals,
+    WorldPosition,
+    Depth,
+}
+
+/// How the random points of the supersampled primary rays, their soft shadow rays and the
+/// ambient occlusion samples are spread
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleSequence {
+    /// Independent random points, changing every frame
+    #[default]
+    Random,
+    /// A Halton sequence continued across frames and offset per pixel, covering the pixel
+    /// and light more evenly for less noise at the same sample count. Seeded from the
+    /// [`SimulationSeed`]
+    Halton,
+}
+
+/// What the trace pass shows where rays leave the world without hitting anything
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum VoxelBackground {
+    /// The procedural sky or the [`Skybox`]
+    #[default]
+    Sky,
+    /// Not affected by `exposure`
+    Color(Color),
+    /// Zero alpha, so the view can be composited over whatever is rendered below it. The
+    /// camera's target and the cameras drawn after it must keep the alpha channel
+    Transparent,
 }
 
 #[derive(Component, Clone, ExtractComponent)]
 pub struct TraceSettings {
-    pub show_ray_steps: bool,
+    pub debug_view: DebugView,
+    /// Primary rays traced per pixel, jittered within the pixel and averaged for anti-aliasing.
+    /// 1 traces through the pixel center only
     pub samples: u32,
+    pub sample_sequence: SampleSequence,
     pub shadows: bool,
+    /// Angular radius of the sun in radians, shadow rays are jittered within it and averaged
+    /// over `samples` for penumbras. Shadows stay hard at 0 or with a single sample
+    pub shadow_softness: f32,
+    pub ao: bool,
+    pub ao_radius: f32,
+    /// Fraction of the viewport resolution the trace pass renders at, in `0.25..=1.0`
+    pub render_scale: f32,
+    /// Fraction of the traced resolution lighting is computed at before being bilinearly
+    /// upsampled, in `0.25..=1.0`
+    pub lighting_scale: f32,
+    /// Reflections followed off surfaces in [`ReflectiveMaterials`](crate::ReflectiveMaterials)
+    pub reflection_bounces: u32,
+    /// Voxels of [`TransparentMaterials`](crate::TransparentMaterials) a ray crosses before
+    /// the next one is shaded as opaque, 0 makes every material opaque
+    pub max_transparency_steps: u32,
+    /// Multiplier on the traced color before tonemapping, `2^ev` for an exposure value.
+    /// Debug views are not affected
+    pub exposure: f32,
+    /// Counts the steps of the primary rays into [`TraceStats`]. Off by default since every
+    /// pixel then writes to the same atomic counters
+    pub collect_stats: bool,
+    /// Meters in front of the camera that primary rays skip, so voxels voxelized at the
+    /// camera, like a held object or its own collider, don't block the view
+    pub ray_near: f32,
+    /// Meters from the camera after which primary rays stop marching and count as misses,
+    /// in the color and the position attachment alike. `f32::INFINITY` traces the whole world
+    pub ray_far: f32,
+    pub background: VoxelBackground,
+    /// Replaces [`SkySettings::sun_intensity`] for this camera when `Some`, `None` uses the
+    /// global setting
+    pub sun_intensity: Option<f32>,
 }
 
 impl Default for TraceSettings {
     fn default() -> Self {
         Self {
-            show_ray_steps: false,
+            debug_view: DebugView::None,
             samples: 1,
+            sample_sequence: SampleSequence::Random,
             shadows: true,
+            shadow_softness: 0.0,
+            ao: false,
+            ao_radius: 0.5,
+            render_scale: 1.0,
+            lighting_scale: 1.0,
+            reflection_bounces: 1,
+            max_transparency_steps: 16,
+            exposure: 1.0,
+            collect_stats: false,
+            ray_near: 0.0,
+            ray_far: f32::INFINITY,
+            background: VoxelBackground::Sky,
+            sun_intensity: None,
         }
     }
 }
 
+impl TraceSettings {
+    /// Starts from the default settings
+    pub fn builder() -> TraceSettingsBuilder {
+        TraceSettingsBuilder(Self::default())
+    }
+
+    pub fn clamped_render_scale(&self) -> f32 {
+        self.render_scale.clamp(0.25, 1.0)
+    }
+
+    pub fn clamped_lighting_scale(&self) -> f32 {
+        self.lighting_scale.clamp(0.25, 1.0)
+    }
+}
+
+/// Sets the fields of [`TraceSettings`] by name, see [`TraceSettings::builder`]
+#[derive(Clone)]
+pub struct TraceSettingsBuilder(TraceSettings);
+
+impl TraceSettingsBuilder {
+    pub fn debug_view(mut self, debug_view: DebugView) -> Self {
+        self.0.debug_view = debug_view;
+        self
+    }
+
+    /// Shorthand for the [`DebugView::RaySteps`] debug view
+    pub fn show_ray_steps(self, show: bool) -> Self {
+        self.debug_view(if show { DebugView::RaySteps } else { DebugView::None })
+    }
+
+    pub fn samples(mut self, samples: u32) -> Self {
+        self.0.samples = samples;
+        self
+    }
+
+    pub fn sample_sequence(mut self, sample_sequence: SampleSequence) -> Self {
+        self.0.sample_sequence = sample_sequence;
+        self
+    }
+
+    pub fn shadows(mut self, shadows: bool) -> Self {
+        self.0.shadows = shadows;
+        self
+    }
+
+    pub fn shadow_softness(mut self, shadow_softness: f32) -> Self {
+        self.0.shadow_softness = shadow_softness;
+        self
+    }
+
+    pub fn ao(mut self, ao: bool) -> Self {
+        self.0.ao = ao;
+        self
+    }
+
+    pub fn ao_radius(mut self, ao_radius: f32) -> Self {
+        self.0.ao_radius = ao_radius;
+        self
+    }
+
+    pub fn render_scale(mut self, render_scale: f32) -> Self {
+        self.0.render_scale = render_scale;
+        self
+    }
+
+    pub fn lighting_scale(mut self, lighting_scale: f32) -> Self {
+        self.0.lighting_scale = lighting_scale;
+        self
+    }
+
+    pub fn reflection_bounces(mut self, reflection_bounces: u32) -> Self {
+        self.0.reflection_bounces = reflection_bounces;
+        self
+    }
+
+    pub fn max_transparency_steps(mut self, max_transparency_steps: u32) -> Self {
+        self.0.max_transparency_steps = max_transparency_steps;
+        self
+    }
+
+    pub fn exposure(mut self, exposure: f32) -> Self {
+        self.0.exposure = exposure;
+        self
+    }
+
+    pub fn collect_stats(mut self, collect_stats: bool) -> Self {
+        self.0.collect_stats = collect_stats;
+        self
+    }
+
+    pub fn ray_near(mut self, ray_near: f32) -> Self {
+        self.0.ray_near = ray_near;
+        self
+    }
+
+    pub fn ray_far(mut self, ray_far: f32) -> Self {
+        self.0.ray_far = ray_far;
+        self
+    }
+
+    pub fn background(mut self, background: VoxelBackground) -> Self {
+        self.0.background = background;
+        self
+    }
+
+    /// Overrides [`SkySettings::sun_intensity`] for this camera
+    pub fn sun_intensity(mut self, sun_intensity: f32) -> Self {
+        self.0.sun_intensity = Some(sun_intensity);
+        self
+    }
+
+    pub fn build(self) -> TraceSettings {
+        self.0
+    }
+}
+
 #[derive(Clone, ShaderType)]
 pub struct TraceUniforms {
     pub camera: Mat4,
@@ -76,11 +363,110 @@ pub struct TraceUniforms {
     pub last_camera: Mat4,
     pub projection: Mat4,
     pub time: f32,
-    pub show_ray_steps: u32,
+    pub debug_view: u32,
     pub samples: u32,
+    /// 0 for random samples, 1 for halton
+    pub sample_sequence: u32,
+    pub frame: u32,
+    /// See [`SimulationSeed`]
+    pub seed: u32,
     pub shadows: u32,
+    pub shadow_softness: f32,
+    pub ao_radius: f32,
+    pub skybox: u32,
+    pub reflection_bounces: u32,
+    pub max_transparency_steps: u32,
+    pub exposure: f32,
+    pub ray_near: f32,
+    /// 0 without a far clip
+    pub ray_far: f32,
+    pub sun_intensity: f32,
+    /// 0 for the sky, 1 for `background`
+    pub background_mode: u32,
+    /// Linear rgba
+    pub background: Vec4,
+    /// Position and size of the camera's viewport in physical pixels of the render target
+    pub viewport: Vec4,
+}
+
+/// Cubemap sampled by rays that miss every voxel, the procedural sky is used when `None`.
+/// The image must have a `TextureViewDimension::Cube` view.
+#[derive(Resource, Clone, Default, Deref, DerefMut, ExtractResource)]
+pub struct Skybox(pub Option<Handle<Image>>);
+
+/// Sky lighting shared by every camera, cameras can override it through their
+/// [`TraceSettings`]
+#[derive(Resource, Clone, ExtractResource)]
+pub struct SkySettings {
+    /// Multiplier on the direct light of the sun, the sky's indirect light is unaffected
+    pub sun_intensity: f32,
+}
+
+impl Default for SkySettings {
+    fn default() -> Self {
+        Self { sun_intensity: 1.0 }
+    }
+}
+
+/// Set `invalidate_history` for a frame to drop everything the trace pass keeps from previous
+/// frames, so temporal effects don't smear the old scene over the new one. It is reset at the
+/// start of every frame. Set it after changes the previous frames can't be reprojected across:
+/// - voxel edits through [`VoxelWorld`](crate::VoxelWorld) such as `set_voxel`, `fill_box`,
+///   `fill_sphere`, `stroke` and `paste_region`
+/// - loading a world through [`LoadVoxelWorld`](crate::LoadVoxelWorld)
+/// - lighting changes to [`PointLights`] or [`Skybox`]
+/// - teleporting a camera
+#[derive(Resource, Clone, Copy, Default, ExtractResource)]
+pub struct TraceHistory {
+    pub invalidate_history: bool,
+}
+
+fn reset_trace_history(mut trace_history: ResMut<TraceHistory>) {
+    if trace_history.invalidate_history {
+        trace_history.invalidate_history = false;
+    }
+}
+
+#[derive(Clone)]
+pub struct VoxelPointLight {
+    pub position: Vec3,
+    pub color: Color,
+    pub intensity: f32,
+    pub range: f32,
+}
+
+impl Default for VoxelPointLight {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            color: Color::WHITE,
+            intensity: 1.0,
+            range: 10.0,
+        }
+    }
 }
 
+/// Point lights shaded by the trace pass, only the first `MAX_POINT_LIGHTS` are used
+#[derive(Resource, Clone, Default, Deref, DerefMut, ExtractResource)]
+pub struct PointLights(pub Vec<VoxelPointLight>);
+
+#[derive(Default, Clone, Copy, ShaderType)]
+struct ExtractedPointLight {
+    position: Vec3,
+    range: f32,
+    color: Vec3,
+    intensity: f32,
+}
+
+#[derive(Default, Clone, ShaderType)]
+struct ExtractedPointLights {
+    count: u32,
+    lights: [ExtractedPointLight; MAX_POINT_LIGHTS],
+}
+
+#[derive(Resource, Deref, DerefMut)]
+struct PointLightsBuffer(StorageBuffer<ExtractedPointLights>);
+
 #[derive(Component, Deref, DerefMut)]
 pub struct ViewTraceUniformBuffer {
     pub buffer: UniformBuffer<TraceUniforms>,
@@ -89,17 +475,99 @@ pub struct ViewTraceUniformBuffer {
 #[derive(Resource, Deref, DerefMut)]
 struct LastCameras(HashMap<Entity, Mat4>);
 
+/// Blits the scaled trace output to the view target
+#[derive(Component)]
+pub struct ViewTraceUpscalingPipeline(CachedRenderPipelineId);
+
+fn prepare_upscaling_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    blit_pipeline: Res<BlitPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<BlitPipeline>>,
+    query: Query<(Entity, &ExtractedView), With<TraceSettings>>,
+) {
+    for (entity, view) in query.iter() {
+        let key = BlitPipelineKey {
+            texture_format: color_format(view.hdr),
+            blend_state: None,
+            samples: 1,
+        };
+        let pipeline = pipelines.specialize(&pipeline_cache, &blit_pipeline, key);
+
+        commands
+            .entity(entity)
+            .insert(ViewTraceUpscalingPipeline(pipeline));
+    }
+}
+
+fn prepare_trace_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    trace_pipeline_data: Res<TracePipelineData>,
+    attachment_settings: Res<RenderAttachmentSettings>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<TracePipelineData>>,
+    query: Query<(Entity, &TraceSettings, &ExtractedView, Option<&Tonemapping>)>,
+) {
+    let position_format = attachment_settings.position_format;
+    let mut specialize = |pass, stats, hdr, tonemapping| {
+        let key = TracePipelineKey {
+            pass,
+            position_format,
+            stats,
+            hdr,
+            tonemapping: if hdr { Tonemapping::None } else { tonemapping },
+        };
+        pipelines.specialize(&pipeline_cache, &trace_pipeline_data, key)
+    };
+
+    for (entity, settings, view, tonemapping) in query.iter() {
+        let stats = settings.collect_stats;
+        let tonemapping = tonemapping.copied().unwrap_or_default();
+
+        // At scale 1.0 the trace pipeline shades every pixel itself. Without hdr the lighting
+        // is always at full scale, see `RenderAttachments::lighting`
+        let pipelines = if view.hdr && settings.clamped_lighting_scale() < 1.0 {
+            ViewTracePipelines {
+                trace: specialize(TracePass::Composite, stats, true, tonemapping),
+                lighting: Some(specialize(TracePass::Lighting, false, true, tonemapping)),
+            }
+        } else {
+            ViewTracePipelines {
+                trace: specialize(TracePass::Trace, stats, view.hdr, tonemapping),
+                lighting: None,
+            }
+        };
+        commands.entity(entity).insert(pipelines);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn prepare_uniforms(
     mut commands: Commands,
     query: Query<(Entity, &TraceSettings, &ExtractedView)>,
     time: Res<Time>,
+    frame_count: Res<FrameCount>,
+    simulation_seed: Res<SimulationSeed>,
+    skybox: Res<Skybox>,
+    sky_settings: Res<SkySettings>,
+    gpu_images: Res<RenderAssets<Image>>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    trace_history: Res<TraceHistory>,
     mut last_cameras: ResMut<LastCameras>,
 ) {
+    if trace_history.invalidate_history {
+        last_cameras.clear();
+    }
+
     let elapsed = time.elapsed_seconds_f64();
+    let has_skybox = skybox
+        .0
+        .as_ref()
+        .is_some_and(|skybox| gpu_images.get(skybox).is_some());
 
     for (entity, settings, view) in query.iter() {
+        let viewport = view.viewport.as_vec4();
         let projection = view.projection;
         let inverse_projection = projection.inverse();
         let view = view.transform.compute_matrix();
@@ -111,15 +579,43 @@ fn prepare_uniforms(
         let last_camera = *last_cameras.get(&entity).unwrap_or(&camera);
         last_cameras.insert(entity, camera);
 
+        let (background_mode, background) = match settings.background {
+            VoxelBackground::Sky => (0, Vec4::ZERO),
+            VoxelBackground::Color(color) => (1, Vec4::from(color.as_linear_rgba_f32())),
+            VoxelBackground::Transparent => (1, Vec4::ZERO),
+        };
+
         let uniforms = TraceUniforms {
             camera,
             camera_inverse,
             last_camera,
             projection,
             time: elapsed as f32,
-            show_ray_steps: settings.show_ray_steps as u32,
+            debug_view: settings.debug_view as u32,
             samples: settings.samples,
+            sample_sequence: settings.sample_sequence as u32,
+            frame: frame_count.0,
+            seed: simulation_seed.0,
             shadows: settings.shadows as u32,
+            shadow_softness: settings.shadow_softness.max(0.0),
+            ao_radius: settings.ao_radius,
+            skybox: has_skybox as u32,
+            reflection_bounces: settings.reflection_bounces,
+            max_transparency_steps: settings.max_transparency_steps,
+            exposure: settings.exposure.max(0.0),
+            ray_near: settings.ray_near.max(0.0),
+            ray_far: if settings.ray_far.is_finite() {
+                settings.ray_far.max(0.0)
+            } else {
+                0.0
+            },
+            sun_intensity: settings
+                .sun_intensity
+                .unwrap_or(sky_settings.sun_intensity)
+                .max(0.0),
+            background_mode,
+            background,
+            viewport,
         };
 
         let mut uniform_buffer = UniformBuffer::from(uniforms);
@@ -132,20 +628,41 @@ fn prepare_uniforms(
     }
 }
 
+fn prepare_point_lights(
+    point_lights: Res<PointLights>,
+    mut point_lights_buffer: ResMut<PointLightsBuffer>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let mut extracted = ExtractedPointLights::default();
+    for (i, light) in point_lights.iter().take(MAX_POINT_LIGHTS).enumerate() {
+        extracted.lights[i] = ExtractedPointLight {
+            position: light.position,
+            range: light.range,
+            color: Vec4::from(light.color.as_linear_rgba_f32()).truncate(),
+            intensity: light.intensity,
+        };
+        extracted.count += 1;
+    }
+
+    point_lights_buffer.set(extracted);
+    point_lights_buffer.write_buffer(&render_device, &render_queue);
+}
+
 impl FromWorld for TracePipelineData {
 
     fn from_world(render_world: &mut World) -> Self {
         let voxel_data = render_world.resource::<VoxelData>();
         let asset_server = render_world.resource::<AssetServer>();
+        let render_device = render_world.resource::<RenderDevice>();
 
         let voxel_bind_group_layout = voxel_data.bind_group_layout.clone();
 
-        let trace_shader_handle =
+        let trace_shader =
             asset_server.load("embedded://bevy_voxel_engine/voxel_pipeline/trace/trace.wgsl");
 
-        let trace_bind_group_layout = render_world
-            .resource::<RenderDevice>()
-            .create_bind_group_layout(
+        let trace_bind_group_layout = |position_format: PositionFormat| {
+            render_device.create_bind_group_layout(
                 "trace bind group layout",
                 &[
                     BindGroupLayoutEntry {
@@ -173,27 +690,151 @@ impl FromWorld for TracePipelineData {
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::StorageTexture {
                             access: StorageTextureAccess::ReadWrite,
-                            format: TextureFormat::Rgba32Float,
+                            format: position_format.texture_format(),
                             view_dimension: TextureViewDimension::D2,
                         },
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(
+                                ExtractedPointLights::SHADER_SIZE.into(),
+                            ),
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Float { filterable: true },
+                            view_dimension: TextureViewDimension::Cube,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(stats::TRACE_STATS_SIZE),
+                        },
+                        count: None,
+                    },
                 ],
-            );
+            )
+        };
+        let trace_bind_group_layouts = [PositionFormat::Rgba32Float, PositionFormat::Rgba16Float]
+            .into_iter()
+            .map(|position_format| (position_format, trace_bind_group_layout(position_format)))
+            .collect();
 
-        let trace_pipeline_descriptor = RenderPipelineDescriptor {
-            label: Some("trace pipeline".into()),
-            layout: vec![
-                voxel_bind_group_layout.clone(),
-                trace_bind_group_layout.clone(),
+        let lighting_bind_group_layout = render_device.create_bind_group_layout(
+            "trace lighting bind group layout",
+            &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
             ],
+        );
+
+        let lighting_sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("trace lighting sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        TracePipelineData {
+            trace_shader,
+            voxel_bind_group_layout,
+            trace_bind_group_layouts,
+            lighting_bind_group_layout,
+            lighting_sampler,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for TracePipelineData {
+    type Key = TracePipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut shader_defs = vec![ShaderDefVal::UInt(
+            "MAX_POINT_LIGHTS".into(),
+            MAX_POINT_LIGHTS as u32,
+        )];
+        if key.position_format == PositionFormat::Rgba16Float {
+            shader_defs.push("POSITION_RGBA16FLOAT".into());
+        }
+        if key.stats {
+            shader_defs.push("TRACE_STATS".into());
+        }
+        if !key.hdr {
+            shader_defs.push("TONEMAP_IN_SHADER".into());
+            match key.tonemapping {
+                Tonemapping::None => shader_defs.push("TONEMAP_METHOD_NONE".into()),
+                Tonemapping::Reinhard => shader_defs.push("TONEMAP_METHOD_REINHARD".into()),
+                Tonemapping::ReinhardLuminance => {
+                    shader_defs.push("TONEMAP_METHOD_REINHARD_LUMINANCE".into())
+                }
+                // the other curves need lookup tables, aces is the closest without one
+                _ => {}
+            }
+        }
+
+        let mut layout = vec![
+            self.voxel_bind_group_layout.clone(),
+            self.trace_bind_group_layout(key.position_format).clone(),
+        ];
+        let format = color_format(key.hdr);
+        let label = match key.pass {
+            TracePass::Trace => "trace pipeline",
+            TracePass::Lighting => {
+                shader_defs.push("LIGHTING_PASS".into());
+                "trace lighting pipeline"
+            }
+            TracePass::Composite => {
+                shader_defs.push("LIGHTING_UPSAMPLE".into());
+                layout.push(self.lighting_bind_group_layout.clone());
+                "trace composite pipeline"
+            }
+        };
+
+        RenderPipelineDescriptor {
+            label: Some(label.into()),
+            layout,
             vertex: fullscreen_shader_vertex_state(),
             fragment: Some(FragmentState {
-                shader: trace_shader_handle,
-                shader_defs: Vec::new(),
+                shader: self.trace_shader.clone(),
+                shader_defs,
                 entry_point: "fragment".into(),
                 targets: vec![Some(ColorTargetState {
-                    format: ViewTarget::TEXTURE_FORMAT_HDR,
+                    format,
                     blend: None,
                     write_mask: ColorWrites::ALL,
                 })],
@@ -202,14 +843,6 @@ impl FromWorld for TracePipelineData {
             depth_stencil: None,
             multisample: MultisampleState::default(),
             push_constant_ranges: vec![],
-        };
-
-        let cache = render_world.resource::<PipelineCache>();
-        let trace_pipeline_id = cache.queue_render_pipeline(trace_pipeline_descriptor);
-
-        TracePipelineData {
-            trace_pipeline_id,
-            trace_bind_group_layout,
         }
     }
 }
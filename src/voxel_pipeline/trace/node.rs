@@ -1,16 +1,21 @@
-use super::{TracePipelineData, ViewTraceUniformBuffer};
+use super::{
+    stats::TraceStatsBuffer, PointLightsBuffer, Skybox, TracePipelineData, TraceSettings,
+    ViewTracePipelines, ViewTraceUniformBuffer, ViewTraceUpscalingPipeline,
+};
 use crate::voxel_pipeline::{
-    attachments::RenderAttachments,
+    attachments::{RenderAttachmentSettings, RenderAttachments},
     voxel_world::VoxelData, 
     RenderGraphSettings,
 };
 use bevy::{
+    core_pipeline::blit::BlitPipeline,
     prelude::*,
     render::{
         render_asset::RenderAssets,
         render_graph::{self, ViewNode},
         render_resource::*,
-        view::ViewTarget,
+        texture::FallbackImage,
+        view::{ExtractedView, ViewTarget},
     },
 };
 
@@ -22,6 +27,10 @@ impl ViewNode for TraceNode {
         &'static ViewTarget,
         &'static ViewTraceUniformBuffer,
         &'static RenderAttachments,
+        &'static TraceSettings,
+        &'static ViewTraceUpscalingPipeline,
+        &'static ExtractedView,
+        &'static ViewTracePipelines,
     );
 
     fn run(
@@ -35,21 +44,35 @@ impl ViewNode for TraceNode {
         let voxel_data = world.resource::<VoxelData>();
         let trace_pipeline_data = world.resource::<TracePipelineData>();
         let render_graph_settings = world.resource::<RenderGraphSettings>();
+        let point_lights_buffer = world.resource::<PointLightsBuffer>();
+        let fallback_images = world.resource::<FallbackImage>();
+        let skybox = world.resource::<Skybox>();
+        let attachment_settings = world.resource::<RenderAttachmentSettings>();
+        let trace_stats_buffer = world.resource::<TraceStatsBuffer>();
 
         if !render_graph_settings.trace {
             return Ok(());
         }
 
-        let (target, trace_uniform_buffer, render_attachments) = view_query;
+        let (
+            target,
+            trace_uniform_buffer,
+            render_attachments,
+            trace_settings,
+            upscaling_pipeline,
+            view,
+            trace_pipelines,
+        ) = view_query;
 
-        let trace_pipeline =
-            match pipeline_cache.get_render_pipeline(trace_pipeline_data.trace_pipeline_id) {
-                Some(pipeline) => pipeline,
-                None => return Ok(()),
-            };
+        let trace_pipeline = match pipeline_cache.get_render_pipeline(trace_pipelines.trace) {
+            Some(pipeline) => pipeline,
+            None => return Ok(()),
+        };
 
-        let post_process = target.post_process_write();
-        let destination = post_process.destination;
+        // The trace doesn't read the previous output, writing the main texture in place keeps
+        // the other viewports of a target shared between cameras
+        let destination = target.main_texture_view();
+        let viewport = view.viewport.as_vec4();
 
         let gpu_images = world.get_resource::<RenderAssets<Image>>().unwrap();
 
@@ -61,13 +84,19 @@ impl ViewNode for TraceNode {
             .get(&render_attachments.position)
             .expect("position image not found")
             .texture_view;
+        let skybox = skybox
+            .0
+            .as_ref()
+            .and_then(|skybox| gpu_images.get(skybox))
+            .unwrap_or(&fallback_images.cube);
 
         let trace_bind_group =
             render_context
                 .render_device()
                 .create_bind_group(
                     None,
-                    &trace_pipeline_data.trace_bind_group_layout,
+                    trace_pipeline_data
+                        .trace_bind_group_layout(attachment_settings.position_format),
                     &[
                         BindGroupEntry {
                             binding: 0,
@@ -81,13 +110,90 @@ impl ViewNode for TraceNode {
                             binding: 2,
                             resource: BindingResource::TextureView(&position),
                         },
+                        BindGroupEntry {
+                            binding: 3,
+                            resource: point_lights_buffer.binding().unwrap(),
+                        },
+                        BindGroupEntry {
+                            binding: 4,
+                            resource: BindingResource::TextureView(&skybox.texture_view),
+                        },
+                        BindGroupEntry {
+                            binding: 5,
+                            resource: BindingResource::Sampler(&skybox.sampler),
+                        },
+                        BindGroupEntry {
+                            binding: 6,
+                            resource: trace_stats_buffer.buffer.as_entire_binding(),
+                        },
                     ],
                 );
 
+        let lighting = &gpu_images
+            .get(&render_attachments.lighting)
+            .expect("lighting image not found")
+            .texture_view;
+        let lighting_bind_group = render_context.render_device().create_bind_group(
+            None,
+            &trace_pipeline_data.lighting_bind_group_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(lighting),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::Sampler(&trace_pipeline_data.lighting_sampler),
+                },
+            ],
+        );
+
+        // Below full scale the lighting is rendered to its own attachment first
+        if let Some(lighting_pipeline) = trace_pipelines.lighting {
+            let lighting_pipeline = match pipeline_cache.get_render_pipeline(lighting_pipeline) {
+                Some(pipeline) => pipeline,
+                None => return Ok(()),
+            };
+
+            let lighting_descriptor = RenderPassDescriptor {
+                label: Some("trace lighting pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: lighting,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            };
+
+            let mut render_pass = render_context
+                .command_encoder()
+                .begin_render_pass(&lighting_descriptor);
+
+            render_pass.set_bind_group(0, &voxel_data.bind_group, &[]);
+            render_pass.set_bind_group(1, &trace_bind_group, &[]);
+
+            render_pass.set_pipeline(lighting_pipeline);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        // When rendering below native resolution trace into the scaled color attachment
+        // and upscale it to the view target afterwards
+        let scaled = trace_settings.clamped_render_scale() < 1.0;
+        let color = &gpu_images
+            .get(&render_attachments.color)
+            .expect("color image not found")
+            .texture_view;
+        let trace_destination = if scaled { color } else { destination };
+
         let destination_descriptor = RenderPassDescriptor {
             label: Some("trace pass"),
             color_attachments: &[Some(RenderPassColorAttachment {
-                view: destination,
+                view: trace_destination,
                 resolve_target: None,
                 ops: Operations {
                     load: LoadOp::Load,
@@ -104,13 +210,67 @@ impl ViewNode for TraceNode {
                 .command_encoder()
                 .begin_render_pass(&destination_descriptor);
 
+            if !scaled {
+                render_pass.set_viewport(viewport.x, viewport.y, viewport.z, viewport.w, 0.0, 1.0);
+            }
             render_pass.set_bind_group(0, &voxel_data.bind_group, &[]);
             render_pass.set_bind_group(1, &trace_bind_group, &[]);
+            if trace_pipelines.lighting.is_some() {
+                render_pass.set_bind_group(2, &lighting_bind_group, &[]);
+            }
 
             render_pass.set_pipeline(trace_pipeline);
             render_pass.draw(0..3, 0..1);
         }
 
+        if scaled {
+            let blit_pipeline = world.resource::<BlitPipeline>();
+            let upscaling_pipeline = match pipeline_cache.get_render_pipeline(upscaling_pipeline.0) {
+                Some(pipeline) => pipeline,
+                None => return Ok(()),
+            };
+
+            let upscaling_bind_group = render_context.render_device().create_bind_group(
+                None,
+                &blit_pipeline.texture_bind_group,
+                &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(color),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::Sampler(&blit_pipeline.sampler),
+                    },
+                ],
+            );
+
+            let upscaling_descriptor = RenderPassDescriptor {
+                label: Some("trace upscaling pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: destination,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            };
+
+            let mut render_pass = render_context
+                .command_encoder()
+                .begin_render_pass(&upscaling_descriptor);
+
+            render_pass.set_viewport(viewport.x, viewport.y, viewport.z, viewport.w, 0.0, 1.0);
+            render_pass.set_bind_group(0, &upscaling_bind_group, &[]);
+
+            render_pass.set_pipeline(upscaling_pipeline);
+            render_pass.draw(0..3, 0..1);
+        }
+
         Ok(())
     }
 }
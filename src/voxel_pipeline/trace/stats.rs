@@ -0,0 +1,149 @@
+use super::TraceSettings;
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+};
+use crossbeam_channel::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Ray count, total steps and max steps as u32s
+pub(super) const TRACE_STATS_SIZE: u64 = 12;
+
+/// Steps taken by the primary rays of the views with [`TraceSettings::collect_stats`],
+/// summed over all of them. Read back from the gpu, so it lags a frame or two behind
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct TraceStats {
+    /// One per primary ray, so `samples` per traced pixel
+    pub rays: u32,
+    pub average_steps: f32,
+    pub max_steps: u32,
+}
+
+#[derive(Resource)]
+struct TraceStatsReceiver(Receiver<TraceStats>);
+
+#[derive(Resource)]
+pub(super) struct TraceStatsBuffer {
+    pub(super) buffer: Buffer,
+    readback: Buffer,
+    /// Set once the readback is mapped, `None` while it is waiting or not in use
+    mapped: Arc<Mutex<Option<Result<(), BufferAsyncError>>>>,
+    in_flight: bool,
+    sender: Sender<TraceStats>,
+}
+
+pub(super) fn build(app: &mut App) {
+    app.init_resource::<TraceStats>()
+        .add_systems(PreUpdate, receive_trace_stats);
+}
+
+pub(super) fn finish(app: &mut App) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    app.insert_resource(TraceStatsReceiver(receiver));
+
+    let render_app = app.sub_app_mut(RenderApp);
+    let render_device = render_app.world.resource::<RenderDevice>();
+
+    let buffer = render_device.create_buffer(&BufferDescriptor {
+        label: Some("trace stats buffer"),
+        size: TRACE_STATS_SIZE,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let readback = render_device.create_buffer(&BufferDescriptor {
+        label: Some("trace stats readback buffer"),
+        size: TRACE_STATS_SIZE,
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    render_app
+        .insert_resource(TraceStatsBuffer {
+            buffer,
+            readback,
+            mapped: Arc::new(Mutex::new(None)),
+            in_flight: false,
+            sender,
+        })
+        .add_systems(Render, clear_trace_stats.in_set(RenderSet::Prepare))
+        .add_systems(Render, read_back_trace_stats.in_set(RenderSet::Cleanup));
+}
+
+fn receive_trace_stats(receiver: Res<TraceStatsReceiver>, mut trace_stats: ResMut<TraceStats>) {
+    if let Some(stats) = receiver.0.try_iter().last() {
+        *trace_stats = stats;
+    }
+}
+
+fn clear_trace_stats(
+    query: Query<&TraceSettings>,
+    stats_buffer: Res<TraceStatsBuffer>,
+    render_queue: Res<RenderQueue>,
+) {
+    if query.iter().any(|settings| settings.collect_stats) {
+        render_queue.write_buffer(&stats_buffer.buffer, 0, &[0; TRACE_STATS_SIZE as usize]);
+    }
+}
+
+/// Runs after the render graph so the copy sees this frame's counts, a new copy is only
+/// started once the previous one has been read
+fn read_back_trace_stats(
+    query: Query<&TraceSettings>,
+    mut stats_buffer: ResMut<TraceStatsBuffer>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if stats_buffer.in_flight {
+        let Some(mapped) = stats_buffer.mapped.lock().unwrap().take() else {
+            return;
+        };
+        stats_buffer.in_flight = false;
+
+        if mapped.is_ok() {
+            let mapped_range = stats_buffer.readback.slice(..).get_mapped_range();
+            let data: &[u32] = bytemuck::cast_slice(&mapped_range);
+            let (rays, total_steps, max_steps) = (data[0], data[1], data[2]);
+            drop(mapped_range);
+            stats_buffer.readback.unmap();
+
+            let average_steps = match rays {
+                0 => 0.0,
+                rays => total_steps as f32 / rays as f32,
+            };
+            let _ = stats_buffer.sender.send(TraceStats {
+                rays,
+                average_steps,
+                max_steps,
+            });
+        }
+    }
+
+    if !query.iter().any(|settings| settings.collect_stats) {
+        return;
+    }
+
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("trace stats readback encoder"),
+    });
+    encoder.copy_buffer_to_buffer(
+        &stats_buffer.buffer,
+        0,
+        &stats_buffer.readback,
+        0,
+        TRACE_STATS_SIZE,
+    );
+    render_queue.submit([encoder.finish()]);
+
+    let mapped = stats_buffer.mapped.clone();
+    stats_buffer
+        .readback
+        .slice(..)
+        .map_async(MapMode::Read, move |result| {
+            *mapped.lock().unwrap() = Some(result);
+        });
+    stats_buffer.in_flight = true;
+}
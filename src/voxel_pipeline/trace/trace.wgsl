@@ -1,14 +1,20 @@
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
 #import bevy_voxel_engine::common::{
-    VOXELS_PER_METER,
     PI,
     VoxelUniforms,
     TraceUniforms,
     Ray,
-    skybox
+    skybox,
+    uniform_cone,
+    sample_2d,
 }
 #import bevy_voxel_engine::raytracing::{
+    HitInfo,
     shoot_ray,
+    shoot_ray_through,
+    intersect_scene,
+    is_reflective,
+    surface_normal,
 }
 #import bevy_voxel_engine::bindings::{
     voxel_world,
@@ -24,14 +30,64 @@ var<uniform> trace_uniforms: TraceUniforms;
 @group(1) @binding(1)
 var normal: texture_storage_2d<rgba16float, read_write>;
 @group(1) @binding(2)
+#ifdef POSITION_RGBA16FLOAT
+var position: texture_storage_2d<rgba16float, read_write>;
+#else
 var position: texture_storage_2d<rgba32float, read_write>;
+#endif
+@group(1) @binding(3)
+var<storage, read> point_lights: PointLights;
+@group(1) @binding(4)
+var skybox_texture: texture_cube<f32>;
+@group(1) @binding(5)
+var skybox_sampler: sampler;
+
+#ifdef TRACE_STATS
+struct TraceStats {
+    rays: atomic<u32>,
+    total_steps: atomic<u32>,
+    max_steps: atomic<u32>,
+};
+
+@group(1) @binding(6)
+var<storage, read_write> trace_stats: TraceStats;
+#endif
+
+#ifdef LIGHTING_UPSAMPLE
+@group(2) @binding(0)
+var lighting_texture: texture_2d<f32>;
+@group(2) @binding(1)
+var lighting_sampler: sampler;
+#endif
+
+// seeds the per pixel offset of the halton sequence, set at the start of the fragment shader
+var<private> pixel_seed: vec3<u32>;
+
+// point in [0, 1) for the primary ray `sample` of this frame, dimension 0 is the subpixel
+// jitter and 1 the soft shadows
+fn sample_point(sample: u32, dimension: u32, seed: vec3<u32>) -> vec2<f32> {
+    let index = trace_uniforms.frame * max(trace_uniforms.samples, 1u) + sample;
+    return sample_2d(trace_uniforms.sample_sequence, index, dimension, pixel_seed, seed);
+}
+
+struct PointLight {
+    position: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+};
+
+struct PointLights {
+    count: u32,
+    lights: array<PointLight, #{MAX_POINT_LIGHTS}>,
+};
 
 struct DirectLightningInfo {
     color: vec3<f32>,
     shadow: f32,
 };
 
-fn calculate_direct(sun_dir: vec3<f32>, sky_color: vec3<f32>, material: vec4<f32>, pos: vec3<f32>, normal: vec3<f32>, seed: vec3<u32>, shadow_samples: u32) -> DirectLightningInfo {
+fn calculate_direct(sun_dir: vec3<f32>, sky_color: vec3<f32>, material: vec4<f32>, pos: vec3<f32>, normal: vec3<f32>, seed: vec3<u32>, sample: u32, shadow_samples: u32) -> DirectLightningInfo {
     // Diffuse
     let diffuse = max(dot(normal, -normalize(sun_dir)), 0.0);
 
@@ -39,9 +95,23 @@ fn calculate_direct(sun_dir: vec3<f32>, sky_color: vec3<f32>, material: vec4<f32
     var shadow = 1.0;
 
     if trace_uniforms.shadows != 0u {
-        let shadow_ray = Ray(pos, -sun_dir);
-        let shadow_hit = shoot_ray(shadow_ray, 0.0, 0u);
-        shadow = f32(!shadow_hit.hit);
+        // a single sample can't resolve a penumbra without noise, keep it hard. Supersampled
+        // pixels jitter one shadow ray per primary ray and average them
+        let softness = trace_uniforms.shadow_softness;
+        if softness > 0.0 && trace_uniforms.samples > 1u {
+            var lit = 0.0;
+            for (var i = 0u; i < shadow_samples; i += 1u) {
+                let u = sample_point(sample + i, 1u, seed + i * 7919u);
+                let shadow_dir = uniform_cone(-normalize(sun_dir), softness, u);
+                let shadow_hit = shoot_ray(Ray(pos, shadow_dir), 0.0, 0u);
+                lit += f32(!shadow_hit.hit);
+            }
+            shadow = lit / f32(shadow_samples);
+        } else {
+            let shadow_ray = Ray(pos, -sun_dir);
+            let shadow_hit = shoot_ray(shadow_ray, 0.0, 0u);
+            shadow = f32(!shadow_hit.hit);
+        }
     }
 
     // Emissive
@@ -55,6 +125,40 @@ fn calculate_direct(sun_dir: vec3<f32>, sky_color: vec3<f32>, material: vec4<f32
     return DirectLightningInfo(color, shadow);
 }
 
+fn calculate_point_lights(pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var color = vec3(0.0);
+
+    for (var i = 0u; i < point_lights.count; i += 1u) {
+        let light = point_lights.lights[i];
+        let to_light = light.position - pos;
+        let distance = length(to_light);
+        if distance >= light.range || distance == 0.0 {
+            continue;
+        }
+
+        let light_dir = to_light / distance;
+        let diffuse = max(dot(normal, light_dir), 0.0);
+        if diffuse == 0.0 {
+            continue;
+        }
+
+        // Shadow
+        if trace_uniforms.shadows != 0u {
+            let shadow_hit = shoot_ray(Ray(pos, light_dir), distance, 0u);
+            if shadow_hit.hit {
+                continue;
+            }
+        }
+
+        // smooth falloff that reaches zero at the light's range
+        let falloff = pow(clamp(1.0 - pow(distance / light.range, 4.0), 0.0, 1.0), 2.0) / (distance * distance + 1.0);
+
+        color += light.color * light.intensity * diffuse * falloff;
+    }
+
+    return color;
+}
+
 fn get_voxel(pos: vec3<f32>) -> f32 {
     if any(pos < vec3(0.0)) || any(pos >= vec3(f32(voxel_uniforms.texture_size))) {
         return 0.0;
@@ -107,55 +211,258 @@ fn calculate_sun_progress(sun_dir: vec3<f32>) -> f32 {
     return 0.2;
 }
 
-@fragment
-fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
-    let seed = vec3<u32>(in.position.xyz) * 100u + u32(trace_uniforms.time * 120.0) * 15236u;
-    let resolution = vec2<f32>(textureDimensions(normal));
-    var clip_space = vec2(1.0, -1.0) * (in.uv * 2.0 - 1.0);
-    var output_color = vec3(0.0);
+fn sky(dir: vec3<f32>, time_of_day: f32) -> vec3<f32> {
+    if trace_uniforms.skybox != 0u {
+        return textureSampleLevel(skybox_texture, skybox_sampler, dir, 0.0).rgb;
+    }
+    return skybox(dir, time_of_day).sky_color;
+}
+
+// Light reaching a hit, the shaded color is this times the material color. The shadow rays
+// are the samples from `sample` on
+fn lighting(hit: HitInfo, dir: vec3<f32>, time_of_day: f32, seed: vec3<u32>, sample: u32, shadow_samples: u32) -> vec3<f32> {
+    let skybox_info = skybox(dir, time_of_day);
+    // the ambient occlusion is sampled along the voxel faces, so only direct light is shaded
+    // with the mesh normals of voxelized meshes
+    let shading_normal = surface_normal(hit);
+
+    // Direct lighting
+    let direct_lighting = calculate_direct(skybox_info.sun_dir, skybox_info.sky_color, hit.material, hit.pos, shading_normal, seed + 1u, sample, shadow_samples);
+
+    // Indirect lighting
+    let texture_coords = (hit.pos - voxel_uniforms.origin) * voxel_uniforms.voxels_per_meter + f32(voxel_uniforms.texture_size) / 2.0;
+    let ao = voxel_ao(texture_coords, hit.normal.zxy, hit.normal.yzx);
+    let uv = glmod(vec2(dot(hit.normal * texture_coords.yzx, vec3(1.0)), dot(hit.normal * texture_coords.zxy, vec3(1.0))), vec2(1.0));
+
+    let interpolated_ao_pweig = mix(mix(ao.z, ao.w, uv.x), mix(ao.y, ao.x, uv.x), uv.y);
+    let voxel_ao = pow(interpolated_ao_pweig, 1.0 / 3.0);
+    let indirect_lighting_color = vec3(0.3 * voxel_ao);
+
+    let sun_progress = calculate_sun_progress(skybox_info.sun_dir);
 
+    let point_lighting = calculate_point_lights(hit.pos, shading_normal);
+
+    return (indirect_lighting_color + direct_lighting.color * trace_uniforms.sun_intensity) * sun_progress + point_lighting;
+}
+
+fn shade(hit: HitInfo, dir: vec3<f32>, time_of_day: f32, seed: vec3<u32>, sample: u32) -> vec3<f32> {
+    if !hit.hit {
+        return sky(dir, time_of_day);
+    }
+
+    return hit.material.rgb * lighting(hit, dir, time_of_day, seed, sample, 1u);
+}
+
+// Ray through a point of the viewport in clip space
+fn camera_ray(clip_space: vec2<f32>) -> Ray {
+    // unproject the pixel at the near plane and further in, the rays converge on the camera
+    // for perspective projections and stay parallel for orthographic ones
     let pos1 = trace_uniforms.camera_inverse * vec4(clip_space.x, clip_space.y, 1.0, 1.0);
     let dir1 = trace_uniforms.camera_inverse * vec4(clip_space.x, clip_space.y, 0.01, 1.0);
     let pos = pos1.xyz / pos1.w;
     let dir = normalize(dir1.xyz / dir1.w - pos);
-    var ray = Ray(pos, dir);
-
-    let hit = shoot_ray(ray, 0.0, 0u);
-    var steps = hit.steps;
+    // skip the voxels right at the camera
+    return Ray(pos + dir * trace_uniforms.ray_near, dir);
+}
 
-    let timespan = 1.0;
-    let w = clamp((trace_uniforms.time * timespan + 12.0) % 24.0, 0.0, 24.0);
-    let skybox_info = skybox(ray.dir, w);
+// Primary ray from camera_ray, the march stops at the far clip and what's past it is a miss
+fn shoot_primary_ray(ray: Ray) -> HitInfo {
+    if trace_uniforms.ray_far <= 0.0 {
+        return shoot_ray_through(ray, 0.0, 0u, trace_uniforms.max_transparency_steps);
+    }
 
-    var samples = 0.0;
+    // the ray already starts at the near clip
+    let far = max(trace_uniforms.ray_far - trace_uniforms.ray_near, 0.0001);
+    var hit = shoot_ray_through(ray, far, 0u, trace_uniforms.max_transparency_steps);
     if hit.hit {
-        // Direct lighting
-        let direct_lighting = calculate_direct(skybox_info.sun_dir, skybox_info.sky_color, hit.material, hit.pos, hit.normal, seed + 1u, trace_uniforms.samples);
+        return hit;
+    }
 
-        // Indirect lighting
-        let texture_coords = hit.pos * VOXELS_PER_METER + f32(voxel_uniforms.texture_size) / 2.0;
-        let ao = voxel_ao(texture_coords, hit.normal.zxy, hit.normal.yzx);
-        let uv = glmod(vec2(dot(hit.normal * texture_coords.yzx, vec3(1.0)), dot(hit.normal * texture_coords.zxy, vec3(1.0))), vec2(1.0));
+    // limited rays don't look for the ground outside of the world
+    let wtr = voxel_uniforms.voxels_per_meter * 2.0 / f32(voxel_uniforms.texture_size);
+    var ground = intersect_scene(Ray((ray.pos - voxel_uniforms.origin) * wtr, ray.dir), hit.steps);
+    if ground.hit && distance(ground.pos, ray.pos) < far {
+        ground.transmittance = hit.transmittance;
+        return ground;
+    }
 
-        let interpolated_ao_pweig = mix(mix(ao.z, ao.w, uv.x), mix(ao.y, ao.x, uv.x), uv.y);
-        let voxel_ao = pow(interpolated_ao_pweig, 1.0 / 3.0);
-        let indirect_lighting_color = vec3(0.3 * voxel_ao);
+    // same as the misses of unlimited rays
+    let infinity = 1000000000.0 * ray.dir;
+    hit.pos = infinity;
+    hit.reprojection_pos = infinity;
+    hit.normal = vec3(0.0);
+    return hit;
+}
 
-        let sun_progress = calculate_sun_progress(skybox_info.sun_dir);
+struct TracedRay {
+    hit: HitInfo,
+    // the hit that is shaded after following reflective surfaces
+    shaded_hit: HitInfo,
+    shaded_dir: vec3<f32>,
+    tint: vec3<f32>,
+};
 
-        output_color = (indirect_lighting_color + direct_lighting.color) * hit.material.rgb * sun_progress;
-    } else {
-        output_color = skybox_info.sky_color;
+fn trace_ray(ray: Ray) -> TracedRay {
+    let hit = shoot_primary_ray(ray);
+
+    // Follow reflective surfaces, tinting by each mirror's color and the transparent voxels
+    // the rays went through
+    var shaded_hit = hit;
+    var shaded_dir = ray.dir;
+    var tint = hit.transmittance;
+    for (var i = 0u; i < trace_uniforms.reflection_bounces; i += 1u) {
+        if !shaded_hit.hit || !is_reflective(shaded_hit.data) {
+            break;
+        }
+
+        tint *= shaded_hit.material.rgb;
+        shaded_dir = reflect((shaded_hit.portals * vec4(shaded_dir, 0.0)).xyz, shaded_hit.normal);
+        shaded_hit = shoot_ray_through(Ray(shaded_hit.pos, shaded_dir), 0.0, 0u, trace_uniforms.max_transparency_steps);
+        tint *= shaded_hit.transmittance;
     }
 
-    if trace_uniforms.show_ray_steps != 0u {
-        output_color = vec3<f32>(f32(steps) / 100.0);
+    return TracedRay(hit, shaded_hit, shaded_dir, tint);
+}
+
+#ifndef LIGHTING_PASS
+// Exposed color and alpha of one primary ray, rays that miss everything show the background
+// instead of the sky
+fn shade_ray(traced: TracedRay, uv: vec2<f32>, time_of_day: f32, seed: vec3<u32>, sample: u32) -> vec4<f32> {
+    if !traced.hit.hit && trace_uniforms.background_mode != 0u {
+        return trace_uniforms.background;
     }
 
-    output_color = max(output_color, vec3(0.0));
+#ifdef LIGHTING_UPSAMPLE
+    // the lighting was rendered below the trace resolution, filter it back up
+    var shaded = sky(traced.shaded_dir, time_of_day);
+    if traced.shaded_hit.hit {
+        let upsampled = textureSampleLevel(lighting_texture, lighting_sampler, uv, 0.0).rgb;
+        shaded = traced.shaded_hit.material.rgb * upsampled;
+    }
+#else
+    let shaded = shade(traced.shaded_hit, traced.shaded_dir, time_of_day, seed, sample);
+#endif
+
+    return vec4(traced.tint * shaded * trace_uniforms.exposure, 1.0);
+}
+#endif
+
+#ifdef TONEMAP_IN_SHADER
+// Stephen Hill's fit of the aces transforms, matching `Tonemapping::AcesFitted`
+fn aces_fitted(color: vec3<f32>) -> vec3<f32> {
+    let rgb_to_rrt = mat3x3<f32>(
+        vec3(0.59719, 0.35458, 0.04823),
+        vec3(0.07600, 0.90834, 0.01566),
+        vec3(0.02840, 0.13383, 0.83777),
+    );
+    let odt_to_rgb = mat3x3<f32>(
+        vec3(1.60475, -0.53108, -0.07367),
+        vec3(-0.10208, 1.10813, -0.00605),
+        vec3(-0.00327, -0.07276, 1.07602),
+    );
+
+    let v = color * rgb_to_rrt;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+    return saturate((a / b) * odt_to_rgb);
+}
 
-    textureStore(normal, vec2<i32>(in.position.xy), vec4(hit.normal, 0.0));
-    textureStore(position, vec2<i32>(in.position.xy), vec4(hit.reprojection_pos, 0.0));
+// The camera's `Tonemapping` when it needs no lookup table, aces otherwise
+fn tonemap(color: vec3<f32>) -> vec3<f32> {
+#ifdef TONEMAP_METHOD_NONE
+    return saturate(color);
+#else ifdef TONEMAP_METHOD_REINHARD
+    return color / (1.0 + color);
+#else ifdef TONEMAP_METHOD_REINHARD_LUMINANCE
+    let luminance = dot(color, vec3(0.2126, 0.7152, 0.0722));
+    return color * (1.0 / (1.0 + luminance));
+#else
+    return aces_fitted(color);
+#endif
+}
+#endif
 
-    return vec4<f32>(output_color, 1.0);
-}
\ No newline at end of file
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let seed = vec3<u32>(in.position.xyz) * 100u + u32(trace_uniforms.time * 120.0) * 15236u;
+    pixel_seed = vec3<u32>(in.position.xyz) * 100u + trace_uniforms.seed;
+    let resolution = vec2<f32>(textureDimensions(normal));
+    var clip_space = vec2(1.0, -1.0) * (in.uv * 2.0 - 1.0);
+    var output_color = vec3(0.0);
+
+    let ray = camera_ray(clip_space);
+    let pos = ray.pos - ray.dir * trace_uniforms.ray_near;
+    let traced = trace_ray(ray);
+    let hit = traced.hit;
+    let steps = hit.steps;
+
+    let timespan = 1.0;
+    let w = clamp((trace_uniforms.time * timespan + 12.0) % 24.0, 0.0, 24.0);
+
+#ifdef LIGHTING_PASS
+    // only the lighting is written, the composite shades misses and materials per pixel
+    var lighting_color = vec3(0.0);
+    if traced.shaded_hit.hit {
+        lighting_color = lighting(traced.shaded_hit, traced.shaded_dir, w, seed, 0u, trace_uniforms.samples);
+    }
+    return vec4(lighting_color, 1.0);
+#else
+    // the first ray goes through the pixel center, the others are jittered within the pixel
+    // and averaged for anti-aliasing
+    var shaded = shade_ray(traced, in.uv, w, seed, 0u);
+    var total_steps = steps;
+    var max_steps = steps;
+    for (var i = 1u; i < trace_uniforms.samples; i += 1u) {
+        let sample_seed = seed + i * 7919u;
+        let jitter = (sample_point(i, 0u, sample_seed) - 0.5) / resolution;
+        let sample_traced = trace_ray(camera_ray(clip_space + vec2(2.0, -2.0) * jitter));
+        shaded += shade_ray(sample_traced, in.uv + jitter, w, sample_seed, i);
+        total_steps += sample_traced.hit.steps;
+        max_steps = max(max_steps, sample_traced.hit.steps);
+    }
+    shaded /= f32(max(trace_uniforms.samples, 1u));
+
+#ifdef TRACE_STATS
+    atomicAdd(&trace_stats.rays, max(trace_uniforms.samples, 1u));
+    atomicAdd(&trace_stats.total_steps, total_steps);
+    atomicMax(&trace_stats.max_steps, max_steps);
+#endif
+
+    var output_alpha = 1.0;
+    switch trace_uniforms.debug_view {
+        // ray steps
+        case 1u: {
+            output_color = vec3<f32>(f32(steps) / 100.0);
+        }
+        // normals
+        case 2u: {
+            output_color = surface_normal(hit) * 0.5 + 0.5;
+        }
+        // world position
+        case 3u: {
+            output_color = fract(hit.pos);
+        }
+        // depth, white near the camera
+        case 4u: {
+            output_color = vec3(f32(hit.hit) * (1.0 - min(length(hit.reprojection_pos - pos) / 100.0, 1.0)));
+        }
+        default: {
+            output_color = shaded.rgb;
+            output_alpha = shaded.a;
+        }
+    }
+
+    output_color = max(output_color, vec3(0.0));
+#ifdef TONEMAP_IN_SHADER
+    // 8 bit targets skip the tonemapping pass
+    output_color = tonemap(output_color);
+#endif
+
+    // the attachments cover the camera's viewport rather than the whole target
+    let attachment_pixel = vec2<i32>(in.uv * resolution);
+    textureStore(normal, attachment_pixel, vec4(hit.normal, 0.0));
+    textureStore(position, attachment_pixel, vec4(hit.reprojection_pos, 0.0));
+
+    return vec4<f32>(output_color, output_alpha);
+#endif
+}
@@ -1,8 +1,11 @@
 use crate::{
-    load::{Pallete, GH},
-    LoadVoxelWorld,
+    load::{VoxelFormat, VoxelPalette, GH},
+    physics::AnimationClock,
+    Flags, LoadVoxelWorld, VoxelWorldLoaded, VOXELS_PER_METER,
 };
 use bevy::{
+    ecs::{archetype::Archetypes, entity::Entities},
+    gizmos::GizmoPlugin,
     prelude::*,
     render::{
         Render, RenderApp, RenderSet,
@@ -11,21 +14,80 @@ use bevy::{
         renderer::{RenderDevice, RenderQueue},
     },
 };
-use std::sync::Arc;
+use crossbeam_channel::{Receiver, Sender};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
 
 pub struct VoxelWorldPlugin;
 
+/// Size and scale of the voxel world, insert before adding the engine plugin
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct VoxelWorldSettings {
+    /// Voxels along each axis of the empty world created at startup, a power of two between
+    /// 8 and 2048
+    pub texture_size: u32,
+    /// Voxels per world unit, can be changed at runtime
+    pub voxels_per_meter: f32,
+    /// Keeps the mesh normals of voxels written by a [`VoxelizationMaterial`] with `normals`
+    /// for smooth shading, takes 4 more bytes per voxel
+    ///
+    /// [`VoxelizationMaterial`]: crate::VoxelizationMaterial
+    pub surface_normals: bool,
+    /// Keeps the exact rgb of voxels voxelized with a `Color`, `Texture` or `VertexColor`
+    /// `VoxelizationMaterialType` instead of only showing their palette material, takes 4
+    /// more bytes per voxel. Alpha isn't stored: it only decides which voxels are written
+    /// through `alpha_cutoff` and `dither`, and how transparent a voxel is still comes from
+    /// the [`TransparentMaterials`] entry of its material
+    pub voxel_colors: bool,
+    /// Pixels along each side of the image meshes are rasterized into for voxelization,
+    /// `None` for one per voxel. Lower resolutions are cheaper but leave holes in thin
+    /// surfaces, higher ones supersample them. Can be changed at runtime
+    pub voxelization_resolution: Option<u32>,
+}
+
+impl Default for VoxelWorldSettings {
+    fn default() -> Self {
+        Self {
+            texture_size: 256,
+            voxels_per_meter: VOXELS_PER_METER,
+            surface_normals: false,
+            voxel_colors: false,
+            voxelization_resolution: None,
+        }
+    }
+}
+
 impl Plugin for VoxelWorldPlugin {
-    fn build(&self, _app: &mut App) {}
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VoxelWorldSettings>()
+            .init_resource::<VoxelWorldDebug>();
+        if app.is_plugin_added::<GizmoPlugin>() {
+            app.add_systems(PostUpdate, draw_voxel_world_bounds);
+        }
+    }
 
     fn finish(&self, app: &mut App) {
         let render_device = app.sub_app(RenderApp).world.resource::<RenderDevice>();
 
         let render_queue = app.sub_app(RenderApp).world.resource::<RenderQueue>();
 
-        let gh = GH::empty(256);
+        let settings = *app.world.resource::<VoxelWorldSettings>();
+        let texture_size = validate_texture_size(settings.texture_size, render_device);
+        let voxels_per_meter = validate_voxels_per_meter(settings.voxels_per_meter);
+        let gh = GH::empty(texture_size);
+        let (save_sender, save_receiver) = crossbeam_channel::unbounded();
+        let (loaded_sender, loaded_receiver) = crossbeam_channel::unbounded();
+        let cpu_voxel_world = VoxelWorld {
+            gh: Arc::new(gh.clone()),
+            edits: Vec::new(),
+            edited_regions: Vec::new(),
+            save_sender,
+            voxels_per_meter,
+            origin: Vec3::ZERO,
+        };
         let buffer_size = gh.get_buffer_size();
-        let texture_size = gh.texture_size;
         let gh_offsets = gh.get_offsets();
 
         let mut levels = [UVec4::ZERO; 8];
@@ -37,11 +99,22 @@ impl Plugin for VoxelWorldPlugin {
     
         // Uniforms
         let voxel_uniforms = VoxelUniforms {
-            pallete: gh.pallete.into(),
+            pallete: gh.pallete.clone().into(),
             portals: [ExtractedPortal::default(); 32],
             levels,
             offsets,
+            reflective_materials: [UVec4::ZERO; 2],
+            material_densities: [Vec4::ZERO; 64],
+            material_alphas: [Vec4::ONE; 64],
+            fluid_materials: 0,
             texture_size,
+            surface_normals: settings.surface_normals as u32,
+            voxel_colors: settings.voxel_colors as u32,
+            voxels_per_meter,
+            origin: Vec3::ZERO,
+            time: 0.0,
+            palette_animation_count: 0,
+            palette_animations: [ExtractedMaterialAnimation::default(); MAX_PALETTE_ANIMATIONS],
         };
         let mut uniform_buffer = UniformBuffer::from(voxel_uniforms.clone());
         uniform_buffer.write_buffer(&render_device, &render_queue);
@@ -60,13 +133,22 @@ impl Plugin for VoxelWorldPlugin {
                 sample_count: 1,
                 dimension: TextureDimension::D3,
                 format: TextureFormat::R16Uint,
-                usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_DST,
+                usage: TextureUsages::STORAGE_BINDING
+                    | TextureUsages::COPY_DST
+                    | TextureUsages::COPY_SRC,
                 view_formats: &[],
             },
             TextureDataOrder::default(),
             &gh.texture_data.clone(),
         );
-        let voxel_world = voxel_world.create_view(&TextureViewDescriptor::default());
+        let voxel_world_texture = voxel_world;
+        let voxel_world = voxel_world_texture.create_view(&TextureViewDescriptor::default());
+        let voxel_normals_texture =
+            create_normals_texture(render_device, texture_size, settings.surface_normals);
+        let voxel_normals = voxel_normals_texture.create_view(&TextureViewDescriptor::default());
+        let voxel_colors_texture =
+            create_colors_texture(render_device, texture_size, settings.voxel_colors);
+        let voxel_colors = voxel_colors_texture.create_view(&TextureViewDescriptor::default());
 
         // Storage
         let grid_hierarchy = render_device.create_buffer_with_data(&BufferInitDescriptor {
@@ -123,6 +205,26 @@ impl Plugin for VoxelWorldPlugin {
                         ty: BindingType::Sampler(SamplerBindingType::Filtering),
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::VERTEX_FRAGMENT | ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadWrite,
+                            format: TextureFormat::R32Uint,
+                            view_dimension: TextureViewDimension::D3,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: ShaderStages::VERTEX_FRAGMENT | ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadWrite,
+                            format: TextureFormat::R32Uint,
+                            view_dimension: TextureViewDimension::D3,
+                        },
+                        count: None,
+                    },
                 ],
             );
 
@@ -146,22 +248,61 @@ impl Plugin for VoxelWorldPlugin {
                     binding: 3,
                     resource: BindingResource::Sampler(&texture_sampler),
                 },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(&voxel_normals),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::TextureView(&voxel_colors),
+                },
             ],
         );
 
         app.insert_resource(LoadVoxelWorld::None)
             .insert_resource(NewGH::None)
+            .insert_resource(LoadedGH(None))
+            .insert_resource(VoxelWorldLoadedReceiver(loaded_receiver))
+            .add_event::<VoxelWorldLoaded>()
+            .insert_resource(cpu_voxel_world)
+            .insert_resource(gh.pallete)
+            .init_resource::<VoxelEdits>()
             .insert_resource(voxel_uniforms)
+            .init_resource::<ReflectiveMaterials>()
+            .init_resource::<MaterialDensities>()
+            .init_resource::<TransparentMaterials>()
+            .init_resource::<PaletteAnimation>()
             .add_plugins(ExtractResourcePlugin::<NewGH>::default())
+            .add_plugins(ExtractResourcePlugin::<LoadedGH>::default())
             .add_plugins(ExtractResourcePlugin::<VoxelUniforms>::default())
-            .add_systems(Update, load_voxel_world);
+            .add_plugins(ExtractResourcePlugin::<VoxelEdits>::default())
+            .add_plugins(ExtractResourcePlugin::<VoxelPalette>::default())
+            .add_systems(
+                Update,
+                (
+                    (load_voxel_world, update_voxel_palette, update_palette_animation).chain(),
+                    update_reflective_materials,
+                    update_material_densities,
+                    update_transparent_materials,
+                    update_voxels_per_meter,
+                ),
+            )
+            .add_systems(PreUpdate, send_voxel_world_loaded)
+            .add_systems(PostUpdate, (apply_voxel_edit_components, take_voxel_edits).chain());
 
         let render_app = app.sub_app_mut(RenderApp);
 
         render_app
             .insert_resource(VoxelData {
                 uniform_buffer,
+                voxel_world_texture,
                 voxel_world,
+                voxel_normals_texture,
+                voxel_normals,
+                surface_normals: settings.surface_normals,
+                voxel_colors_texture,
+                voxel_colors,
+                direct_colors: settings.voxel_colors,
                 grid_hierarchy,
                 texture_sampler,
                 bind_group_layout,
@@ -169,14 +310,118 @@ impl Plugin for VoxelWorldPlugin {
             })
             .add_systems(Render, prepare_uniforms.in_set(RenderSet::Prepare))
             .add_systems(Render, load_voxel_world_prepare.in_set(RenderSet::Prepare))
-            .add_systems(Render, queue_bind_group.in_set(RenderSet::Queue));
+            .add_systems(
+                Render,
+                write_voxel_edits
+                    .in_set(RenderSet::Prepare)
+                    .after(load_voxel_world_prepare),
+            )
+            .insert_resource(VoxelWorldSaves {
+                requests: save_receiver,
+                pending: Vec::new(),
+            })
+            .insert_resource(VoxelWorldLoadedSender(loaded_sender))
+            .add_systems(Render, queue_bind_group.in_set(RenderSet::Queue))
+            .add_systems(Render, notify_voxel_world_loaded.in_set(RenderSet::Cleanup))
+            .add_systems(Render, save_voxel_world.in_set(RenderSet::Cleanup));
+    }
+}
+
+/// Non positive scales would flip or collapse the world
+fn validate_voxels_per_meter(voxels_per_meter: f32) -> f32 {
+    if voxels_per_meter > 0.0 && voxels_per_meter.is_finite() {
+        voxels_per_meter
+    } else {
+        warn!(
+            "Voxels per meter {} must be positive, using {}",
+            voxels_per_meter, VOXELS_PER_METER
+        );
+        VOXELS_PER_METER
+    }
+}
+
+/// Normals packed with the material they were written for, see `pack_normal` in the
+/// voxelization shader. A single texel when the normals aren't kept
+fn create_normals_texture(
+    render_device: &RenderDevice,
+    texture_size: u32,
+    surface_normals: bool,
+) -> Texture {
+    let size = if surface_normals { texture_size } else { 1 };
+    render_device.create_texture(&TextureDescriptor {
+        label: Some("voxel normals texture"),
+        size: Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: size,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D3,
+        format: TextureFormat::R32Uint,
+        usage: TextureUsages::STORAGE_BINDING,
+        view_formats: &[],
+    })
+}
+
+/// Colors packed with the material they were written for, see `pack_color` in the common
+/// shader. A single texel when the colors aren't kept
+fn create_colors_texture(
+    render_device: &RenderDevice,
+    texture_size: u32,
+    voxel_colors: bool,
+) -> Texture {
+    let size = if voxel_colors { texture_size } else { 1 };
+    render_device.create_texture(&TextureDescriptor {
+        label: Some("voxel colors texture"),
+        size: Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: size,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D3,
+        format: TextureFormat::R32Uint,
+        usage: TextureUsages::STORAGE_BINDING,
+        view_formats: &[],
+    })
+}
+
+/// The grid hierarchy has 8 levels starting at a size of 8
+fn validate_texture_size(texture_size: u32, render_device: &RenderDevice) -> u32 {
+    let mut size = texture_size.clamp(8, 2048).next_power_of_two();
+    if size != texture_size {
+        warn!(
+            "Voxel world size {} must be a power of two between 8 and 2048, using {}",
+            texture_size, size
+        );
+    }
+
+    let max_size = render_device.limits().max_texture_dimension_3d;
+    if size > max_size {
+        size = 1 << max_size.ilog2();
+        warn!(
+            "Voxel world size exceeds the gpu's max 3d texture size of {}, using {}",
+            max_size, size
+        );
     }
+
+    size
 }
 
 #[derive(Resource)]
 pub struct VoxelData {
     pub uniform_buffer: UniformBuffer<VoxelUniforms>,
+    pub voxel_world_texture: Texture,
     pub voxel_world: TextureView,
+    pub voxel_normals_texture: Texture,
+    pub voxel_normals: TextureView,
+    pub surface_normals: bool,
+    pub voxel_colors_texture: Texture,
+    pub voxel_colors: TextureView,
+    /// See [`VoxelWorldSettings::voxel_colors`]
+    pub direct_colors: bool,
     pub grid_hierarchy: Buffer,
     pub texture_sampler: Sampler,
     pub bind_group_layout: BindGroupLayout,
@@ -188,16 +433,53 @@ pub struct PalleteEntry {
     pub colour: Vec4,
 }
 
-impl Into<[PalleteEntry; 256]> for Pallete {
+/// The alpha channel marks emissive materials for the trace shader
+fn pallete_colour(color: Vec3, emission: f32) -> Vec4 {
+    if emission > 0.0 {
+        (color * (1.0 + emission)).extend(1.0)
+    } else {
+        color.extend(0.0)
+    }
+}
+
+impl Into<[PalleteEntry; 256]> for VoxelPalette {
     fn into(self) -> [PalleteEntry; 256] {
         let mut pallete = [PalleteEntry::default(); 256];
         for i in 0..256 {
-            pallete[i].colour = self[i].into();
+            pallete[i].colour = pallete_colour(self[i].color, self[i].emission);
         }
         pallete
     }
 }
 
+pub const MAX_PALETTE_ANIMATIONS: usize = 16;
+pub const MAX_PALETTE_ANIMATION_COLORS: usize = 4;
+
+/// Cycles the color of `material` through `colors` every `period` seconds, blending linearly
+/// and wrapping back to the first color. The emission of the material in the
+/// [`VoxelPalette`] is kept
+#[derive(Clone, Debug)]
+pub struct MaterialAnimation {
+    pub material: u8,
+    /// Linear rgb, at most [`MAX_PALETTE_ANIMATION_COLORS`] are used
+    pub colors: Vec<Vec3>,
+    pub period: f32,
+}
+
+/// Material colors animated over time by the trace shader without editing voxels, e.g. for
+/// glowing lava or shimmering water. Material 0 and animations without colors are ignored,
+/// at most [`MAX_PALETTE_ANIMATIONS`] are used
+#[derive(Resource, Clone, Default, Deref, DerefMut)]
+pub struct PaletteAnimation(pub Vec<MaterialAnimation>);
+
+#[derive(Default, Debug, Clone, Copy, ShaderType)]
+pub struct ExtractedMaterialAnimation {
+    pub colors: [Vec4; MAX_PALETTE_ANIMATION_COLORS],
+    pub material: u32,
+    pub color_count: u32,
+    pub period: f32,
+}
+
 #[derive(Default, Debug, Clone, Copy, ShaderType)]
 pub struct ExtractedPortal {
     pub transformation: Mat4,
@@ -211,15 +493,892 @@ pub struct VoxelUniforms {
     pub portals: [ExtractedPortal; 32],
     pub levels: [UVec4; 8],
     pub offsets: [UVec4; 8],
+    /// Bitset of material ids, see [`ReflectiveMaterials`]
+    pub reflective_materials: [UVec4; 2],
+    /// See [`MaterialDensities`], packed 4 per vector
+    pub material_densities: [Vec4; 64],
+    /// See [`TransparentMaterials`], packed 4 per vector
+    pub material_alphas: [Vec4; 64],
+    /// Materials with a density, the physics pass skips buoyancy when there are none
+    pub fluid_materials: u32,
     pub texture_size: u32,
+    /// See [`VoxelWorldSettings::surface_normals`]
+    pub surface_normals: u32,
+    /// See [`VoxelWorldSettings::voxel_colors`]
+    pub voxel_colors: u32,
+    /// See [`VoxelWorldSettings::voxels_per_meter`]
+    pub voxels_per_meter: f32,
+    /// See [`VoxelWorld::origin`]
+    pub origin: Vec3,
+    /// Seconds since startup, only updated while there is a [`PaletteAnimation`]
+    pub time: f32,
+    pub palette_animation_count: u32,
+    pub palette_animations: [ExtractedMaterialAnimation; MAX_PALETTE_ANIMATIONS],
+}
+
+/// Converts a world position to the voxel containing it, matching voxelization
+pub fn world_to_voxel(world_pos: Vec3, voxel_uniforms: &VoxelUniforms) -> IVec3 {
+    let size = voxel_uniforms.texture_size as f32;
+    ((world_pos - voxel_uniforms.origin) * voxel_uniforms.voxels_per_meter + size / 2.0)
+        .floor()
+        .as_ivec3()
+}
+
+/// World position of the center of the voxel, the inverse of [`world_to_voxel`]
+pub fn voxel_to_world(voxel_pos: IVec3, voxel_uniforms: &VoxelUniforms) -> Vec3 {
+    let size = voxel_uniforms.texture_size as f32;
+    (voxel_pos.as_vec3() + 0.5 - size / 2.0) / voxel_uniforms.voxels_per_meter
+        + voxel_uniforms.origin
+}
+
+/// Material ids that reflect rays instead of being shaded as diffuse, tinted by the material color
+#[derive(Resource, Clone, Deref, DerefMut)]
+pub struct ReflectiveMaterials(pub [bool; 256]);
+
+impl Default for ReflectiveMaterials {
+    fn default() -> Self {
+        Self([false; 256])
+    }
+}
+
+/// Density of each material id relative to [`VoxelPhysics::density`](crate::VoxelPhysics),
+/// water being 1. Bodies overlapping voxels of materials with a density are pushed up and
+/// slowed down by them, 0 for materials that aren't fluids
+#[derive(Resource, Clone, Deref, DerefMut)]
+pub struct MaterialDensities(pub [f32; 256]);
+
+impl Default for MaterialDensities {
+    fn default() -> Self {
+        Self([0.0; 256])
+    }
+}
+
+/// Alpha of each material id, 1 for opaque materials. The trace continues rays through the
+/// voxels of materials below 1, each voxel crossed tints the light behind it by the material
+/// color in proportion to the alpha, up to [`TraceSettings::max_transparency_steps`](
+/// crate::TraceSettings::max_transparency_steps) voxels. Shadow rays still stop at them
+#[derive(Resource, Clone, Deref, DerefMut)]
+pub struct TransparentMaterials(pub [f32; 256]);
+
+impl Default for TransparentMaterials {
+    fn default() -> Self {
+        Self([1.0; 256])
+    }
+}
+
+/// Draws the bounds of the voxel world with gizmos, meshes outside of them voxelize to
+/// nothing. Requires bevy's `GizmoPlugin`, which `DefaultPlugins` includes
+#[derive(Resource, Clone)]
+pub struct VoxelWorldDebug {
+    pub enabled: bool,
+    pub color: Color,
+}
+
+impl Default for VoxelWorldDebug {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            color: Color::YELLOW,
+        }
+    }
+}
+
+/// The world is a cube of `texture_size` voxels centered on its origin
+fn draw_voxel_world_bounds(
+    voxel_world_debug: Res<VoxelWorldDebug>,
+    voxel_uniforms: Res<VoxelUniforms>,
+    mut gizmos: Gizmos,
+) {
+    if !voxel_world_debug.enabled {
+        return;
+    }
+
+    let size = voxel_uniforms.texture_size as f32 / voxel_uniforms.voxels_per_meter;
+    gizmos.cuboid(
+        Transform::from_translation(voxel_uniforms.origin).with_scale(Vec3::splat(size)),
+        voxel_world_debug.color,
+    );
+}
+
+/// Cpu copy of the loaded voxel world. Edits made on the gpu by physics, automata and
+/// voxelization are not reflected here
+#[derive(Resource, Clone)]
+pub struct VoxelWorld {
+    gh: Arc<GH>,
+    save_sender: Sender<SaveRequest>,
+    edits: Vec<(IVec3, u16)>,
+    /// Inclusive bounds of the regions filled this frame
+    edited_regions: Vec<(IVec3, IVec3)>,
+    voxels_per_meter: f32,
+    origin: Vec3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Voxel {
+    pub material: u8,
+    pub flags: Flags,
+}
+
+/// Voxels copied from a box of the world with [`VoxelWorld::copy_region`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VoxelClipboard {
+    pub size: UVec3,
+    /// Indexed by `(x * size.y + y) * size.z + z`
+    pub voxels: Vec<Voxel>,
+}
+
+impl VoxelClipboard {
+    /// `None` outside the clipboard
+    pub fn get(&self, position: UVec3) -> Option<Voxel> {
+        if position.cmpge(self.size).any() {
+            return None;
+        }
+
+        let index = (position.x * self.size.y + position.y) * self.size.z + position.z;
+        Some(self.voxels[index as usize])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoxelHit {
+    pub position: IVec3,
+    pub normal: IVec3,
+    pub material: u8,
+}
+
+impl VoxelWorld {
+    /// Width of the voxel world in voxels along each axis
+    pub fn size(&self) -> u32 {
+        self.gh.texture_size
+    }
+
+    /// Voxels per world unit, see [`VoxelWorldSettings::voxels_per_meter`]
+    pub fn voxels_per_meter(&self) -> f32 {
+        self.voxels_per_meter
+    }
+
+    /// World position of the center of the voxel world. Zero unless the world is streamed,
+    /// see [`LoadVoxelWorld::Stream`]
+    pub fn origin(&self) -> Vec3 {
+        self.origin
+    }
+
+    fn index(&self, position: IVec3) -> Option<usize> {
+        let size = self.size() as i32;
+        if position.cmplt(IVec3::ZERO).any() || position.cmpge(IVec3::splat(size)).any() {
+            return None;
+        }
+
+        // in usize, the voxel count of a 2048 world overflows i32
+        let dim = size as usize;
+        let [x, y, z] = position.to_array().map(|axis| axis as usize);
+        Some((x * dim * dim + y * dim + z) * 2)
+    }
+
+    /// Raw voxel value (material | flags << 8)
+    fn get_data(&self, position: IVec3) -> Option<u16> {
+        let index = self.index(position)?;
+        let data = &self.gh.texture_data;
+        Some(u16::from_le_bytes([data[index], data[index + 1]]))
+    }
+
+    /// `None` outside the world
+    pub fn get_voxel(&self, position: IVec3) -> Option<Voxel> {
+        let data = self.get_data(position)?;
+        Some(Voxel {
+            material: (data & 0xFF) as u8,
+            flags: Flags::from((data >> 8) as u8),
+        })
+    }
+
+    /// Sets the voxel and uploads it to the gpu at the end of the frame, returns `false`
+    /// without doing anything when outside the world
+    pub fn set_voxel(&mut self, position: IVec3, material: u8, flags: Flags) -> bool {
+        let Some(index) = self.index(position) else {
+            return false;
+        };
+
+        let gh = Arc::make_mut(&mut self.gh);
+        gh.texture_data[index] = material;
+        gh.texture_data[index + 1] = flags.bits();
+
+        self.edits
+            .push((position, material as u16 | (flags.bits() as u16) << 8));
+        true
+    }
+
+    /// Sets every voxel between `min` and `max` inclusive, clamped to the world. The box
+    /// is uploaded to the gpu in a single write at the end of the frame
+    pub fn fill_box(&mut self, min: IVec3, max: IVec3, material: u8, flags: Flags) {
+        self.fill_region(min, max, material, flags, |_| true);
+    }
+
+    /// Sets every voxel whose center is within `radius` meters of the world position
+    /// `center`, clamped to the world. Fill with material 0 to carve out a hole
+    pub fn fill_sphere(&mut self, center: Vec3, radius: f32, material: u8, flags: Flags) {
+        let size = self.size() as f32;
+        let center = (center - self.origin) * self.voxels_per_meter + size / 2.0;
+        let radius = radius * self.voxels_per_meter;
+
+        let min = (center - radius).floor().as_ivec3();
+        let max = (center + radius).floor().as_ivec3();
+        self.fill_region(min, max, material, flags, |position| {
+            (position.as_vec3() + 0.5).distance_squared(center) <= radius * radius
+        });
+    }
+
+    /// Sets every voxel whose center is within `radius` meters of the segment between the
+    /// world positions `from` and `to`, e.g. between a brush's positions on consecutive frames
+    /// so fast strokes don't leave gaps. The stroke is uploaded as a single region
+    pub fn stroke(&mut self, from: Vec3, to: Vec3, radius: f32, material: u8, flags: Flags) {
+        let size = self.size() as f32;
+        let from = (from - self.origin) * self.voxels_per_meter + size / 2.0;
+        let to = (to - self.origin) * self.voxels_per_meter + size / 2.0;
+        let radius = radius * self.voxels_per_meter;
+
+        let min = (from.min(to) - radius).floor().as_ivec3();
+        let max = (from.max(to) + radius).floor().as_ivec3();
+        let segment = to - from;
+        let length_squared = segment.length_squared().max(f32::EPSILON);
+        self.fill_region(min, max, material, flags, |position| {
+            let center = position.as_vec3() + 0.5;
+            let t = ((center - from).dot(segment) / length_squared).clamp(0.0, 1.0);
+            (from + segment * t).distance_squared(center) <= radius * radius
+        });
+    }
+
+    /// Positions and voxels with a nonzero material between `min` and `max` inclusive,
+    /// clamped to the world
+    pub fn voxels_in(&self, min: IVec3, max: IVec3) -> Vec<(IVec3, Voxel)> {
+        let size = self.size() as i32;
+        let min = min.max(IVec3::ZERO);
+        let max = max.min(IVec3::splat(size - 1));
+
+        let mut voxels = Vec::new();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let position = IVec3::new(x, y, z);
+                    let voxel = self.get_voxel(position).unwrap();
+                    if voxel.material != 0 {
+                        voxels.push((position, voxel));
+                    }
+                }
+            }
+        }
+        voxels
+    }
+
+    /// Copies the voxels between `min` and `max` inclusive, clamped to the world
+    pub fn copy_region(&self, min: IVec3, max: IVec3) -> VoxelClipboard {
+        let size = self.size() as i32;
+        let min = min.max(IVec3::ZERO);
+        let max = max.min(IVec3::splat(size - 1));
+        if min.cmpgt(max).any() {
+            return VoxelClipboard::default();
+        }
+
+        let mut voxels = Vec::new();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    voxels.push(self.get_voxel(IVec3::new(x, y, z)).unwrap());
+                }
+            }
+        }
+
+        VoxelClipboard {
+            size: (max - min + 1).as_uvec3(),
+            voxels,
+        }
+    }
+
+    /// Writes the clipboard with its minimum corner at `at`, clipped to the world. Empty
+    /// voxels of the clipboard leave the world untouched when `skip_empty` is set. The
+    /// pasted box is uploaded as a single region
+    pub fn paste_region(&mut self, clipboard: &VoxelClipboard, at: IVec3, skip_empty: bool) {
+        let size = self.size() as i32;
+        let min = at.max(IVec3::ZERO);
+        let max = (at + clipboard.size.as_ivec3() - 1).min(IVec3::splat(size - 1));
+        if min.cmpgt(max).any() {
+            return;
+        }
+
+        let dim = size as usize;
+        let gh = Arc::make_mut(&mut self.gh);
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let position = IVec3::new(x, y, z);
+                    let voxel = clipboard.get((position - at).as_uvec3()).unwrap();
+                    if skip_empty && voxel.material == 0 {
+                        continue;
+                    }
+
+                    let index = (x as usize * dim * dim + y as usize * dim + z as usize) * 2;
+                    gh.texture_data[index] = voxel.material;
+                    gh.texture_data[index + 1] = voxel.flags.bits();
+                }
+            }
+        }
+
+        self.edited_regions.push((min, max));
+    }
+
+    fn fill_region(
+        &mut self,
+        min: IVec3,
+        max: IVec3,
+        material: u8,
+        flags: Flags,
+        inside: impl Fn(IVec3) -> bool,
+    ) {
+        let size = self.size() as i32;
+        let min = min.max(IVec3::ZERO);
+        let max = max.min(IVec3::splat(size - 1));
+        if min.cmpgt(max).any() {
+            return;
+        }
+
+        let dim = size as usize;
+        let gh = Arc::make_mut(&mut self.gh);
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    if !inside(IVec3::new(x, y, z)) {
+                        continue;
+                    }
+
+                    let index = (x as usize * dim * dim + y as usize * dim + z as usize) * 2;
+                    gh.texture_data[index] = material;
+                    gh.texture_data[index + 1] = flags.bits();
+                }
+            }
+        }
+
+        self.edited_regions.push((min, max));
+    }
+
+    /// Replaces the whole cpu copy and moves it to `origin`, the caller uploads it with
+    /// [`NewGH`]
+    pub(crate) fn replace(&mut self, gh: GH, origin: Vec3) -> Arc<GH> {
+        self.gh = Arc::new(gh);
+        self.origin = origin;
+        self.edits.clear();
+        self.edited_regions.clear();
+        self.gh.clone()
+    }
+
+    /// Moves every voxel by `offset` voxels and the world to `origin`, voxels moved outside
+    /// the world are dropped. The caller uploads the world with [`NewGH`]
+    pub(crate) fn shift(&mut self, offset: IVec3, origin: Vec3, palette: &VoxelPalette) -> Arc<GH> {
+        let size = self.size() as i32;
+        let mut gh = GH::empty(size as u32);
+        gh.pallete = palette.clone();
+
+        let min = offset.max(IVec3::ZERO);
+        let max = (offset + size).min(IVec3::splat(size));
+        if min.cmplt(max).all() {
+            let row = (max.z - min.z) as usize * 2;
+            for x in min.x..max.x {
+                for y in min.y..max.y {
+                    let source = self.index(IVec3::new(x, y, min.z) - offset).unwrap();
+                    let target = ((x * size + y) * size + min.z) as usize * 2;
+                    gh.texture_data[target..target + row]
+                        .copy_from_slice(&self.gh.texture_data[source..source + row]);
+                }
+            }
+        }
+
+        self.replace(gh, origin)
+    }
+
+    pub(crate) fn set_origin(&mut self, origin: Vec3) {
+        self.origin = origin;
+    }
+
+    /// Copies raw voxels laid out like the texture data into the box at `min`, uploaded at the
+    /// end of the frame. The box must be inside the world
+    pub(crate) fn write_region(&mut self, min: IVec3, size: UVec3, data: &[u8]) {
+        let dim = self.size() as usize;
+        let row = size.z as usize * 2;
+        let gh = Arc::make_mut(&mut self.gh);
+        for x in 0..size.x as usize {
+            for y in 0..size.y as usize {
+                let source = (x * size.y as usize + y) * row;
+                let (tx, ty) = (min.x as usize + x, min.y as usize + y);
+                let start = (tx * dim * dim + ty * dim + min.z as usize) * 2;
+                gh.texture_data[start..start + row].copy_from_slice(&data[source..source + row]);
+            }
+        }
+
+        self.edited_regions.push((min, min + size.as_ivec3() - 1));
+    }
+
+    /// Reads the voxel world back from the gpu, including edits made by physics and
+    /// automata, and writes it to a MagicaVoxel `.vox` file. `on_complete` is called from
+    /// the render world once the file has been written, with an `InvalidInput` error for
+    /// worlds wider than 256 voxels
+    pub fn save(
+        &self,
+        path: impl Into<String>,
+        on_complete: impl FnOnce(io::Result<()>) + Send + Sync + 'static,
+    ) {
+        let path = path.into();
+        self.read_back(move |gh| {
+            let result = gh.and_then(|gh| {
+                let vox = gh.to_vox()?;
+                let mut file = std::fs::File::create(&path)?;
+                vox.write_vox(&mut file)
+            });
+            on_complete(result);
+        });
+    }
+
+    /// Greedy meshes the cpu copy of the world, see [`VoxelWorld::read_back_mesh`] to include
+    /// the edits made on the gpu
+    pub fn to_mesh(&self) -> Mesh {
+        self.gh.to_mesh(self.voxels_per_meter).translated_by(self.origin)
+    }
+
+    /// Reads the voxel world back from the gpu like [`VoxelWorld::save`] and greedy meshes
+    /// every voxel that isn't animated, e.g. for a static collision mesh. `on_complete` is
+    /// called from the render world with the mesh
+    pub fn read_back_mesh(
+        &self,
+        on_complete: impl FnOnce(io::Result<Mesh>) + Send + Sync + 'static,
+    ) {
+        let (voxels_per_meter, origin) = (self.voxels_per_meter, self.origin);
+        self.read_back(move |gh| {
+            on_complete(gh.map(|gh| gh.to_mesh(voxels_per_meter).translated_by(origin)))
+        });
+    }
+
+    fn read_back(&self, on_complete: impl FnOnce(io::Result<GH>) + Send + Sync + 'static) {
+        let _ = self.save_sender.send(SaveRequest {
+            on_complete: std::boxed::Box::new(on_complete),
+        });
+    }
+
+    /// Converts a world position to the voxel containing it, matching voxelization
+    pub fn world_to_voxel(&self, world_pos: Vec3) -> IVec3 {
+        ((world_pos - self.origin) * self.voxels_per_meter + self.size() as f32 / 2.0)
+            .floor()
+            .as_ivec3()
+    }
+
+    /// World position of the center of the voxel, the inverse of [`VoxelWorld::world_to_voxel`]
+    pub fn voxel_to_world(&self, voxel_pos: IVec3) -> Vec3 {
+        (voxel_pos.as_vec3() + 0.5 - self.size() as f32 / 2.0) / self.voxels_per_meter
+            + self.origin
+    }
+
+    /// Steps through the voxels along the ray and returns the first solid one within
+    /// `max_dist` meters. Portals are passed through without being followed
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<VoxelHit> {
+        let dir = dir.try_normalize()?;
+        let size = self.size() as f32;
+        let start = ((origin - self.origin) * self.voxels_per_meter + size / 2.0).to_array();
+        let dir = dir.to_array();
+        let max_t = max_dist * self.voxels_per_meter;
+
+        // Clip the ray to the bounds of the world
+        let mut t_enter = 0.0_f32;
+        let mut t_exit = max_t;
+        let mut enter_axis = None;
+        for axis in 0..3 {
+            if dir[axis] == 0.0 {
+                if start[axis] < 0.0 || start[axis] >= size {
+                    return None;
+                }
+                continue;
+            }
+
+            let t0 = (0.0 - start[axis]) / dir[axis];
+            let t1 = (size - start[axis]) / dir[axis];
+            let (near, far) = (t0.min(t1), t0.max(t1));
+            if near > t_enter {
+                t_enter = near;
+                enter_axis = Some(axis);
+            }
+            t_exit = t_exit.min(far);
+        }
+        if t_enter > t_exit {
+            return None;
+        }
+
+        let mut voxel = [0; 3];
+        let mut step = [0; 3];
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            let pos = start[axis] + dir[axis] * t_enter;
+            voxel[axis] = (pos.floor() as i32).clamp(0, size as i32 - 1);
+
+            if dir[axis] > 0.0 {
+                step[axis] = 1;
+                t_max[axis] = t_enter + (voxel[axis] as f32 + 1.0 - pos) / dir[axis];
+            } else if dir[axis] < 0.0 {
+                step[axis] = -1;
+                t_max[axis] = t_enter + (voxel[axis] as f32 - pos) / dir[axis];
+            }
+            if dir[axis] != 0.0 {
+                t_delta[axis] = 1.0 / dir[axis].abs();
+            }
+        }
+
+        let mut normal = [0; 3];
+        if let Some(axis) = enter_axis {
+            normal[axis] = -step[axis];
+        }
+
+        loop {
+            let position = IVec3::from_array(voxel);
+            let data = self.get_data(position)?;
+            let material = (data & 0xFF) as u8;
+            let flags = (data >> 8) as u8;
+            if material != 0 && flags & Flags::PORTAL_FLAG.bits() == 0 {
+                return Some(VoxelHit {
+                    position,
+                    normal: IVec3::from_array(normal),
+                    material,
+                });
+            }
+
+            let axis = if t_max[0] < t_max[1] {
+                if t_max[0] < t_max[2] {
+                    0
+                } else {
+                    2
+                }
+            } else if t_max[1] < t_max[2] {
+                1
+            } else {
+                2
+            };
+            if t_max[axis] > t_exit {
+                return None;
+            }
+
+            voxel[axis] += step[axis];
+            t_max[axis] += t_delta[axis];
+            normal = [0; 3];
+            normal[axis] = -step[axis];
+        }
+    }
+}
+
+/// Edit of the voxel world applied once on the frame it is spawned, e.g. to carve out a
+/// crater with `VoxelEdit::Sphere { center, radius, material: 0 }`. The component is then
+/// removed, and the entity despawned if it has nothing else. Material 0 clears the flags,
+/// other materials are solid and collide
+#[derive(Component, Clone, Copy, Debug)]
+pub enum VoxelEdit {
+    /// Same as [`VoxelWorld::fill_sphere`]
+    Sphere { center: Vec3, radius: f32, material: u8 },
+    /// Voxels between the world positions `min` and `max` inclusive, same as
+    /// [`VoxelWorld::fill_box`]
+    Box { min: Vec3, max: Vec3, material: u8 },
+}
+
+fn apply_voxel_edit_components(
+    mut commands: Commands,
+    voxel_edits: Query<(Entity, &VoxelEdit)>,
+    entities: &Entities,
+    archetypes: &Archetypes,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    let flags = |material| {
+        if material == 0 {
+            Flags::empty()
+        } else {
+            Flags::COLLISION_FLAG
+        }
+    };
+    for (entity, voxel_edit) in voxel_edits.iter() {
+        match *voxel_edit {
+            VoxelEdit::Sphere {
+                center,
+                radius,
+                material,
+            } => voxel_world.fill_sphere(center, radius, material, flags(material)),
+            VoxelEdit::Box { min, max, material } => {
+                let min = voxel_world.world_to_voxel(min);
+                let max = voxel_world.world_to_voxel(max);
+                voxel_world.fill_box(min, max, material, flags(material));
+            }
+        }
+
+        let only_edit = match entities.get(entity) {
+            Some(location) => archetypes[location.archetype_id].components().count() == 1,
+            None => false,
+        };
+        if only_edit {
+            commands.entity(entity).despawn();
+        } else {
+            commands.entity(entity).remove::<VoxelEdit>();
+        }
+    }
+}
+
+/// Voxel edits made this frame, written to the voxel texture in the render world
+#[derive(Resource, ExtractResource, Clone, Default)]
+struct VoxelEdits {
+    voxels: Vec<(IVec3, u16)>,
+    regions: Vec<VoxelRegion>,
+}
+
+/// Voxel data of a box in the world, in the same x, y, z order as the texture data of [`GH`]
+#[derive(Clone)]
+struct VoxelRegion {
+    min: IVec3,
+    size: UVec3,
+    data: Vec<u8>,
+}
+
+fn take_voxel_edits(mut voxel_world: ResMut<VoxelWorld>, mut voxel_edits: ResMut<VoxelEdits>) {
+    voxel_edits.voxels = std::mem::take(&mut voxel_world.edits);
+
+    // Regions are copied after all edits this frame so they hold the final voxel values
+    let dim = voxel_world.size() as usize;
+    let data = &voxel_world.gh.texture_data;
+    voxel_edits.regions = voxel_world
+        .edited_regions
+        .iter()
+        .map(|(min, max)| {
+            let size = (*max - *min + 1).as_uvec3();
+            let mut region = Vec::with_capacity((size.x * size.y * size.z) as usize * 2);
+            for x in min.x..=max.x {
+                for y in min.y..=max.y {
+                    let start = (x as usize * dim * dim + y as usize * dim + min.z as usize) * 2;
+                    region.extend_from_slice(&data[start..start + size.z as usize * 2]);
+                }
+            }
+
+            VoxelRegion {
+                min: *min,
+                size,
+                data: region,
+            }
+        })
+        .collect();
+    voxel_world.edited_regions.clear();
+}
+
+fn write_voxel_edits(
+    voxel_edits: Res<VoxelEdits>,
+    voxel_data: Res<VoxelData>,
+    render_queue: Res<RenderQueue>,
+) {
+    for (position, data) in voxel_edits.voxels.iter() {
+        // the texture is indexed zyx
+        render_queue.write_texture(
+            ImageCopyTexture {
+                texture: &voxel_data.voxel_world_texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: position.z as u32,
+                    y: position.y as u32,
+                    z: position.x as u32,
+                },
+                aspect: TextureAspect::All,
+            },
+            &data.to_le_bytes(),
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(2),
+                rows_per_image: Some(1),
+            },
+            Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    for region in voxel_edits.regions.iter() {
+        render_queue.write_texture(
+            ImageCopyTexture {
+                texture: &voxel_data.voxel_world_texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: region.min.z as u32,
+                    y: region.min.y as u32,
+                    z: region.min.x as u32,
+                },
+                aspect: TextureAspect::All,
+            },
+            &region.data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(region.size.z * 2),
+                rows_per_image: Some(region.size.y),
+            },
+            Extent3d {
+                width: region.size.z,
+                height: region.size.y,
+                depth_or_array_layers: region.size.x,
+            },
+        );
+    }
+}
+
+struct SaveRequest {
+    on_complete: std::boxed::Box<dyn FnOnce(io::Result<GH>) + Send + Sync>,
+}
+
+struct PendingSave {
+    request: SaveRequest,
+    buffer: Buffer,
+    texture_size: u32,
+    padded_bytes_per_row: u32,
+    mapped: Arc<Mutex<Option<Result<(), BufferAsyncError>>>>,
+}
+
+#[derive(Resource)]
+struct VoxelWorldSaves {
+    requests: Receiver<SaveRequest>,
+    pending: Vec<PendingSave>,
+}
+
+/// Copies the voxel texture to a buffer for each save or mesh request and hands the world to
+/// the request once the buffer has been mapped on a later frame
+fn save_voxel_world(
+    mut saves: ResMut<VoxelWorldSaves>,
+    voxel_data: Res<VoxelData>,
+    voxel_uniforms: Res<VoxelUniforms>,
+    voxel_palette: Res<VoxelPalette>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let saves = saves.as_mut();
+
+    // Finish saves whose buffers are mapped
+    let mut i = 0;
+    while i < saves.pending.len() {
+        let mapped = saves.pending[i].mapped.lock().unwrap().take();
+        let Some(mapped) = mapped else {
+            i += 1;
+            continue;
+        };
+
+        let pending = saves.pending.swap_remove(i);
+        let result = mapped
+            .map_err(io::Error::other)
+            .map(|_| {
+                let size = pending.texture_size as usize;
+                let row = size * 2;
+                let padded_row = pending.padded_bytes_per_row as usize;
+
+                let mapped_range = pending.buffer.slice(..).get_mapped_range();
+                let mut texture_data = Vec::with_capacity(row * size * size);
+                for chunk in mapped_range.chunks(padded_row) {
+                    texture_data.extend_from_slice(&chunk[..row]);
+                }
+                drop(mapped_range);
+                pending.buffer.unmap();
+
+                GH {
+                    levels: [0; 8],
+                    texture_size: pending.texture_size,
+                    texture_data,
+                    pallete: voxel_palette.clone(),
+                }
+            });
+
+        (pending.request.on_complete)(result);
+    }
+
+    // Start new saves
+    for request in saves.requests.try_iter() {
+        let texture_size = voxel_uniforms.texture_size;
+        let padded_bytes_per_row =
+            (texture_size * 2).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("voxel world save buffer"),
+            size: padded_bytes_per_row as u64 * texture_size as u64 * texture_size as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("voxel world save encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            voxel_data.voxel_world_texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(texture_size),
+                },
+            },
+            Extent3d {
+                width: texture_size,
+                height: texture_size,
+                depth_or_array_layers: texture_size,
+            },
+        );
+        render_queue.submit([encoder.finish()]);
+
+        let mapped = Arc::new(Mutex::new(None));
+        let callback_mapped = mapped.clone();
+        buffer.slice(..).map_async(MapMode::Read, move |result| {
+            *callback_mapped.lock().unwrap() = Some(result);
+        });
+
+        saves.pending.push(PendingSave {
+            request,
+            buffer,
+            texture_size,
+            padded_bytes_per_row,
+            mapped,
+        });
+    }
 }
 
 #[derive(Resource, ExtractResource, Clone)]
-enum NewGH {
+pub(crate) enum NewGH {
     Some(Arc<GH>),
     None,
 }
 
+/// Size of the world [`load_voxel_world`] put in [`NewGH`] this frame, unlike the worlds
+/// shifted in by streaming it's announced with [`VoxelWorldLoaded`] once uploaded
+#[derive(Resource, ExtractResource, Clone)]
+pub(crate) struct LoadedGH(Option<UVec3>);
+
+#[derive(Resource)]
+struct VoxelWorldLoadedReceiver(Receiver<VoxelWorldLoaded>);
+
+#[derive(Resource)]
+struct VoxelWorldLoadedSender(Sender<VoxelWorldLoaded>);
+
+fn send_voxel_world_loaded(
+    receiver: Res<VoxelWorldLoadedReceiver>,
+    mut loaded_events: EventWriter<VoxelWorldLoaded>,
+) {
+    loaded_events.send_batch(receiver.0.try_iter());
+}
+
+/// Runs after the render graph has been submitted, the upload is queued ahead of it so it has
+/// completed once the gpu has finished this frame's work
+fn notify_voxel_world_loaded(
+    loaded_gh: Res<LoadedGH>,
+    sender: Res<VoxelWorldLoadedSender>,
+    render_queue: Res<RenderQueue>,
+) {
+    if let Some(size) = loaded_gh.0 {
+        let sender = sender.0.clone();
+        render_queue.on_submitted_work_done(move || {
+            let _ = sender.send(VoxelWorldLoaded { size });
+        });
+    }
+}
+
 fn prepare_uniforms(
     voxel_uniforms: Res<VoxelUniforms>,
     mut voxel_data: ResMut<VoxelData>,
@@ -232,38 +1391,177 @@ fn prepare_uniforms(
         .write_buffer(&render_device, &render_queue);
 }
 
-fn load_voxel_world(
-    mut load_voxel_world: ResMut<LoadVoxelWorld>,
-    mut new_gh: ResMut<NewGH>,
+fn update_voxel_palette(
+    voxel_palette: Res<VoxelPalette>,
     mut voxel_uniforms: ResMut<VoxelUniforms>,
 ) {
-    match load_voxel_world.as_ref() {
-        LoadVoxelWorld::Empty(_) | LoadVoxelWorld::File(_) => {
-            let gh = match load_voxel_world.as_ref() {
-                LoadVoxelWorld::Empty(size) => GH::empty(*size),
-                LoadVoxelWorld::File(path) => {
-                    let file = std::fs::read(path).unwrap();
-                    GH::from_vox(&file).unwrap()
-                }
-                LoadVoxelWorld::None => unreachable!(),
-            };
+    if voxel_palette.is_changed() {
+        voxel_uniforms.pallete = voxel_palette.clone().into();
+    }
+}
 
-            let mut levels = [UVec4::ZERO; 8];
-            for i in 0..8 {
-                levels[i] = UVec4::new(gh.levels[i], 0, 0, 0);
+fn update_palette_animation(
+    animation_clock: Res<AnimationClock>,
+    palette_animation: Res<PaletteAnimation>,
+    voxel_palette: Res<VoxelPalette>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+) {
+    if palette_animation.is_changed() || voxel_palette.is_changed() {
+        let mut count = 0;
+        for animation in palette_animation
+            .iter()
+            .filter(|animation| animation.material != 0 && !animation.colors.is_empty())
+            .take(MAX_PALETTE_ANIMATIONS)
+        {
+            let emission = voxel_palette[animation.material as usize].emission;
+            let mut extracted = ExtractedMaterialAnimation {
+                material: animation.material as u32,
+                period: animation.period.max(0.001),
+                ..default()
+            };
+            for (i, color) in animation
+                .colors
+                .iter()
+                .take(MAX_PALETTE_ANIMATION_COLORS)
+                .enumerate()
+            {
+                extracted.colors[i] = pallete_colour(*color, emission);
+                extracted.color_count += 1;
             }
 
-            voxel_uniforms.pallete = gh.pallete.clone().into();
-            voxel_uniforms.levels = levels;
-            voxel_uniforms.texture_size = gh.texture_size;
+            voxel_uniforms.palette_animations[count] = extracted;
+            count += 1;
+        }
+        voxel_uniforms.palette_animation_count = count as u32;
+    }
+
+    // time only moves the colors, avoid re-extracting the uniforms every frame without them
+    if voxel_uniforms.palette_animation_count > 0 {
+        voxel_uniforms.time = animation_clock.0;
+    }
+}
+
+fn update_reflective_materials(
+    reflective_materials: Res<ReflectiveMaterials>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+) {
+    if !reflective_materials.is_changed() {
+        return;
+    }
+
+    let mut bits = [UVec4::ZERO; 2];
+    for (i, _) in reflective_materials.iter().enumerate().filter(|(_, r)| **r) {
+        bits[i / 128][(i / 32) % 4] |= 1 << (i % 32);
+    }
+    voxel_uniforms.reflective_materials = bits;
+}
+
+fn update_material_densities(
+    material_densities: Res<MaterialDensities>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+) {
+    if !material_densities.is_changed() {
+        return;
+    }
+
+    let mut densities = [Vec4::ZERO; 64];
+    for (i, density) in material_densities.iter().enumerate() {
+        densities[i / 4][i % 4] = *density;
+    }
+    voxel_uniforms.material_densities = densities;
+    voxel_uniforms.fluid_materials =
+        material_densities.iter().filter(|density| **density != 0.0).count() as u32;
+}
+
+fn update_transparent_materials(
+    transparent_materials: Res<TransparentMaterials>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+) {
+    if !transparent_materials.is_changed() {
+        return;
+    }
+
+    let mut alphas = [Vec4::ONE; 64];
+    for (i, alpha) in transparent_materials.iter().enumerate() {
+        alphas[i / 4][i % 4] = alpha.clamp(0.0, 1.0);
+    }
+    voxel_uniforms.material_alphas = alphas;
+}
 
-            *new_gh = NewGH::Some(Arc::new(gh));
-            *load_voxel_world = LoadVoxelWorld::None;
+fn update_voxels_per_meter(
+    settings: Res<VoxelWorldSettings>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+
+    let voxels_per_meter = validate_voxels_per_meter(settings.voxels_per_meter);
+    if voxel_uniforms.voxels_per_meter != voxels_per_meter {
+        voxel_uniforms.voxels_per_meter = voxels_per_meter;
+        voxel_world.voxels_per_meter = voxels_per_meter;
+    }
+}
+
+pub(crate) fn load_voxel_world(
+    mut load_voxel_world: ResMut<LoadVoxelWorld>,
+    mut new_gh: ResMut<NewGH>,
+    mut loaded_gh: ResMut<LoadedGH>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+    mut voxel_world: ResMut<VoxelWorld>,
+    mut voxel_palette: ResMut<VoxelPalette>,
+    render_device: Res<RenderDevice>,
+) {
+    loaded_gh.0 = None;
+    let gh = match load_voxel_world.as_ref() {
+        LoadVoxelWorld::Empty(size) => {
+            let texture_size = size.max_element();
+            if size.min_element() != texture_size {
+                warn!(
+                    "Voxel worlds are cubes, using {} voxels per axis for the empty world of {}",
+                    texture_size, size
+                );
+            }
+            GH::empty(validate_texture_size(texture_size, &render_device))
+        }
+        LoadVoxelWorld::File(path, model) => {
+            let file = std::fs::read(path).unwrap();
+            let format = VoxelFormat::from_path(path)
+                .or_else(|| VoxelFormat::from_bytes(&file))
+                .unwrap_or_else(|| panic!("Unknown voxel format of {}", path));
+            GH::load(&file, format, model.unwrap_or(0)).unwrap()
+        }
+        LoadVoxelWorld::Bytes(bytes) => {
+            let format = VoxelFormat::from_bytes(bytes).expect("Unknown voxel format");
+            GH::load(bytes, format, 0).unwrap()
         }
-        LoadVoxelWorld::None => {
+        LoadVoxelWorld::Generate(generator) => GH::generate(
+            voxel_world.size(),
+            voxel_palette.clone(),
+            generator.as_ref(),
+        ),
+        // Started by the streaming plugin
+        LoadVoxelWorld::Stream(_) | LoadVoxelWorld::None => {
             *new_gh = NewGH::None;
+            return;
         }
+    };
+
+    let mut levels = [UVec4::ZERO; 8];
+    for i in 0..8 {
+        levels[i] = UVec4::new(gh.levels[i], 0, 0, 0);
     }
+
+    *voxel_palette = gh.pallete.clone();
+    voxel_uniforms.levels = levels;
+    voxel_uniforms.texture_size = gh.texture_size;
+    voxel_uniforms.origin = Vec3::ZERO;
+
+    loaded_gh.0 = Some(UVec3::splat(gh.texture_size));
+    let gh = voxel_world.replace(gh, Vec3::ZERO);
+    *new_gh = NewGH::Some(gh);
+    *load_voxel_world = LoadVoxelWorld::None;
 }
 
 fn load_voxel_world_prepare(
@@ -296,13 +1594,26 @@ fn load_voxel_world_prepare(
                 sample_count: 1,
                 dimension: TextureDimension::D3,
                 format: TextureFormat::R16Uint,
-                usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_DST,
+                usage: TextureUsages::STORAGE_BINDING
+                    | TextureUsages::COPY_DST
+                    | TextureUsages::COPY_SRC,
                 view_formats: &[],
             },
             TextureDataOrder::default(),
             &gh.texture_data,
         );
         voxel_data.voxel_world = voxel_world.create_view(&TextureViewDescriptor::default());
+        voxel_data.voxel_world_texture = voxel_world;
+
+        // normals and colors of the old world would be read for the voxels of the new one
+        let voxel_normals =
+            create_normals_texture(&render_device, gh.texture_size, voxel_data.surface_normals);
+        voxel_data.voxel_normals = voxel_normals.create_view(&TextureViewDescriptor::default());
+        voxel_data.voxel_normals_texture = voxel_normals;
+        let voxel_colors =
+            create_colors_texture(&render_device, gh.texture_size, voxel_data.direct_colors);
+        voxel_data.voxel_colors = voxel_colors.create_view(&TextureViewDescriptor::default());
+        voxel_data.voxel_colors_texture = voxel_colors;
     }
 }
 
@@ -327,6 +1638,14 @@ fn queue_bind_group(render_device: Res<RenderDevice>, mut voxel_data: ResMut<Vox
                 binding: 3,
                 resource: BindingResource::Sampler(&voxel_data.texture_sampler),
             },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(&voxel_data.voxel_normals),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: BindingResource::TextureView(&voxel_data.voxel_colors),
+            },
         ],
     );
     voxel_data.bind_group = bind_group;
@@ -1,13 +1,17 @@
-use super::voxel_world::{VoxelData, VoxelUniforms};
-use crate::{Flags, RenderGraphSettings, VOXELS_PER_METER};
+use super::voxel_world::{NewGH, VoxelData, VoxelUniforms, VoxelWorldSettings};
+use crate::{load::VoxelPalette, Flags, RenderGraphSettings};
 
 use bevy::{
-    asset::{load_internal_asset, Handle},
+    asset::embedded_asset,
     core_pipeline::{core_3d::Transparent3d},
-    ecs::system::{
-        lifetimeless::{Read, SQuery, SRes},
-        SystemParamItem,
+    ecs::{
+        query::QueryItem,
+        system::{
+            lifetimeless::{Read, SQuery, SRes},
+            SystemParamItem,
+        },
     },
+    math::Mat3A,
     pbr::{
         DrawMesh, MeshPipeline, MeshPipelineKey, RenderMeshInstances, SetMeshBindGroup,
         SetMeshViewBindGroup,
@@ -17,37 +21,36 @@ use bevy::{
         Render, RenderApp, RenderSet,
         camera::{RenderTarget, ScalingMode, ClearColorConfig},
         extract_component::{ExtractComponent, ExtractComponentPlugin},
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
         mesh::MeshVertexBufferLayout,
+        primitives::Aabb,
         render_asset::RenderAssets,
+        batching::batch_and_prepare_render_phase,
         render_phase::{
-            AddRenderCommand, DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult,
-            RenderPhase, SetItemPipeline, TrackedRenderPass,
+            sort_phase_system, AddRenderCommand, DrawFunctions, PhaseItem, RenderCommand,
+            RenderCommandResult, RenderPhase, SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
         texture::FallbackImage,
         view::ExtractedView,
     },
-    utils::HashMap,
+    utils::{HashMap, HashSet},
 };
 
-const VOXELIZATION_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(1975691635883203525);
-
 pub struct VoxelizationPlugin;
 
 impl Plugin for VoxelizationPlugin {
 
     fn build(&self, app: &mut App) {
-        load_internal_asset!(
-            app,
-            VOXELIZATION_SHADER_HANDLE,
-            "shaders/voxelization.wgsl",
-            Shader::from_wgsl
-        );
+        embedded_asset!(app, "src/", "shaders/voxelization.wgsl");
 
-        app.add_plugins(ExtractComponentPlugin::<VoxelizationMaterial>::default())
+        app.init_resource::<VoxelizationDebug>()
+            .add_plugins(ExtractResourcePlugin::<VoxelizationDebug>::default())
+            .add_plugins(ExtractComponentPlugin::<VoxelizationMaterial>::default())
+            .add_plugins(ExtractComponentPlugin::<VoxelizationCamera>::default())
             .add_systems(Startup, setup)
-            .add_systems(Update, update_cameras);
+            .add_systems(Update, (update_cameras, toggle_cameras, voxelize_scenes));
     }
 
     fn finish(&self, app: &mut App) {
@@ -56,12 +59,19 @@ impl Plugin for VoxelizationPlugin {
             .add_render_command::<Transparent3d, DrawCustom>()
             .init_resource::<VoxelizationPipeline>()
             .init_resource::<SpecializedMeshPipelines<VoxelizationPipeline>>()
-            .insert_resource(VoxelizationUniformsResource(HashMap::new()))
+            .init_resource::<VoxelizationBindGroups>()
+            .init_resource::<PendingVoxelization>()
             .add_systems(
                 Render,
                 (
                     queue_custom.in_set(RenderSet::QueueMeshes),
                     queue_bind_group.in_set(RenderSet::Queue),
+                    sort_voxelization_phases
+                        .in_set(RenderSet::PhaseSort)
+                        .after(sort_phase_system::<Transparent3d>),
+                    batch_voxelization_phases
+                        .in_set(RenderSet::PrepareResources)
+                        .after(batch_and_prepare_render_phase::<Transparent3d, MeshPipeline>),
                 ),
             );
     }
@@ -70,11 +80,15 @@ impl Plugin for VoxelizationPlugin {
 #[derive(Resource, Deref, DerefMut)]
 struct VoxelizationImage(Handle<Image>);
 
-#[derive(Component)]
-struct VoxelizationCamera;
+/// `axis` is the world axis the camera looks along, 0 for x, 1 for y and 2 for z
+#[derive(Component, Clone, ExtractComponent)]
+struct VoxelizationCamera {
+    axis: usize,
+}
 
 fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
-    // image that is the size of the render world to create the correct ammount of fragments
+    // image that is the size of the voxelization resolution to create the correct ammount of
+    // fragments
     let size = Extent3d {
         width: 1,
         height: 1,
@@ -110,7 +124,7 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
             main_texture_usages: Default::default(),
             camera_3d: Camera3d::default(),
             ..default()
-        }, VoxelizationCamera)).id();
+        }, VoxelizationCamera { axis: i as usize })).id();
 
         commands.spawn(TargetCamera(camera));
     }
@@ -119,61 +133,117 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
 fn update_cameras(
     voxelization_image: Res<VoxelizationImage>,
     mut images: ResMut<Assets<Image>>,
-    mut voxelization_cameras: Query<(&mut Transform, &mut Projection), With<VoxelizationCamera>>,
+    mut voxelization_cameras: Query<(&mut Transform, &mut Projection, &VoxelizationCamera)>,
     voxel_uniforms: Res<VoxelUniforms>,
+    settings: Res<VoxelWorldSettings>,
+    mut current_placement: Local<Option<(u32, f32, Vec3)>>,
 ) {
-    let voxelization_image = images
-        .get_mut(voxelization_image.id())
-        .expect("Voxelization image not found");
-
-    if voxelization_image.size().x as u32 != voxel_uniforms.texture_size {
-
-        // Update cameras
-        debug!(
-            "Updating {} voxelization cameras to a resolution of {}",
-            voxelization_cameras.iter().len(),
-            voxel_uniforms.texture_size
-        );
-
-        let mut i = 0;
-        for (mut transform, mut projection) in voxelization_cameras.iter_mut() {
-            // Resize image
-            let size = voxel_uniforms.texture_size;
-            voxelization_image.resize(Extent3d {
+    // Checked without `get_mut`, which would mark the image as modified and reallocate it
+    // on the gpu every frame
+    let current_size = images
+        .get(voxelization_image.id())
+        .expect("Voxelization image not found")
+        .size()
+        .x;
+
+    // the cameras always cover the whole world, the fragments are mapped back to voxels by
+    // the shader from the image size
+    let size = settings
+        .voxelization_resolution
+        .unwrap_or(voxel_uniforms.texture_size)
+        .max(1);
+    let texture_size = voxel_uniforms.texture_size;
+    let voxels_per_meter = voxel_uniforms.voxels_per_meter;
+    let origin = voxel_uniforms.origin;
+    if current_size != size {
+        // Resize image
+        images
+            .get_mut(voxelization_image.id())
+            .unwrap()
+            .resize(Extent3d {
                 width: size,
                 height: size,
                 depth_or_array_layers: 1,
             });
+    } else if *current_placement == Some((texture_size, voxels_per_meter, origin)) {
+        return;
+    }
+    *current_placement = Some((texture_size, voxels_per_meter, origin));
+
+    // Update cameras
+    debug!(
+        "Updating {} voxelization cameras to a resolution of {} at {} voxels per meter",
+        voxelization_cameras.iter().len(),
+        size,
+        voxels_per_meter
+    );
+
+    for (mut transform, mut projection, voxelization_camera) in voxelization_cameras.iter_mut() {
+        // Update camera
+        *transform = match voxelization_camera.axis {
+            0 => Transform::from_translation(origin).looking_at(origin + Vec3::X, Vec3::Y),
+            1 => Transform::from_translation(origin).looking_at(origin + Vec3::Y, Vec3::Z),
+            2 => Transform::from_translation(origin).looking_at(origin + Vec3::Z, Vec3::Y),
+            _ => panic!("Too many voxelization cameras"),
+        };
 
-            // Update camera
-            *transform = match i {
-                0 => Transform::from_translation(Vec3::ZERO).looking_at(Vec3::X, Vec3::Y),
-                1 => Transform::from_translation(Vec3::ZERO).looking_at(Vec3::Y, Vec3::Z),
-                2 => Transform::from_translation(Vec3::ZERO).looking_at(Vec3::Z, Vec3::Y),
-                _ => panic!("Too many voxelization cameras"),
-            };
+        let side = texture_size as f32 / voxels_per_meter / 2.0;
+        
+        *projection = Projection::Orthographic(OrthographicProjection {
+            near: -side,
+            far: side,
+            scaling_mode: ScalingMode::Fixed {
+                width: 2.0 * side,
+                height: 2.0 * side,
+            },
+            ..default()
+        });
+    }
+}
 
-            let side = size as f32 / VOXELS_PER_METER / 2.0;
-            
-            *projection = Projection::Orthographic(OrthographicProjection {
-                near: -side,
-                far: side,
-                scaling_mode: ScalingMode::Fixed {
-                    width: 2.0 * side,
-                    height: 2.0 * side,
-                },
-                ..default()
-            });
+/// Turns the voxelization cameras off with [`RenderGraphSettings::voxelization`], so worlds
+/// without voxelized meshes don't render their empty passes
+fn toggle_cameras(
+    render_graph_settings: Res<RenderGraphSettings>,
+    mut voxelization_cameras: Query<&mut Camera, With<VoxelizationCamera>>,
+) {
+    if !render_graph_settings.is_changed() {
+        return;
+    }
 
-            i += 1;
+    for mut camera in voxelization_cameras.iter_mut() {
+        if camera.is_active != render_graph_settings.voxelization {
+            camera.is_active = render_graph_settings.voxelization;
         }
     }
 }
 
-#[derive(Component, Clone, ExtractComponent)]
+#[derive(Component, Clone)]
 pub struct VoxelizationMaterial {
     pub material: VoxelizationMaterialType,
-    pub flags: u8,
+    pub flags: Flags,
+    pub axes: VoxelizationAxes,
+    pub mode: VoxelizationMode,
+    /// Light emitted by the voxels as a multiplier on the material colour, rounded to
+    /// steps of 1 between 0 and 7 and stored in the voxel flags
+    pub emissive: f32,
+    /// Texels of `VoxelizationMaterialType::Texture` with an alpha below this are not voxelized
+    pub alpha_cutoff: f32,
+    /// Samples `VoxelizationMaterialType::Texture` with bilinear filtering instead of the
+    /// nearest texel, the texture format must be filterable
+    pub filter: bool,
+    /// Voxels written inwards from the surface along the mesh normals. Only the surface is
+    /// voxelized, never the interior, so closed meshes are hollow: physics bodies collide
+    /// with the shell but bodies inside it stay inside, and a thicker shell keeps fast
+    /// bodies from passing through. 0 is treated as 1
+    pub thickness: u32,
+    /// Stores the mesh normal of the voxels for shading them smoothly instead of by the voxel
+    /// faces, needs [`VoxelWorldSettings::surface_normals`](crate::VoxelWorldSettings)
+    pub normals: bool,
+    /// Writes the surface voxels with a probability of how much of them lies inside the mesh,
+    /// times the texel alpha for `VoxelizationMaterialType::Texture`, instead of writing every
+    /// touched voxel. Softens the blocky edges of meshes voxelized at a low resolution
+    pub dither: bool,
 }
 
 impl Default for VoxelizationMaterial {
@@ -181,20 +251,206 @@ impl Default for VoxelizationMaterial {
         Self {
             material: VoxelizationMaterialType::Material(10),
             flags: Flags::ANIMATION_FLAG,
+            axes: VoxelizationAxes::ALL,
+            emissive: 0.0,
+            mode: VoxelizationMode::EveryFrame,
+            alpha_cutoff: 0.0,
+            filter: false,
+            thickness: 1,
+            normals: false,
+            dither: false,
         }
     }
 }
 
+impl ExtractComponent for VoxelizationMaterial {
+    type QueryData = (
+        Ref<'static, VoxelizationMaterial>,
+        Ref<'static, GlobalTransform>,
+        Option<Read<Aabb>>,
+    );
+    type QueryFilter = ();
+    type Out = (VoxelizationMaterial, VoxelizationChanged, VoxelizationBounds);
+
+    fn extract_component(
+        (voxelization_material, transform, aabb): QueryItem<'_, Self::QueryData>,
+    ) -> Option<Self::Out> {
+        let changed = match voxelization_material.mode {
+            VoxelizationMode::EveryFrame => true,
+            VoxelizationMode::Once => voxelization_material.is_added(),
+            VoxelizationMode::OnChange => {
+                voxelization_material.is_changed() || transform.is_changed()
+            }
+        };
+
+        let bounds = aabb.map(|aabb| {
+            let affine = transform.affine();
+            let center = affine.transform_point3a(aabb.center);
+            let matrix = affine.matrix3;
+            let half_extents = Mat3A::from_cols(
+                matrix.x_axis.abs(),
+                matrix.y_axis.abs(),
+                matrix.z_axis.abs(),
+            ) * aabb.half_extents;
+            (
+                Vec3::from(center - half_extents),
+                Vec3::from(center + half_extents),
+            )
+        });
+
+        Some((
+            voxelization_material.clone(),
+            VoxelizationChanged(changed),
+            VoxelizationBounds(bounds),
+        ))
+    }
+}
+
+/// Overrides every [`VoxelizationMaterial`] for debugging, e.g. to check that a mesh that
+/// doesn't show up is voxelized at all. Changing it voxelizes `Once` and `OnChange` meshes
+/// again
+#[derive(Resource, ExtractResource, Clone, Default, Debug)]
+pub struct VoxelizationDebug {
+    /// Voxelizes every mesh with this palette material, ignoring its texture, vertex colors
+    /// or material
+    pub force_material: Option<u8>,
+}
+
+/// When a mesh is voxelized. Voxels written by `Once` and `OnChange` meshes are kept between
+/// frames, so they should not have `ANIMATION_FLAG` or `PORTAL_FLAG` which are cleared every
+/// frame. `OnChange` does not erase the voxels written at the previous transform
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VoxelizationMode {
+    #[default]
+    EveryFrame,
+    /// Voxelized when added and again whenever a new voxel world is loaded
+    Once,
+    /// Voxelized when added and whenever its `VoxelizationMaterial` or `Transform` changes
+    OnChange,
+}
+
+/// Whether the mesh needs to be voxelized this frame according to its [`VoxelizationMode`]
+#[derive(Component)]
+pub struct VoxelizationChanged(bool);
+
+/// World space min and max of the mesh, `None` when it has no `Aabb`
+#[derive(Component)]
+pub struct VoxelizationBounds(Option<(Vec3, Vec3)>);
+
+/// Meshes waiting to be voxelized, kept until their pipeline is ready and they have been drawn
+#[derive(Resource, Default, Deref, DerefMut)]
+struct PendingVoxelization(HashSet<Entity>);
+
+/// Voxelizes every mesh of the scene spawned on this entity, see `VoxelizationSceneBundle`
+#[derive(Component, Clone, Default)]
+pub struct VoxelizationScene {
+    /// Added to each mesh of the scene with `material` and `emissive` taken from the mesh's
+    /// `StandardMaterial`, its base color is mapped to the nearest [`VoxelPalette`] material
+    pub template: VoxelizationMaterial,
+}
+
+#[allow(clippy::type_complexity)]
+fn voxelize_scenes(
+    mut commands: Commands,
+    voxelization_scenes: Query<(Entity, &VoxelizationScene)>,
+    children: Query<&Children>,
+    meshes: Query<
+        Option<&Handle<StandardMaterial>>,
+        (With<Handle<Mesh>>, Without<VoxelizationMaterial>),
+    >,
+    standard_materials: Res<Assets<StandardMaterial>>,
+    voxel_palette: Res<VoxelPalette>,
+) {
+    for (scene_entity, voxelization_scene) in voxelization_scenes.iter() {
+        for entity in children.iter_descendants(scene_entity) {
+            let Ok(standard_material) = meshes.get(entity) else {
+                continue;
+            };
+
+            let mut voxelization_material = voxelization_scene.template.clone();
+            if let Some(standard_material) =
+                standard_material.and_then(|handle| standard_materials.get(handle))
+            {
+                let base_color = Vec4::from(standard_material.base_color.as_linear_rgba_f32());
+                voxelization_material.material = VoxelizationMaterialType::Material(
+                    voxel_palette.nearest_material(base_color.truncate()),
+                );
+
+                let emissive = Vec4::from(standard_material.emissive.as_linear_rgba_f32());
+                voxelization_material.emissive = emissive.truncate().max_element();
+            }
+
+            commands.entity(entity).insert(voxelization_material);
+        }
+    }
+}
+
+/// The axes the mesh is voxelized along, each axis is a separate render of the mesh.
+/// Surfaces facing away from every enabled axis get holes, so a flat mesh only needs the
+/// axis of its normal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoxelizationAxes {
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+}
+
+impl VoxelizationAxes {
+    pub const ALL: Self = Self {
+        x: true,
+        y: true,
+        z: true,
+    };
+    pub const X: Self = Self {
+        x: true,
+        y: false,
+        z: false,
+    };
+    pub const Y: Self = Self {
+        x: false,
+        y: true,
+        z: false,
+    };
+    pub const Z: Self = Self {
+        x: false,
+        y: false,
+        z: true,
+    };
+
+    fn contains(&self, axis: usize) -> bool {
+        [self.x, self.y, self.z][axis]
+    }
+}
+
 #[derive(Clone)]
 pub enum VoxelizationMaterialType {
+    /// Material id from the red channel of the texel, the texel color is drawn instead of
+    /// the material's when [`VoxelWorldSettings::voxel_colors`](crate::VoxelWorldSettings)
+    /// is on
     Texture(Handle<Image>),
     Material(u8),
+    /// Nearest non emissive palette material to the mesh's `ATTRIBUTE_COLOR`, meshes
+    /// without vertex colors are treated as white. Drawn with the vertex color itself with
+    /// [`VoxelWorldSettings::voxel_colors`](crate::VoxelWorldSettings)
+    VertexColor,
+    /// Nearest non emissive palette material to the color, drawn with the color itself when
+    /// [`VoxelWorldSettings::voxel_colors`](crate::VoxelWorldSettings) is on. The alpha is
+    /// used like the texel alpha of `Texture` by `alpha_cutoff` and `dither`
+    Color(Color),
 }
 
 #[derive(Clone, ShaderType)]
 pub struct VoxelizationUniforms {
     material: u32,
     flags: u32,
+    vertex_colors: u32,
+    alpha_cutoff: f32,
+    thickness: u32,
+    normals: u32,
+    dither: u32,
+    direct_color: u32,
+    /// Linear rgba of `VoxelizationMaterialType::Color`
+    color: Vec4,
 }
 
 impl From<&VoxelizationMaterial> for VoxelizationUniforms {
@@ -202,10 +458,23 @@ impl From<&VoxelizationMaterial> for VoxelizationUniforms {
         let material = match &value.material {
             VoxelizationMaterialType::Texture(_) => 255,
             VoxelizationMaterialType::Material(material) => *material as u32,
+            VoxelizationMaterialType::VertexColor | VoxelizationMaterialType::Color(_) => 0,
+        };
+        let color = match &value.material {
+            VoxelizationMaterialType::Color(color) => Vec4::from(color.as_linear_rgba_f32()),
+            _ => Vec4::ZERO,
         };
+        let emission = value.emissive.round().clamp(0.0, 7.0) as u8;
         Self {
             material,
-            flags: value.flags as u32,
+            flags: value.flags.with_emission(emission).bits() as u32,
+            vertex_colors: matches!(value.material, VoxelizationMaterialType::VertexColor) as u32,
+            alpha_cutoff: value.alpha_cutoff,
+            thickness: value.thickness.max(1),
+            normals: value.normals as u32,
+            dither: value.dither as u32,
+            direct_color: matches!(value.material, VoxelizationMaterialType::Color(_)) as u32,
+            color,
         }
     }
 }
@@ -221,9 +490,65 @@ type DrawCustom = (
 
 #[derive(Resource)]
 pub struct VoxelizationPipeline {
+    shader: Handle<Shader>,
     mesh_pipeline: MeshPipeline,
     world_bind_group_layout: BindGroupLayout,
     voxelization_bind_group_layout: BindGroupLayout,
+    /// Layout for [`VoxelizationMaterial::filter`], which needs a filterable texture
+    filtering_bind_group_layout: BindGroupLayout,
+    nearest_sampler: Sampler,
+    linear_sampler: Sampler,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VoxelizationPipelineKey {
+    mesh_key: MeshPipelineKey,
+    filtering: bool,
+}
+
+fn create_voxelization_bind_group_layout(
+    render_device: &RenderDevice,
+    filtering: bool,
+) -> BindGroupLayout {
+    let sampler_type = if filtering {
+        SamplerBindingType::Filtering
+    } else {
+        SamplerBindingType::NonFiltering
+    };
+
+    render_device.create_bind_group_layout(
+        None,
+        &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(
+                        VoxelizationUniforms::SHADER_SIZE.into(),
+                    ),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: filtering },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Sampler(sampler_type),
+                count: None,
+            },
+        ],
+    )
 }
 
 impl FromWorld for VoxelizationPipeline {
@@ -233,71 +558,62 @@ impl FromWorld for VoxelizationPipeline {
 
         let world_bind_group_layout = voxel_world_data.bind_group_layout.clone();
         let voxelization_bind_group_layout =
-            render_device.create_bind_group_layout(
-                None,
-                &[
-                    BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: ShaderStages::FRAGMENT,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: BufferSize::new(
-                                VoxelizationUniforms::SHADER_SIZE.into(),
-                            ),
-                        },
-                        count: None,
-                    },
-                    BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: ShaderStages::FRAGMENT,
-                        ty: BindingType::Texture {
-                            sample_type: TextureSampleType::Float { filterable: false },
-                            view_dimension: TextureViewDimension::D2,
-                            multisampled: false,
-                        },
-                        count: None,
-                    },
-                    BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: ShaderStages::FRAGMENT,
-                        ty: BindingType::Sampler(SamplerBindingType::NonFiltering),
-                        count: None,
-                    },
-                ],
-            );
+            create_voxelization_bind_group_layout(render_device, false);
+        let filtering_bind_group_layout =
+            create_voxelization_bind_group_layout(render_device, true);
+
+        let nearest_sampler = render_device.create_sampler(&SamplerDescriptor::default());
+        let linear_sampler = render_device.create_sampler(&SamplerDescriptor {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        let shader = world.resource::<AssetServer>().load(
+            "embedded://bevy_voxel_engine/voxel_pipeline/shaders/voxelization.wgsl",
+        );
 
         VoxelizationPipeline {
+            shader,
             mesh_pipeline: world.resource::<MeshPipeline>().clone(),
             world_bind_group_layout,
             voxelization_bind_group_layout,
+            filtering_bind_group_layout,
+            nearest_sampler,
+            linear_sampler,
         }
     }
 }
 
 impl SpecializedMeshPipeline for VoxelizationPipeline {
-    type Key = MeshPipelineKey;
+    type Key = VoxelizationPipelineKey;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
+        let mut descriptor = self.mesh_pipeline.specialize(key.mesh_key, layout)?;
 
-        descriptor.vertex.shader = VOXELIZATION_SHADER_HANDLE;
-        descriptor.fragment.as_mut().unwrap().shader = VOXELIZATION_SHADER_HANDLE;
+        descriptor.vertex.shader = self.shader.clone();
+        descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
 
         descriptor
             .vertex
             .shader_defs
             .push("MESH_BINDGROUP_1".into());
 
+        let voxelization_bind_group_layout = if key.filtering {
+            &self.filtering_bind_group_layout
+        } else {
+            &self.voxelization_bind_group_layout
+        };
+
         descriptor.layout = vec![
-            self.mesh_pipeline.get_view_layout(key.into()).clone(),
+            self.mesh_pipeline.get_view_layout(key.mesh_key.into()).clone(),
             self.mesh_pipeline.mesh_layouts.model_only.clone(),
             self.world_bind_group_layout.clone(),
-            self.voxelization_bind_group_layout.clone(),
+            voxelization_bind_group_layout.clone(),
         ];
 
         descriptor.primitive.cull_mode = None;
@@ -306,17 +622,45 @@ impl SpecializedMeshPipeline for VoxelizationPipeline {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn queue_custom(
     transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     custom_pipeline: Res<VoxelizationPipeline>,
     mut pipelines: ResMut<SpecializedMeshPipelines<VoxelizationPipeline>>,
     mut pipeline_cache: ResMut<PipelineCache>,
     render_meshes: Res<RenderAssets<Mesh>>,
-    material_meshes: Query<Entity, With<VoxelizationMaterial>>,
+    material_meshes: Query<(
+        Entity,
+        &VoxelizationMaterial,
+        &VoxelizationChanged,
+        &VoxelizationBounds,
+    )>,
+    mut pending_voxelization: ResMut<PendingVoxelization>,
+    new_gh: Res<NewGH>,
+    voxel_uniforms: Res<VoxelUniforms>,
     render_mesh_instances: Res<RenderMeshInstances>,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
+    // only the voxelization cameras, the transparent phase of voxel cameras draws gizmos and
+    // other meshes over the traced image
+    mut views: Query<(
+        &ExtractedView,
+        &mut RenderPhase<Transparent3d>,
+        &VoxelizationCamera,
+    )>,
     render_graph_settings: Res<RenderGraphSettings>,
+    voxelization_debug: Res<VoxelizationDebug>,
 ) {
+    // A new voxel world replaces the voxels of static meshes, and a changed debug override
+    // has to be written over them
+    let new_world = matches!(new_gh.as_ref(), NewGH::Some(_));
+    let revoxelize = new_world || voxelization_debug.is_changed();
+    pending_voxelization.retain(|entity| material_meshes.contains(*entity));
+    for (entity, voxelization_material, changed, _) in &material_meshes {
+        if voxelization_material.mode != VoxelizationMode::EveryFrame && (changed.0 || revoxelize) {
+            pending_voxelization.insert(entity);
+        }
+    }
+
+    // changes stay pending while voxelization is off
     if !render_graph_settings.voxelization {
         return;
     }
@@ -326,12 +670,38 @@ fn queue_custom(
         .get_id::<DrawCustom>()
         .unwrap();
 
-    let key = MeshPipelineKey::from_primitive_topology(PrimitiveTopology::TriangleList);
+    let mesh_key = MeshPipelineKey::from_primitive_topology(PrimitiveTopology::TriangleList);
 
-    for (view, mut transparent_phase) in &mut views {
+    // Meshes entirely outside the world have nothing to write, pending ones stay pending until
+    // they are inside it
+    let half_size = voxel_uniforms.texture_size as f32 / 2.0 / voxel_uniforms.voxels_per_meter;
+    let world_min = voxel_uniforms.origin - half_size;
+    let world_max = voxel_uniforms.origin + half_size;
+    let in_world = |bounds: &VoxelizationBounds| match bounds.0 {
+        Some((min, max)) => min.cmple(world_max).all() && max.cmpge(world_min).all(),
+        None => true,
+    };
+
+    let mut voxelized = Vec::new();
+
+    for (view, mut transparent_phase, voxelization_camera) in &mut views {
         let rangefinder = view.rangefinder3d();
 
-         for entity in &material_meshes {
+         for (entity, voxelization_material, _, bounds) in &material_meshes {
+            if voxelization_material.mode != VoxelizationMode::EveryFrame
+                && !pending_voxelization.contains(&entity)
+            {
+                continue;
+            }
+
+            if !in_world(bounds) {
+                continue;
+            }
+
+            if !voxelization_material.axes.contains(voxelization_camera.axis) {
+                continue;
+            }
+
             let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
                 continue;
             };
@@ -339,10 +709,18 @@ fn queue_custom(
                 continue;
             };
 
+            let key = VoxelizationPipelineKey {
+                mesh_key,
+                filtering: voxelization_material.filter,
+            };
             let pipeline = pipelines
                 .specialize(&mut pipeline_cache, &custom_pipeline, key, &mesh.layout)
                 .unwrap();
 
+            if pipeline_cache.get_render_pipeline(pipeline).is_some() {
+                voxelized.push(entity);
+            }
+
             transparent_phase.add(Transparent3d {
                 entity,
                 pipeline,
@@ -354,75 +732,196 @@ fn queue_custom(
             });
         }
     }
+
+    // The voxel world bind group is only replaced after queueing, so meshes queued for a new
+    // world are drawn again next frame
+    if !new_world {
+        for entity in voxelized {
+            pending_voxelization.remove(&entity);
+        }
+    }
 }
 
 #[derive(Component, Deref, DerefMut)]
 struct VoxelizationBindGroup(BindGroup);
 
-#[derive(Resource, Deref, DerefMut)]
-struct VoxelizationUniformsResource(HashMap<Entity, UniformBuffer<VoxelizationUniforms>>);
+/// Materials with equal uniforms and texture share a bind group
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct VoxelizationMaterialKey {
+    material: u32,
+    flags: u32,
+    vertex_colors: u32,
+    alpha_cutoff: u32,
+    thickness: u32,
+    normals: u32,
+    dither: u32,
+    direct_color: u32,
+    color: [u32; 4],
+    texture: Option<AssetId<Image>>,
+    filter: bool,
+}
 
+impl VoxelizationMaterialKey {
+    fn new(uniforms: &VoxelizationUniforms, voxelization_material: &VoxelizationMaterial) -> Self {
+        let texture = match &voxelization_material.material {
+            VoxelizationMaterialType::Texture(texture) => Some(texture.id()),
+            _ => None,
+        };
+        Self {
+            material: uniforms.material,
+            flags: uniforms.flags,
+            vertex_colors: uniforms.vertex_colors,
+            alpha_cutoff: uniforms.alpha_cutoff.to_bits(),
+            thickness: uniforms.thickness,
+            normals: uniforms.normals,
+            dither: uniforms.dither,
+            direct_color: uniforms.direct_color,
+            color: uniforms.color.to_array().map(f32::to_bits),
+            texture,
+            filter: voxelization_material.filter,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+struct VoxelizationBindGroups {
+    uniforms: HashMap<VoxelizationMaterialKey, UniformBuffer<VoxelizationUniforms>>,
+    /// Meshes in the same batch share their mesh and bind group, so they can be drawn with a
+    /// single instanced draw
+    batches: HashMap<Entity, u32>,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn queue_bind_group(
     mut commands: Commands,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     voxelization_materials: Query<(Entity, &VoxelizationMaterial)>,
+    render_mesh_instances: Res<RenderMeshInstances>,
     gpu_images: Res<RenderAssets<Image>>,
     voxelization_pipeline: Res<VoxelizationPipeline>,
     fallback_images: Res<FallbackImage>,
-    mut voxelization_uniforms: ResMut<VoxelizationUniformsResource>,
+    voxelization_debug: Res<VoxelizationDebug>,
+    mut voxelization_bind_groups: ResMut<VoxelizationBindGroups>,
 ) {
+    let voxelization_bind_groups = voxelization_bind_groups.as_mut();
+    let mut bind_groups = HashMap::new();
+    let mut batches = HashMap::new();
+    voxelization_bind_groups.batches.clear();
+
     for (entity, voxelization_material) in voxelization_materials.iter() {
-        let uniforms = voxelization_uniforms
-            .entry(entity)
-            .or_insert(UniformBuffer::from(VoxelizationUniforms::from(
-                voxelization_material,
-            )));
+        let forced_material;
+        let voxelization_material = match voxelization_debug.force_material {
+            Some(material) => {
+                forced_material = VoxelizationMaterial {
+                    material: VoxelizationMaterialType::Material(material),
+                    ..voxelization_material.clone()
+                };
+                &forced_material
+            }
+            None => voxelization_material,
+        };
 
-        uniforms.set(voxelization_material.into());
-        uniforms.write_buffer(&render_device, &render_queue);
+        let uniforms = VoxelizationUniforms::from(voxelization_material);
+        let key = VoxelizationMaterialKey::new(&uniforms, voxelization_material);
 
-        let sampler = render_device.create_sampler(&SamplerDescriptor::default());
+        let bind_group = bind_groups.entry(key.clone()).or_insert_with(|| {
+            let uniform_buffer = voxelization_bind_groups
+                .uniforms
+                .entry(key.clone())
+                .or_insert_with(|| UniformBuffer::from(uniforms.clone()));
+            uniform_buffer.set(uniforms);
+            uniform_buffer.write_buffer(&render_device, &render_queue);
 
-        let image_view =
-            if let VoxelizationMaterialType::Texture(texture) = &voxelization_material.material {
+            let (layout, sampler) = if voxelization_material.filter {
+                (
+                    &voxelization_pipeline.filtering_bind_group_layout,
+                    &voxelization_pipeline.linear_sampler,
+                )
+            } else {
+                (
+                    &voxelization_pipeline.voxelization_bind_group_layout,
+                    &voxelization_pipeline.nearest_sampler,
+                )
+            };
+
+            let image_view = if let VoxelizationMaterialType::Texture(texture) =
+                &voxelization_material.material
+            {
                 gpu_images.get(texture).unwrap_or(&fallback_images.d2)
             } else {
                 &fallback_images.d2
             };
 
-        let voxelization_bind_group = render_device.create_bind_group(
-            None,
-            &voxelization_pipeline.voxelization_bind_group_layout,
-            &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: uniforms.binding().unwrap(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(&image_view.texture_view),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::Sampler(&sampler),
-                },
-            ],
-        );
+            render_device.create_bind_group(
+                None,
+                layout,
+                &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buffer.binding().unwrap(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(&image_view.texture_view),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::Sampler(sampler),
+                    },
+                ],
+            )
+        });
 
         commands
             .entity(entity)
-            .insert(VoxelizationBindGroup(voxelization_bind_group));
+            .insert(VoxelizationBindGroup(bind_group.clone()));
+
+        let mesh = render_mesh_instances
+            .get(&entity)
+            .map(|mesh_instance| mesh_instance.mesh_asset_id);
+        let next_batch = batches.len() as u32;
+        let batch = *batches.entry((key, mesh)).or_insert(next_batch);
+        voxelization_bind_groups.batches.insert(entity, batch);
     }
 
-    let mut to_remove = Vec::new();
-    for entity in voxelization_uniforms.keys() {
-        if voxelization_materials.get(*entity).is_err() {
-            to_remove.push(*entity);
-        }
+    voxelization_bind_groups
+        .uniforms
+        .retain(|key, _| bind_groups.contains_key(key));
+}
+
+/// Groups the meshes of each batch together, the order they are voxelized in doesn't matter
+fn sort_voxelization_phases(
+    voxelization_bind_groups: Res<VoxelizationBindGroups>,
+    mut views: Query<&mut RenderPhase<Transparent3d>, With<VoxelizationCamera>>,
+) {
+    for mut transparent_phase in &mut views {
+        transparent_phase
+            .items
+            .sort_by_key(|item| voxelization_bind_groups.batches.get(&item.entity).copied());
     }
-    for entity in to_remove {
-        voxelization_uniforms.remove(&entity);
+}
+
+/// Merges consecutive meshes of the same batch into one instanced draw, once
+/// `batch_and_prepare_render_phase` has given each of them its own instance
+fn batch_voxelization_phases(
+    voxelization_bind_groups: Res<VoxelizationBindGroups>,
+    mut views: Query<&mut RenderPhase<Transparent3d>, With<VoxelizationCamera>>,
+) {
+    let batches = &voxelization_bind_groups.batches;
+    for mut transparent_phase in &mut views {
+        transparent_phase.items.dedup_by(|item, previous| {
+            let batch = batches.get(&item.entity);
+            let mergeable = batch.is_some()
+                && batch == batches.get(&previous.entity)
+                && item.pipeline == previous.pipeline
+                && item.dynamic_offset == previous.dynamic_offset
+                && item.batch_range.start == previous.batch_range.end;
+            if mergeable {
+                previous.batch_range.end = item.batch_range.end;
+            }
+            mergeable
+        });
     }
 }
 